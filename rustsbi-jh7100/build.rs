@@ -2,5 +2,11 @@
 
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
-    println!("cargo:rustc-link-arg=-Trustsbi-jh7100/src/u740.ld");
+    // guard regions must be naturally aligned powers of two for a single NAPOT PMP entry each;
+    // defined before the linker script, which derives `hart_stack_stride` from it
+    if std::env::var_os("CARGO_FEATURE_STACK_GUARD").is_some() {
+        println!("cargo:rustc-link-arg-bins=--defsym=stack_guard_size=4K");
+    }
+    // firmware binary only, host tests of `lib.rs` link as usual
+    println!("cargo:rustc-link-arg-bins=-Trustsbi-jh7100/src/u740.ld");
 }
@@ -0,0 +1,136 @@
+//! Board specific hooks
+//!
+//! A board is chosen by its `board-*` feature, JH7100 VisionFive v1 by default. Each board module
+//! exports every hook listed here; a board without special needs re-exports the default one.
+#[cfg(feature = "board-visionfive-v1")]
+mod visionfive_v1;
+#[cfg(feature = "board-visionfive-v1")]
+pub use visionfive_v1::*;
+
+#[cfg(not(any(feature = "board-visionfive-v1")))]
+compile_error!("no board selected, enable one `board-*` feature");
+
+use crate::peripheral::{Clint, Plic, Uart};
+use riscv::register::{mie, mip};
+
+// A naturally aligned power-of-two region opened to supervisor by one PMP entry, see `set_pmp`
+pub struct PmpRegion {
+    pub name: &'static str,
+    pub base: usize,
+    pub size: usize,
+    // R, W and X bits of pmpcfg entry
+    pub permission: u8,
+    // device registers rather than memory; atomics faulting here may be emulated, see
+    // feature `emulate-mmio-amo`
+    pub mmio: bool,
+}
+
+// What a hart does if its supervisor runtime ever completes, see `execute_supervisor`
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompleteAction {
+    // panic with a diagnostic message, halting this hart
+    Halt,
+    // shut the system down through SRST, with reason system failure
+    Shutdown,
+    // cold reboot through SRST, with reason system failure
+    Reboot,
+}
+
+// What a hart does after its panic report, see `panic_action`
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanicAction {
+    // halt the hart, waiting for interrupts with all of them disabled
+    Halt,
+    // warm reboot through SRST after a delay, with reason system failure
+    Reboot,
+    // run the maintenance shell on the console to inspect the hart
+    Shell,
+}
+
+// How mtimecmp is written, see `Clint::set_timer`
+pub use crate::peripheral::MtimecmpWrite;
+
+pub const PMP_RW: u8 = 0b011;
+pub const PMP_RWX: u8 = 0b111;
+
+const fn pmp_regions_napot(regions: &[PmpRegion]) -> bool {
+    let mut i = 0;
+    while i < regions.len() {
+        let size = regions[i].size;
+        if size < 8 || !size.is_power_of_two() || regions[i].base & (size - 1) != 0 {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const _: () = assert!(
+    pmp_regions_napot(PMP_REGIONS),
+    "pmp region not naturally aligned"
+);
+const _: () = assert!(BOOT_HART_ID < crate::NUM_HARTS, "boot hart out of range");
+const _: () = assert!(
+    SERVICE_HART_ID < crate::NUM_HARTS,
+    "service hart out of range"
+);
+
+// Interrupt sources of JH7100 PLIC, `riscv,ndev` in device tree
+const PLIC_NUM_SOURCES: usize = 127;
+
+// Each board also exports `PMP_REGIONS`, the memory and MMIO regions opened to supervisor, and
+// `BOOT_HART_ID`, the hart expected to arrive first from reset and claim boot; the hart which
+// claims it initializes firmware and enters the payload while other harts wait for HSM start,
+// see `boot_hart`. `SECONDARY_CHECKIN_TIMEOUT_US` is how long boot hart waits for the other
+// harts to check in before it goes on without them, and `ENTRY_DELAY_US` microseconds boot hart
+// waits right after reset before touching any peripheral, for boards whose clocks or DDR need to
+// settle on cold boot. `SERVICE_HART_ID` is the hart which runs firmware background tasks with
+// feature `service-hart`, see `tick`. `early_uart_pinmux` is called once on boot hart before the
+// console UART is first used, to route its TX and RX to the console pins through the GPIO mux.
+// `reset_cause` reads why the SoC was last reset from the board's reset cause register, or
+// returns None if there is none and the DRAM flag of `boot_reason` should decide. `CONSOLE_NODE`
+// is the device tree path of the console UART, which `earlycon` describes for supervisor.
+// `SUPERVISOR_COMPLETE_ACTION` is what a hart does if its supervisor runtime completes, which a
+// working supervisor never lets happen. `PANIC_ACTION` is what a hart does after its panic
+// report, unless feature `panic-reboot` or `panic-shell` overrides it; see `panic_action`.
+// `MEDELEG_MUTABLE` holds the `medeleg` bits supervisor may change at runtime, within the
+// exceptions `delegation` can forward; zero keeps delegation read-only. `MTIMECMP_WRITE` is how
+// the board's CLINT takes a new mtimecmp. `l2_flush_range` writes back and invalidates the
+// cache lines holding a physical range of DRAM, for supervisor drivers on a board without
+// coherent DMA, see `cache`.
+//
+// With feature `memory-fixup`, it also exports `DRAM_BASE` and `DRAM_SIZE` of the memory actually
+// fitted, which replace the `memory` node of device tree passed to supervisor. With feature
+// `dram-test`, it exports `DRAM_TEST_RANGE`, the part of DRAM tested at boot, clipped to the
+// memory node. With feature `sbi-forward`, it exports `FORWARDED_EXTENSIONS`, the SBI extension
+// IDs re-issued to a parent machine mode firmware instead of handled locally.
+
+// Default `early_uart_pinmux`, for boards whose bootrom has already muxed the console pins
+pub fn default_early_uart_pinmux() {}
+
+// Default `reset_cause`, for boards without a reset cause register
+pub fn default_reset_cause() -> Option<crate::boot_reason::BootReason> {
+    None
+}
+
+// Default `l2_flush_range`, for boards whose DMA is coherent with the caches
+#[allow(unused)]
+pub fn default_l2_flush_range(_range: core::ops::Range<usize>) {}
+
+// Default `pre_boot_quiesce`, called on each hart right before entering supervisor.
+//
+// Masks what firmware may have enabled, so that no stray interrupt reaches supervisor
+// before it installs its own handlers.
+pub fn default_pre_boot_quiesce(hart_id: usize) {
+    unsafe { Uart::preloaded_uart0() }.disable_interrupts();
+    // machine external context of each hart is 2 * hart_id, see `interrupts-extended` of PLIC
+    Plic::new(0x0c00_0000 as *mut u8).disable_all(hart_id * 2, PLIC_NUM_SOURCES);
+    Clint::new(0x2000000 as *mut u8).set_timer(hart_id, u64::MAX);
+    unsafe {
+        mie::clear_mtimer();
+        mip::clear_stimer();
+        mip::clear_ssoft();
+    }
+}
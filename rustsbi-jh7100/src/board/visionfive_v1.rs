@@ -0,0 +1,137 @@
+//! StarFive VisionFive v1 with JH7100; firmware only touches on-chip peripherals
+pub use super::default_pre_boot_quiesce as pre_boot_quiesce;
+// Console is UART3, TX on GPIO14 and RX on GPIO13, which the bootrom muxes already. A revision
+// whose bootrom leaves them as plain GPIOs programs the GPIO block at 0x1191_0000 here:
+//
+// | Offset             | Register                           | Console value
+// |:-------------------|:-----------------------------------|:----------------------------------
+// | `0x000`            | GPIOEN, enables the mux            | 1
+// | `0x050 + 8 * pin`  | output signal of `pin`             | 121 (UART3 TX) for GPIO14
+// | `0x054 + 8 * pin`  | output enable signal of `pin`      | 0 (always on) for GPIO14, 1 (off) for GPIO13
+// | `0x250 + 4 * sig`  | pin driving input signal `sig`     | pin + 2, 15 for signal 14 (UART3 RX)
+pub use super::default_early_uart_pinmux as early_uart_pinmux;
+// No reset cause register is documented for JH7100
+pub use super::default_reset_cause as reset_cause;
+use super::{CompleteAction, MtimecmpWrite, PanicAction, PmpRegion, PMP_RW, PMP_RWX};
+
+// Entries 0 to 7, or 1 to 8 behind the guard entry of feature `stack-guard`; feature
+// `pmp-allow-all` takes the next one
+pub const PMP_REGIONS: &[PmpRegion] = &[
+    PmpRegion {
+        name: "peripherals",
+        base: 0x1000_0000,
+        size: 0x800_0000,
+        permission: PMP_RW,
+        mmio: true,
+    },
+    PmpRegion {
+        name: "CLINT, cache controller",
+        base: 0x200_0000,
+        size: 0x200_0000,
+        permission: PMP_RW,
+        mmio: true,
+    },
+    PmpRegion {
+        name: "QSPI",
+        base: 0x2000_0000,
+        size: 0x2000_0000,
+        permission: PMP_RWX,
+        mmio: false,
+    },
+    PmpRegion {
+        name: "DRAM",
+        base: 0x8000_0000,
+        size: 0x8000_0000,
+        permission: PMP_RWX,
+        mmio: false,
+    },
+    PmpRegion {
+        name: "DRAM",
+        base: 0x1_0000_0000,
+        size: 0x1_0000_0000,
+        permission: PMP_RWX,
+        mmio: false,
+    },
+    PmpRegion {
+        name: "DRAM",
+        base: 0x2_0000_0000,
+        size: 0x8000_0000,
+        permission: PMP_RWX,
+        mmio: false,
+    },
+    PmpRegion {
+        name: "PLIC",
+        base: 0xc00_0000,
+        size: 0x400_0000,
+        permission: PMP_RWX,
+        mmio: true,
+    },
+    PmpRegion {
+        name: "internal RAM, ROM",
+        base: 0x1800_0000,
+        size: 0x800_0000,
+        permission: PMP_RWX,
+        mmio: false,
+    },
+];
+
+// both U74 cores can boot; hart 0 is released first and claims boot
+pub const BOOT_HART_ID: usize = 0;
+
+// boot hart also runs firmware background tasks, with feature `service-hart`
+pub const SERVICE_HART_ID: usize = BOOT_HART_ID;
+
+// secondary U74 checks in within microseconds of boot hart's roll call; one second means dead
+pub const SECONDARY_CHECKIN_TIMEOUT_US: u64 = 1_000_000;
+
+// no settle time needed so far; raise it if cold boots turn out flaky
+pub const ENTRY_DELAY_US: u64 = 0;
+
+// VisionFive v1 is fitted with 8GiB LPDDR4
+#[cfg(feature = "memory-fixup")]
+pub const DRAM_BASE: usize = 0x8000_0000;
+#[cfg(feature = "memory-fixup")]
+pub const DRAM_SIZE: usize = 0x2_0000_0000;
+
+// Whole DRAM; testing 8GiB takes minutes, narrow it for quicker boots
+#[cfg(feature = "dram-test")]
+pub const DRAM_TEST_RANGE: core::ops::Range<usize> = 0x8000_0000..0x2_8000_0000;
+
+// SiFive CLINT of U74 takes a 64-bit store to mtimecmp as a whole
+pub const MTIMECMP_WRITE: MtimecmpWrite = MtimecmpWrite::Single;
+
+// Console UART0, `serial0` of the device tree
+pub const CONSOLE_NODE: &str = "/soc/serial@12440000";
+
+// A completed supervisor runtime is a firmware bug; stop there for it to be looked at
+pub const SUPERVISOR_COMPLETE_ACTION: CompleteAction = CompleteAction::Halt;
+
+// Keep a panicked hart as it is for a debugger or a look at the console
+pub const PANIC_ACTION: PanicAction = PanicAction::Halt;
+
+// Delegation stays as firmware set it up; list exceptions here to let supervisor take them back
+pub const MEDELEG_MUTABLE: usize = 0;
+
+// VisionFive v1 boots this firmware straight from the second stage loader, with no machine mode
+// firmware below it; list extension IDs here when layering it over a parent SBI
+#[cfg(feature = "sbi-forward")]
+pub const FORWARDED_EXTENSIONS: &[usize] = &[];
+
+// SiFive L2 cache controller of JH7100 at 0x0201_0000, in the CLINT region of `PMP_REGIONS`; a
+// physical address written to its Flush64 register writes back and invalidates the line holding
+// it, along with any copy in the U74 L1 data caches
+const L2_FLUSH64: usize = 0x0201_0200;
+const L2_LINE_SIZE: usize = 64;
+
+// JH7100 DMA bypasses the caches, drivers flush buffers through `cache`
+pub fn l2_flush_range(range: core::ops::Range<usize>) {
+    let start = range.start & !(L2_LINE_SIZE - 1);
+    unsafe {
+        // stores before the call reach the cache before their lines are flushed
+        core::arch::asm!("fence rw, rw");
+        for line in (start..range.end).step_by(L2_LINE_SIZE) {
+            core::ptr::write_volatile(L2_FLUSH64 as *mut u64, line as u64);
+        }
+        core::arch::asm!("fence rw, rw");
+    }
+}
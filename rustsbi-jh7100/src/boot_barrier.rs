@@ -0,0 +1,108 @@
+//! Startup barrier between boot hart and secondary harts
+//!
+//! A secondary hart may reach `rust_main` before boot hart has cleared bss, so it cannot record
+//! itself right after reset. Once firmware is initialized, boot hart raises a machine software
+//! interrupt on each secondary as a roll call; a secondary waits for it, checks in, and only then
+//! parks in `hsm::pause` until HSM starts it.
+//!
+//! Boot hart waits at most `board::SECONDARY_CHECKIN_TIMEOUT_US` for all check-ins. A secondary
+//! which never checks in is reported and left out; boot goes on with the harts that arrived.
+//!
+//! With feature `hart-self-test`, a secondary runs `hart_test` before checking in. One that fails
+//! checks in as failed and parks for good; it is never started by HSM. Boot hart stops the boot
+//! on such a hart, or with feature `degraded-boot` reports it and goes on without it.
+use crate::peripheral::Clint;
+use crate::println;
+use crate::smp;
+use crate::NUM_HARTS;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use riscv::register::{mie, mip};
+
+// Bit `i` set if hart `i` has checked in; boot hart sets its own bit
+static PRESENT_HARTS: AtomicUsize = AtomicUsize::new(0);
+// Bit `i` set if hart `i` checked in but failed its self-test
+static FAILED_HARTS: AtomicUsize = AtomicUsize::new(0);
+// `HartTestError` code of each failed hart, written before its bit in `FAILED_HARTS`
+const NO_FAILURE: AtomicU8 = AtomicU8::new(0);
+static FAILURES: [AtomicU8; NUM_HARTS] = [NO_FAILURE; NUM_HARTS];
+
+// Called on each secondary hart before it parks; returns after boot hart's roll call, or never if
+// the hart fails its self-test
+pub fn check_in(hart_id: usize) {
+    let clint = Clint::new(0x2000000 as *mut u8);
+    unsafe {
+        // msip is zero from reset, a pending one is the roll call; do not clear it before waiting
+        let prev_msoft = mie::read().msoft();
+        mie::set_msoft();
+        while !mip::read().msoft() {
+            riscv::asm::wfi();
+        }
+        if !prev_msoft {
+            mie::clear_msoft();
+        }
+    }
+    clint.clear_soft(hart_id);
+    #[cfg(feature = "hart-self-test")]
+    if let Err(e) = crate::hart_test::run(hart_id) {
+        FAILURES[hart_id].store(e as u8, Ordering::Relaxed);
+        FAILED_HARTS.fetch_or(1 << hart_id, Ordering::Release);
+        PRESENT_HARTS.fetch_or(1 << hart_id, Ordering::Release);
+        park_failed();
+    }
+    PRESENT_HARTS.fetch_or(1 << hart_id, Ordering::Release);
+}
+
+// A hart which failed its self-test takes no interrupt and runs nothing until reset
+fn park_failed() -> ! {
+    unsafe {
+        core::arch::asm!("csrw mie, zero");
+        loop {
+            riscv::asm::wfi();
+        }
+    }
+}
+
+// Called on boot hart after bss and console are initialized; returns bitmask of usable harts,
+// those present and not failed
+pub fn wait_for_secondaries(boot_hart_id: usize) -> usize {
+    PRESENT_HARTS.fetch_or(1 << boot_hart_id, Ordering::Release);
+    let secondaries = ((1 << NUM_HARTS) - 1) & !(1 << boot_hart_id);
+    let timeout_us = crate::board::SECONDARY_CHECKIN_TIMEOUT_US;
+    let present = smp::broadcast_and_wait(secondaries, &PRESENT_HARTS, timeout_us);
+    // roll calls of missing harts stay pending; a hart arriving late still checks in and parks
+    for hart_id in smp::missing(secondaries, present) {
+        println!(
+            "[rustsbi] warning: hart {} did not check in within {} us, continue without it",
+            hart_id, timeout_us
+        );
+    }
+    let failed = FAILED_HARTS.load(Ordering::Acquire);
+    #[cfg(feature = "hart-self-test")]
+    for hart_id in (0..NUM_HARTS).filter(|id| failed & (1 << id) != 0) {
+        let reason =
+            crate::hart_test::HartTestError::from_code(FAILURES[hart_id].load(Ordering::Relaxed))
+                .map_or("unknown failure", |e| e.name());
+        if cfg!(not(feature = "degraded-boot")) {
+            panic!("hart {} failed self-test, {}", hart_id, reason)
+        }
+        println!(
+            "[rustsbi] warning: hart {} failed self-test, {}; continue without it",
+            hart_id, reason
+        );
+    }
+    let usable = present & !failed;
+    println!(
+        "[rustsbi] {} of {} harts usable, {} degraded",
+        usable.count_ones(),
+        NUM_HARTS,
+        NUM_HARTS - usable.count_ones() as usize
+    );
+    usable
+}
+
+// Whether given hart checked in at boot and passed its self-test; false before the barrier has
+// completed
+pub fn is_present(hart_id: usize) -> bool {
+    let usable = PRESENT_HARTS.load(Ordering::Acquire) & !FAILED_HARTS.load(Ordering::Acquire);
+    hart_id < NUM_HARTS && usable & (1 << hart_id) != 0
+}
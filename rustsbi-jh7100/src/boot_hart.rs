@@ -0,0 +1,69 @@
+//! Boot hart claim
+//!
+//! Harts reach `rust_main` in whatever order the SoC releases them from reset. Exactly one of
+//! them must run the one-time initialization, i.e. clear bss, set up heap and console, prepare
+//! device tree and payload, and enter the payload; the others must not touch any of it before
+//! that is done. The claiming protocol:
+//!
+//! 1. Every hart, right after its stack is checked, calls `claim`, which swaps `BOOT_HART` from
+//!    `UNCLAIMED` to its hart ID with a single compare-and-swap. The payload runs in supervisor
+//!    mode on boot hart, thus a hart whose `misa` lacks S does not try, and is always secondary.
+//! 2. The one hart whose swap succeeds is boot hart. It runs the initialization, then sends the
+//!    others a roll call, see `boot_barrier`.
+//! 3. Every other hart sees the swap fail and is secondary, however early it arrived. It writes
+//!    nothing shared, and waits in `boot_barrier::check_in` for the roll call; it parks in
+//!    `hsm::pause` until HSM starts it.
+//!
+//! `BOOT_HART` is kept in .data, as boot hart clears .bss after it has claimed; a hart arriving
+//! late must still see the claim. Like `RELOCATED` of feature `self-relocate`, this relies on
+//! the loader writing a fresh image on each boot.
+//!
+//! `board::BOOT_HART_ID` is the hart expected to arrive first, which is boot hart on a normal
+//! boot; if another hart wins, it boots the payload instead, and `board::BOOT_HART_ID` is started
+//! by HSM like any other secondary. `id` tells which hart won. With feature
+//! `reverse-boot-order`, for testing, `board::BOOT_HART_ID` holds back its claim until another
+//! hart has claimed, or for at most `board::SECONDARY_CHECKIN_TIMEOUT_US`, so that the other hart
+//! boots as if released first.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const UNCLAIMED: usize = usize::MAX;
+
+// Hart ID of boot hart, or `UNCLAIMED`; kept in .data, as it is written before bss is cleared
+#[link_section = ".data.boot_hart"]
+static BOOT_HART: AtomicUsize = AtomicUsize::new(UNCLAIMED);
+
+// Claim boot for current hart; returns true on the one hart which is boot hart, never on a hart
+// without supervisor mode
+pub fn claim(hart_id: usize) -> bool {
+    if !riscv::register::misa::read().map_or(false, |isa| isa.has_extension('S')) {
+        return false;
+    }
+    #[cfg(feature = "reverse-boot-order")]
+    if hart_id == crate::board::BOOT_HART_ID {
+        wait_for_other_claim();
+    }
+    BOOT_HART
+        .compare_exchange(UNCLAIMED, hart_id, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+}
+
+// Boot hart; `board::BOOT_HART_ID` before any hart has claimed
+pub fn id() -> usize {
+    match BOOT_HART.load(Ordering::Acquire) {
+        UNCLAIMED => crate::board::BOOT_HART_ID,
+        hart_id => hart_id,
+    }
+}
+
+// Wait on mtime, whose frequency is only assumed this early; the timeout needs a running mtime
+#[cfg(feature = "reverse-boot-order")]
+fn wait_for_other_claim() {
+    let clint = crate::peripheral::Clint::new(0x2000000 as *mut u8);
+    let timeout = clint.us_to_ticks(crate::board::SECONDARY_CHECKIN_TIMEOUT_US);
+    let start = clint.get_mtime();
+    while BOOT_HART.load(Ordering::Acquire) == UNCLAIMED
+        && clint.get_mtime().wrapping_sub(start) < timeout
+    {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,65 @@
+//! Boot reason
+//!
+//! Tells a fresh power-on from a warm reset, after which DRAM contents may still be trusted.
+//! The board's reset cause register is read first, see `board::reset_cause`. A board without one
+//! falls back to a flag firmware leaves in its scratch DRAM on every boot: DRAM which held the
+//! flag through the reset was retained, so the boot is warm; garbage after power-on reads cold.
+//! With neither, or when DRAM test has overwritten the flag before it is read, the reason is
+//! unknown.
+use crate::board;
+use crate::scratch::{self, SLOT_BOOT_STATE};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootReason {
+    Cold,
+    Warm,
+    Unknown,
+}
+
+impl BootReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            BootReason::Cold => "cold",
+            BootReason::Warm => "warm",
+            BootReason::Unknown => "unknown",
+        }
+    }
+}
+
+// "RSBIBOOT", the chance that DRAM after power-on holds it by accident is negligible
+const MAGIC: u64 = 0x5253_4249_424f_4f54;
+
+#[repr(C)]
+struct BootState {
+    magic: u64,
+    // boots in a row found with the flag in place
+    warm_boots: u64,
+}
+
+// Find out the boot reason, and leave the flag for the next boot; called once on boot hart
+// after scratch region is initialized. Returns the reason and the count of warm boots in a row.
+pub fn detect() -> (BootReason, u64) {
+    let state = match unsafe { scratch::slot(SLOT_BOOT_STATE) } {
+        // slots are aligned to their offset in 2MiB aligned scratch region
+        Some(buf) => unsafe { &mut *(buf.as_mut_ptr() as *mut BootState) },
+        None => return (board::reset_cause().unwrap_or(BootReason::Unknown), 0),
+    };
+    let retained = unsafe { core::ptr::read_volatile(&state.magic) } == MAGIC;
+    let warm_boots = if retained {
+        unsafe { core::ptr::read_volatile(&state.warm_boots) }.wrapping_add(1)
+    } else {
+        0
+    };
+    unsafe {
+        core::ptr::write_volatile(&mut state.magic, MAGIC);
+        core::ptr::write_volatile(&mut state.warm_boots, warm_boots);
+    }
+    let from_flag = if cfg!(feature = "dram-test") {
+        BootReason::Unknown
+    } else if retained {
+        BootReason::Warm
+    } else {
+        BootReason::Cold
+    };
+    (board::reset_cause().unwrap_or(from_flag), warm_boots)
+}
@@ -0,0 +1,165 @@
+//! Firmware build configuration query
+//!
+//! Vendor extension `EXTENSION_BUILD_INFO`, function 0 returns `SBI_SUCCESS` in a0 and a bitmap
+//! of what this firmware was built with in a1, so that a kernel or test can adapt to it. Unlike
+//! base extension probe, it also reports instruction emulation and other firmware features.
+//! Function 1 returns the mtime frequency firmware uses in a1, which is the `timebase-frequency`
+//! of device tree, or `DEFAULT_TIMEBASE_FREQUENCY` if that is missing or out of range.
+//!
+//! Bit assignments are stable; a bit is never reused, unassigned bits read as zero.
+//!
+//! | Bit | Meaning                                              | Source
+//! |:----|:-----------------------------------------------------|:-------------------------
+//! | 0   | Timer extension                                      | probe
+//! | 1   | IPI extension                                        | probe
+//! | 2   | RFENCE extension                                     | probe
+//! | 3   | HSM extension                                        | probe
+//! | 4   | SRST extension                                       | probe
+//! | 5   | PMU extension                                        | probe
+//! | 6   | DBCN extension                                       | probe
+//! | 7   | FWFT extension                                       | probe
+//! | 16  | `rdtime` emulation                                   | always
+//! | 17  | misaligned load and store emulation                  | always
+//! | 18  | Zawrs `wrs.nto`, `wrs.sto` emulation                 | feature `emulate-zawrs`
+//! | 19  | Zicond `czero.eqz`, `czero.nez` emulation            | feature `emulate-zicond`
+//! | 20  | compressed instruction emulation on harts without C  | feature `rvc-emulation`
+//! | 21  | lr, sc and amo emulation on MMIO                     | feature `emulate-mmio-amo`
+//! | 22  | `sstatus` access emulation                           | feature `emulate-sstatus`
+//! | 23  | common Zbb instruction emulation                     | feature `bitmanip-emulation`
+//! | 24  | fixed `cycle` and `instret` reads                    | feature `fixed-counters`
+//! | 25  | `sret` emulation under `mstatus.TSR`                 | feature `emulate-sret`
+//! | 32  | IPI doorbell                                         | feature `ipi-doorbell`
+//! | 33  | hang watchdog                                        | feature `hang-watchdog`
+//! | 34  | firmware timer tick                                  | feature `firmware-timer`
+//! | 35  | SBI call and delegated trap trace                    | feature `sbi-trace`
+//! | 36  | diagnostics vendor extension                         | feature `diagnostics`
+//! | 37  | supervisor checkpoint                                | feature `checkpoint`
+//! | 38  | memory node fixup                                    | feature `memory-fixup`
+//! | 39  | catch-all PMP region                                 | feature `pmp-allow-all`
+//! | 40  | boot handoff structure                               | feature `handoff-info`
+//! | 41  | self-relocation from any load address                | feature `self-relocate`
+//! | 42  | SBI calls forwarded to a parent firmware             | feature `sbi-forward`
+//! | 43  | SBI call latency histograms                          | feature `ecall-latency`
+//! | 44  | console fallback to in-memory log                    | feature `console-fallback`
+//! | 45  | background tasks on a single service hart            | feature `service-hart`
+//! | 46  | A/B payload slots                                    | feature `payload-slots`
+//! | 47  | PMP guard regions below firmware stacks              | feature `stack-guard`
+//! | 48  | firmware tables passed from the image                | feature `firmware-tables`
+//! | 49  | heap allocations zeroed                              | feature `zero-heap`
+//!
+//! Extension bits are what base extension probe would answer for the same extension on the
+//! calling hart, they never disagree.
+use rustsbi::SbiRet;
+
+const FUNCTION_GET_BUILD_CONFIG: usize = 0x0;
+const FUNCTION_GET_TIMEBASE_FREQUENCY: usize = 0x1;
+
+const EXTENSION_BASE: usize = 0x10;
+const FUNCTION_BASE_PROBE_EXTENSION: usize = 0x3;
+
+// SBI extensions reported by bits 0 to 7, in bit order
+const PROBED_EXTENSIONS: [usize; 8] = [
+    0x5449_4D45, // Timer
+    0x73_5049,   // IPI
+    0x5246_4E43, // RFENCE
+    0x48_534D,   // HSM
+    0x5352_5354, // SRST
+    0x504D_55,   // PMU
+    0x4442_434E, // DBCN
+    0x4657_4654, // FWFT
+];
+
+const EMULATE_RDTIME: usize = 1 << 16;
+const EMULATE_MISALIGNED: usize = 1 << 17;
+const EMULATE_ZAWRS: usize = 1 << 18;
+const EMULATE_ZICOND: usize = 1 << 19;
+const EMULATE_RVC: usize = 1 << 20;
+const EMULATE_MMIO_AMO: usize = 1 << 21;
+const EMULATE_SSTATUS: usize = 1 << 22;
+const EMULATE_BITMANIP: usize = 1 << 23;
+const FIXED_COUNTERS: usize = 1 << 24;
+const EMULATE_SRET: usize = 1 << 25;
+const IPI_DOORBELL: usize = 1 << 32;
+const HANG_WATCHDOG: usize = 1 << 33;
+const FIRMWARE_TIMER: usize = 1 << 34;
+const SBI_TRACE: usize = 1 << 35;
+const DIAGNOSTICS: usize = 1 << 36;
+const CHECKPOINT: usize = 1 << 37;
+const MEMORY_FIXUP: usize = 1 << 38;
+const PMP_ALLOW_ALL: usize = 1 << 39;
+const HANDOFF_INFO: usize = 1 << 40;
+const SELF_RELOCATE: usize = 1 << 41;
+const SBI_FORWARD: usize = 1 << 42;
+const ECALL_LATENCY: usize = 1 << 43;
+const CONSOLE_FALLBACK: usize = 1 << 44;
+const SERVICE_HART: usize = 1 << 45;
+const PAYLOAD_SLOTS: usize = 1 << 46;
+const STACK_GUARD: usize = 1 << 47;
+const FIRMWARE_TABLES: usize = 1 << 48;
+const ZERO_HEAP: usize = 1 << 49;
+
+// Firmware capabilities fixed at build time, as (bit, whether built with it)
+const BUILT_WITH: [(usize, bool); 28] = [
+    (EMULATE_RDTIME, true),
+    (EMULATE_MISALIGNED, true),
+    (EMULATE_ZAWRS, cfg!(feature = "emulate-zawrs")),
+    (EMULATE_ZICOND, cfg!(feature = "emulate-zicond")),
+    (EMULATE_RVC, cfg!(feature = "rvc-emulation")),
+    (EMULATE_MMIO_AMO, cfg!(feature = "emulate-mmio-amo")),
+    (EMULATE_SSTATUS, cfg!(feature = "emulate-sstatus")),
+    (EMULATE_BITMANIP, cfg!(feature = "bitmanip-emulation")),
+    (FIXED_COUNTERS, cfg!(feature = "fixed-counters")),
+    (EMULATE_SRET, cfg!(feature = "emulate-sret")),
+    (IPI_DOORBELL, cfg!(feature = "ipi-doorbell")),
+    (HANG_WATCHDOG, cfg!(feature = "hang-watchdog")),
+    (FIRMWARE_TIMER, cfg!(feature = "firmware-timer")),
+    (SBI_TRACE, cfg!(feature = "sbi-trace")),
+    (DIAGNOSTICS, cfg!(feature = "diagnostics")),
+    (CHECKPOINT, cfg!(feature = "checkpoint")),
+    (MEMORY_FIXUP, cfg!(feature = "memory-fixup")),
+    (PMP_ALLOW_ALL, cfg!(feature = "pmp-allow-all")),
+    (HANDOFF_INFO, cfg!(feature = "handoff-info")),
+    (SELF_RELOCATE, cfg!(feature = "self-relocate")),
+    (SBI_FORWARD, cfg!(feature = "sbi-forward")),
+    (ECALL_LATENCY, cfg!(feature = "ecall-latency")),
+    (CONSOLE_FALLBACK, cfg!(feature = "console-fallback")),
+    (SERVICE_HART, cfg!(feature = "service-hart")),
+    (PAYLOAD_SLOTS, cfg!(feature = "payload-slots")),
+    (STACK_GUARD, cfg!(feature = "stack-guard")),
+    (FIRMWARE_TABLES, cfg!(feature = "firmware-tables")),
+    (ZERO_HEAP, cfg!(feature = "zero-heap")),
+];
+
+// Handler of vendor extension `EXTENSION_BUILD_INFO`
+pub fn handle_ecall(function: usize, _param: [usize; 6]) -> SbiRet {
+    match function {
+        FUNCTION_GET_BUILD_CONFIG => SbiRet::ok(build_config()),
+        FUNCTION_GET_TIMEBASE_FREQUENCY => {
+            SbiRet::ok(crate::peripheral::timebase_frequency() as usize)
+        }
+        _ => SbiRet::not_supported(),
+    }
+}
+
+fn build_config() -> usize {
+    let mut bitmap = 0;
+    for (bit, extension) in PROBED_EXTENSIONS.iter().enumerate() {
+        if probe(*extension) {
+            bitmap |= 1 << bit;
+        }
+    }
+    for (bit, built) in BUILT_WITH {
+        if built {
+            bitmap |= bit;
+        }
+    }
+    bitmap
+}
+
+// Same answer as base extension probe from supervisor, including extensions handled in firmware
+fn probe(extension: usize) -> bool {
+    let param = [extension, 0, 0, 0, 0, 0];
+    let ans = crate::fwft::handle_ecall(EXTENSION_BASE, FUNCTION_BASE_PROBE_EXTENSION, param)
+        .unwrap_or_else(|| rustsbi::ecall(EXTENSION_BASE, FUNCTION_BASE_PROBE_EXTENSION, param));
+    ans.value != 0
+}
@@ -0,0 +1,40 @@
+//! Cache maintenance for supervisor
+//!
+//! JH7100 has no coherent DMA: devices read and write DRAM behind the U74 caches, and a driver
+//! must write back and invalidate the lines a buffer occupies around every transfer. The L2
+//! cache controller which does it sits in machine mode MMIO on some boards, and its register
+//! layout is board specific; vendor extension `EXTENSION_CACHE` gives supervisor one call for it:
+//!
+//! | Function | Parameters                        | Returns
+//! |:---------|:----------------------------------|:---------
+//! | 0        | a0: physical address, a1: length  | 0; lines over `[a0, a0 + a1)` written back and invalidated
+//!
+//! The range is widened to whole cache lines; a zero length flushes nothing. Returns
+//! `SBI_ERR_INVALID_PARAM` if the range does not lie in DRAM opened to supervisor, or wraps
+//! around. The call runs to the end of the range on the calling hart, one line at a time, so a
+//! driver should flush its buffers rather than all of DRAM. See `board::l2_flush_range`.
+use rustsbi::SbiRet;
+
+const FUNCTION_FLUSH_DCACHE_RANGE: usize = 0x0;
+
+// Handler of vendor extension `EXTENSION_CACHE`
+pub fn handle_ecall(function: usize, param: [usize; 6]) -> SbiRet {
+    match function {
+        FUNCTION_FLUSH_DCACHE_RANGE => flush_dcache_range(param[0], param[1]),
+        _ => SbiRet::not_supported(),
+    }
+}
+
+fn flush_dcache_range(paddr: usize, len: usize) -> SbiRet {
+    let end = match paddr.checked_add(len) {
+        Some(end) => end,
+        None => return SbiRet::invalid_param(),
+    };
+    if paddr < crate::DRAM_PMP_START || end > crate::DRAM_PMP_END {
+        return SbiRet::invalid_param();
+    }
+    if len != 0 {
+        crate::board::l2_flush_range(paddr..end);
+    }
+    SbiRet::ok(0)
+}
@@ -0,0 +1,169 @@
+//! Supervisor state checkpoint and restore
+//!
+//! For checkpointing experiments: the state of the supervisor running on current hart is
+//! serialized into a supervisor buffer at an ecall boundary, and written back later, through
+//! vendor extension `EXTENSION_CHECKPOINT`:
+//!
+//! - function `FUNCTION_SAVE` takes the buffer address in a0 and its length in a1, at least
+//!   `CHECKPOINT_SIZE` bytes, and returns 0 in a1;
+//! - function `FUNCTION_RESTORE` takes a buffer written by `FUNCTION_SAVE`. It does not return
+//!   on success; instead the call which saved the checkpoint returns again, with 1 in a1.
+//!
+//! The buffer is accessed with supervisor's translation and protection, and must lie in DRAM
+//! opened to supervisor. Errors are `SBI_ERR_INVALID_PARAM` for a short buffer or one which holds
+//! no checkpoint, and `SBI_ERR_INVALID_ADDRESS` for a buffer firmware may not access.
+//!
+//! Captured: general registers x1 to x31, `mstatus` (including all `sstatus` fields), `mepc`
+//! as supervisor pc, and supervisor CSRs `sie`, `sip`, `stvec`, `sscratch`, `sepc`, `scause`,
+//! `stval`, `satp` and `scounteren`. Of `mstatus` only the `sstatus` fields are restored, and
+//! the restored state always runs in supervisor mode: a buffer is supervisor memory, and must not
+//! bring back machine mode fields such as MPP, MPRV, TVM or TSR.
+//!
+//! Not captured: memory of any kind, floating point registers, supervisor timer deadline,
+//! pending machine interrupts, HSM state, and translation caches, which are flushed on restore.
+//!
+//! Buffer format is an array of native endian `usize` words: magic, word count, then the
+//! captured registers in above order.
+use crate::execute::{get_vaddr_u8, put_vaddr_slice, ECALL_LENGTH};
+use crate::runtime::SupervisorContext;
+use rustsbi::SbiRet;
+
+const FUNCTION_SAVE: usize = 0x0;
+const FUNCTION_RESTORE: usize = 0x1;
+
+const CHECKPOINT_MAGIC: usize = 0x5253_4249_4350_0001; // "RSBICP", format 1
+const CONTEXT_WORDS: usize = 33; // x1 to x31, mstatus, mepc; see `SupervisorContext`
+const CSR_WORDS: usize = 9;
+const HEADER_WORDS: usize = 2;
+pub const CHECKPOINT_SIZE: usize = (HEADER_WORDS + CONTEXT_WORDS + CSR_WORDS) * 8;
+
+// Index of words in `SupervisorContext`
+const A0_WORD: usize = 9;
+const A1_WORD: usize = 10;
+const MSTATUS_WORD: usize = 31;
+const MEPC_WORD: usize = 32;
+
+// `sstatus` fields of `mstatus` taken from a checkpoint: SIE, SPIE, SPP, FS, SUM and MXR
+const SSTATUS_RESTORED: usize = 1 << 1 | 1 << 5 | 1 << 8 | 0b11 << 13 | 1 << 18 | 1 << 19;
+const MSTATUS_MPP: usize = 0b11 << 11;
+const MSTATUS_MPP_SUPERVISOR: usize = 0b01 << 11;
+
+macro_rules! read_csrs {
+    ($($csr: literal),*) => {
+        [$({
+            let value: usize;
+            unsafe { core::arch::asm!(concat!("csrr {}, ", $csr), out(reg) value) };
+            value
+        }),*]
+    };
+}
+
+macro_rules! write_csrs {
+    ($values: expr, $($csr: literal),*) => {
+        let mut values = $values.iter();
+        $(unsafe { core::arch::asm!(concat!("csrw ", $csr, ", {}"), in(reg) *values.next().unwrap()) };)*
+    };
+}
+
+// Registered for base extension probe only; `execute` passes calls to `handle_ecall` instead,
+// which needs the supervisor context
+pub fn handle_probe(_function: usize, _param: [usize; 6]) -> SbiRet {
+    SbiRet::not_supported()
+}
+
+// Handler of vendor extension `EXTENSION_CHECKPOINT`; returns None after a restore, when the
+// context is that of the call which saved the checkpoint, returning past its ecall
+pub fn handle_ecall(ctx: &mut SupervisorContext) -> Option<SbiRet> {
+    let (addr, len) = (ctx.a0, ctx.a1);
+    match ctx.a6 {
+        FUNCTION_SAVE => Some(save(ctx, addr, len)),
+        FUNCTION_RESTORE => restore(ctx, addr, len).err(),
+        _ => Some(SbiRet::not_supported()),
+    }
+}
+
+// Save supervisor state of current hart into the buffer at `addr`, as it will be when the call
+// returns: past the ecall, returning 1 in a1
+fn save(ctx: &SupervisorContext, addr: usize, len: usize) -> SbiRet {
+    if len < CHECKPOINT_SIZE {
+        return SbiRet::invalid_param();
+    }
+    let mut context = unsafe { *(ctx as *const _ as *const [usize; CONTEXT_WORDS]) };
+    context[A0_WORD] = 0; // SBI_SUCCESS
+    context[A1_WORD] = 1;
+    context[MEPC_WORD] = context[MEPC_WORD].wrapping_add(ECALL_LENGTH);
+    let csrs: [usize; CSR_WORDS] = read_csrs!(
+        "sie",
+        "sip",
+        "stvec",
+        "sscratch",
+        "sepc",
+        "scause",
+        "stval",
+        "satp",
+        "scounteren"
+    );
+    let header = [CHECKPOINT_MAGIC, CONTEXT_WORDS + CSR_WORDS];
+    let words = header.iter().chain(context.iter()).chain(csrs.iter());
+    let mut buf = [0u8; CHECKPOINT_SIZE];
+    for (chunk, word) in buf.chunks_exact_mut(8).zip(words) {
+        chunk.copy_from_slice(&word.to_ne_bytes());
+    }
+    match put_vaddr_slice(addr, &buf) {
+        CHECKPOINT_SIZE => SbiRet::ok(0),
+        _ => SbiRet::invalid_address(),
+    }
+}
+
+// Restore supervisor state of current hart from the buffer at `addr` written by `save`
+fn restore(ctx: &mut SupervisorContext, addr: usize, len: usize) -> Result<(), SbiRet> {
+    if len < CHECKPOINT_SIZE {
+        return Err(SbiRet::invalid_param());
+    }
+    let mut buf = [0u8; CHECKPOINT_SIZE];
+    read_buffer(addr, &mut buf).ok_or_else(SbiRet::invalid_address)?;
+    let mut words = [0usize; HEADER_WORDS + CONTEXT_WORDS + CSR_WORDS];
+    for (word, chunk) in words.iter_mut().zip(buf.chunks_exact(8)) {
+        *word = usize::from_ne_bytes([
+            chunk[0], chunk[1], chunk[2], chunk[3], chunk[4], chunk[5], chunk[6], chunk[7],
+        ]);
+    }
+    if words[0] != CHECKPOINT_MAGIC || words[1] != CONTEXT_WORDS + CSR_WORDS {
+        return Err(SbiRet::invalid_param());
+    }
+    let (context, csrs) = words[HEADER_WORDS..].split_at_mut(CONTEXT_WORDS);
+    let target = unsafe { &mut *(ctx as *mut _ as *mut [usize; CONTEXT_WORDS]) };
+    let mstatus = target[MSTATUS_WORD] & !SSTATUS_RESTORED & !MSTATUS_MPP
+        | context[MSTATUS_WORD] & SSTATUS_RESTORED
+        | MSTATUS_MPP_SUPERVISOR;
+    context[MSTATUS_WORD] = mstatus;
+    target.copy_from_slice(context);
+    write_csrs!(
+        csrs,
+        "sie",
+        "sip",
+        "stvec",
+        "sscratch",
+        "sepc",
+        "scause",
+        "stval",
+        "satp",
+        "scounteren"
+    );
+    // restored address space may differ from the current one
+    unsafe { core::arch::asm!("sfence.vma") };
+    crate::runtime::check_supervisor_entry(ctx);
+    Ok(())
+}
+
+// Read supervisor buffer at virtual address `addr`, as `put_vaddr_slice` writes one
+fn read_buffer(addr: usize, buf: &mut [u8]) -> Option<()> {
+    match addr.checked_add(buf.len()) {
+        Some(end) if addr >= crate::DRAM_PMP_START && end <= crate::DRAM_PMP_END => {}
+        _ => return None,
+    }
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = unsafe { get_vaddr_u8(addr + i) }?;
+    }
+    Some(())
+}
@@ -0,0 +1,464 @@
+//! Firmware console with boot-time and runtime backends
+//!
+//! Early boot writes every byte to UART and waits until it is sent. After runtime is initialized,
+//! `switch_backend` moves the console to a ring buffer backend: bytes are queued and drained to
+//! UART whenever the transmitter is ready, and on machine timer ticks. `println!` of RustSBI goes
+//! through this console in both stages, thus callers never change.
+//!
+//! Panic reports of different harts are serialized with `begin_panic_report`, so that two harts
+//! panicking at the same time do not interleave their messages.
+//!
+//! Firmware's own messages are printed with this crate's `println!`, which supervisor may silence
+//! through vendor extension `EXTENSION_CONSOLE_CONTROL`, e.g. during a benchmark. Output of
+//! supervisor itself is never silenced, and neither are panic reports nor the system halt line.
+//!
+//! Each `println!` line is cut off after `MAX_LINE_LEN` bytes of message, marked by `TRUNCATED`,
+//! so that a corrupt value formatted into a message cannot keep a hart printing for minutes at
+//! 115200 baud. Formatting stops at the cut. Panic report lines have the higher cap
+//! `MAX_PANIC_LINE_LEN`, to keep the panic message and its location whole. Supervisor output is
+//! passed on as it comes, and never cut.
+//!
+//! Console UART is given up on once its transmitter holding register has stayed busy for
+//! `STALL_TIMEOUT_US` and `STALL_POLLS` polls in a row, e.g. when UART is not where
+//! `preloaded_uart0` expects it. A working UART at any usual baud rate empties its FIFO within
+//! milliseconds, and the poll count keeps a single late poll, e.g. after a long interrupt, from
+//! counting as a stall. While mtime does not advance, e.g. not yet clocked,
+//! `STALL_POLLS_WITHOUT_MTIME` busy polls alone count as a stall. Giving up is one way, noted
+//! once, and reported by function 1 of `EXTENSION_CONSOLE_CONTROL`. A bus that reads the line
+//! status as all ones looks like a working UART.
+//!
+//! Output after that is dropped, unless feature `console-fallback` keeps it in the in-memory log
+//! of `memory_log`, in scratch slot `SLOT_LOG`, or in the early buffer of that log in firmware
+//! memory while the scratch region is not up. The note goes at the start of the log; without
+//! it, the note is offered to UART once without waiting, in case the transmitter recovers.
+//!
+//! A line written to UART directly, past a console lock that is never released, see
+//! `write_line_exclusive`, gives up on a byte busy as long and drops the rest of the line; it
+//! cannot switch to the log, which belongs to the lock holder.
+//! Semihosting is not used, its `ebreak` traps into firmware itself unless a debugger is attached.
+#[cfg(feature = "console-fallback")]
+use crate::memory_log::MemoryLog;
+use crate::peripheral::{Clint, Uart};
+use core::convert::Infallible;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embedded_hal::serial::{Read, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    // Write byte to UART and wait until the transmitter is empty
+    Polling,
+    // Queue bytes in ring buffer, send them when the transmitter is ready
+    Buffered,
+}
+
+const RING_BUFFER_SIZE: usize = 1024;
+
+struct ConsoleState {
+    uart: Option<Uart>,
+    backend: Backend,
+    ring: [u8; RING_BUFFER_SIZE],
+    head: usize, // index of next byte to send
+    len: usize,
+    stall: Stall,
+    #[cfg(feature = "console-fallback")]
+    log: MemoryLog,
+}
+
+// A transmitter busy this long and for this many polls in a row is taken as stuck, 100ms
+const STALL_TIMEOUT_US: u64 = 100_000;
+const STALL_POLLS: usize = 1000;
+// Busy polls taken as a stuck transmitter without the help of mtime, a second or more
+const STALL_POLLS_WITHOUT_MTIME: usize = 10_000_000;
+
+// mtime of the first poll which found the transmitter busy, and busy polls since
+type Stall = Option<(u64, usize)>;
+
+// Set once console UART is given up on; output goes to the in-memory log or is dropped
+static FALLBACK: AtomicBool = AtomicBool::new(false);
+
+static CONSOLE: spin::Mutex<ConsoleState> = spin::Mutex::new(ConsoleState {
+    uart: None,
+    backend: Backend::Polling,
+    ring: [0; RING_BUFFER_SIZE],
+    head: 0,
+    len: 0,
+    stall: None,
+    #[cfg(feature = "console-fallback")]
+    log: MemoryLog::new(),
+});
+
+// Longest firmware message in bytes, about 90ms at 115200 baud; fits a supervisor context dump
+pub const MAX_LINE_LEN: usize = 1024;
+// Longest panic report line in bytes
+pub const MAX_PANIC_LINE_LEN: usize = 4096;
+const TRUNCATED: &str = " [truncated]";
+#[cfg(feature = "console-fallback")]
+const STALL_NOTE: &[u8] = b"[rustsbi] console UART stalled, output continues in memory log\n";
+#[cfg(not(feature = "console-fallback"))]
+const STALL_NOTE: &[u8] = b"[rustsbi] console UART stalled, output dropped\n";
+
+// Console handle registered into RustSBI legacy stdio
+pub struct Console;
+
+// Print a line of firmware output, unless supervisor has silenced it
+#[macro_export]
+macro_rules! println {
+    ($($arg:tt)*) => {
+        if $crate::console::output_enabled() {
+            $crate::console::print_line(format_args!($($arg)*), $crate::console::MAX_LINE_LEN)
+        }
+    };
+}
+
+// Print a message and a line break, cut off after `max_len` bytes; the line is not interleaved
+// with output of other harts
+pub fn print_line(args: fmt::Arguments, max_len: usize) {
+    write_truncated(&mut *CONSOLE.lock(), args, max_len);
+}
+
+static OUTPUT_ENABLED: AtomicBool = AtomicBool::new(true);
+
+const FUNCTION_SET_OUTPUT: usize = 0x0;
+const FUNCTION_GET_FALLBACK: usize = 0x1;
+
+pub fn output_enabled() -> bool {
+    OUTPUT_ENABLED.load(Ordering::Relaxed)
+}
+
+// Turn firmware output back on for a message which must never be lost
+pub fn force_output() {
+    OUTPUT_ENABLED.store(true, Ordering::Relaxed);
+}
+
+// Whether console UART was given up on
+pub fn fallback_active() -> bool {
+    FALLBACK.load(Ordering::Acquire)
+}
+
+// Handler of vendor extension `EXTENSION_CONSOLE_CONTROL`; function 0 enables firmware output if
+// a0 is 1, silences it if a0 is 0, and returns whether it was enabled before; function 1 returns
+// whether console UART was given up on
+pub fn handle_ecall(function: usize, param: [usize; 6]) -> rustsbi::SbiRet {
+    match (function, param[0]) {
+        (FUNCTION_SET_OUTPUT, enable @ (0 | 1)) => {
+            let previous = OUTPUT_ENABLED.swap(enable == 1, Ordering::Relaxed);
+            rustsbi::SbiRet::ok(previous as usize)
+        }
+        (FUNCTION_SET_OUTPUT, _) => rustsbi::SbiRet::invalid_param(),
+        (FUNCTION_GET_FALLBACK, _) => rustsbi::SbiRet::ok(fallback_active() as usize),
+        _ => rustsbi::SbiRet::not_supported(),
+    }
+}
+
+// Initialize console on polling backend, and use it as RustSBI standard input and output
+pub fn init(uart: Uart) {
+    CONSOLE.lock().uart = Some(uart);
+    rustsbi::legacy_stdio::init_legacy_stdio_embedded_hal(Console);
+}
+
+// Switch console backend; pending output of the previous backend is flushed first.
+//
+// The console lock is held during the whole switch, so no byte written concurrently
+// on another hart could get lost or reordered.
+pub fn switch_backend(backend: Backend) {
+    let mut state = CONSOLE.lock();
+    state.drain_blocking();
+    state.backend = backend;
+}
+
+// Send out queued bytes as long as the transmitter is ready, never blocks.
+//
+// Registered as a timer tick task; skips when another hart is using the console.
+pub fn drain() {
+    if let Some(mut state) = CONSOLE.try_lock() {
+        state.drain();
+    }
+}
+
+// Block until all queued output has been sent
+pub fn flush() {
+    CONSOLE.lock().drain_blocking();
+}
+
+// Set while a hart prints its panic report
+static PANIC_REPORT: AtomicBool = AtomicBool::new(false);
+
+// Wait for other harts to finish their panic reports and start one on current hart.
+//
+// Returns false if no report could be started within `timeout` mtime ticks; the report of
+// another hart is still being printed, or current hart panicked again while reporting.
+pub fn begin_panic_report(timeout: u64) -> bool {
+    force_output();
+    let clint = Clint::new(0x2000000 as *mut u8);
+    let deadline = clint.get_mtime() + timeout;
+    while PANIC_REPORT
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        if clint.get_mtime() >= deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+// Finish panic report of current hart, after its output has been flushed
+pub fn end_panic_report() {
+    PANIC_REPORT.store(false, Ordering::Release);
+}
+
+// Write a short line as a whole, without interleaving with output of other harts.
+//
+// Meant for panics which could not start a report; if the console stays locked for `timeout`
+// mtime ticks, its holder may never release it, and the line is written to UART directly.
+pub fn write_line_exclusive(args: fmt::Arguments, timeout: u64) {
+    let clint = Clint::new(0x2000000 as *mut u8);
+    let deadline = clint.get_mtime() + timeout;
+    loop {
+        if let Some(mut state) = CONSOLE.try_lock() {
+            write_truncated(&mut *state, args, MAX_PANIC_LINE_LEN);
+            state.drain_blocking();
+            return;
+        }
+        if clint.get_mtime() >= deadline {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    // a stuck UART would never take the line, and the log belongs to the lock holder
+    if fallback_active() {
+        return;
+    }
+    let mut uart = unsafe { Uart::preloaded_uart0() };
+    write_truncated(DirectUart(&mut uart), args, MAX_PANIC_LINE_LEN);
+}
+
+// Write a message cut off after `max_len` bytes, then a line break
+fn write_truncated(out: impl fmt::Write, args: fmt::Arguments, max_len: usize) {
+    let mut line = Truncate {
+        out,
+        left: max_len,
+        truncated: false,
+    };
+    // fails once the message is cut, which stops formatting the rest
+    fmt::write(&mut line, args).ok();
+    if line.truncated {
+        line.out.write_str(TRUNCATED).ok();
+    }
+    line.out.write_str("\n").ok();
+}
+
+// Passes on at most `left` more bytes, cut at a character boundary
+struct Truncate<W> {
+    out: W,
+    left: usize,
+    truncated: bool,
+}
+
+impl<W: fmt::Write> fmt::Write for Truncate<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if s.len() <= self.left {
+            self.left -= s.len();
+            return self.out.write_str(s);
+        }
+        let mut end = self.left;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.out.write_str(&s[..end])?;
+        self.left = 0;
+        self.truncated = true;
+        Err(fmt::Error)
+    }
+}
+
+struct DirectUart<'a>(&'a mut Uart);
+
+impl fmt::Write for DirectUart<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if !retry_direct(|| self.0.write(byte)) {
+                return Err(fmt::Error);
+            }
+        }
+        match retry_direct(|| self.0.flush()) {
+            true => Ok(()),
+            false => Err(fmt::Error),
+        }
+    }
+}
+
+// Retry a UART operation until it succeeds; returns false once the transmitter counts as stuck
+fn retry_direct(mut op: impl FnMut() -> nb::Result<(), Infallible>) -> bool {
+    let mut stall = None;
+    loop {
+        if op().is_ok() {
+            return true;
+        }
+        if count_stall(&mut stall) {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// Count a busy poll of the transmitter; returns true once it has been busy for
+// `STALL_TIMEOUT_US` and `STALL_POLLS` polls in a row, or `STALL_POLLS_WITHOUT_MTIME` polls
+// while mtime stands still
+fn count_stall(stall: &mut Stall) -> bool {
+    let clint = Clint::new(0x2000000 as *mut u8);
+    let now = clint.get_mtime();
+    let (since, polls) = stall.get_or_insert((now, 0));
+    *polls += 1;
+    let timed_out =
+        *polls >= STALL_POLLS && now.wrapping_sub(*since) >= clint.us_to_ticks(STALL_TIMEOUT_US);
+    // mtime standing still cannot time out, a poll count alone decides
+    let counted_out = now == *since && *polls >= STALL_POLLS_WITHOUT_MTIME;
+    timed_out || counted_out
+}
+
+impl fmt::Write for ConsoleState {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+impl ConsoleState {
+    fn write_byte(&mut self, byte: u8) {
+        match self.backend {
+            Backend::Polling => self.send(byte),
+            Backend::Buffered => {
+                self.push(byte);
+                self.drain();
+            }
+        }
+    }
+
+    fn drain(&mut self) {
+        while self.len != 0 && self.uart_ready() {
+            let byte = self.ring[self.head];
+            self.head = (self.head + 1) % RING_BUFFER_SIZE;
+            self.len -= 1;
+            self.send(byte);
+        }
+    }
+
+    fn drain_blocking(&mut self) {
+        while self.len != 0 {
+            self.drain();
+        }
+        while !self.uart_ready() {
+            core::hint::spin_loop();
+        }
+    }
+
+    // Write a byte to UART; once UART is given up on, to the in-memory log, or drop it without
+    // one. Waits while the UART holding register is full, as `drain_blocking` does; a newline,
+    // sent as CR LF, may wait once in between
+    fn send(&mut self, byte: u8) {
+        loop {
+            if fallback_active() {
+                #[cfg(feature = "console-fallback")]
+                self.log.write_byte(byte);
+                return;
+            }
+            let sent = match self.uart.as_mut() {
+                Some(uart) => uart.write(byte).is_ok(),
+                None => true, // output without a UART is dropped
+            };
+            if sent {
+                return;
+            }
+            while !self.uart_ready() {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    // Whether UART can take another byte; output without a UART is dropped
+    fn uart_ready(&mut self) -> bool {
+        if fallback_active() {
+            return true;
+        }
+        let uart = match self.uart.as_mut() {
+            Some(uart) => uart,
+            None => return true,
+        };
+        if uart.flush().is_ok() {
+            self.stall = None;
+            return true;
+        }
+        if !count_stall(&mut self.stall) {
+            return false;
+        }
+        self.stall = None;
+        FALLBACK.store(true, Ordering::Release);
+        #[cfg(feature = "console-fallback")]
+        for byte in STALL_NOTE {
+            self.log.write_byte(*byte);
+        }
+        #[cfg(not(feature = "console-fallback"))]
+        for byte in STALL_NOTE {
+            uart.write(*byte).ok();
+        }
+        true
+    }
+
+    fn push(&mut self, byte: u8) {
+        while self.len == RING_BUFFER_SIZE {
+            self.drain();
+        }
+        self.ring[(self.head + self.len) % RING_BUFFER_SIZE] = byte;
+        self.len += 1;
+    }
+}
+
+impl Read<u8> for Console {
+    type Error = Infallible;
+
+    #[inline]
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        match CONSOLE.lock().uart.as_mut() {
+            Some(uart) => uart.read(),
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+}
+
+impl Write<u8> for Console {
+    type Error = Infallible;
+
+    #[inline]
+    fn write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+        let mut state = CONSOLE.lock();
+        match state.backend {
+            Backend::Polling => {
+                state.send(byte);
+                Ok(())
+            }
+            Backend::Buffered => {
+                state.push(byte);
+                state.drain();
+                Ok(())
+            }
+        }
+    }
+
+    #[inline]
+    fn flush(&mut self) -> nb::Result<(), Infallible> {
+        let mut state = CONSOLE.lock();
+        match state.backend {
+            Backend::Polling => match state.uart_ready() {
+                true => Ok(()),
+                false => Err(nb::Error::WouldBlock),
+            },
+            // queued bytes are sent later, do not wait for them here
+            Backend::Buffered => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Debug halt of a chosen hart
+//!
+//! Unlike HSM stop, a halted hart is frozen in machine mode and never resumes. The requesting
+//! hart marks the target and sends it an IPI; the target prints its machine and supervisor
+//! CSRs with the supervisor context it was running, then parks with all interrupts masked.
+//! Useful to inspect a hang on one core from another core.
+//!
+//! The same vendor extension also hex dumps supervisor memory, see `memory_dump`, prints
+//! trap counters of every hart, see `trap_stats`, with feature `ecall-latency` their SBI call
+//! latency histograms, see `ecall_latency`, walks supervisor page tables, see
+//! `page_table_dump`, arms PC watchpoints on supervisor code, see `watchpoint`, and prints the
+//! memory map firmware knows of, see `memory_map`. With feature
+//! `stack-guard`, function `FUNCTION_OVERFLOW_STACK` overflows the firmware stack of the calling
+//! hart on purpose, to check that `stack_guard` catches it; it never returns.
+use crate::peripheral::Clint;
+use crate::println;
+use crate::runtime::SupervisorContext;
+use core::sync::atomic::{AtomicBool, Ordering};
+use riscv::register::{mcause, mepc, mie, mtval, satp, scause, sepc, stval};
+use rustsbi::SbiRet;
+
+const FUNCTION_DEBUG_HALT_HART: usize = 0x0;
+const FUNCTION_DUMP_MEMORY: usize = 0x1;
+const FUNCTION_PRINT_TRAP_STATS: usize = 0x2;
+const FUNCTION_PRINT_ECALL_LATENCY: usize = 0x3;
+const FUNCTION_DUMP_PAGE_TABLES: usize = 0x4;
+const FUNCTION_SET_WATCHPOINT: usize = 0x5;
+const FUNCTION_CLEAR_WATCHPOINT: usize = 0x6;
+const FUNCTION_OVERFLOW_STACK: usize = 0x7;
+const FUNCTION_PRINT_MEMORY_MAP: usize = 0x8;
+
+const HSM_STATE_STARTED: usize = 0;
+
+const CLEAR: AtomicBool = AtomicBool::new(false);
+static HALT_REQUESTED: [AtomicBool; crate::NUM_HARTS] = [CLEAR; crate::NUM_HARTS];
+static HALTED: [AtomicBool; crate::NUM_HARTS] = [CLEAR; crate::NUM_HARTS];
+
+// Handler of vendor extension `EXTENSION_DIAGNOSTICS`
+pub fn handle_ecall(function: usize, param: [usize; 6]) -> SbiRet {
+    match function {
+        FUNCTION_DEBUG_HALT_HART => halt_hart(param[0]),
+        FUNCTION_DUMP_MEMORY => crate::memory_dump::dump(param[0], param[1]),
+        FUNCTION_PRINT_TRAP_STATS => {
+            crate::hart_csr_utils::print_trap_stats();
+            SbiRet::ok(0)
+        }
+        #[cfg(feature = "ecall-latency")]
+        FUNCTION_PRINT_ECALL_LATENCY => {
+            crate::ecall_latency::print();
+            SbiRet::ok(0)
+        }
+        FUNCTION_DUMP_PAGE_TABLES => crate::page_table_dump::dump(param[0]),
+        FUNCTION_SET_WATCHPOINT => crate::watchpoint::set(param[0]),
+        FUNCTION_CLEAR_WATCHPOINT => crate::watchpoint::clear(param[0]),
+        #[cfg(feature = "stack-guard")]
+        FUNCTION_OVERFLOW_STACK => SbiRet::ok(crate::stack_guard::overflow(0)),
+        FUNCTION_PRINT_MEMORY_MAP => {
+            crate::memory_map::print();
+            SbiRet::ok(0)
+        }
+        _ => SbiRet::not_supported(),
+    }
+}
+
+fn halt_hart(target: usize) -> SbiRet {
+    // the calling hart cannot be halted, there would be nobody to read the dump
+    if target >= crate::NUM_HARTS || target == crate::execute::calling_hart() {
+        return SbiRet::invalid_param();
+    }
+    if HALTED[target].load(Ordering::Acquire) || HALT_REQUESTED[target].swap(true, Ordering::AcqRel)
+    {
+        return SbiRet::already_stopped();
+    }
+    // a stopped or suspended hart waits for an HSM command in firmware, it must not get this IPI
+    if rustsbi::Hsm::hart_get_status(&*crate::HSM, target).value != HSM_STATE_STARTED {
+        HALT_REQUESTED[target].store(false, Ordering::Release);
+        return SbiRet::failed();
+    }
+    Clint::new(0x2000000 as *mut u8).send_soft(target);
+    SbiRet::ok(0)
+}
+
+// Take halt request of current hart; checked first on every machine software interrupt
+pub fn take_request(hart_id: usize) -> bool {
+    HALT_REQUESTED[hart_id].swap(false, Ordering::AcqRel)
+}
+
+// Print CSRs and supervisor context of current hart, then park it forever
+pub fn dump_and_park(hart_id: usize, ctx: &SupervisorContext) -> ! {
+    Clint::new(0x2000000 as *mut u8).clear_soft(hart_id);
+    HALTED[hart_id].store(true, Ordering::Release);
+    println!("[rustsbi] hart {} halted on debug request", hart_id);
+    print_context(ctx);
+    crate::console::flush();
+    // nothing may wake this hart up
+    unsafe {
+        mie::clear_msoft();
+        mie::clear_mtimer();
+        mie::clear_mext();
+        loop {
+            riscv::asm::wfi();
+        }
+    }
+}
+
+// Print machine trap CSRs, supervisor CSRs and supervisor context of current hart
+pub fn print_context(ctx: &SupervisorContext) {
+    println!(
+        "[rustsbi] mepc: {:#x}, mcause: {:#x}, mtval: {:#x}",
+        mepc::read(),
+        mcause::read().bits(),
+        mtval::read()
+    );
+    println!(
+        "[rustsbi] supervisor pc: {:#x}, sepc: {:#x}, scause: {:#x}, stval: {:#x}, satp: {:#x}",
+        ctx.mepc,
+        sepc::read(),
+        scause::read().bits(),
+        stval::read(),
+        satp::read().bits()
+    );
+    println!("[rustsbi] supervisor context: {:x?}", ctx);
+}
@@ -0,0 +1,1015 @@
+//! Decoder of the instructions firmware emulates
+//!
+//! Only splits instruction bits into fields; reading registers, memory or CSRs is left to the
+//! emulation functions in `feature`, which take the decoded `Instruction`. This module depends on
+//! `core` only, so it builds for the host as well as for firmware; its tests run on the host, see
+//! `lib.rs`.
+
+// major opcodes, bits 6:0
+pub const OPCODE_LOAD: u32 = 0b000_0011;
+pub const OPCODE_STORE: u32 = 0b010_0011;
+pub const OPCODE_OP: u32 = 0b011_0011;
+pub const OPCODE_SYSTEM: u32 = 0b111_0011;
+pub const OPCODE_OP_IMM: u32 = 0b001_0011;
+pub const OPCODE_OP_IMM_32: u32 = 0b001_1011;
+pub const OPCODE_OP_32: u32 = 0b011_1011;
+pub const OPCODE_LUI: u32 = 0b011_0111;
+pub const OPCODE_AUIPC: u32 = 0b001_0111;
+pub const OPCODE_JAL: u32 = 0b110_1111;
+pub const OPCODE_JALR: u32 = 0b110_0111;
+pub const OPCODE_BRANCH: u32 = 0b110_0011;
+pub const OPCODE_AMO: u32 = 0b010_1111;
+pub const OPCODE_MISC_MEM: u32 = 0b000_1111;
+
+// wrs.nto and wrs.sto from Zawrs, encoded without register fields
+const INS_WRS_NTO: u32 = 0x00D0_0073;
+const INS_WRS_STO: u32 = 0x01D0_0073;
+
+// sret, illegal in supervisor while mstatus.TSR is set
+const INS_SRET: u32 = 0x1020_0073;
+
+// mret, and mnret from Smrnmi; both are illegal below machine level
+const INS_MRET: u32 = 0x3020_0073;
+const INS_MNRET: u32 = 0x7020_0073;
+
+const INS_EBREAK: u32 = 0x0010_0073;
+
+// funct7 of czero.eqz and czero.nez from Zicond
+const FUNCT7_CZERO: u32 = 0b000_0111;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsrOp {
+    ReadWrite,
+    ReadSet,
+    ReadClear,
+    // the immediate forms take a 5-bit zero extended immediate in place of rs1
+    ReadWriteImm,
+    ReadSetImm,
+    ReadClearImm,
+}
+
+// Operations of the A extension, from funct5 of the AMO major opcode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AmoOp {
+    LoadReserved,
+    StoreConditional,
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    MinUnsigned,
+    MaxUnsigned,
+}
+
+// Instructions of bitmanip extensions Zba, Zbb and Zbs for RV64
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitmanipOp {
+    // Zba
+    Sh1add,
+    Sh2add,
+    Sh3add,
+    AddUw,
+    Sh1addUw,
+    Sh2addUw,
+    Sh3addUw,
+    SlliUw,
+    // Zbb
+    Andn,
+    Orn,
+    Xnor,
+    Clz,
+    Clzw,
+    Ctz,
+    Ctzw,
+    Cpop,
+    Cpopw,
+    Max,
+    Maxu,
+    Min,
+    Minu,
+    SextB,
+    SextH,
+    ZextH,
+    Rol,
+    Rolw,
+    Ror,
+    Rori,
+    Roriw,
+    Rorw,
+    OrcB,
+    Rev8,
+    // Zbs
+    Bclr,
+    Bclri,
+    Bext,
+    Bexti,
+    Binv,
+    Binvi,
+    Bset,
+    Bseti,
+}
+
+impl BitmanipOp {
+    pub fn mnemonic(&self) -> &'static str {
+        use BitmanipOp::*;
+        match self {
+            Sh1add => "sh1add",
+            Sh2add => "sh2add",
+            Sh3add => "sh3add",
+            AddUw => "add.uw",
+            Sh1addUw => "sh1add.uw",
+            Sh2addUw => "sh2add.uw",
+            Sh3addUw => "sh3add.uw",
+            SlliUw => "slli.uw",
+            Andn => "andn",
+            Orn => "orn",
+            Xnor => "xnor",
+            Clz => "clz",
+            Clzw => "clzw",
+            Ctz => "ctz",
+            Ctzw => "ctzw",
+            Cpop => "cpop",
+            Cpopw => "cpopw",
+            Max => "max",
+            Maxu => "maxu",
+            Min => "min",
+            Minu => "minu",
+            SextB => "sext.b",
+            SextH => "sext.h",
+            ZextH => "zext.h",
+            Rol => "rol",
+            Rolw => "rolw",
+            Ror => "ror",
+            Rori => "rori",
+            Roriw => "roriw",
+            Rorw => "rorw",
+            OrcB => "orc.b",
+            Rev8 => "rev8",
+            Bclr => "bclr",
+            Bclri => "bclri",
+            Bext => "bext",
+            Bexti => "bexti",
+            Binv => "binv",
+            Binvi => "binvi",
+            Bset => "bset",
+            Bseti => "bseti",
+        }
+    }
+
+    // Name of the extension that defines this instruction
+    pub fn extension(&self) -> &'static str {
+        use BitmanipOp::*;
+        match self {
+            Sh1add | Sh2add | Sh3add | AddUw | Sh1addUw | Sh2addUw | Sh3addUw | SlliUw => "Zba",
+            Bclr | Bclri | Bext | Bexti | Binv | Binvi | Bset | Bseti => "Zbs",
+            _ => "Zbb",
+        }
+    }
+}
+
+// Instructions of the H extension, which U74 lacks; they are only decoded to be reported
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HypervisorOp {
+    HlvB,
+    HlvBu,
+    HlvH,
+    HlvHu,
+    HlvxHu,
+    HlvW,
+    HlvWu,
+    HlvxWu,
+    HlvD,
+    HsvB,
+    HsvH,
+    HsvW,
+    HsvD,
+    HfenceVvma,
+    HfenceGvma,
+    // from Svinval, defined along with H
+    HinvalVvma,
+    HinvalGvma,
+}
+
+impl HypervisorOp {
+    pub fn mnemonic(&self) -> &'static str {
+        use HypervisorOp::*;
+        match self {
+            HlvB => "hlv.b",
+            HlvBu => "hlv.bu",
+            HlvH => "hlv.h",
+            HlvHu => "hlv.hu",
+            HlvxHu => "hlvx.hu",
+            HlvW => "hlv.w",
+            HlvWu => "hlv.wu",
+            HlvxWu => "hlvx.wu",
+            HlvD => "hlv.d",
+            HsvB => "hsv.b",
+            HsvH => "hsv.h",
+            HsvW => "hsv.w",
+            HsvD => "hsv.d",
+            HfenceVvma => "hfence.vvma",
+            HfenceGvma => "hfence.gvma",
+            HinvalVvma => "hinval.vvma",
+            HinvalGvma => "hinval.gvma",
+        }
+    }
+}
+
+// Cache block operations of Zicbom and Zicboz, which U74 lacks; they are only decoded to be
+// reported
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CboOp {
+    Inval,
+    Clean,
+    Flush,
+    Zero,
+}
+
+impl CboOp {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            CboOp::Inval => "cbo.inval",
+            CboOp::Clean => "cbo.clean",
+            CboOp::Flush => "cbo.flush",
+            CboOp::Zero => "cbo.zero",
+        }
+    }
+
+    // Name of the extension that defines this instruction
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CboOp::Zero => "Zicboz",
+            _ => "Zicbom",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Csr {
+        op: CsrOp,
+        rd: u8,
+        rs1: u8,
+        csr: u16,
+    },
+    // integer loads; `signed` is false for lbu, lhu and lwu
+    Load {
+        rd: u8,
+        rs1: u8,
+        offset: isize,
+        width: usize,
+        signed: bool,
+    },
+    Store {
+        rs1: u8,
+        rs2: u8,
+        offset: isize,
+        width: usize,
+    },
+    CzeroEqz {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    CzeroNez {
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    // lr, sc and amo*, of 4 or 8 bytes; aq and rl bits are ignored, emulation is fully ordered
+    Amo {
+        op: AmoOp,
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+        width: usize,
+    },
+    // a Zba, Zbb or Zbs instruction; for immediate and unary forms `rs2` is not a register
+    Bitmanip {
+        op: BitmanipOp,
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    // hypervisor load, store or fence; `rd` of stores and fences and `rs2` of loads are zero
+    Hypervisor {
+        op: HypervisorOp,
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    // cache block operation on the block containing address in `rs1`
+    Cbo {
+        op: CboOp,
+        rs1: u8,
+    },
+    WrsNto,
+    WrsSto,
+    Sret,
+    Mret,
+    Mnret,
+}
+
+// Length in bytes of the instruction whose lowest halfword is `low`
+pub fn length(low: u16) -> usize {
+    if low & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+#[inline]
+pub fn opcode(ins: u32) -> u32 {
+    ins & 0b111_1111
+}
+
+#[inline]
+pub fn rd(ins: u32) -> u8 {
+    ((ins >> 7) & 0b1_1111) as u8
+}
+
+#[inline]
+pub fn funct3(ins: u32) -> u32 {
+    (ins >> 12) & 0b111
+}
+
+#[inline]
+pub fn rs1(ins: u32) -> u8 {
+    ((ins >> 15) & 0b1_1111) as u8
+}
+
+#[inline]
+pub fn rs2(ins: u32) -> u8 {
+    ((ins >> 20) & 0b1_1111) as u8
+}
+
+#[inline]
+pub fn funct7(ins: u32) -> u32 {
+    ins >> 25
+}
+
+// Sign extended immediate of I-type instructions
+#[inline]
+pub fn imm_i(ins: u32) -> isize {
+    (ins as i32 >> 20) as isize
+}
+
+// Sign extended immediate of S-type instructions, imm[11:5] from bits 31:25, imm[4:0] from 11:7
+#[inline]
+pub fn imm_s(ins: u32) -> isize {
+    (((ins as i32 >> 25) << 5) | ((ins >> 7) & 0b1_1111) as i32) as isize
+}
+
+// Decode a 32-bit instruction, None if it is not one firmware knows how to emulate or report
+pub fn decode(ins: u32) -> Option<Instruction> {
+    match ins {
+        INS_WRS_NTO => return Some(Instruction::WrsNto),
+        INS_WRS_STO => return Some(Instruction::WrsSto),
+        INS_SRET => return Some(Instruction::Sret),
+        INS_MRET => return Some(Instruction::Mret),
+        INS_MNRET => return Some(Instruction::Mnret),
+        _ => {}
+    }
+    let instruction = match opcode(ins) {
+        OPCODE_LOAD => {
+            let (width, signed) = match funct3(ins) {
+                0b000 => (1, true),  // lb
+                0b001 => (2, true),  // lh
+                0b010 => (4, true),  // lw
+                0b011 => (8, true),  // ld
+                0b100 => (1, false), // lbu
+                0b101 => (2, false), // lhu
+                0b110 => (4, false), // lwu
+                _ => return None,
+            };
+            Instruction::Load {
+                rd: rd(ins),
+                rs1: rs1(ins),
+                offset: imm_i(ins),
+                width,
+                signed,
+            }
+        }
+        OPCODE_STORE => match funct3(ins) {
+            // sb, sh, sw, sd
+            funct3 @ 0b000..=0b011 => Instruction::Store {
+                rs1: rs1(ins),
+                rs2: rs2(ins),
+                offset: imm_s(ins),
+                width: 1 << funct3,
+            },
+            _ => return None,
+        },
+        OPCODE_OP if funct7(ins) == FUNCT7_CZERO => match funct3(ins) {
+            0b101 => Instruction::CzeroEqz {
+                rd: rd(ins),
+                rs1: rs1(ins),
+                rs2: rs2(ins),
+            },
+            0b111 => Instruction::CzeroNez {
+                rd: rd(ins),
+                rs1: rs1(ins),
+                rs2: rs2(ins),
+            },
+            _ => return None,
+        },
+        OPCODE_OP | OPCODE_OP_32 | OPCODE_OP_IMM | OPCODE_OP_IMM_32 => Instruction::Bitmanip {
+            op: decode_bitmanip(ins)?,
+            rd: rd(ins),
+            rs1: rs1(ins),
+            rs2: rs2(ins),
+        },
+        OPCODE_AMO => {
+            let width = match funct3(ins) {
+                0b010 => 4,
+                0b011 => 8,
+                _ => return None,
+            };
+            let op = match funct7(ins) >> 2 {
+                0b00010 if rs2(ins) == 0 => AmoOp::LoadReserved,
+                0b00011 => AmoOp::StoreConditional,
+                0b00001 => AmoOp::Swap,
+                0b00000 => AmoOp::Add,
+                0b00100 => AmoOp::Xor,
+                0b01100 => AmoOp::And,
+                0b01000 => AmoOp::Or,
+                0b10000 => AmoOp::Min,
+                0b10100 => AmoOp::Max,
+                0b11000 => AmoOp::MinUnsigned,
+                0b11100 => AmoOp::MaxUnsigned,
+                _ => return None,
+            };
+            Instruction::Amo {
+                op,
+                rd: rd(ins),
+                rs1: rs1(ins),
+                rs2: rs2(ins),
+                width,
+            }
+        }
+        // cbo.*: funct3 of CBO, imm[11:0] selects the operation, rd is zero
+        OPCODE_MISC_MEM if funct3(ins) == 0b010 && rd(ins) == 0 => {
+            let op = match ins >> 20 {
+                0b000 => CboOp::Inval,
+                0b001 => CboOp::Clean,
+                0b010 => CboOp::Flush,
+                0b100 => CboOp::Zero,
+                _ => return None,
+            };
+            Instruction::Cbo { op, rs1: rs1(ins) }
+        }
+        OPCODE_SYSTEM if matches!(funct3(ins), 0b000 | 0b100) => Instruction::Hypervisor {
+            op: decode_hypervisor(ins)?,
+            rd: rd(ins),
+            rs1: rs1(ins),
+            rs2: rs2(ins),
+        },
+        OPCODE_SYSTEM => {
+            let op = match funct3(ins) {
+                0b001 => CsrOp::ReadWrite,
+                0b010 => CsrOp::ReadSet,
+                0b011 => CsrOp::ReadClear,
+                0b101 => CsrOp::ReadWriteImm,
+                0b110 => CsrOp::ReadSetImm,
+                0b111 => CsrOp::ReadClearImm,
+                _ => return None,
+            };
+            Instruction::Csr {
+                op,
+                rd: rd(ins),
+                rs1: rs1(ins),
+                csr: (ins >> 20) as u16,
+            }
+        }
+        _ => return None,
+    };
+    Some(instruction)
+}
+
+// Bitmanip operation of an OP, OP-32, OP-IMM or OP-IMM-32 instruction, None for base
+// instructions and other encodings
+fn decode_bitmanip(ins: u32) -> Option<BitmanipOp> {
+    use BitmanipOp::*;
+    // imm[11:0] of unary forms, imm[11:6] of RV64 shift-immediate forms
+    let imm12 = ins >> 20;
+    let imm_high = ins >> 26;
+    let op = match (opcode(ins), funct3(ins)) {
+        (OPCODE_OP, funct3) => match (funct7(ins), funct3) {
+            (0b001_0000, 0b010) => Sh1add,
+            (0b001_0000, 0b100) => Sh2add,
+            (0b001_0000, 0b110) => Sh3add,
+            (0b010_0000, 0b111) => Andn,
+            (0b010_0000, 0b110) => Orn,
+            (0b010_0000, 0b100) => Xnor,
+            (0b000_0101, 0b110) => Max,
+            (0b000_0101, 0b111) => Maxu,
+            (0b000_0101, 0b100) => Min,
+            (0b000_0101, 0b101) => Minu,
+            (0b011_0000, 0b001) => Rol,
+            (0b011_0000, 0b101) => Ror,
+            (0b010_0100, 0b001) => Bclr,
+            (0b010_0100, 0b101) => Bext,
+            (0b011_0100, 0b001) => Binv,
+            (0b001_0100, 0b001) => Bset,
+            _ => return None,
+        },
+        (OPCODE_OP_32, funct3) => match (funct7(ins), funct3) {
+            (0b000_0100, 0b000) => AddUw,
+            (0b000_0100, 0b100) if rs2(ins) == 0 => ZextH,
+            (0b001_0000, 0b010) => Sh1addUw,
+            (0b001_0000, 0b100) => Sh2addUw,
+            (0b001_0000, 0b110) => Sh3addUw,
+            (0b011_0000, 0b001) => Rolw,
+            (0b011_0000, 0b101) => Rorw,
+            _ => return None,
+        },
+        (OPCODE_OP_IMM, 0b001) => match (imm12, imm_high) {
+            (0x600, _) => Clz,
+            (0x601, _) => Ctz,
+            (0x602, _) => Cpop,
+            (0x604, _) => SextB,
+            (0x605, _) => SextH,
+            (_, 0b01_0010) => Bclri,
+            (_, 0b01_1010) => Binvi,
+            (_, 0b00_1010) => Bseti,
+            _ => return None,
+        },
+        (OPCODE_OP_IMM, 0b101) => match (imm12, imm_high) {
+            (0x287, _) => OrcB,
+            (0x6b8, _) => Rev8,
+            (_, 0b01_1000) => Rori,
+            (_, 0b01_0010) => Bexti,
+            _ => return None,
+        },
+        (OPCODE_OP_IMM_32, 0b001) => match (imm12, imm_high) {
+            (0x600, _) => Clzw,
+            (0x601, _) => Ctzw,
+            (0x602, _) => Cpopw,
+            (_, 0b00_0010) => SlliUw,
+            _ => return None,
+        },
+        (OPCODE_OP_IMM_32, 0b101) if funct7(ins) == 0b011_0000 => Roriw,
+        _ => return None,
+    };
+    Some(op)
+}
+
+// Hypervisor operation of a SYSTEM instruction with funct3 0 or 4, None for other encodings,
+// including those with a nonzero field the H extension reserves
+fn decode_hypervisor(ins: u32) -> Option<HypervisorOp> {
+    use HypervisorOp::*;
+    let op = match (funct3(ins), funct7(ins), rs2(ins), rd(ins)) {
+        (0b100, 0b011_0000, 0b00000, _) => HlvB,
+        (0b100, 0b011_0000, 0b00001, _) => HlvBu,
+        (0b100, 0b011_0010, 0b00000, _) => HlvH,
+        (0b100, 0b011_0010, 0b00001, _) => HlvHu,
+        (0b100, 0b011_0010, 0b00011, _) => HlvxHu,
+        (0b100, 0b011_0100, 0b00000, _) => HlvW,
+        (0b100, 0b011_0100, 0b00001, _) => HlvWu,
+        (0b100, 0b011_0100, 0b00011, _) => HlvxWu,
+        (0b100, 0b011_0110, 0b00000, _) => HlvD,
+        (0b100, 0b011_0001, _, 0) => HsvB,
+        (0b100, 0b011_0011, _, 0) => HsvH,
+        (0b100, 0b011_0101, _, 0) => HsvW,
+        (0b100, 0b011_0111, _, 0) => HsvD,
+        (0b000, 0b001_0001, _, 0) => HfenceVvma,
+        (0b000, 0b011_0001, _, 0) => HfenceGvma,
+        (0b000, 0b001_0011, _, 0) => HinvalVvma,
+        (0b000, 0b011_0011, _, 0) => HinvalGvma,
+        _ => return None,
+    };
+    Some(op)
+}
+
+// Decode a 16-bit RV64C instruction into what it expands to; only integer loads and stores.
+//
+// Their offsets are zero extended and scaled, 3-bit register fields address x8 to x15.
+pub fn decode_compressed(ins: u16) -> Option<Instruction> {
+    let ins = ins as u32;
+    let bits = |high: u32, low: u32| (ins >> low) & ((1 << (high - low + 1)) - 1);
+    let rd_rs2_prime = 8 + bits(4, 2) as u8;
+    let rs1_prime = 8 + bits(9, 7) as u8;
+    const SP: u8 = 2;
+    let load = |rd: u8, rs1: u8, offset: u32, width: usize| Instruction::Load {
+        rd,
+        rs1,
+        offset: offset as isize,
+        width,
+        signed: true,
+    };
+    let store = |rs1: u8, rs2: u8, offset: u32, width: usize| Instruction::Store {
+        rs1,
+        rs2,
+        offset: offset as isize,
+        width,
+    };
+    let instruction = match (bits(1, 0), bits(15, 13)) {
+        // c.lw: uimm[5:3] = ins[12:10], uimm[2] = ins[6], uimm[6] = ins[5]
+        (0b00, 0b010) => {
+            let offset = bits(12, 10) << 3 | bits(6, 6) << 2 | bits(5, 5) << 6;
+            load(rd_rs2_prime, rs1_prime, offset, 4)
+        }
+        // c.ld: uimm[5:3] = ins[12:10], uimm[7:6] = ins[6:5]
+        (0b00, 0b011) => {
+            let offset = bits(12, 10) << 3 | bits(6, 5) << 6;
+            load(rd_rs2_prime, rs1_prime, offset, 8)
+        }
+        // c.sw
+        (0b00, 0b110) => {
+            let offset = bits(12, 10) << 3 | bits(6, 6) << 2 | bits(5, 5) << 6;
+            store(rs1_prime, rd_rs2_prime, offset, 4)
+        }
+        // c.sd
+        (0b00, 0b111) => {
+            let offset = bits(12, 10) << 3 | bits(6, 5) << 6;
+            store(rs1_prime, rd_rs2_prime, offset, 8)
+        }
+        // c.lwsp: uimm[5] = ins[12], uimm[4:2] = ins[6:4], uimm[7:6] = ins[3:2]
+        (0b10, 0b010) => {
+            let offset = bits(12, 12) << 5 | bits(6, 4) << 2 | bits(3, 2) << 6;
+            load(bits(11, 7) as u8, SP, offset, 4)
+        }
+        // c.ldsp: uimm[5] = ins[12], uimm[4:3] = ins[6:5], uimm[8:6] = ins[4:2]
+        (0b10, 0b011) => {
+            let offset = bits(12, 12) << 5 | bits(6, 5) << 3 | bits(4, 2) << 6;
+            load(bits(11, 7) as u8, SP, offset, 8)
+        }
+        // c.swsp: uimm[5:2] = ins[12:9], uimm[7:6] = ins[8:7]
+        (0b10, 0b110) => {
+            let offset = bits(12, 9) << 2 | bits(8, 7) << 6;
+            store(SP, bits(6, 2) as u8, offset, 4)
+        }
+        // c.sdsp: uimm[5:3] = ins[12:10], uimm[8:6] = ins[9:7]
+        (0b10, 0b111) => {
+            let offset = bits(12, 10) << 3 | bits(9, 7) << 6;
+            store(SP, bits(6, 2) as u8, offset, 8)
+        }
+        _ => return None,
+    };
+    Some(instruction)
+}
+
+// Sign extend the low `bits` bits of `value`
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    ((value << (32 - bits)) as i32) >> (32 - bits)
+}
+
+fn encode_i(imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm as u32 & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn encode_r(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn encode_s(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    (imm >> 5) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0b1_1111) << 7 | OPCODE_STORE
+}
+
+fn encode_b(imm: i32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    let imm_high = (imm >> 12 & 1) << 6 | (imm >> 5 & 0b11_1111);
+    let imm_low = (imm >> 1 & 0b1111) << 1 | (imm >> 11 & 1);
+    imm_high << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | imm_low << 7 | OPCODE_BRANCH
+}
+
+fn encode_j(imm: i32, rd: u32) -> u32 {
+    let imm = imm as u32;
+    let bits =
+        (imm >> 20 & 1) << 19 | (imm >> 1 & 0x3ff) << 9 | (imm >> 11 & 1) << 8 | (imm >> 12 & 0xff);
+    bits << 12 | rd << 7 | OPCODE_JAL
+}
+
+// Expand a 16-bit RV64C integer instruction into the 32-bit instruction it stands for, for
+// harts without C extension. None for reserved encodings and floating point instructions.
+pub fn expand_compressed(ins: u16) -> Option<u32> {
+    let ins = ins as u32;
+    let bits = |high: u32, low: u32| (ins >> low) & ((1 << (high - low + 1)) - 1);
+    let rd = bits(11, 7);
+    let rs2 = bits(6, 2);
+    let rd_rs2_prime = 8 + bits(4, 2);
+    let rs1_prime = 8 + bits(9, 7);
+    const SP: u32 = 2;
+    const RA: u32 = 1;
+    // sign extended imm[5] = ins[12], imm[4:0] = ins[6:2]
+    let imm6 = sign_extend(bits(12, 12) << 5 | bits(6, 2), 6);
+    let shamt = bits(12, 12) << 5 | bits(6, 2);
+    let expanded = match (bits(1, 0), bits(15, 13)) {
+        // c.addi4spn: nzuimm[5:4] = ins[12:11], nzuimm[9:6] = ins[10:7], nzuimm[2] = ins[6],
+        // nzuimm[3] = ins[5]
+        (0b00, 0b000) => {
+            let imm = bits(12, 11) << 4 | bits(10, 7) << 6 | bits(6, 6) << 2 | bits(5, 5) << 3;
+            if imm == 0 {
+                return None;
+            }
+            encode_i(imm as i32, SP, 0b000, rd_rs2_prime, OPCODE_OP_IMM)
+        }
+        // c.lw, c.ld
+        (0b00, 0b010) | (0b00, 0b011) => {
+            let offset = match bits(15, 13) {
+                0b010 => bits(12, 10) << 3 | bits(6, 6) << 2 | bits(5, 5) << 6,
+                _ => bits(12, 10) << 3 | bits(6, 5) << 6,
+            };
+            encode_i(
+                offset as i32,
+                rs1_prime,
+                bits(14, 13),
+                rd_rs2_prime,
+                OPCODE_LOAD,
+            )
+        }
+        // c.sw, c.sd
+        (0b00, 0b110) | (0b00, 0b111) => {
+            let offset = match bits(15, 13) {
+                0b110 => bits(12, 10) << 3 | bits(6, 6) << 2 | bits(5, 5) << 6,
+                _ => bits(12, 10) << 3 | bits(6, 5) << 6,
+            };
+            encode_s(offset, rd_rs2_prime, rs1_prime, bits(14, 13))
+        }
+        // c.addi, c.nop
+        (0b01, 0b000) => encode_i(imm6, rd, 0b000, rd, OPCODE_OP_IMM),
+        // c.addiw
+        (0b01, 0b001) if rd != 0 => encode_i(imm6, rd, 0b000, rd, OPCODE_OP_IMM_32),
+        // c.li
+        (0b01, 0b010) => encode_i(imm6, 0, 0b000, rd, OPCODE_OP_IMM),
+        // c.addi16sp: nzimm[9] = ins[12], nzimm[4] = ins[6], nzimm[6] = ins[5],
+        // nzimm[8:7] = ins[4:3], nzimm[5] = ins[2]
+        (0b01, 0b011) if rd == SP => {
+            let imm = bits(12, 12) << 9
+                | bits(6, 6) << 4
+                | bits(5, 5) << 6
+                | bits(4, 3) << 7
+                | bits(2, 2) << 5;
+            if imm == 0 {
+                return None;
+            }
+            encode_i(sign_extend(imm, 10), SP, 0b000, SP, OPCODE_OP_IMM)
+        }
+        // c.lui: nzimm[17] = ins[12], nzimm[16:12] = ins[6:2]
+        (0b01, 0b011) if rd != 0 && imm6 != 0 => (imm6 as u32) << 12 | rd << 7 | OPCODE_LUI,
+        (0b01, 0b100) => match bits(11, 10) {
+            // c.srli, c.srai
+            0b00 => encode_i(shamt as i32, rs1_prime, 0b101, rs1_prime, OPCODE_OP_IMM),
+            0b01 => encode_i(
+                (0x400 | shamt) as i32,
+                rs1_prime,
+                0b101,
+                rs1_prime,
+                OPCODE_OP_IMM,
+            ),
+            // c.andi
+            0b10 => encode_i(imm6, rs1_prime, 0b111, rs1_prime, OPCODE_OP_IMM),
+            _ => {
+                let (funct7, funct3, opcode) = match (bits(12, 12), bits(6, 5)) {
+                    (0, 0b00) => (0b010_0000, 0b000, OPCODE_OP),    // c.sub
+                    (0, 0b01) => (0, 0b100, OPCODE_OP),             // c.xor
+                    (0, 0b10) => (0, 0b110, OPCODE_OP),             // c.or
+                    (0, 0b11) => (0, 0b111, OPCODE_OP),             // c.and
+                    (1, 0b00) => (0b010_0000, 0b000, OPCODE_OP_32), // c.subw
+                    (1, 0b01) => (0, 0b000, OPCODE_OP_32),          // c.addw
+                    _ => return None,
+                };
+                encode_r(funct7, rd_rs2_prime, rs1_prime, funct3, rs1_prime, opcode)
+            }
+        },
+        // c.j: offset[11] = ins[12], offset[4] = ins[11], offset[9:8] = ins[10:9],
+        // offset[10] = ins[8], offset[6] = ins[7], offset[7] = ins[6], offset[3:1] = ins[5:3],
+        // offset[5] = ins[2]
+        (0b01, 0b101) => {
+            let offset = bits(12, 12) << 11
+                | bits(11, 11) << 4
+                | bits(10, 9) << 8
+                | bits(8, 8) << 10
+                | bits(7, 7) << 6
+                | bits(6, 6) << 7
+                | bits(5, 3) << 1
+                | bits(2, 2) << 5;
+            encode_j(sign_extend(offset, 12), 0)
+        }
+        // c.beqz, c.bnez: offset[8] = ins[12], offset[4:3] = ins[11:10], offset[7:6] = ins[6:5],
+        // offset[2:1] = ins[4:3], offset[5] = ins[2]
+        (0b01, 0b110) | (0b01, 0b111) => {
+            let offset = bits(12, 12) << 8
+                | bits(11, 10) << 3
+                | bits(6, 5) << 6
+                | bits(4, 3) << 1
+                | bits(2, 2) << 5;
+            encode_b(sign_extend(offset, 9), 0, rs1_prime, bits(13, 13))
+        }
+        // c.slli
+        (0b10, 0b000) if rd != 0 => encode_i(shamt as i32, rd, 0b001, rd, OPCODE_OP_IMM),
+        // c.lwsp, c.ldsp
+        (0b10, 0b010) | (0b10, 0b011) if rd != 0 => {
+            let offset = match bits(15, 13) {
+                0b010 => bits(12, 12) << 5 | bits(6, 4) << 2 | bits(3, 2) << 6,
+                _ => bits(12, 12) << 5 | bits(6, 5) << 3 | bits(4, 2) << 6,
+            };
+            encode_i(offset as i32, SP, bits(14, 13), rd, OPCODE_LOAD)
+        }
+        (0b10, 0b100) => match (bits(12, 12), rd, rs2) {
+            (0, 0, _) => return None,
+            (0, rs1, 0) => encode_i(0, rs1, 0b000, 0, OPCODE_JALR), // c.jr
+            (0, rd, rs2) => encode_r(0, rs2, 0, 0b000, rd, OPCODE_OP), // c.mv
+            (1, 0, 0) => INS_EBREAK,                                // c.ebreak
+            (1, rs1, 0) => encode_i(0, rs1, 0b000, RA, OPCODE_JALR), // c.jalr
+            (_, rd, rs2) => encode_r(0, rs2, rd, 0b000, rd, OPCODE_OP), // c.add
+        },
+        // c.swsp, c.sdsp
+        (0b10, 0b110) | (0b10, 0b111) => {
+            let offset = match bits(15, 13) {
+                0b110 => bits(12, 9) << 2 | bits(8, 7) << 6,
+                _ => bits(12, 10) << 3 | bits(9, 7) << 6,
+            };
+            encode_s(offset, rs2, SP, bits(14, 13))
+        }
+        _ => return None,
+    };
+    Some(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A0: u8 = 10;
+    const A1: u8 = 11;
+
+    #[test]
+    fn rdtime() {
+        // csrrs a0, time, zero
+        assert_eq!(
+            decode(0xC010_2573),
+            Some(Instruction::Csr {
+                op: CsrOp::ReadSet,
+                rd: A0,
+                rs1: 0,
+                csr: 0xC01,
+            })
+        );
+        // rdtimeh, only valid for RV32 supervisors but decoded all the same
+        let rdtimeh = encode_i(0xC81, 0, 0b010, A0 as u32, OPCODE_SYSTEM);
+        assert!(matches!(
+            decode(rdtimeh),
+            Some(Instruction::Csr { csr: 0xC81, .. })
+        ));
+    }
+
+    #[test]
+    fn csr_ops() {
+        let ops = [
+            (0b001, CsrOp::ReadWrite),
+            (0b010, CsrOp::ReadSet),
+            (0b011, CsrOp::ReadClear),
+            (0b101, CsrOp::ReadWriteImm),
+            (0b110, CsrOp::ReadSetImm),
+            (0b111, CsrOp::ReadClearImm),
+        ];
+        for (funct3, op) in ops {
+            // csr* a0, sstatus, a1; the immediate forms read `a1` as uimm 11
+            let ins = encode_i(0x100, A1 as u32, funct3, A0 as u32, OPCODE_SYSTEM);
+            assert_eq!(
+                decode(ins),
+                Some(Instruction::Csr {
+                    op,
+                    rd: A0,
+                    rs1: A1,
+                    csr: 0x100,
+                })
+            );
+        }
+        // the whole 12-bit CSR number, not sign extended
+        let ins = encode_i(0xFFF, 0, 0b010, A0 as u32, OPCODE_SYSTEM);
+        assert!(matches!(
+            decode(ins),
+            Some(Instruction::Csr { csr: 0xFFF, .. })
+        ));
+        // funct3 4 is not a CSR instruction
+        assert_eq!(
+            decode(encode_i(0x100, 0, 0b100, A0 as u32, OPCODE_SYSTEM)),
+            None
+        );
+    }
+
+    #[test]
+    fn loads() {
+        let widths = [
+            (0b000, 1, true),
+            (0b001, 2, true),
+            (0b010, 4, true),
+            (0b011, 8, true),
+            (0b100, 1, false),
+            (0b101, 2, false),
+            (0b110, 4, false),
+        ];
+        for (funct3, width, signed) in widths {
+            // misaligned for every width above 1: l* a0, 3(a1)
+            let ins = encode_i(3, A1 as u32, funct3, A0 as u32, OPCODE_LOAD);
+            assert_eq!(
+                decode(ins),
+                Some(Instruction::Load {
+                    rd: A0,
+                    rs1: A1,
+                    offset: 3,
+                    width,
+                    signed,
+                })
+            );
+        }
+        // ld a0, -1(a1)
+        let ins = encode_i(-1, A1 as u32, 0b011, A0 as u32, OPCODE_LOAD);
+        assert!(matches!(
+            decode(ins),
+            Some(Instruction::Load { offset: -1, .. })
+        ));
+        assert_eq!(
+            decode(encode_i(0, A1 as u32, 0b111, A0 as u32, OPCODE_LOAD)),
+            None
+        );
+    }
+
+    #[test]
+    fn stores() {
+        // sd a0, -3(a1)
+        let ins = encode_s(-3i32 as u32 & 0xfff, A0 as u32, A1 as u32, 0b011);
+        assert_eq!(
+            decode(ins),
+            Some(Instruction::Store {
+                rs1: A1,
+                rs2: A0,
+                offset: -3,
+                width: 8,
+            })
+        );
+        assert_eq!(decode(encode_s(0, A0 as u32, A1 as u32, 0b100)), None);
+    }
+
+    #[test]
+    fn compressed_loads() {
+        // c.lw a0, 4(a1)
+        assert_eq!(
+            decode_compressed(0x41C8),
+            Some(Instruction::Load {
+                rd: A0,
+                rs1: A1,
+                offset: 4,
+                width: 4,
+                signed: true,
+            })
+        );
+        // c.ldsp a0, 8(sp)
+        assert_eq!(
+            decode_compressed(0x6522),
+            Some(Instruction::Load {
+                rd: A0,
+                rs1: 2,
+                offset: 8,
+                width: 8,
+                signed: true,
+            })
+        );
+        assert_eq!(length(0x41C8), 2);
+        assert_eq!(length(0x2503), 4);
+        // expands to lw a0, 4(a1)
+        assert_eq!(
+            expand_compressed(0x41C8),
+            Some(encode_i(4, A1 as u32, 0b010, A0 as u32, OPCODE_LOAD))
+        );
+    }
+
+    #[test]
+    fn cbo() {
+        let ops = [
+            (0b000, CboOp::Inval),
+            (0b001, CboOp::Clean),
+            (0b010, CboOp::Flush),
+            (0b100, CboOp::Zero),
+        ];
+        for (imm, op) in ops {
+            // cbo.* (a0)
+            let ins = encode_i(imm, A0 as u32, 0b010, 0, OPCODE_MISC_MEM);
+            assert_eq!(decode(ins), Some(Instruction::Cbo { op, rs1: A0 }));
+        }
+        // cbo.clean (a0), as the assembler encodes it
+        assert_eq!(
+            decode(0x0015_200F),
+            Some(Instruction::Cbo {
+                op: CboOp::Clean,
+                rs1: A0,
+            })
+        );
+        // reserved operation, nonzero rd, and fence are not CBO
+        assert_eq!(
+            decode(encode_i(0b011, A0 as u32, 0b010, 0, OPCODE_MISC_MEM)),
+            None
+        );
+        assert_eq!(
+            decode(encode_i(0b001, A0 as u32, 0b010, 1, OPCODE_MISC_MEM)),
+            None
+        );
+        assert_eq!(decode(0x0FF0_000F), None);
+    }
+}
@@ -0,0 +1,80 @@
+//! Exception and interrupt delegation query and control
+//!
+//! Vendor extension `EXTENSION_DELEGATION` lets supervisor read `medeleg` and `mideleg` of the
+//! calling hart as firmware set them up, and un-delegate or re-delegate exceptions in
+//! `board::MEDELEG_MUTABLE`, e.g. to have firmware report every breakpoint while debugging.
+//!
+//! | Function | Parameters       | Returns
+//! |:---------|:-----------------|:---------
+//! | 0        |                  | `medeleg`
+//! | 1        |                  | `mideleg`
+//! | 2        | a0: new medeleg  | previous `medeleg`, or `SBI_ERR_DENIED` if a bit outside the mutable mask would change
+//! | 3        |                  | mask of `medeleg` bits function 2 may change
+//!
+//! The mutable mask is the board's, limited to `SAFE_MEDELEG`: instruction misaligned (0),
+//! instruction access fault (1), breakpoint (3), user ecall (8) and the page faults (12, 13,
+//! 15), plus load and store access faults (5, 7) unless firmware emulates atomics on MMIO. Every
+//! other bit is read-only: firmware itself handles illegal instructions, supervisor ecalls and,
+//! unless FWFT changed them, misaligned loads and stores, and firmware never takes supervisor
+//! interrupts. `mideleg` is read-only. The board default is an empty mask, thus read-only.
+//!
+//! An exception un-delegated this way is printed with its cause, pc and trap value, then handed
+//! to supervisor's trap handler as if it were delegated. Changes apply to the calling hart only,
+//! and stay across its HSM stop and start until the next boot.
+use crate::board::MEDELEG_MUTABLE;
+use riscv::register::{medeleg, mideleg};
+use rustsbi::SbiRet;
+
+const FUNCTION_GET_MEDELEG: usize = 0x0;
+const FUNCTION_GET_MIDELEG: usize = 0x1;
+const FUNCTION_SET_MEDELEG: usize = 0x2;
+const FUNCTION_GET_MEDELEG_MUTABLE: usize = 0x3;
+
+const SBI_ERR_DENIED: usize = -4isize as usize;
+
+// Exceptions firmware can forward to supervisor without needing them itself
+const SAFE_MEDELEG: usize = 1 << 0
+    | 1 << 1
+    | 1 << 3
+    | 1 << 8
+    | 1 << 12
+    | 1 << 13
+    | 1 << 15
+    | if cfg!(feature = "emulate-mmio-amo") {
+        0
+    } else {
+        1 << 5 | 1 << 7
+    };
+
+const _: () = assert!(
+    MEDELEG_MUTABLE & !SAFE_MEDELEG == 0,
+    "board::MEDELEG_MUTABLE allows exceptions firmware must keep"
+);
+
+// Handler of vendor extension `EXTENSION_DELEGATION`
+pub fn handle_ecall(function: usize, param: [usize; 6]) -> SbiRet {
+    match function {
+        FUNCTION_GET_MEDELEG => SbiRet::ok(medeleg::read().bits()),
+        FUNCTION_GET_MIDELEG => SbiRet::ok(mideleg::read().bits()),
+        FUNCTION_SET_MEDELEG => set_medeleg(param[0]),
+        FUNCTION_GET_MEDELEG_MUTABLE => SbiRet::ok(MEDELEG_MUTABLE),
+        _ => SbiRet::not_supported(),
+    }
+}
+
+fn set_medeleg(value: usize) -> SbiRet {
+    let previous = medeleg::read().bits();
+    if (value ^ previous) & !MEDELEG_MUTABLE != 0 {
+        return SbiRet {
+            error: SBI_ERR_DENIED,
+            value: 0,
+        };
+    }
+    unsafe { core::arch::asm!("csrw medeleg, {}", in(reg) value) };
+    SbiRet::ok(previous)
+}
+
+// Whether an exception with this cause code may reach firmware because supervisor un-delegated it
+pub fn is_undelegated(code: usize) -> bool {
+    code < usize::BITS as usize && MEDELEG_MUTABLE & 1 << code != 0
+}
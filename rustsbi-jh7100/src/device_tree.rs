@@ -1,4 +1,5 @@
-// use alloc::collections::BTreeMap;
+use alloc::collections::BTreeMap;
+use serde::de::IgnoredAny;
 use serde_derive::Deserialize;
 use serde_device_tree::{self, error::Result};
 
@@ -8,6 +9,8 @@ struct Tree<'a> {
     // aliases: BTreeMap<&'a str, &'a str>,
     #[serde(borrow)]
     chosen: Option<Chosen<'a>>,
+    #[serde(borrow)]
+    cpus: Option<BTreeMap<&'a str, IgnoredAny>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -16,13 +19,1268 @@ struct Chosen<'a> {
     stdout_path: Option<&'a str>,
 }
 
-pub unsafe fn parse_device_tree(dtb_pa: usize) -> Result<()> {
-    let tree: Tree = serde_device_tree::from_raw(dtb_pa as *const u8)?;
-    use rustsbi::println;
-    if let Some(chosen) = tree.chosen {
-        if let Some(stdout_path) = chosen.stdout_path {
-            println!("[rustsbi] stdout path: {}", stdout_path);
+// Plausible `timebase-frequency` in Hz. Below, a tick is longer than the shortest firmware
+// delays; above, no CLINT is clocked that fast and microsecond conversions overflow within hours.
+pub const TIMEBASE_FREQUENCY_RANGE: core::ops::RangeInclusive<u64> = 10_000..=1_000_000_000;
+
+// What firmware reads from device tree; a node or property the tree lacks is None
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BoardInfo<'a> {
+    // `stdout-path` of `/chosen`
+    pub stdout_path: Option<&'a str>,
+    // node `stdout-path` points at, with an alias resolved through `/aliases`
+    pub stdout_node: Option<&'a str>,
+    // `cpu@` nodes under `/cpus`
+    pub num_harts: usize,
+    // `timebase-frequency` of `/cpus`, as is, not yet checked against the valid range
+    pub timebase_frequency: Option<u64>,
+    // first bank [start, end) of the top level memory node, see `dram_range`
+    pub memory: Option<(usize, usize)>,
+}
+
+impl BoardInfo<'_> {
+    // `timebase-frequency` if it is within `TIMEBASE_FREQUENCY_RANGE`; None makes firmware fall
+    // back to the default
+    pub fn valid_timebase_frequency(&self) -> Option<u64> {
+        self.timebase_frequency
+            .filter(|frequency| TIMEBASE_FREQUENCY_RANGE.contains(frequency))
+    }
+}
+
+// Parse device tree bytes; the tree must lie within `dtb`, its header is checked against it
+pub fn parse_device_tree(dtb: &[u8]) -> Result<BoardInfo<'_>> {
+    let header = Header::read(dtb).map_err(serde::de::Error::custom)?;
+    let dtb = &dtb[..header.totalsize];
+    // deserializer only reads within the header's blocks, just checked to be inside `dtb`
+    let tree: Tree = unsafe { serde_device_tree::from_raw(dtb.as_ptr()) }?;
+    let num_harts = tree.cpus.map_or(0, |cpus| {
+        cpus.keys().filter(|name| name.starts_with("cpu@")).count()
+    });
+    // the deserializer keeps the terminating nul of string properties
+    let stdout_path = tree
+        .chosen
+        .and_then(|chosen| chosen.stdout_path)
+        .map(|path| path.trim_end_matches('\0'));
+    Ok(BoardInfo {
+        stdout_path,
+        stdout_node: stdout_path.and_then(|path| stdout_node(dtb, path)),
+        num_harts,
+        timebase_frequency: timebase_frequency(dtb),
+        memory: dram_range(dtb),
+    })
+}
+
+// Parse device tree at a physical address, as passed by the previous boot stage
+pub unsafe fn parse_device_tree_at(dtb_pa: usize) -> Result<BoardInfo<'static>> {
+    let header = core::slice::from_raw_parts(dtb_pa as *const u8, FDT_HEADER_SIZE);
+    if be32(header, 0) != FDT_MAGIC {
+        return Err(serde::de::Error::custom("invalid device tree magic"));
+    }
+    let totalsize = be32(header, 4) as usize;
+    parse_device_tree(core::slice::from_raw_parts(dtb_pa as *const u8, totalsize))
+}
+
+// Report what was parsed and settle the timebase frequency; called once on boot hart. It drives
+// console and CLINT, thus it is left out of the host test build, see `lib.rs`
+#[cfg(not(test))]
+pub fn apply_board_info(info: &BoardInfo) {
+    use crate::peripheral::{set_timebase_frequency, DEFAULT_TIMEBASE_FREQUENCY};
+    use crate::println;
+    match (info.valid_timebase_frequency(), info.timebase_frequency) {
+        (Some(frequency), _) => set_timebase_frequency(frequency),
+        // a zero or absurd value would divide by zero or overflow in tick conversions
+        (None, Some(frequency)) => {
+            println!(
+                "[rustsbi] warning: timebase-frequency {} out of range, using {}",
+                frequency, DEFAULT_TIMEBASE_FREQUENCY
+            );
+            set_timebase_frequency(DEFAULT_TIMEBASE_FREQUENCY)
+        }
+        (None, None) => {
+            println!(
+                "[rustsbi] warning: no valid timebase-frequency in /cpus, using {}",
+                DEFAULT_TIMEBASE_FREQUENCY
+            );
+            set_timebase_frequency(DEFAULT_TIMEBASE_FREQUENCY)
+        }
+    }
+    if let Some(stdout_path) = info.stdout_path {
+        println!("[rustsbi] stdout path: {}", stdout_path);
+        apply_stdout_options(info, stdout_path);
+    }
+    // SBI stack is only allocated for `NUM_HARTS` harts, see `entry`
+    if info.num_harts > crate::NUM_HARTS {
+        println!(
+            "[rustsbi] warning: device tree has {} harts, SBI stack only allocated for {}",
+            info.num_harts,
+            crate::NUM_HARTS
+        );
+    }
+}
+
+// Node path of `stdout-path`, the part before its options; a path not starting with `/` is an
+// alias, looked up in `/aliases`
+fn stdout_node<'a>(dtb: &'a [u8], stdout_path: &'a str) -> Option<&'a str> {
+    let path = stdout_path.split(':').next()?;
+    if path.starts_with('/') {
+        return Some(path);
+    }
+    let alias = property(dtb, "/aliases", path)?;
+    let alias = alias.split(|b| *b == 0).next()?;
+    core::str::from_utf8(alias).ok()
+}
+
+// Set console UART to the line settings after the colon of `stdout-path`: 115200n8 if there are
+// none, or, with a warning, if they do not parse. A `stdout-path` to another node is not firmware's
+// console and leaves the UART as the previous boot stage set it up.
+#[cfg(not(test))]
+fn apply_stdout_options(info: &BoardInfo, stdout_path: &str) {
+    use crate::peripheral::{LineSettings, Uart};
+    use crate::println;
+    if info.stdout_node != Some(crate::board::CONSOLE_NODE) {
+        return println!(
+            "[rustsbi] stdout path is not console UART {}, line settings unchanged",
+            crate::board::CONSOLE_NODE
+        );
+    }
+    // a stalled UART would never drain for the new settings to apply
+    if crate::console::fallback_active() {
+        return println!("[rustsbi] warning: console UART stalled, line settings unchanged");
+    }
+    let settings = match stdout_path.split_once(':') {
+        None => LineSettings::DEFAULT,
+        Some((_, options)) => LineSettings::parse(options).unwrap_or_else(|e| {
+            println!(
+                "[rustsbi] warning: stdout path options '{}' invalid, {}; using {}",
+                options,
+                e,
+                LineSettings::DEFAULT
+            );
+            LineSettings::DEFAULT
+        }),
+    };
+    unsafe { Uart::preloaded_uart0() }.set_line(&settings);
+    println!("[rustsbi] console UART line settings: {}", settings);
+}
+
+// Raw flattened device tree access, for what the deserializer above cannot do:
+// decoding `reg` by the parent's cell counts and writing a modified copy of the tree.
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+#[derive(Clone, Copy)]
+struct Header {
+    totalsize: usize,
+    off_dt_struct: usize,
+    off_dt_strings: usize,
+    off_mem_rsvmap: usize,
+    size_dt_strings: usize,
+    size_dt_struct: usize,
+}
+
+impl Header {
+    fn read(dtb: &[u8]) -> core::result::Result<Header, &'static str> {
+        if dtb.len() < FDT_HEADER_SIZE || be32(dtb, 0) != FDT_MAGIC {
+            return Err("invalid device tree magic");
+        }
+        let header = Header {
+            totalsize: be32(dtb, 4) as usize,
+            off_dt_struct: be32(dtb, 8) as usize,
+            off_dt_strings: be32(dtb, 12) as usize,
+            off_mem_rsvmap: be32(dtb, 16) as usize,
+            size_dt_strings: be32(dtb, 32) as usize,
+            size_dt_struct: be32(dtb, 36) as usize,
+        };
+        if header.totalsize > dtb.len()
+            || header.off_dt_struct + header.size_dt_struct > header.totalsize
+            || header.off_dt_strings + header.size_dt_strings > header.totalsize
+        {
+            return Err("device tree blocks out of bound");
+        }
+        Ok(header)
+    }
+
+    fn string<'a>(&self, dtb: &'a [u8], name_offset: usize) -> &'a str {
+        let strings = &dtb[self.off_dt_strings..self.off_dt_strings + self.size_dt_strings];
+        let bytes = strings.get(name_offset..).unwrap_or(&[]);
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        core::str::from_utf8(&bytes[..len]).unwrap_or("")
+    }
+
+    // Offset of given string in strings block, if present
+    fn find_string(&self, dtb: &[u8], name: &str) -> Option<usize> {
+        let strings = &dtb[self.off_dt_strings..self.off_dt_strings + self.size_dt_strings];
+        let mut offset = 0;
+        for s in strings.split(|b| *b == 0) {
+            if s == name.as_bytes() {
+                return Some(offset);
+            }
+            offset += s.len() + 1;
         }
+        None
+    }
+}
+
+fn be32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+fn be64(bytes: &[u8], offset: usize) -> u64 {
+    ((be32(bytes, offset) as u64) << 32) | be32(bytes, offset + 4) as u64
+}
+
+enum Token<'a> {
+    BeginNode(&'a str),
+    EndNode,
+    Prop(&'a str, &'a [u8]),
+}
+
+// Iterates structure block, yields every token with its offset inside the block
+struct Tokens<'a> {
+    dtb: &'a [u8],
+    header: Header,
+    pos: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(dtb: &'a [u8], header: Header) -> Self {
+        Tokens {
+            dtb,
+            header,
+            pos: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = (usize, Token<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let block = &self.dtb[self.header.off_dt_struct..][..self.header.size_dt_struct];
+        loop {
+            let offset = self.pos;
+            if offset + 4 > block.len() {
+                return None;
+            }
+            self.pos += 4;
+            match be32(block, offset) {
+                FDT_BEGIN_NODE => {
+                    let rest = &block[self.pos..];
+                    let len = rest.iter().position(|b| *b == 0)?;
+                    let name = core::str::from_utf8(&rest[..len]).ok()?;
+                    self.pos = align_up(self.pos + len + 1, 4);
+                    return Some((offset, Token::BeginNode(name)));
+                }
+                FDT_END_NODE => return Some((offset, Token::EndNode)),
+                FDT_PROP => {
+                    let len = be32(block, self.pos) as usize;
+                    let name = self
+                        .header
+                        .string(self.dtb, be32(block, self.pos + 4) as usize);
+                    let value = block.get(self.pos + 8..self.pos + 8 + len)?;
+                    self.pos = align_up(self.pos + 8 + len, 4);
+                    return Some((offset, Token::Prop(name, value)));
+                }
+                FDT_NOP => continue,
+                // FDT_END, or unknown token
+                _ => return None,
+            }
+        }
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+// `#address-cells` and `#size-cells` of a node, giving the layout of `reg` in its children
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cells {
+    pub address: u32,
+    pub size: u32,
+}
+
+impl Cells {
+    // values the devicetree specification assumes when a node has neither property
+    pub const DEFAULT: Cells = Cells {
+        address: 2,
+        size: 1,
+    };
+
+    // One `reg` entry in bytes; error unless both widths are one or two cells, which covers
+    // the 2/1 and 2/2 layouts of RISC-V device trees as well as 32-bit 1/1
+    fn entry_len(self) -> core::result::Result<usize, &'static str> {
+        match (self.address, self.size) {
+            (1..=2, 1..=2) => Ok((self.address + self.size) as usize * 4),
+            _ => Err("unsupported cell size"),
+        }
+    }
+}
+
+// Decode `reg` of a node into (address, size) pairs, `cells` taken from its parent
+pub fn reg_entries(
+    value: &[u8],
+    cells: Cells,
+) -> core::result::Result<impl Iterator<Item = (u64, u64)> + '_, &'static str> {
+    let entry_len = cells.entry_len()?;
+    if value.len() % entry_len != 0 {
+        return Err("reg length not a multiple of its cells");
+    }
+    let read = |entry: &[u8], offset: usize, cells: u32| match cells {
+        1 => be32(entry, offset) as u64,
+        _ => be64(entry, offset),
+    };
+    Ok(value.chunks_exact(entry_len).map(move |entry| {
+        let size_offset = cells.address as usize * 4;
+        (
+            read(entry, 0, cells.address),
+            read(entry, size_offset, cells.size),
+        )
+    }))
+}
+
+// Encode one `reg` entry with given cells into `buf`, returns its length
+fn encode_reg(
+    buf: &mut [u8; 16],
+    cells: Cells,
+    address: u64,
+    size: u64,
+) -> core::result::Result<usize, &'static str> {
+    let entry_len = cells.entry_len()?;
+    let mut pos = 0;
+    for (value, cells) in [(address, cells.address), (size, cells.size)] {
+        if cells == 1 {
+            let value = u32::try_from(value).map_err(|_| "reg value exceeds its cells")?;
+            buf[pos..pos + 4].copy_from_slice(&value.to_be_bytes());
+        } else {
+            buf[pos..pos + 8].copy_from_slice(&value.to_be_bytes());
+        }
+        pos += cells as usize * 4;
+    }
+    Ok(entry_len)
+}
+
+// Cells of every node from root down to current one, kept while walking `Tokens`.
+//
+// Properties of a node come before its children, so a child's `reg` is always read after
+// the parent's cell counts are known.
+const MAX_DEPTH: usize = 16;
+
+struct CellsStack {
+    cells: [Cells; MAX_DEPTH],
+    // 1 inside root node, 0 outside any node
+    depth: usize,
+}
+
+impl CellsStack {
+    fn new() -> Self {
+        CellsStack {
+            cells: [Cells::DEFAULT; MAX_DEPTH],
+            depth: 0,
+        }
+    }
+
+    fn begin_node(&mut self) -> core::result::Result<(), &'static str> {
+        if self.depth + 1 >= MAX_DEPTH {
+            return Err("device tree nested too deep");
+        }
+        self.depth += 1;
+        self.cells[self.depth] = Cells::DEFAULT;
+        Ok(())
+    }
+
+    fn end_node(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+    }
+
+    // Record cell counts if given property of current node is one of them
+    fn prop(&mut self, name: &str, value: &[u8]) -> core::result::Result<(), &'static str> {
+        let cells = &mut self.cells[self.depth];
+        let field = match name {
+            "#address-cells" => &mut cells.address,
+            "#size-cells" => &mut cells.size,
+            _ => return Ok(()),
+        };
+        if value.len() != 4 {
+            return Err("invalid cell count property");
+        }
+        *field = be32(value, 0);
+        Ok(())
+    }
+
+    // Cells of current node, for `reg` of its children
+    fn current(&self) -> Cells {
+        self.cells[self.depth]
+    }
+
+    // Cells of parent node, for `reg` of current node
+    fn parent(&self) -> Cells {
+        self.cells[self.depth.saturating_sub(1)]
+    }
+}
+
+// DRAM range [start, end) from the first `reg` entry of top level memory node
+pub fn dram_range(dtb: &[u8]) -> Option<(usize, usize)> {
+    let header = Header::read(dtb).ok()?;
+    let mut cells = CellsStack::new();
+    let mut in_memory = false;
+    for (_, token) in Tokens::new(dtb, header) {
+        match token {
+            Token::BeginNode(name) => {
+                cells.begin_node().ok()?;
+                in_memory = cells.depth == 2 && (name == "memory" || name.starts_with("memory@"));
+            }
+            Token::EndNode => {
+                cells.end_node();
+                in_memory = false;
+            }
+            Token::Prop("reg", value) if in_memory => {
+                let (start, size) = reg_entries(value, cells.parent()).ok()?.next()?;
+                return Some((start as usize, (start + size) as usize));
+            }
+            Token::Prop(name, value) => cells.prop(name, value).ok()?,
+        }
+    }
+    None
+}
+
+// `timebase-frequency` of `/cpus`, one or two cells
+pub fn timebase_frequency(dtb: &[u8]) -> Option<u64> {
+    let header = Header::read(dtb).ok()?;
+    let mut depth = 0;
+    let mut in_cpus = false;
+    for (_, token) in Tokens::new(dtb, header) {
+        match token {
+            Token::BeginNode(name) => {
+                depth += 1;
+                in_cpus = depth == 2 && name == "cpus";
+            }
+            Token::EndNode => {
+                depth -= 1;
+                in_cpus = false;
+            }
+            Token::Prop("timebase-frequency", value) if in_cpus => {
+                return match value.len() {
+                    4 => Some(be32(value, 0) as u64),
+                    8 => Some(be64(value, 0)),
+                    _ => None,
+                };
+            }
+            Token::Prop(..) => {}
+        }
+    }
+    None
+}
+
+// Initrd range [start, end) of `linux,initrd-start` and `linux,initrd-end` in `/chosen`, one or
+// two cells each
+pub fn chosen_initrd(dtb: &[u8]) -> Option<(usize, usize)> {
+    let cells = |value: &[u8]| match value.len() {
+        4 => Some(be32(value, 0) as usize),
+        8 => Some(be64(value, 0) as usize),
+        _ => None,
+    };
+    let start = cells(property(dtb, "/chosen", "linux,initrd-start")?)?;
+    let end = cells(property(dtb, "/chosen", "linux,initrd-end")?)?;
+    Some((start, end))
+}
+
+// Rewrite `reg` of the first top level memory node in place to a single range [base, base + size).
+//
+// The new entry is encoded with the root's cells. Further `reg` entries are dropped and their
+// space is filled with nop tokens, thus the tree keeps its size.
+pub fn fixup_memory_node(
+    dtb: &mut [u8],
+    base: usize,
+    size: usize,
+) -> core::result::Result<(), &'static str> {
+    let header = Header::read(dtb)?;
+    let mut cells = CellsStack::new();
+    let mut in_memory = false;
+    let mut reg = None;
+    for (offset, token) in Tokens::new(dtb, header) {
+        match token {
+            Token::BeginNode(name) => {
+                cells.begin_node()?;
+                in_memory = cells.depth == 2 && (name == "memory" || name.starts_with("memory@"));
+            }
+            Token::EndNode => {
+                cells.end_node();
+                in_memory = false;
+            }
+            Token::Prop("reg", value) if in_memory => {
+                // also validates the old value against the root's cells
+                if reg_entries(value, cells.parent())?.next().is_none() {
+                    return Err("empty reg in memory node");
+                }
+                reg = Some((offset, value.len(), cells.parent()));
+                break;
+            }
+            Token::Prop(name, value) => cells.prop(name, value)?,
+        }
+    }
+    let (offset, len, reg_cells) = reg.ok_or("no memory node")?;
+    let mut entry = [0u8; 16];
+    let entry_len = encode_reg(&mut entry, reg_cells, base as u64, size as u64)?;
+    // property token, value length, name offset, then value
+    let prop = header.off_dt_struct + offset;
+    dtb[prop + 4..prop + 8].copy_from_slice(&(entry_len as u32).to_be_bytes());
+    let value = prop + 12;
+    dtb[value..value + entry_len].copy_from_slice(&entry[..entry_len]);
+    for nop in (value + entry_len..value + len).step_by(4) {
+        dtb[nop..nop + 4].copy_from_slice(&FDT_NOP.to_be_bytes());
     }
     Ok(())
 }
+
+// Add `linux,initrd-start` and `linux,initrd-end` to `/chosen` of a tree in place, creating
+// `/chosen` if absent. Both are written as two cells.
+//
+// Structure block grows and the strings block after it moves up; the tree must end with its
+// strings block, as trees written by `copy_with_reserved_memory` do, and `dtb` past the tree's
+// `totalsize` must have room for what is added.
+pub fn add_chosen_initrd(
+    dtb: &mut [u8],
+    start: usize,
+    end: usize,
+) -> core::result::Result<(), &'static str> {
+    const INITRD_START: &str = "linux,initrd-start";
+    const INITRD_END: &str = "linux,initrd-end";
+    let header = Header::read(dtb)?;
+    if header.off_dt_strings < header.off_dt_struct + header.size_dt_struct
+        || header.off_dt_strings + header.size_dt_strings != header.totalsize
+    {
+        return Err("strings block is not at end of device tree");
+    }
+    let mut depth = 0;
+    let mut in_chosen = false;
+    let mut chosen_end = None;
+    let mut root_end = None;
+    for (offset, token) in Tokens::new(dtb, header) {
+        match token {
+            Token::BeginNode(name) => {
+                depth += 1;
+                if depth == 2 && name == "chosen" {
+                    in_chosen = true;
+                }
+            }
+            Token::EndNode => {
+                if depth == 2 && in_chosen {
+                    chosen_end = Some(offset);
+                    in_chosen = false;
+                } else if depth == 1 {
+                    root_end = Some(offset);
+                }
+                depth -= 1;
+            }
+            Token::Prop(INITRD_START, _) | Token::Prop(INITRD_END, _) if in_chosen => {
+                return Err("device tree already has an initrd in /chosen")
+            }
+            Token::Prop(..) => {}
+        }
+    }
+    let (insert_at, create_chosen) = match (chosen_end, root_end) {
+        (Some(offset), _) => (offset, false),
+        (None, Some(offset)) => (offset, true),
+        (None, None) => return Err("no root node in device tree"),
+    };
+    // new strings go at the end of strings block, which is the end of the tree
+    let mut strings_len = header.size_dt_strings;
+    let mut new_strings: [&str; 2] = [""; 2];
+    let mut new_strings_count = 0;
+    let mut name_offset = |name: &'static str| match header.find_string(dtb, name) {
+        Some(offset) => offset,
+        None => {
+            let offset = strings_len;
+            strings_len += name.len() + 1;
+            new_strings[new_strings_count] = name;
+            new_strings_count += 1;
+            offset
+        }
+    };
+    let start_name = name_offset(INITRD_START);
+    let end_name = name_offset(INITRD_END);
+    // at most a `chosen` node header, two 8-byte properties and an end token
+    let mut inserted = [0u8; 64];
+    let mut w = Writer {
+        buf: &mut inserted,
+        pos: 0,
+    };
+    if create_chosen {
+        w.begin_node("chosen")?;
+    }
+    w.prop(start_name, &(start as u64).to_be_bytes())?;
+    w.prop(end_name, &(end as u64).to_be_bytes())?;
+    if create_chosen {
+        w.put_u32(FDT_END_NODE)?;
+    }
+    let inserted_len = w.pos;
+    let totalsize = header.totalsize + inserted_len + (strings_len - header.size_dt_strings);
+    if totalsize > dtb.len() {
+        return Err("device tree buffer too small");
+    }
+    let insert_at = header.off_dt_struct + insert_at;
+    dtb.copy_within(insert_at..header.totalsize, insert_at + inserted_len);
+    dtb[insert_at..insert_at + inserted_len].copy_from_slice(&inserted[..inserted_len]);
+    let mut w = Writer {
+        buf: dtb,
+        pos: header.totalsize + inserted_len,
+    };
+    for name in &new_strings[..new_strings_count] {
+        w.put(name.as_bytes())?;
+        w.put(&[0])?;
+    }
+    for (offset, value) in [
+        (4, totalsize),
+        (12, header.off_dt_strings + inserted_len),
+        (32, strings_len),
+        (36, header.size_dt_struct + inserted_len),
+    ] {
+        dtb[offset..offset + 4].copy_from_slice(&(value as u32).to_be_bytes());
+    }
+    Ok(())
+}
+
+// Where a property of a node is in structure block
+struct PropertyLocation {
+    // offset of the property token, and length of its value; None if the node lacks it
+    property: Option<(usize, usize)>,
+    // offset of the node's end token, where a new property is added
+    node_end: usize,
+}
+
+// Find property `name` of node at absolute `path`, e.g. `/soc/serial@12440000` or `/`
+fn locate_property(
+    dtb: &[u8],
+    header: Header,
+    path: &str,
+    name: &str,
+) -> core::result::Result<PropertyLocation, &'static str> {
+    let component = |i| path.split('/').filter(|c| !c.is_empty()).nth(i);
+    let components = path.split('/').filter(|c| !c.is_empty()).count();
+    // nodes on the way to `path` that current node is inside of, root not counted
+    let mut matched = 0;
+    let mut depth = 0;
+    let mut property = None;
+    for (offset, token) in Tokens::new(dtb, header) {
+        let in_node = depth == components + 1 && matched == components;
+        match token {
+            Token::BeginNode(node) => {
+                depth += 1;
+                // a node at depth d is component d - 2 of the path, root being depth 1
+                if depth >= 2 && matched == depth - 2 && component(matched) == Some(node) {
+                    matched += 1;
+                }
+            }
+            Token::EndNode => {
+                if in_node {
+                    return Ok(PropertyLocation {
+                        property,
+                        node_end: offset,
+                    });
+                }
+                if depth >= 2 && matched == depth - 1 {
+                    matched -= 1;
+                }
+                depth -= 1;
+            }
+            Token::Prop(prop, value) if in_node && prop == name => {
+                property = Some((offset, value.len()));
+            }
+            Token::Prop(..) => {}
+        }
+    }
+    Err("no such node in device tree")
+}
+
+// Value of property `name` of node at absolute `path`, None if either is missing
+pub fn property<'a>(dtb: &'a [u8], path: &str, name: &str) -> Option<&'a [u8]> {
+    let header = Header::read(dtb).ok()?;
+    let (offset, len) = locate_property(dtb, header, path, name).ok()?.property?;
+    dtb.get(header.off_dt_struct + offset + 12..)?.get(..len)
+}
+
+// Set property `name` of node at absolute `path` to `value` in place, adding the property if the
+// node lacks it; returns false if it already had that value, leaving the tree untouched.
+//
+// The structure block grows or shrinks and the strings block after it moves; the tree must end
+// with its strings block, as trees written by `copy_with_reserved_memory` do, and `dtb` past the
+// tree's `totalsize` must have room for what is added.
+pub fn set_property(
+    dtb: &mut [u8],
+    path: &str,
+    name: &'static str,
+    value: &[u8],
+) -> core::result::Result<bool, &'static str> {
+    let header = Header::read(dtb)?;
+    if header.off_dt_strings < header.off_dt_struct + header.size_dt_struct
+        || header.off_dt_strings + header.size_dt_strings != header.totalsize
+    {
+        return Err("strings block is not at end of device tree");
+    }
+    let location = locate_property(dtb, header, path, name)?;
+    let (at, old_len) = match location.property {
+        Some((offset, len)) => {
+            let old = &dtb[header.off_dt_struct + offset + 12..][..len];
+            if old == value {
+                return Ok(false);
+            }
+            (offset, 12 + align_up(len, 4))
+        }
+        None => (location.node_end, 0),
+    };
+    let (name_offset, new_string) = match header.find_string(dtb, name) {
+        Some(offset) => (offset, 0),
+        None => (header.size_dt_strings, name.len() + 1),
+    };
+    let new_len = 12 + align_up(value.len(), 4);
+    let size_dt_struct = header.size_dt_struct + new_len - old_len;
+    let totalsize = header.totalsize + new_len - old_len + new_string;
+    if totalsize > dtb.len() {
+        return Err("device tree buffer too small");
+    }
+    // move everything after the old property, strings block included, to its new place
+    let at = header.off_dt_struct + at;
+    dtb.copy_within(at + old_len..header.totalsize, at + new_len);
+    let mut w = Writer { buf: dtb, pos: at };
+    w.prop(name_offset, value)?;
+    w.pos = header.totalsize + new_len - old_len;
+    if new_string != 0 {
+        w.put(name.as_bytes())?;
+        w.put(&[0])?;
+    }
+    for (offset, value) in [
+        (4, totalsize),
+        (12, header.off_dt_strings + new_len - old_len),
+        (32, header.size_dt_strings + new_string),
+        (36, size_dt_struct),
+    ] {
+        dtb[offset..offset + 4].copy_from_slice(&(value as u32).to_be_bytes());
+    }
+    Ok(true)
+}
+
+// Copy device tree into `dst`, adding a `no-map` child node of `/reserved-memory` which covers
+// [base, base + size). `/reserved-memory` is created if the tree does not have one.
+//
+// Returns size of the new device tree.
+pub fn copy_with_reserved_memory(
+    dtb: &[u8],
+    dst: &mut [u8],
+    node_name: &str,
+    base: usize,
+    size: usize,
+) -> core::result::Result<usize, &'static str> {
+    let header = Header::read(dtb)?;
+    // find where to insert: before end of `/reserved-memory`, or before end of root node
+    let mut cells = CellsStack::new();
+    let mut in_reserved = false;
+    let mut reserved_end = None;
+    let mut root_end = None;
+    for (offset, token) in Tokens::new(dtb, header) {
+        match token {
+            Token::BeginNode(name) => {
+                cells.begin_node()?;
+                if cells.depth == 2 && name == "reserved-memory" {
+                    in_reserved = true;
+                }
+            }
+            Token::EndNode => {
+                if cells.depth == 2 && in_reserved {
+                    reserved_end = Some((offset, cells.current()));
+                    in_reserved = false;
+                } else if cells.depth == 1 {
+                    root_end = Some(offset);
+                }
+                cells.end_node();
+            }
+            Token::Prop(name, value) => cells.prop(name, value)?,
+        }
+    }
+    // a created `/reserved-memory` has two address and two size cells
+    let created_cells = Cells {
+        address: 2,
+        size: 2,
+    };
+    let (insert_at, create_parent, reg_cells) = match (reserved_end, root_end) {
+        (Some((offset, cells)), _) => (offset, false, cells),
+        (None, Some(offset)) => (offset, true, created_cells),
+        (None, None) => return Err("no root node in device tree"),
+    };
+    let mut reg_value = [0u8; 16];
+    let reg_len = encode_reg(&mut reg_value, reg_cells, base as u64, size as u64)?;
+    // strings used by new properties, appended to strings block if absent
+    let mut strings_len = header.size_dt_strings;
+    let mut new_strings: [&str; 5] = [""; 5];
+    let mut new_strings_count = 0;
+    let mut name_offset = |name: &'static str| match header.find_string(dtb, name) {
+        Some(offset) => offset,
+        None => {
+            let offset = strings_len;
+            strings_len += name.len() + 1;
+            new_strings[new_strings_count] = name;
+            new_strings_count += 1;
+            offset
+        }
+    };
+    let reg = name_offset("reg");
+    let no_map = name_offset("no-map");
+    let parent_props = if create_parent {
+        Some((
+            name_offset("#address-cells"),
+            name_offset("#size-cells"),
+            name_offset("ranges"),
+        ))
+    } else {
+        None
+    };
+    let mut w = Writer { buf: dst, pos: 0 };
+    w.skip(FDT_HEADER_SIZE)?;
+    // memory reservation block, entries end with a zero entry
+    w.align(8)?;
+    let off_mem_rsvmap = w.pos;
+    let mut entry = header.off_mem_rsvmap;
+    loop {
+        let bytes = dtb
+            .get(entry..entry + 16)
+            .ok_or("memory reservation out of bound")?;
+        w.put(bytes)?;
+        entry += 16;
+        if be64(bytes, 0) == 0 && be64(bytes, 8) == 0 {
+            break;
+        }
+    }
+    // structure block with inserted nodes
+    let off_dt_struct = w.pos;
+    let old_struct = &dtb[header.off_dt_struct..][..header.size_dt_struct];
+    w.put(&old_struct[..insert_at])?;
+    if let Some((address_cells, size_cells, ranges)) = parent_props {
+        w.begin_node("reserved-memory")?;
+        w.prop(address_cells, &created_cells.address.to_be_bytes())?;
+        w.prop(size_cells, &created_cells.size.to_be_bytes())?;
+        w.prop(ranges, &[])?;
+    }
+    let mut node = [0u8; 32];
+    let node = format_node_name(&mut node, node_name, base)?;
+    w.begin_node(node)?;
+    w.prop(reg, &reg_value[..reg_len])?;
+    w.prop(no_map, &[])?;
+    w.put_u32(FDT_END_NODE)?;
+    if parent_props.is_some() {
+        w.put_u32(FDT_END_NODE)?;
+    }
+    w.put(&old_struct[insert_at..])?;
+    let size_dt_struct = w.pos - off_dt_struct;
+    // strings block
+    let off_dt_strings = w.pos;
+    w.put(&dtb[header.off_dt_strings..][..header.size_dt_strings])?;
+    for name in &new_strings[..new_strings_count] {
+        w.put(name.as_bytes())?;
+        w.put(&[0])?;
+    }
+    let totalsize = w.pos;
+    // header: magic, version fields and boot cpu are kept from the original tree
+    let buf = w.buf;
+    buf[..FDT_HEADER_SIZE].copy_from_slice(&dtb[..FDT_HEADER_SIZE]);
+    for (offset, value) in [
+        (4, totalsize),
+        (8, off_dt_struct),
+        (12, off_dt_strings),
+        (16, off_mem_rsvmap),
+        (32, strings_len),
+        (36, size_dt_struct),
+    ] {
+        buf[offset..offset + 4].copy_from_slice(&(value as u32).to_be_bytes());
+    }
+    Ok(totalsize)
+}
+
+// `name@base` in hexadecimal, as unit address convention of device tree
+fn format_node_name<'a>(
+    buf: &'a mut [u8],
+    name: &str,
+    base: usize,
+) -> core::result::Result<&'a str, &'static str> {
+    use core::fmt::Write;
+    struct Cursor<'b>(&'b mut [u8], usize);
+    impl Write for Cursor<'_> {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            let end = self.1 + s.len();
+            self.0
+                .get_mut(self.1..end)
+                .ok_or(core::fmt::Error)?
+                .copy_from_slice(s.as_bytes());
+            self.1 = end;
+            Ok(())
+        }
+    }
+    let mut cursor = Cursor(buf, 0);
+    write!(cursor, "{}@{:x}", name, base).map_err(|_| "node name too long")?;
+    let len = cursor.1;
+    core::str::from_utf8(&buf[..len]).map_err(|_| "node name not utf-8")
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) -> core::result::Result<(), &'static str> {
+        let end = self.pos + bytes.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or("device tree buffer too small")?
+            .copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn put_u32(&mut self, value: u32) -> core::result::Result<(), &'static str> {
+        self.put(&value.to_be_bytes())
+    }
+
+    fn skip(&mut self, len: usize) -> core::result::Result<(), &'static str> {
+        if self.pos + len > self.buf.len() {
+            return Err("device tree buffer too small");
+        }
+        self.pos += len;
+        Ok(())
+    }
+
+    fn align(&mut self, align: usize) -> core::result::Result<(), &'static str> {
+        let aligned = align_up(self.pos, align);
+        while self.pos < aligned {
+            self.put(&[0])?;
+        }
+        Ok(())
+    }
+
+    fn begin_node(&mut self, name: &str) -> core::result::Result<(), &'static str> {
+        self.put_u32(FDT_BEGIN_NODE)?;
+        self.put(name.as_bytes())?;
+        self.put(&[0])?;
+        self.align(4)
+    }
+
+    fn prop(&mut self, name_offset: usize, value: &[u8]) -> core::result::Result<(), &'static str> {
+        self.put_u32(FDT_PROP)?;
+        self.put_u32(value.len() as u32)?;
+        self.put_u32(name_offset as u32)?;
+        self.put(value)?;
+        self.align(4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    // Flattened device tree built token by token, for trees the board's own does not cover
+    struct Builder {
+        structure: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn new() -> Self {
+            Builder {
+                structure: Vec::new(),
+                strings: Vec::new(),
+            }
+        }
+
+        fn token(&mut self, token: u32) -> &mut Self {
+            self.structure.extend_from_slice(&token.to_be_bytes());
+            self
+        }
+
+        fn pad(&mut self) {
+            self.structure.resize(align_up(self.structure.len(), 4), 0);
+        }
+
+        fn begin(&mut self, name: &str) -> &mut Self {
+            self.token(FDT_BEGIN_NODE);
+            self.structure.extend_from_slice(name.as_bytes());
+            self.structure.push(0);
+            self.pad();
+            self
+        }
+
+        fn end(&mut self) -> &mut Self {
+            self.token(FDT_END_NODE)
+        }
+
+        fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let name_offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.token(FDT_PROP)
+                .token(value.len() as u32)
+                .token(name_offset);
+            self.structure.extend_from_slice(value);
+            self.pad();
+            self
+        }
+
+        fn prop_u32(&mut self, name: &str, value: u32) -> &mut Self {
+            self.prop(name, &value.to_be_bytes())
+        }
+
+        fn prop_str(&mut self, name: &str, value: &str) -> &mut Self {
+            let mut bytes = value.as_bytes().to_vec();
+            bytes.push(0);
+            self.prop(name, &bytes)
+        }
+
+        // Header, an empty memory reservation block, structure block with its end token, then
+        // strings block; kept in u64s, as the deserializer reads the header in place
+        fn build(&mut self) -> Dtb {
+            self.token(0x9);
+            let off_mem_rsvmap = FDT_HEADER_SIZE;
+            let off_dt_struct = off_mem_rsvmap + 16;
+            let off_dt_strings = off_dt_struct + self.structure.len();
+            let totalsize = off_dt_strings + self.strings.len();
+            let mut bytes = Vec::new();
+            for field in [
+                FDT_MAGIC,
+                totalsize as u32,
+                off_dt_struct as u32,
+                off_dt_strings as u32,
+                off_mem_rsvmap as u32,
+                17,
+                16,
+                0,
+                self.strings.len() as u32,
+                self.structure.len() as u32,
+            ] {
+                bytes.extend_from_slice(&field.to_be_bytes());
+            }
+            bytes.extend_from_slice(&[0; 16]);
+            bytes.extend_from_slice(&self.structure);
+            bytes.extend_from_slice(&self.strings);
+            let mut words = std::vec![0u64; align_up(bytes.len(), 8) / 8];
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    bytes.as_ptr(),
+                    words.as_mut_ptr() as *mut u8,
+                    bytes.len(),
+                )
+            };
+            Dtb {
+                words,
+                len: bytes.len(),
+            }
+        }
+    }
+
+    struct Dtb {
+        words: Vec<u64>,
+        len: usize,
+    }
+
+    impl Dtb {
+        fn bytes(&self) -> &[u8] {
+            unsafe { core::slice::from_raw_parts(self.words.as_ptr() as *const u8, self.len) }
+        }
+    }
+
+    // Root with 2/2 cells, two harts and a UART console, up to where memory nodes go
+    fn board_tree(builder: &mut Builder) -> &mut Builder {
+        builder
+            .begin("")
+            .prop_u32("#address-cells", 2)
+            .prop_u32("#size-cells", 2)
+            .begin("cpus")
+            .prop_u32("#address-cells", 1)
+            .prop_u32("#size-cells", 0)
+            .prop_u32("timebase-frequency", 6_250_000)
+            .begin("cpu@0")
+            .end()
+            .begin("cpu@1")
+            .end()
+            .end()
+    }
+
+    fn reg(entries: &[(u64, u64)]) -> Vec<u8> {
+        let mut reg = Vec::new();
+        for (address, size) in entries {
+            reg.extend_from_slice(&address.to_be_bytes());
+            reg.extend_from_slice(&size.to_be_bytes());
+        }
+        reg
+    }
+
+    #[test]
+    fn complete_tree() {
+        let dtb = board_tree(&mut Builder::new())
+            .begin("chosen")
+            .prop_str("stdout-path", "/soc/serial@12440000:115200n8")
+            .end()
+            .begin("memory@80000000")
+            .prop_str("device_type", "memory")
+            .prop("reg", &reg(&[(0x8000_0000, 0x2_0000_0000)]))
+            .end()
+            .end()
+            .build();
+        let info = parse_device_tree(dtb.bytes()).unwrap();
+        assert_eq!(
+            info,
+            BoardInfo {
+                stdout_path: Some("/soc/serial@12440000:115200n8"),
+                stdout_node: Some("/soc/serial@12440000"),
+                num_harts: 2,
+                timebase_frequency: Some(6_250_000),
+                memory: Some((0x8000_0000, 0x2_8000_0000)),
+            }
+        );
+    }
+
+    // Tree whose `/cpus` has given `timebase-frequency` value
+    fn tree_with_timebase(value: &[u8]) -> Dtb {
+        Builder::new()
+            .begin("")
+            .begin("cpus")
+            .prop("timebase-frequency", value)
+            .begin("cpu@0")
+            .end()
+            .end()
+            .end()
+            .build()
+    }
+
+    #[test]
+    fn bogus_timebase_frequency() {
+        for (value, frequency) in [
+            (&0u32.to_be_bytes()[..], 0),
+            (&9_999u32.to_be_bytes()[..], 9_999),
+            (&u64::MAX.to_be_bytes()[..], u64::MAX),
+            (&1_000_000_001u64.to_be_bytes()[..], 1_000_000_001),
+        ] {
+            let dtb = tree_with_timebase(value);
+            let info = parse_device_tree(dtb.bytes()).unwrap();
+            // reported as is, but not used
+            assert_eq!(info.timebase_frequency, Some(frequency));
+            assert_eq!(info.valid_timebase_frequency(), None);
+        }
+        // neither one nor two cells
+        let dtb = tree_with_timebase(&[0, 0x5F, 0x5E]);
+        let info = parse_device_tree(dtb.bytes()).unwrap();
+        assert_eq!(info.timebase_frequency, None);
+        assert_eq!(info.valid_timebase_frequency(), None);
+        // both ends of the range are valid, in either cell count
+        for frequency in [10_000u64, 1_000_000_000] {
+            let dtb = tree_with_timebase(&(frequency as u32).to_be_bytes());
+            let info = parse_device_tree(dtb.bytes()).unwrap();
+            assert_eq!(info.valid_timebase_frequency(), Some(frequency));
+            let dtb = tree_with_timebase(&frequency.to_be_bytes());
+            let info = parse_device_tree(dtb.bytes()).unwrap();
+            assert_eq!(info.valid_timebase_frequency(), Some(frequency));
+        }
+    }
+
+    #[test]
+    fn stdout_alias() {
+        let dtb = board_tree(&mut Builder::new())
+            .begin("aliases")
+            .prop_str("serial0", "/soc/serial@12440000")
+            .end()
+            .begin("chosen")
+            .prop_str("stdout-path", "serial0:9600e7")
+            .end()
+            .end()
+            .build();
+        let info = parse_device_tree(dtb.bytes()).unwrap();
+        assert_eq!(info.stdout_path, Some("serial0:9600e7"));
+        assert_eq!(info.stdout_node, Some("/soc/serial@12440000"));
+    }
+
+    #[test]
+    fn missing_memory_node() {
+        let dtb = board_tree(&mut Builder::new())
+            .begin("chosen")
+            .prop_str("stdout-path", "serial0")
+            .end()
+            .end()
+            .build();
+        let info = parse_device_tree(dtb.bytes()).unwrap();
+        assert_eq!(info.memory, None);
+        assert_eq!(info.num_harts, 2);
+        assert_eq!(dram_range(dtb.bytes()), None);
+        let mut dtb = dtb;
+        assert_eq!(
+            fixup_memory_node(unsafe { words_mut(&mut dtb) }, 0x8000_0000, 0x1000),
+            Err("no memory node")
+        );
+    }
+
+    #[test]
+    fn missing_chosen() {
+        let dtb = board_tree(&mut Builder::new())
+            .begin("memory@80000000")
+            .prop("reg", &reg(&[(0x8000_0000, 0x1000_0000)]))
+            .end()
+            .end()
+            .build();
+        let info = parse_device_tree(dtb.bytes()).unwrap();
+        assert_eq!(info.stdout_path, None);
+        assert_eq!(info.stdout_node, None);
+        assert_eq!(info.memory, Some((0x8000_0000, 0x9000_0000)));
+        assert_eq!(chosen_initrd(dtb.bytes()), None);
+        assert_eq!(property(dtb.bytes(), "/chosen", "stdout-path"), None);
+    }
+
+    #[test]
+    fn multiple_memory_banks() {
+        // two banks in one `reg`, then a second memory node; firmware uses the first bank
+        let dtb = board_tree(&mut Builder::new())
+            .begin("memory@80000000")
+            .prop(
+                "reg",
+                &reg(&[(0x8000_0000, 0x4000_0000), (0x1_0000_0000, 0x4000_0000)]),
+            )
+            .end()
+            .begin("memory@200000000")
+            .prop("reg", &reg(&[(0x2_0000_0000, 0x1000_0000)]))
+            .end()
+            .end()
+            .build();
+        let info = parse_device_tree(dtb.bytes()).unwrap();
+        assert_eq!(info.memory, Some((0x8000_0000, 0xC000_0000)));
+        let banks = property(dtb.bytes(), "/memory@80000000", "reg").unwrap();
+        let banks: Vec<_> = reg_entries(
+            banks,
+            Cells {
+                address: 2,
+                size: 2,
+            },
+        )
+        .unwrap()
+        .collect();
+        assert_eq!(
+            banks,
+            [(0x8000_0000, 0x4000_0000), (0x1_0000_0000, 0x4000_0000)]
+        );
+        // the fixup leaves a single bank in place of both
+        let mut dtb = dtb;
+        fixup_memory_node(unsafe { words_mut(&mut dtb) }, 0x8000_0000, 0x8000_0000).unwrap();
+        let reg = property(dtb.bytes(), "/memory@80000000", "reg").unwrap();
+        assert_eq!(reg.len(), 16);
+        assert_eq!(dram_range(dtb.bytes()), Some((0x8000_0000, 0x1_0000_0000)));
+    }
+
+    unsafe fn words_mut(dtb: &mut Dtb) -> &mut [u8] {
+        core::slice::from_raw_parts_mut(dtb.words.as_mut_ptr() as *mut u8, dtb.len)
+    }
+}
@@ -0,0 +1,49 @@
+//! Extensions of downstream forks
+//!
+//! A fork adding board specific SBI calls registers them in `register_extensions`, and keeps
+//! their handlers in its own modules; the dispatch in `execute` and the extension table in
+//! `vendor` stay untouched. Boot hart calls `register_extensions` once in `rust_main`, after the
+//! extensions of RustSBI-JH7100 are registered and before it enters supervisor, thus every
+//! extension is in place before the first supervisor ecall on any hart. To add one:
+//!
+//! 1. pick an extension id from `vendor::EXTENSION_DOWNSTREAM_START` to
+//!    `vendor::EXTENSION_VENDOR_END`; one registered twice panics at boot;
+//! 2. write a `vendor::ExtensionHandler`, which gets the function id, a0 to a5 and the calling
+//!    hart, and returns `SbiRet::not_supported()` for function ids it does not know;
+//! 3. call `vendor::register_vendor_extension(id, handler)` below.
+//!
+//! Base extension probe reports it as available. With feature `example-extension`, the example
+//! below is registered as `EXTENSION_EXAMPLE`:
+//!
+//! | Function | Parameters | Returns
+//! |:---------|:-----------|:---------
+//! | 0        |            | hart id of the caller
+//! | 1        | a, b       | a + b, wrapping
+#[cfg(feature = "example-extension")]
+use crate::vendor;
+#[cfg(feature = "example-extension")]
+use rustsbi::SbiRet;
+
+#[cfg(feature = "example-extension")]
+pub const EXTENSION_EXAMPLE: usize = vendor::EXTENSION_DOWNSTREAM_START;
+
+// Register extensions of this fork; called once on boot hart, before supervisor entry
+pub fn register_extensions() {
+    #[cfg(feature = "example-extension")]
+    vendor::register_vendor_extension(EXTENSION_EXAMPLE, handle_example);
+}
+
+#[cfg(feature = "example-extension")]
+const FUNCTION_EXAMPLE_HART_ID: usize = 0x0;
+#[cfg(feature = "example-extension")]
+const FUNCTION_EXAMPLE_ADD: usize = 0x1;
+
+// Handler of the example extension `EXTENSION_EXAMPLE`
+#[cfg(feature = "example-extension")]
+fn handle_example(function: usize, param: [usize; 6], hart_id: usize) -> SbiRet {
+    match function {
+        FUNCTION_EXAMPLE_HART_ID => SbiRet::ok(hart_id),
+        FUNCTION_EXAMPLE_ADD => SbiRet::ok(param[0].wrapping_add(param[1])),
+        _ => SbiRet::not_supported(),
+    }
+}
@@ -0,0 +1,140 @@
+//! DRAM self-test at boot
+//!
+//! Runs once on boot hart before firmware copies the payload into DRAM. The tested range is
+//! `board::DRAM_TEST_RANGE` clipped to the memory node of device tree, excluding firmware itself
+//! and the payload load area. Each part goes through:
+//!
+//! - walking ones: every single-bit pattern written to and read back from its first word,
+//!   which finds stuck or shorted data lines;
+//! - address in address: every word is written with its own address, then checked and written
+//!   with the inverted address, then checked again, which finds address line faults and cells
+//!   stuck in either polarity.
+//!
+//! Failing cells are logged up to `MAX_REPORTED_FAILURES`, then the result with the first failing
+//! address. It takes seconds per GiB and is meant for hardware validation only; boot continues
+//! either way, so that a failing board can still be inspected.
+use crate::board;
+use crate::println;
+
+// Failing cells printed one by one; the rest are only counted
+const MAX_REPORTED_FAILURES: usize = 16;
+
+// Progress is printed every this many bytes
+const PROGRESS_INTERVAL: usize = 1 << 30;
+
+const WORD: usize = core::mem::size_of::<u64>();
+
+struct Failures {
+    count: usize,
+    first: Option<usize>,
+}
+
+impl Failures {
+    fn report(&mut self, addr: usize, expected: u64, actual: u64) {
+        if self.count < MAX_REPORTED_FAILURES {
+            println!(
+                "[rustsbi] DRAM test: {:#x} expected {:#018x}, read {:#018x}",
+                addr, expected, actual
+            );
+        }
+        self.count += 1;
+        self.first.get_or_insert(addr);
+    }
+}
+
+// Test DRAM in [dram_start, dram_end) as given by device tree, skipping `payload`
+pub fn run(dram_start: usize, dram_end: usize, payload: core::ops::Range<usize>) {
+    extern "C" {
+        static stext: u8;
+        static ebss: u8;
+    }
+    let firmware = unsafe { &stext as *const u8 as usize..&ebss as *const u8 as usize };
+    let start = align_up(dram_start.max(board::DRAM_TEST_RANGE.start));
+    let end = dram_end.min(board::DRAM_TEST_RANGE.end) & !(WORD - 1);
+    if start >= end {
+        println!("[rustsbi] DRAM test: empty test range, skipped");
+        return;
+    }
+    println!(
+        "[rustsbi] DRAM test: {:#x}..{:#x}, skipping firmware {:#x?} and payload {:#x?}",
+        start, end, firmware, payload
+    );
+    let mut failures = Failures {
+        count: 0,
+        first: None,
+    };
+    let mut tested = 0;
+    for (part_start, part_end) in exclude(start, end, [firmware, payload]) {
+        test_part(part_start, part_end, &mut failures, &mut tested);
+    }
+    match failures.first {
+        None => println!("[rustsbi] DRAM test: PASS, {:#x} bytes tested", tested),
+        Some(first) => println!(
+            "[rustsbi] DRAM test: FAIL, {} failing cells, first at {:#x}",
+            failures.count, first
+        ),
+    }
+}
+
+fn test_part(start: usize, end: usize, failures: &mut Failures, tested: &mut usize) {
+    let words = (end - start) / WORD;
+    let base = start as *mut u64;
+    // walking ones on the first word
+    for bit in 0..64 {
+        let pattern = 1u64 << bit;
+        let actual = unsafe {
+            base.write_volatile(pattern);
+            base.read_volatile()
+        };
+        if actual != pattern {
+            failures.report(start, pattern, actual);
+        }
+    }
+    // address in address, then its inverse
+    for i in 0..words {
+        let addr = start + i * WORD;
+        unsafe { base.add(i).write_volatile(addr as u64) };
+    }
+    for i in 0..words {
+        let addr = start + i * WORD;
+        let actual = unsafe { base.add(i).read_volatile() };
+        if actual != addr as u64 {
+            failures.report(addr, addr as u64, actual);
+        }
+        unsafe { base.add(i).write_volatile(!addr as u64) };
+    }
+    for i in 0..words {
+        let addr = start + i * WORD;
+        let actual = unsafe { base.add(i).read_volatile() };
+        if actual != !addr as u64 {
+            failures.report(addr, !addr as u64, actual);
+        }
+        let done = *tested + (i + 1) * WORD;
+        if done % PROGRESS_INTERVAL == 0 {
+            println!("[rustsbi] DRAM test: {} GiB done", done / PROGRESS_INTERVAL);
+        }
+    }
+    *tested += words * WORD;
+}
+
+// Split [start, end) into at most three parts which overlap none of the excluded ranges
+fn exclude(
+    start: usize,
+    end: usize,
+    mut excluded: [core::ops::Range<usize>; 2],
+) -> impl Iterator<Item = (usize, usize)> {
+    excluded.sort_unstable_by_key(|range| range.start);
+    let mut parts = [(0, 0); 3];
+    let mut cursor = start;
+    for (part, range) in parts.iter_mut().zip(excluded.iter()) {
+        let range_start = range.start.clamp(cursor, end) & !(WORD - 1);
+        *part = (cursor, range_start.max(cursor));
+        cursor = align_up(range.end).clamp(cursor, end);
+    }
+    parts[2] = (cursor, end);
+    parts.into_iter().filter(|(start, end)| start < end)
+}
+
+fn align_up(addr: usize) -> usize {
+    (addr + WORD - 1) & !(WORD - 1)
+}
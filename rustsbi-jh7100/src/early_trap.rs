@@ -1,16 +1,14 @@
+use crate::println;
 use riscv::register::{
     mcause, mscratch,
     mstatus::Mstatus,
     mtval,
     mtvec::{self, TrapMode},
 };
-use rustsbi::println;
 
 #[inline]
 pub fn init(hart_id: usize) {
-    let stack_base = unsafe { &super::SBI_STACK } as *const _ as usize;
-    let stack = stack_base + (hart_id + 1) * super::PER_HART_STACK_SIZE;
-    mscratch::write(stack);
+    mscratch::write(super::hart_stack_top(hart_id));
     let mut addr = early_trap_fail as usize;
     if addr & 0x2 != 0 {
         addr += 0x2; // 中断入口地址必须对齐到4个字节
@@ -19,6 +17,16 @@ pub fn init(hart_id: usize) {
 }
 
 extern "C" fn rust_fail(ctx: &SupervisorContext) -> ! {
+    #[cfg(feature = "stack-guard")]
+    {
+        let hart_id = riscv::register::mhartid::read();
+        if crate::stack_guard::guard(hart_id).contains(&mtval::read()) {
+            println!(
+                "rustsbi: early init stage stack overflow on hart {}",
+                hart_id
+            );
+        }
+    }
     println!(
         "rustsbi: early init stage fail, context: {:x?}, mcause: {:?}, mtval: {:x}",
         ctx,
@@ -0,0 +1,113 @@
+//! Console UART description for the supervisor's early console
+//!
+//! Linux `earlycon` takes base, `reg-shift`, `reg-io-width`, `clock-frequency` and
+//! `current-speed` of the `stdout-path` node and drives the UART with them before any driver is
+//! up; a wrong one garbles or silences the console until the real driver takes over. After the
+//! device tree for supervisor is placed, boot hart writes what firmware itself uses for console
+//! UART into node `board::CONSOLE_NODE`, reading the baud rate back from the UART divisor. A
+//! property that already matches is left as it is, a tree that matches throughout is untouched.
+//!
+//! With feature `earlycon`, `stdout-path` of `/chosen` is also pointed at that node with the baud
+//! rate in use, and `earlycon` is appended to `bootargs` if it is not there yet, so that the
+//! kernel's early console starts right where firmware output ends.
+use crate::peripheral::{Uart, UartConfig};
+use crate::println;
+
+// Describe console UART in node `board::CONSOLE_NODE` of device tree `dtb`; called once on boot
+// hart after the device tree for supervisor is placed
+pub fn fixup_device_tree(dtb: &mut [u8]) {
+    // a stalled UART would never drain for its divisor to be read, nor show supervisor output
+    if crate::console::fallback_active() {
+        return println!("[rustsbi] warning: console UART stalled, not described for supervisor");
+    }
+    let config = unsafe { Uart::preloaded_uart0() }.config();
+    match fixup_console_node(dtb, &config) {
+        Ok(0) => println!(
+            "[rustsbi] console node {} matches UART setup",
+            crate::board::CONSOLE_NODE
+        ),
+        Ok(changed) => println!(
+            "[rustsbi] console node {}: {} properties updated to UART setup {:x?}",
+            crate::board::CONSOLE_NODE,
+            changed,
+            config
+        ),
+        Err(e) => println!(
+            "[rustsbi] warning: console node {} not updated, {}",
+            crate::board::CONSOLE_NODE,
+            e
+        ),
+    }
+    #[cfg(feature = "earlycon")]
+    if let Err(e) = fixup_chosen(dtb, &config) {
+        println!("[rustsbi] warning: earlycon not set up in /chosen, {}", e);
+    }
+}
+
+// Returns how many properties were changed
+fn fixup_console_node(dtb: &mut [u8], config: &UartConfig) -> Result<usize, &'static str> {
+    use crate::device_tree::{property, set_property};
+    let node = crate::board::CONSOLE_NODE;
+    // firmware cannot move the UART, a node at another address is the wrong node
+    let reg = property(dtb, node, "reg").ok_or("node has no reg")?;
+    let base = match reg.len() {
+        8.. => u64::from_be_bytes(reg[..8].try_into().unwrap()) as usize,
+        4.. => u32::from_be_bytes(reg[..4].try_into().unwrap()) as usize,
+        _ => return Err("invalid reg"),
+    };
+    if base != config.base {
+        return Err("node is not at console UART address");
+    }
+    let mut properties = [
+        ("reg-shift", config.reg_shift),
+        ("reg-io-width", config.reg_io_width),
+        ("clock-frequency", config.clock),
+        ("current-speed", 0),
+    ];
+    let count = match config.baud {
+        Some(baud) => {
+            properties[3].1 = baud;
+            4
+        }
+        // the previous stage left UART unprogrammed, keep what the tree says
+        None => 3,
+    };
+    let mut changed = 0;
+    for (name, value) in &properties[..count] {
+        if set_property(dtb, node, name, &value.to_be_bytes())? {
+            changed += 1;
+        }
+    }
+    Ok(changed)
+}
+
+#[cfg(feature = "earlycon")]
+fn fixup_chosen(dtb: &mut [u8], config: &UartConfig) -> Result<(), &'static str> {
+    use crate::device_tree::{property, set_property};
+    use alloc::string::String;
+    use core::fmt::Write;
+    let mut value = String::new();
+    match config.baud {
+        Some(baud) => write!(value, "{}:{}n8\0", crate::board::CONSOLE_NODE, baud),
+        None => write!(value, "{}\0", crate::board::CONSOLE_NODE),
+    }
+    .map_err(|_| "cannot format stdout-path")?;
+    set_property(dtb, "/chosen", "stdout-path", value.as_bytes())?;
+    let bootargs = property(dtb, "/chosen", "bootargs")
+        .map(|value| value.split(|b| *b == 0).next().unwrap_or(&[]))
+        .and_then(|value| core::str::from_utf8(value).ok())
+        .unwrap_or("");
+    let has_earlycon = bootargs
+        .split_ascii_whitespace()
+        .any(|arg| arg == "earlycon" || arg.starts_with("earlycon="));
+    if !has_earlycon {
+        let mut value = String::new();
+        match bootargs {
+            "" => write!(value, "earlycon\0"),
+            bootargs => write!(value, "{} earlycon\0", bootargs),
+        }
+        .map_err(|_| "cannot format bootargs")?;
+        set_property(dtb, "/chosen", "bootargs", value.as_bytes())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//! Per-hart SBI call latency histograms
+//!
+//! With feature `ecall-latency`, mtime is read when firmware starts servicing an SBI call and
+//! when its result is ready; the difference is counted in a histogram of the handler which
+//! serviced it, in scratch slot `SLOT_ECALL_LATENCY`. Bucket 0 holds calls done within the same
+//! mtime tick, bucket `b` calls of `2^(b-1)` to `2^b - 1` ticks, and the last one everything
+//! longer. Trap entry and return are not included; an HSM retentive suspend includes the time the
+//! hart stayed suspended.
+//!
+//! Histograms are cleared at each boot and printed in microseconds through vendor extension
+//! `EXTENSION_DIAGNOSTICS`. Like trap counters, each hart only increments its own histogram.
+use crate::execute::{EcallHandler, ECALL_HANDLERS};
+use crate::peripheral::timebase_frequency;
+use crate::println;
+use crate::scratch::{self, SLOT_ECALL_LATENCY};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const BUCKETS: usize = 16;
+
+type HartHistograms = [[AtomicUsize; BUCKETS]; ECALL_HANDLERS.len()];
+
+const _: () = assert!(
+    core::mem::size_of::<HartHistograms>() * crate::NUM_HARTS <= SLOT_ECALL_LATENCY.size(),
+    "latency histograms do not fit in their scratch slot"
+);
+
+fn histograms(hart_id: usize) -> Option<&'static HartHistograms> {
+    if hart_id >= crate::NUM_HARTS {
+        return None;
+    }
+    // slots are aligned to their offset in 2MiB aligned scratch region
+    unsafe { scratch::slot(SLOT_ECALL_LATENCY) }
+        .map(|buf| unsafe { &*(buf.as_ptr() as *const HartHistograms).add(hart_id) })
+}
+
+// Clear histograms of every hart; called once on boot hart after scratch region is initialized
+pub fn init() {
+    for hart_id in 0..crate::NUM_HARTS {
+        if let Some(histograms) = histograms(hart_id) {
+            for bucket in histograms.iter().flatten() {
+                bucket.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// Count an SBI call serviced by `handler` on current hart, from mtime `start` to `end`
+#[inline]
+pub fn record(hart_id: usize, handler: EcallHandler, start: u64, end: u64) {
+    if let Some(histograms) = histograms(hart_id) {
+        let ticks = end.wrapping_sub(start);
+        let bucket = (64 - ticks.leading_zeros() as usize).min(BUCKETS - 1);
+        // only this hart writes, a plain load and store is enough
+        let counter = &histograms[handler as usize][bucket];
+        counter.store(counter.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+    }
+}
+
+// Print histograms of every hart, handlers without calls left out
+pub fn print() {
+    for hart_id in 0..crate::NUM_HARTS {
+        let histograms = match histograms(hart_id) {
+            Some(histograms) => histograms,
+            None => return println!("[rustsbi] unavailable, no firmware scratch memory"),
+        };
+        println!("[rustsbi] hart {} SBI call latency:", hart_id);
+        for handler in ECALL_HANDLERS {
+            let mut counts = [0; BUCKETS];
+            for (count, bucket) in counts.iter_mut().zip(&histograms[handler as usize]) {
+                *count = bucket.load(Ordering::Relaxed);
+            }
+            let calls: usize = counts.iter().sum();
+            if calls == 0 {
+                continue;
+            }
+            println!("[rustsbi]   {:?}, {} calls:", handler, calls);
+            for (bucket, count) in counts.iter().enumerate().filter(|(_, count)| **count != 0) {
+                println!("[rustsbi]     {:>17}: {}", bucket_range(bucket), count);
+            }
+        }
+    }
+}
+
+// Latency range of a bucket in microseconds, e.g. `0.16 .. 0.32 us`
+fn bucket_range(bucket: usize) -> alloc::string::String {
+    let micros = |ticks: u64| {
+        let nanos = ticks * 1_000_000_000 / timebase_frequency();
+        alloc::format!("{}.{:02}", nanos / 1000, nanos % 1000 / 10)
+    };
+    match bucket {
+        0 => alloc::format!("< {} us", micros(1)),
+        _ if bucket == BUCKETS - 1 => alloc::format!(">= {} us", micros(1 << (bucket - 1))),
+        _ => alloc::format!(
+            "{} .. {} us",
+            micros(1 << (bucket - 1)),
+            micros(1 << bucket)
+        ),
+    }
+}
@@ -1,71 +1,332 @@
 use crate::feature;
-use crate::hsm::{pause, HsmCommand, U74Hsm};
+use crate::hsm::{wait_for_start, HsmCommand, U74Hsm};
 use crate::runtime::{MachineTrap, Runtime, SupervisorContext};
+use crate::trap_stats::{self, Emulation, TrapKind};
 use core::{
     ops::{Generator, GeneratorState},
     pin::Pin,
 };
-use riscv::register::scause::{Exception, Interrupt, Trap};
 use riscv::register::*;
 
+// SBI 0.1 legacy extensions, each of which has one function and returns in a0 only:
+//
+// | Extension              | a0 on return
+// |:-----------------------|:-------------
+// | set_timer              | unchanged
+// | console_putchar        | unchanged
+// | console_getchar        | character, or -1 if none
+// | clear_ipi              | unchanged
+// | send_ipi               | unchanged
+// | remote_fence_i         | -2 (not supported)
+// | remote_sfence_vma      | -2 (not supported)
+// | remote_sfence_vma_asid | -2 (not supported)
+// | shutdown               | does not return
+const LEGACY_SET_TIMER: usize = 0x00;
+const LEGACY_CLEAR_IPI: usize = 0x03;
+const LEGACY_SHUTDOWN: usize = 0x08;
+
+// Legacy clear IPI is not handled by RustSBI; it clears pending supervisor software interrupt
+fn legacy_clear_ipi(extension: usize, param: [usize; 6]) -> Option<rustsbi::SbiRet> {
+    if extension != LEGACY_CLEAR_IPI {
+        return None;
+    }
+    unsafe { mip::clear_ssoft() };
+    Some(rustsbi::SbiRet {
+        error: param[0],
+        value: param[1],
+    })
+}
+
+// Legacy set timer is not passed to RustSBI either, whose handler writes machine timer enable
+// and pending supervisor timer by itself after the deadline is recorded, behind `timer`; a
+// deadline in the past is lost and firmware deadlines stop. It is handled as set timer of TIME
+// extension, see `Clint`
+fn legacy_set_timer(extension: usize, param: [usize; 6]) -> Option<rustsbi::SbiRet> {
+    if extension != LEGACY_SET_TIMER {
+        return None;
+    }
+    #[cfg(target_pointer_width = "64")]
+    let time_value = param[0] as u64;
+    #[cfg(target_pointer_width = "32")]
+    let time_value = param[0] as u64 | (param[1] as u64) << 32;
+    crate::timer::set_supervisor_deadline(calling_hart(), time_value);
+    Some(rustsbi::SbiRet {
+        error: param[0],
+        value: param[1],
+    })
+}
+
+// Firmware handler which serviced an ecall; calls passed to RustSBI are told apart by extension
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EcallHandler {
+    Fwft,
+    Vendor,
+    Legacy,
+    Base,
+    Timer,
+    Ipi,
+    Rfence,
+    Hsm,
+    // any other extension, including unknown ones RustSBI rejects as not supported
+    RustsbiDefault,
+    // re-issued to parent firmware, with feature `sbi-forward`
+    Forward,
+}
+
+// Every `EcallHandler`, in discriminant order
+pub const ECALL_HANDLERS: [EcallHandler; 10] = [
+    EcallHandler::Fwft,
+    EcallHandler::Vendor,
+    EcallHandler::Legacy,
+    EcallHandler::Base,
+    EcallHandler::Timer,
+    EcallHandler::Ipi,
+    EcallHandler::Rfence,
+    EcallHandler::Hsm,
+    EcallHandler::RustsbiDefault,
+    EcallHandler::Forward,
+];
+
+const EXTENSION_BASE: usize = 0x10;
+const EXTENSION_TIMER: usize = 0x5449_4D45;
+const EXTENSION_IPI: usize = 0x73_5049;
+const EXTENSION_RFENCE: usize = 0x5246_4E43;
+const EXTENSION_HSM: usize = 0x48_534D;
+const LEGACY_EXTENSIONS: core::ops::RangeInclusive<usize> = 0x00..=0x08;
+
+// Handlers before RustSBI are tried in order, each returns None for calls it does not own
+fn dispatch_ecall(
+    extension: usize,
+    function: usize,
+    param: [usize; 6],
+) -> (rustsbi::SbiRet, EcallHandler) {
+    #[cfg(feature = "sbi-forward")]
+    if let Some(ans) = crate::forward::handle_ecall(extension, function, param) {
+        return (ans, EcallHandler::Forward);
+    }
+    if let Some(ans) = crate::fwft::handle_ecall(extension, function, param) {
+        return (ans, EcallHandler::Fwft);
+    }
+    if let Some(ans) = crate::vendor::handle_ecall(extension, function, param) {
+        return (ans, EcallHandler::Vendor);
+    }
+    if let Some(ans) = legacy_set_timer(extension, param) {
+        return (ans, EcallHandler::Legacy);
+    }
+    if let Some(ans) = legacy_clear_ipi(extension, param) {
+        return (ans, EcallHandler::Legacy);
+    }
+    let handler = match extension {
+        EXTENSION_BASE => EcallHandler::Base,
+        EXTENSION_TIMER => EcallHandler::Timer,
+        EXTENSION_IPI => EcallHandler::Ipi,
+        EXTENSION_RFENCE => EcallHandler::Rfence,
+        EXTENSION_HSM => EcallHandler::Hsm,
+        _ if LEGACY_EXTENSIONS.contains(&extension) => EcallHandler::Legacy,
+        _ => EcallHandler::RustsbiDefault,
+    };
+    (rustsbi::ecall(extension, function, param), handler)
+}
+
+// Hart whose SBI call is being handled.
+//
+// `rustsbi::ecall` and the RustSBI extension traits do not pass the caller to handlers. An ecall
+// traps into machine mode on the hart which made it, and firmware handles it there to the end
+// without switching harts; thus `mhartid` of the handling hart is always the caller, and no
+// global state is needed to pass it. Handlers which act on the calling hart, e.g. HSM suspend,
+// timer, FWFT and firmware extensions, read it through this function.
+#[inline]
+pub fn calling_hart() -> usize {
+    riscv::register::mhartid::read()
+}
+
 pub fn execute_supervisor(supervisor_mepc: usize, hart_id: usize, opaque: usize, hsm: U74Hsm) {
     let mut rt = Runtime::new_sbi_supervisor(supervisor_mepc, hart_id, opaque);
     hsm.record_current_start_finished();
+    #[cfg(feature = "hang-watchdog")]
+    crate::watchdog::start(hart_id);
+    #[cfg(feature = "firmware-timer")]
+    crate::timer::start_tick(hart_id);
+    #[cfg(feature = "diagnostics")]
+    crate::watchpoint::sync_delegation(hart_id);
     loop {
         match Pin::new(&mut rt).resume(()) {
             GeneratorState::Yielded(MachineTrap::SbiCall()) => {
+                #[cfg(feature = "hang-watchdog")]
+                crate::watchdog::feed(hart_id);
                 let ctx = rt.context_mut();
+                #[cfg(feature = "check-ecall")]
+                check_ecall(ctx.mepc);
+                // checkpoint calls read and replace the whole supervisor context
+                #[cfg(feature = "checkpoint")]
+                if ctx.a7 == crate::vendor::EXTENSION_CHECKPOINT {
+                    trap_stats::count_ecall(hart_id, EcallHandler::Vendor);
+                    // after a restore, the call which saved the checkpoint returns instead
+                    if let Some(ans) = crate::checkpoint::handle_ecall(ctx) {
+                        ctx.a0 = ans.error;
+                        ctx.a1 = ans.value;
+                        skip_emulated(ctx, ECALL_LENGTH);
+                    }
+                    continue;
+                }
                 let param = [ctx.a0, ctx.a1, ctx.a2, ctx.a3, ctx.a4, ctx.a5];
-                let ans = rustsbi::ecall(ctx.a7, ctx.a6, param);
+                debug_assert_eq!(calling_hart(), hart_id);
+                #[cfg(feature = "ecall-latency")]
+                let start = crate::peripheral::Clint::new(0x2000000 as *mut u8).get_mtime();
+                let (ans, handler) = dispatch_ecall(ctx.a7, ctx.a6, param);
+                #[cfg(feature = "ecall-latency")]
+                {
+                    let end = crate::peripheral::Clint::new(0x2000000 as *mut u8).get_mtime();
+                    crate::ecall_latency::record(hart_id, handler, start, end);
+                }
+                trap_stats::count_ecall(hart_id, handler);
+                #[cfg(feature = "sbi-trace")]
+                crate::trace::record(hart_id, ctx.a7, ctx.a6, &ans, handler);
                 if ans.error == 0x233 {
                     // hart non-retentive resume
-                    if let Some(HsmCommand::Start(start_paddr, opaque)) = hsm.last_command() {
+                    if let Some(HsmCommand::Start(start_paddr, opaque)) = hsm.take_command() {
                         unsafe {
                             satp::write(0);
                             sstatus::clear_sie();
+                            sscratch::write(0);
                         }
                         hsm.record_current_start_finished();
                         ctx.mstatus = mstatus::read(); // get from modified sstatus
                         ctx.a0 = hart_id;
                         ctx.a1 = opaque;
                         ctx.mepc = start_paddr;
+                        crate::runtime::check_supervisor_entry(ctx);
                     }
+                } else if ctx.a7 <= LEGACY_SHUTDOWN {
+                    // legacy calls return in a0 only, a1 is preserved
+                    ctx.a0 = ans.error;
+                    skip_emulated(ctx, ECALL_LENGTH);
                 } else {
                     ctx.a0 = ans.error;
                     ctx.a1 = ans.value;
-                    ctx.mepc = ctx.mepc.wrapping_add(4);
+                    skip_emulated(ctx, ECALL_LENGTH);
                 }
             }
             GeneratorState::Yielded(MachineTrap::IllegalInstruction()) => {
                 let ctx = rt.context_mut();
-                // FIXME: get_vaddr_u32这个过程可能出错。
-                let ins = unsafe { get_vaddr_u32(ctx.mepc) } as usize;
-                if !emulate_illegal_instruction(ctx, ins) {
+                // a faulting fetch overwrites mtval, which supervisor expects to be kept
+                let mtval = mtval::read();
+                let ins = get_vaddr_instruction(ctx.mepc);
+                if let Some((len, emulation)) =
+                    ins.and_then(|ins| emulate_illegal_instruction(ctx, ins))
+                {
+                    skip_emulated(ctx, len);
+                    trap_stats::count(hart_id, TrapKind::IllegalEmulated);
+                    trap_stats::count_emulation(hart_id, emulation);
+                } else {
+                    // a supervisor returning from machine level is its own bug, it is delivered
+                    // like other privileged instructions; stval holds the instruction whether or
+                    // not hardware reported it in mtval. So are hypervisor loads, stores and
+                    // fences of a supervisor probing for the H extension, which U74 lacks; their
+                    // semantics are not emulated
+
+                    let mtval = match ins {
+                        Some(ins) if is_machine_return(ins) => ins,
+                        _ => mtval,
+                    };
                     unsafe {
                         if feature::should_transfer_trap(ctx) {
-                            feature::do_transfer_trap(
-                                ctx,
-                                Trap::Exception(Exception::IllegalInstruction),
-                            )
+                            trap_stats::count(hart_id, TrapKind::IllegalDelegated);
+                            core::arch::asm!("csrw mtval, {}", in(reg) mtval);
+                            feature::do_transfer_trap(ctx, feature::EXCEPTION_ILLEGAL_INSTRUCTION)
                         } else {
-                            fail_illegal_instruction(ctx, ins)
+                            trap_stats::count(hart_id, TrapKind::IllegalFatal);
+                            fail_illegal_instruction(ctx, ins.unwrap_or(mtval))
                         }
                     }
                 }
             }
-            GeneratorState::Yielded(MachineTrap::MachineTimer()) => unsafe {
-                mip::set_stimer();
-                mie::clear_mtimer();
-            },
-            GeneratorState::Yielded(MachineTrap::MachineSoft()) => match hsm.last_command() {
-                Some(HsmCommand::Start(_start_paddr, _opaque)) => {
-                    panic!("rustsbi-jh7100: illegal state")
+            GeneratorState::Yielded(MachineTrap::LoadMisaligned(addr)) => {
+                trap_stats::count(hart_id, TrapKind::Misaligned);
+                let ctx = rt.context_mut();
+                match feature::emulate_misaligned_load(ctx) {
+                    Some(len) => {
+                        skip_emulated(ctx, len);
+                        trap_stats::count_emulation(hart_id, Emulation::MisalignedLoad);
+                    }
+                    None => fail_misaligned(ctx, feature::EXCEPTION_LOAD_MISALIGNED, addr),
+                }
+            }
+            GeneratorState::Yielded(MachineTrap::StoreMisaligned(addr)) => {
+                trap_stats::count(hart_id, TrapKind::Misaligned);
+                let ctx = rt.context_mut();
+                match feature::emulate_misaligned_store(ctx) {
+                    Some(len) => {
+                        skip_emulated(ctx, len);
+                        trap_stats::count_emulation(hart_id, Emulation::MisalignedStore);
+                    }
+                    None => fail_misaligned(ctx, feature::EXCEPTION_STORE_MISALIGNED, addr),
                 }
-                Some(HsmCommand::Stop) => {
-                    // no hart stop command in JH7100, record stop state and pause
-                    hsm.record_current_stop_finished();
-                    pause();
-                    if let Some(HsmCommand::Start(start_paddr, opaque)) = hsm.last_command() {
+            }
+            #[cfg(feature = "emulate-mmio-amo")]
+            GeneratorState::Yielded(MachineTrap::LoadFault(addr)) => {
+                trap_stats::count(hart_id, TrapKind::AccessFault);
+                let ctx = rt.context_mut();
+                match emulate_faulting_amo(ctx) {
+                    Some(len) => {
+                        skip_emulated(ctx, len);
+                        trap_stats::count_emulation(hart_id, Emulation::MmioAmo);
+                    }
+                    None => transfer_access_fault(ctx, feature::EXCEPTION_LOAD_FAULT, addr),
+                }
+            }
+            #[cfg(feature = "emulate-mmio-amo")]
+            GeneratorState::Yielded(MachineTrap::StoreFault(addr)) => {
+                trap_stats::count(hart_id, TrapKind::AccessFault);
+                let ctx = rt.context_mut();
+                match emulate_faulting_amo(ctx) {
+                    Some(len) => {
+                        skip_emulated(ctx, len);
+                        trap_stats::count_emulation(hart_id, Emulation::MmioAmo);
+                    }
+                    None => transfer_access_fault(ctx, feature::EXCEPTION_STORE_FAULT, addr),
+                }
+            }
+            GeneratorState::Yielded(MachineTrap::MachineTimer()) => {
+                trap_stats::count(hart_id, TrapKind::MachineTimer);
+                // run firmware background tasks before relaying the timer to supervisor
+                crate::tick::run(hart_id);
+                #[cfg(feature = "hang-watchdog")]
+                crate::watchdog::on_machine_timer(hart_id, rt.context_mut());
+                // machine timer stays masked after relaying, unless a firmware deadline is left;
+                // a new supervisor deadline enables it again
+                crate::timer::on_machine_timer(hart_id);
+            }
+            GeneratorState::Yielded(MachineTrap::MachineSoft())
+                if crate::reset::shutdown_requested() =>
+            {
+                crate::reset::ack_and_halt(hart_id)
+            }
+            #[cfg(feature = "diagnostics")]
+            GeneratorState::Yielded(MachineTrap::MachineSoft())
+                if crate::debug_halt::take_request(hart_id) =>
+            {
+                crate::debug_halt::dump_and_park(hart_id, rt.context_mut())
+            }
+            GeneratorState::Yielded(MachineTrap::MachineSoft()) => {
+                trap_stats::count(hart_id, TrapKind::MachineSoft);
+                // watchpoints armed or gone on another hart
+                #[cfg(feature = "diagnostics")]
+                crate::watchpoint::sync_delegation(hart_id);
+                match hsm.take_command() {
+                    Some(HsmCommand::Start(_start_paddr, _opaque)) => {
+                        panic!("rustsbi-jh7100: illegal state")
+                    }
+                    Some(HsmCommand::Stop) => {
+                        // no hart stop command in JH7100, record stop state and pause
+                        hsm.record_current_stop_finished();
+                        // a firmware deadline would keep waking the stopped hart
+                        crate::timer::stop(hart_id);
+                        // IPIs other than a start request leave the hart parked
+                        let (start_paddr, opaque) = wait_for_start(&hsm, hart_id);
+                        #[cfg(feature = "diagnostics")]
+                        crate::watchpoint::sync_delegation(hart_id);
                         // Resuming from a non-retentive suspend state is relatively more involved and requires software
                         // to restore various hart registers and CSRs for all privilege modes.
                         // Upon resuming from non-retentive suspend state, the hart will jump to supervisor-mode at address
@@ -77,60 +338,446 @@ pub fn execute_supervisor(supervisor_mepc: usize, hart_id: usize, opaque: usize,
                         // | `sstatus.SIE` | 0
                         // | a0            | hartid
                         // | a1            | `opaque` parameter
+                        //
+                        // sscratch is cleared as on every supervisor entry, see `runtime`
                         unsafe {
                             satp::write(0);
                             sstatus::clear_sie();
+                            sscratch::write(0);
                         }
                         hsm.record_current_start_finished();
+                        #[cfg(feature = "hang-watchdog")]
+                        crate::watchdog::start(hart_id);
+                        #[cfg(feature = "firmware-timer")]
+                        crate::timer::start_tick(hart_id);
                         let ctx = rt.context_mut();
                         ctx.mstatus = mstatus::read(); // get from modified sstatus
                         ctx.a0 = hart_id;
                         ctx.a1 = opaque;
                         ctx.mepc = start_paddr;
+                        crate::runtime::check_supervisor_entry(ctx);
                     }
+                    None => relay_soft_interrupt(hart_id, rt.context_mut()),
                 }
-                None => unsafe {
-                    // machine software interrupt but no HSM commands - delegate to S mode;
-                    let ctx = rt.context_mut();
-                    let clint = crate::peripheral::Clint::new(0x2000000 as *mut u8);
-                    clint.clear_soft(hart_id); // Clear IPI
-                    if feature::should_transfer_trap(ctx) {
-                        feature::do_transfer_trap(ctx, Trap::Interrupt(Interrupt::SupervisorSoft))
-                    } else {
-                        panic!("rustsbi-jh7100: machine soft interrupt with no hart state monitor command")
-                    }
-                },
-            },
-            GeneratorState::Complete(()) => break,
+            }
+            GeneratorState::Yielded(MachineTrap::UnhandledInterrupt(code)) => {
+                trap_stats::count(hart_id, TrapKind::UnhandledInterrupt);
+                on_unhandled_interrupt(hart_id, code)
+            }
+            #[cfg(feature = "diagnostics")]
+            GeneratorState::Yielded(MachineTrap::Breakpoint()) => {
+                trap_stats::count(hart_id, TrapKind::Undelegated);
+                crate::watchpoint::on_breakpoint(hart_id, rt.context_mut())
+            }
+            GeneratorState::Yielded(MachineTrap::Undelegated(code, mtval)) => {
+                trap_stats::count(hart_id, TrapKind::Undelegated);
+                on_undelegated(hart_id, rt.context_mut(), code, mtval)
+            }
+            GeneratorState::Complete(()) => on_complete(hart_id, rt.context_mut().mepc),
+        }
+    }
+}
+
+// Pass a machine software interrupt without HSM command on to supervisor as its software
+// interrupt; also used by `irq_check`
+#[cfg_attr(feature = "ipi-doorbell", allow(unused_variables))]
+pub fn relay_soft_interrupt(hart_id: usize, ctx: &mut SupervisorContext) {
+    let clint = crate::peripheral::Clint::new(0x2000000 as *mut u8);
+    clint.clear_soft(hart_id); // Clear IPI
+
+    // ring supervisor doorbell; pending bit is raised even while supervisor has soft interrupts
+    // masked, and is taken once it enables them, as an IPI sent by CLINT itself would be
+    #[cfg(feature = "ipi-doorbell")]
+    unsafe {
+        if crate::peripheral::take_doorbell(hart_id) {
+            mip::set_ssoft();
+        }
+    }
+    // without it, delegate to S mode
+    #[cfg(not(feature = "ipi-doorbell"))]
+    unsafe {
+        if feature::should_transfer_trap(ctx) {
+            feature::do_transfer_trap(ctx, feature::INTERRUPT_SUPERVISOR_SOFT)
+        } else {
+            panic!("rustsbi-jh7100: machine soft interrupt with no hart state monitor command")
+        }
+    }
+}
+
+// Supervisor runtime returned instead of yielding a trap, which no running supervisor causes;
+// there is nowhere to return to, end as the board chooses
+fn on_complete(hart_id: usize, mepc: usize) -> ! {
+    use crate::board::{CompleteAction, SUPERVISOR_COMPLETE_ACTION};
+    use crate::reset::{
+        HaltReset, RESET_REASON_SYSTEM_FAILURE, RESET_TYPE_COLD_REBOOT, RESET_TYPE_SHUTDOWN,
+    };
+    let reset_type = match SUPERVISOR_COMPLETE_ACTION {
+        CompleteAction::Halt => panic!(
+            "supervisor runtime completed on hart {}, last mepc {:#x}",
+            hart_id, mepc
+        ),
+        CompleteAction::Shutdown => RESET_TYPE_SHUTDOWN,
+        CompleteAction::Reboot => RESET_TYPE_COLD_REBOOT,
+    };
+    crate::println!(
+        "[rustsbi] error: supervisor runtime completed on hart {}, last mepc {:#x}, {:?}",
+        hart_id,
+        mepc,
+        SUPERVISOR_COMPLETE_ACTION
+    );
+    rustsbi::Reset::system_reset(&HaltReset, reset_type, RESET_REASON_SYSTEM_FAILURE);
+    unreachable!("system reset returned")
+}
+
+// Load from supervisor virtual address with supervisor's translation and protection, evaluates
+// to None if the load faults.
+//
+// The load runs under MSTATUS.MPRV. mtvec is temporarily pointed to a local recovery label, so a
+// fault lands there instead of the trap handler; mstatus saved before MPRV was set is written back
+// on both paths, thus MPRV never stays set. Firmware runs with MSTATUS.MIE clear, no interrupt can
+// enter this window.
+macro_rules! get_vaddr {
+    ($load: literal, $vaddr: expr) => {{
+        let ok: usize;
+        let value: usize;
+        core::arch::asm!(
+            "csrr    {mtvec}, mtvec",
+            "la      {tmp}, 1f",
+            "csrw    mtvec, {tmp}",
+            "li      {tmp}, (1 << 17)",
+            "csrrs   {tmp}, mstatus, {tmp}",
+            concat!($load, "     {value}, 0({vaddr})"),
+            "li      {ok}, 1",
+            "j       2f",
+            ".p2align 2",
+            "1:  li  {ok}, 0",
+            "2:  csrw mstatus, {tmp}",
+            "csrw    mtvec, {mtvec}",
+            mtvec = out(reg) _,
+            tmp = out(reg) _,
+            vaddr = in(reg) $vaddr,
+            value = out(reg) value,
+            ok = lateout(reg) ok,
+        );
+        if ok != 0 {
+            Some(value)
+        } else {
+            None
         }
+    }};
+}
+
+#[inline]
+pub(crate) unsafe fn get_vaddr_u8(vaddr: usize) -> Option<u8> {
+    get_vaddr!("lbu", vaddr).map(|value| value as u8)
+}
+
+#[inline]
+unsafe fn get_vaddr_u16(vaddr: usize) -> Option<u16> {
+    get_vaddr!("lhu", vaddr).map(|value| value as u16)
+}
+
+#[inline]
+pub(crate) unsafe fn get_vaddr_u64(vaddr: usize) -> Option<u64> {
+    get_vaddr!("ld", vaddr).map(|value| value as u64)
+}
+
+// Fetch instruction at supervisor virtual address, 16 or 32 bits; None if it cannot be read.
+//
+// Supervisor pc may be only 2-byte aligned, thus the instruction is read by halfwords. An
+// execute-only page faults here as well, since the read is a load.
+pub(crate) fn get_vaddr_instruction(vaddr: usize) -> Option<usize> {
+    let low = unsafe { get_vaddr_u16(vaddr) }?;
+    if crate::decode::length(low) == 2 {
+        return Some(low as usize);
     }
+    let high = unsafe { get_vaddr_u16(vaddr.wrapping_add(2)) }?;
+    Some(low as usize | (high as usize) << 16)
 }
 
+// Write a byte to supervisor virtual address with supervisor's translation and protection,
+// returns false if the store faults.
+//
+// The store runs under MSTATUS.MPRV like `get_vaddr!`; mtvec is temporarily pointed to a local
+// recovery label, so a faulting store only skips the write instead of entering the trap handler.
 #[inline]
-unsafe fn get_vaddr_u32(vaddr: usize) -> u32 {
-    let mut ans: u32;
+pub(crate) unsafe fn put_vaddr_u8(vaddr: usize, byte: u8) -> bool {
+    let ok: usize;
     core::arch::asm!("
+        csrr    {mtvec}, mtvec
+        la      {tmp}, 1f
+        csrw    mtvec, {tmp}
         li      {tmp}, (1 << 17)
         csrrs   {tmp}, mstatus, {tmp}
-        lwu     {ans}, 0({vaddr})
-        csrw    mstatus, {tmp}
+        sb      {byte}, 0({vaddr})
+        li      {ok}, 1
+        j       2f
+    .p2align 2
+    1:  li      {ok}, 0
+    2:  csrw    mstatus, {tmp}
+        csrw    mtvec, {mtvec}
         ",
+        mtvec = out(reg) _,
         tmp = out(reg) _,
+        byte = in(reg) byte,
         vaddr = in(reg) vaddr,
-        ans = lateout(reg) ans
+        ok = lateout(reg) ok,
+    );
+    ok != 0
+}
+
+// Write bytes into supervisor buffer at virtual address `vaddr`, e.g. for DBCN console read.
+//
+// The whole buffer must lie in DRAM opened to supervisor by PMP, otherwise nothing is written.
+// If a store faults in the middle of the buffer, returns the count of bytes written before it.
+pub(crate) fn put_vaddr_slice(vaddr: usize, data: &[u8]) -> usize {
+    match vaddr.checked_add(data.len()) {
+        Some(end) if vaddr >= crate::DRAM_PMP_START && end <= crate::DRAM_PMP_END => {}
+        _ => return 0,
+    }
+    for (i, byte) in data.iter().enumerate() {
+        if !unsafe { put_vaddr_u8(vaddr + i, *byte) } {
+            return i;
+        }
+    }
+    data.len()
+}
+
+// ecall has no compressed form; an SBI call returns past it, 4 bytes after its pc, and only an
+// ecall raises the supervisor environment call exception, see `check_ecall`
+pub(crate) const ECALL_LENGTH: usize = 4;
+// ecall, SYSTEM opcode with every other field zero
+#[cfg(feature = "check-ecall")]
+const ECALL_INSTRUCTION: usize = 0x0000_0073;
+
+// Make sure the SBI call at supervisor pc `mepc` was made by an ecall, so that stepping over
+// `ECALL_LENGTH` bytes resumes at the next instruction. An instruction supervisor cannot read,
+// e.g. on an execute-only page, is not checked.
+#[cfg(feature = "check-ecall")]
+fn check_ecall(mepc: usize) {
+    match get_vaddr_instruction(mepc) {
+        Some(ECALL_INSTRUCTION) | None => {}
+        Some(ins) => panic!(
+            "SBI call trap at {:#x} not raised by ecall, instruction {:#x}",
+            mepc, ins
+        ),
+    }
+}
+
+// Resume supervisor past an instruction serviced by firmware, `len` bytes after its pc.
+//
+// Emulation functions return how far supervisor pc moves, which is the length of the emulated
+// instruction, 2 or 4, or for a sequence its distance to the resume pc; they never move pc
+// themselves.
+fn skip_emulated(ctx: &mut SupervisorContext, len: usize) {
+    ctx.mepc = ctx.mepc.wrapping_add(len);
+}
+
+// Emulate instruction `ins` at supervisor pc, returns how far pc moves and which emulation did
+// it; None if not emulated
+fn emulate_illegal_instruction(
+    ctx: &mut SupervisorContext,
+    ins: usize,
+) -> Option<(usize, Emulation)> {
+    let len = crate::decode::length(ins as u16);
+    // supervisor built with RVC on a hart without C; without emulation there is no way on
+    if len == 2 && !has_compressed_extension() {
+        #[cfg(feature = "rvc-emulation")]
+        if let Some(len) = feature::emulate_rvc(ctx, ins) {
+            return Some((len, Emulation::Rvc));
+        }
+        fail_compressed_instruction(ctx, ins)
+    }
+    // none of the emulated instructions has a compressed form
+    if len != 4 {
+        return None;
+    }
+    let ins = crate::decode::decode(ins as u32)?;
+    if let Some(len) = feature::emulate_rdtime(ctx, &ins, len) {
+        return Some((len, Emulation::Rdtime));
+    }
+    #[cfg(feature = "fixed-counters")]
+    if let Some(len) = feature::emulate_counters(ctx, &ins, len) {
+        return Some((len, Emulation::Counters));
+    }
+    #[cfg(feature = "emulate-sret")]
+    if let Some(len) = feature::emulate_sret(ctx, &ins) {
+        return Some((len, Emulation::Sret));
+    }
+    #[cfg(feature = "emulate-sstatus")]
+    if let Some(len) = feature::emulate_sstatus(ctx, &ins, len) {
+        return Some((len, Emulation::Sstatus));
+    }
+    #[cfg(feature = "emulate-zawrs")]
+    if let Some(len) = feature::emulate_zawrs(&ins, len) {
+        return Some((len, Emulation::Zawrs));
+    }
+    #[cfg(feature = "emulate-zicond")]
+    if let Some(len) = feature::emulate_zicond(ctx, &ins, len) {
+        return Some((len, Emulation::Zicond));
+    }
+    #[cfg(feature = "bitmanip-emulation")]
+    if let Some(len) = feature::emulate_bitmanip(ctx, &ins, len) {
+        return Some((len, Emulation::Bitmanip));
+    }
+    #[cfg(feature = "emulate-mmio-amo")]
+    if let Some(len) = feature::emulate_mmio_amo(ctx, &ins, len) {
+        return Some((len, Emulation::MmioAmo));
+    }
+    None
+}
+
+// An lr, sc or amo whose access faulted, emulated if it targets MMIO; returns how far pc moves
+#[cfg(feature = "emulate-mmio-amo")]
+fn emulate_faulting_amo(ctx: &mut SupervisorContext) -> Option<usize> {
+    let ins = get_vaddr_instruction(ctx.mepc)?;
+    let len = crate::decode::length(ins as u16);
+    // atomics have no compressed form
+    if len != 4 {
+        return None;
+    }
+    feature::emulate_mmio_amo(ctx, &crate::decode::decode(ins as u32)?, len)
+}
+
+// Deliver an access fault which is not firmware's to supervisor, as if it were delegated
+#[cfg(feature = "emulate-mmio-amo")]
+fn transfer_access_fault(ctx: &mut SupervisorContext, cause: usize, addr: usize) {
+    unsafe {
+        if feature::should_transfer_trap(ctx) {
+            core::arch::asm!("csrw mtval, {}", in(reg) addr);
+            feature::do_transfer_trap(ctx, cause);
+        } else {
+            panic!(
+                "access fault from machine level, mepc: {:016x?}, address: {:016x?}, context: {:016x?}",
+                ctx.mepc, addr, ctx
+            )
+        }
+    }
+}
+
+fn is_machine_return(ins: usize) -> bool {
+    matches!(
+        crate::decode::decode(ins as u32),
+        Some(crate::decode::Instruction::Mret | crate::decode::Instruction::Mnret)
+    )
+}
+
+// Misaligned access could not be emulated, e.g. it faults or is a floating point access.
+//
+// A fault during emulation overwrote mtval, write back the misaligned address before supervisor
+// receives the original exception.
+fn fail_misaligned(ctx: &mut SupervisorContext, cause: usize, addr: usize) {
+    unsafe {
+        if feature::should_transfer_trap(ctx) {
+            core::arch::asm!("csrw mtval, {}", in(reg) addr);
+            feature::do_transfer_trap(ctx, cause);
+        } else {
+            panic!(
+                "misaligned access from machine level, mepc: {:016x?}, address: {:016x?}, context: {:016x?}",
+                ctx.mepc, addr, ctx
+            )
+        }
+    }
+}
+
+// An exception supervisor un-delegated for firmware to see; report it and deliver it to
+// supervisor as if it were still delegated
+fn on_undelegated(hart_id: usize, ctx: &mut SupervisorContext, code: usize, mtval: usize) {
+    crate::println!(
+        "[rustsbi] hart {} undelegated exception {}, mepc {:#x}, mtval {:#x}",
+        hart_id,
+        code,
+        ctx.mepc,
+        mtval
+    );
+    unsafe {
+        if feature::should_transfer_trap(ctx) {
+            core::arch::asm!("csrw mtval, {}", in(reg) mtval);
+            feature::do_transfer_trap(ctx, code);
+        } else {
+            panic!(
+                "exception {} from machine level, mepc: {:016x?}, mtval: {:016x?}, context: {:016x?}",
+                code, ctx.mepc, mtval, ctx
+            )
+        }
+    }
+}
+
+// An interrupt firmware has no handler for; it would trap again as soon as supervisor resumes.
+//
+// By default its enable bit in mie is cleared, so that the source cannot storm, and supervisor
+// goes on without it. With feature `halt-on-unhandled-interrupt` firmware panics instead.
+fn on_unhandled_interrupt(hart_id: usize, code: usize) {
+    let name = interrupt_name(code);
+    if cfg!(feature = "halt-on-unhandled-interrupt") {
+        panic!(
+            "unhandled machine interrupt {} ({}), mip: {:#x}, mie: {:#x}",
+            code,
+            name,
+            mip::read().bits(),
+            mie::read().bits()
+        )
+    }
+    crate::println!(
+        "[rustsbi] hart {} unhandled machine interrupt {} ({}), masked",
+        hart_id,
+        code,
+        name
     );
-    ans
+    if code < usize::BITS as usize {
+        unsafe { core::arch::asm!("csrc mie, {}", in(reg) 1usize << code) };
+    }
 }
 
-fn emulate_illegal_instruction(ctx: &mut SupervisorContext, ins: usize) -> bool {
-    if feature::emulate_rdtime(ctx, ins) {
-        return true;
+// Names of interrupt codes in mcause, from the privileged specification
+fn interrupt_name(code: usize) -> &'static str {
+    match code {
+        0 => "user software",
+        1 => "supervisor software",
+        3 => "machine software",
+        4 => "user timer",
+        5 => "supervisor timer",
+        7 => "machine timer",
+        8 => "user external",
+        9 => "supervisor external",
+        11 => "machine external",
+        13 => "counter overflow",
+        16.. => "platform local",
+        _ => "reserved",
     }
-    false
+}
+
+fn has_compressed_extension() -> bool {
+    misa::read().map_or(false, |isa| isa.has_extension('C'))
+}
+
+fn fail_compressed_instruction(ctx: &mut SupervisorContext, ins: usize) -> ! {
+    #[cfg(not(feature = "rvc-emulation"))]
+    panic!("compressed instruction {:#06x} at {:#x}, but this hart has no C extension (misa.C clear); \
+        build supervisor without RVC, or firmware with feature `rvc-emulation`, context: {:016x?}", ins, ctx.mepc, ctx);
+    #[cfg(feature = "rvc-emulation")]
+    panic!("cannot emulate instructions from {:#06x} at {:#x} on hart without C extension (misa.C clear), \
+        supervisor pc is left unaligned, context: {:016x?}", ins, ctx.mepc, ctx);
 }
 
 // 真·非法指令异常，是M层出现的
 fn fail_illegal_instruction(ctx: &mut SupervisorContext, ins: usize) -> ! {
+    use crate::decode::Instruction;
+    // a supervisor built for extensions this hart lacks; name what it needs
+    if crate::decode::length(ins as u16) == 4 {
+        let unsupported = match crate::decode::decode(ins as u32) {
+            Some(Instruction::Bitmanip { op, .. }) => Some((op.extension(), op.mnemonic())),
+            Some(Instruction::Hypervisor { op, .. }) => Some(("H", op.mnemonic())),
+            Some(Instruction::Cbo { op, .. }) => Some((op.extension(), op.mnemonic())),
+            _ => None,
+        };
+        if let Some((extension, mnemonic)) = unsupported {
+            panic!(
+                "unsupported {} instruction `{}` ({:#010x}) at {:#x}, supervisor was built for an extension this hart lacks, context: {:016x?}",
+                extension, mnemonic, ins, ctx.mepc, ctx
+            )
+        }
+    }
     #[cfg(target_pointer_width = "64")]
     panic!("invalid instruction from machine level, mepc: {:016x?}, instruction: {:016x?}, context: {:016x?}", ctx.mepc, ins, ctx);
     #[cfg(target_pointer_width = "32")]
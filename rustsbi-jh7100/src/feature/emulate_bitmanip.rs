@@ -0,0 +1,45 @@
+use super::registers::{get_register_xi, set_register_xi};
+use crate::decode::{BitmanipOp, Instruction};
+use crate::runtime::SupervisorContext;
+
+// The common Zbb instructions: clz, ctz, cpop and their word forms, min, max, orc.b and rev8.
+// Other Zba, Zbb and Zbs instructions are not emulated, they are reported by name when fatal
+#[inline]
+pub fn emulate_bitmanip(
+    ctx: &mut SupervisorContext,
+    ins: &Instruction,
+    len: usize,
+) -> Option<usize> {
+    let (op, rd, rs1, rs2) = match *ins {
+        Instruction::Bitmanip { op, rd, rs1, rs2 } => (op, rd, rs1, rs2),
+        _ => return None, // is not a bitmanip instruction
+    };
+    // read both sources before writing rd, which may be one of them
+    let a = get_register_xi(ctx, rs1);
+    let b = get_register_xi(ctx, rs2);
+    let result = match op {
+        BitmanipOp::Clz => a.leading_zeros() as usize,
+        BitmanipOp::Ctz => a.trailing_zeros() as usize,
+        BitmanipOp::Cpop => a.count_ones() as usize,
+        BitmanipOp::Clzw => (a as u32).leading_zeros() as usize,
+        BitmanipOp::Ctzw => (a as u32).trailing_zeros() as usize,
+        BitmanipOp::Cpopw => (a as u32).count_ones() as usize,
+        BitmanipOp::Min => (a as isize).min(b as isize) as usize,
+        BitmanipOp::Max => (a as isize).max(b as isize) as usize,
+        BitmanipOp::Minu => a.min(b),
+        BitmanipOp::Maxu => a.max(b),
+        BitmanipOp::OrcB => orc_b(a),
+        BitmanipOp::Rev8 => a.swap_bytes(),
+        _ => return None,
+    };
+    set_register_xi(ctx, rd, result);
+    Some(len) // skip bitmanip instruction
+}
+
+// Each byte becomes 0xff if any of its bits is set, 0 otherwise
+fn orc_b(value: usize) -> usize {
+    (0..core::mem::size_of::<usize>())
+        .map(|byte| 0xff << (byte * 8))
+        .filter(|mask| value & mask != 0)
+        .fold(0, |result, mask| result | mask)
+}
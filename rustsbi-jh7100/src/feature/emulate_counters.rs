@@ -0,0 +1,33 @@
+use super::registers::set_register_xi;
+use crate::decode::{CsrOp, Instruction};
+use crate::runtime::SupervisorContext;
+
+const CSR_CYCLE: u16 = 0xC00;
+const CSR_INSTRET: u16 = 0xC02;
+// upper halves, only valid for RV32 supervisors, as `CSR_TIMEH`
+const CSR_CYCLEH: u16 = 0xC80;
+const CSR_INSTRETH: u16 = 0xC82;
+
+// What every emulated read of cycle and instret returns, and of their upper halves
+pub const FIXED_COUNTER_VALUE: usize = 0;
+
+// csrrs rd, cycle, x0 and csrrs rd, instret, x0, with their upper halves; with feature
+// `fixed-counters`, `enable_counters` leaves them disabled for supervisor so that they trap here
+#[inline]
+pub fn emulate_counters(
+    ctx: &mut SupervisorContext,
+    ins: &Instruction,
+    len: usize,
+) -> Option<usize> {
+    let rd = match *ins {
+        Instruction::Csr {
+            op: CsrOp::ReadSet,
+            rd,
+            rs1: 0,
+            csr: CSR_CYCLE | CSR_INSTRET | CSR_CYCLEH | CSR_INSTRETH,
+        } => rd,
+        _ => return None, // is not a rdcycle or rdinstret instruction
+    };
+    set_register_xi(ctx, rd, FIXED_COUNTER_VALUE);
+    Some(len) // skip rdcycle or rdinstret instruction
+}
@@ -0,0 +1,83 @@
+use super::registers::{get_register_xi, set_register_xi};
+use crate::decode::{self, Instruction};
+use crate::execute::{get_vaddr_instruction, get_vaddr_u8, put_vaddr_u8};
+use crate::runtime::SupervisorContext;
+
+// U74 traps on any misaligned load or store; when supervisor did not ask for delegation
+// through FWFT, they are emulated here byte by byte with supervisor's address translation.
+//
+// Loads are extended into the destination as their funct3 says: lb, lh, lw sign extend,
+// lbu, lhu, lwu zero extend. Stores write exactly the bytes of their width. Returns the length
+// of the emulated instruction, 2 for the compressed forms and 4 otherwise.
+#[inline]
+pub fn emulate_misaligned_load(ctx: &mut SupervisorContext) -> Option<usize> {
+    match fetch_and_decode(ctx) {
+        Some((ins @ Instruction::Load { .. }, len)) if access(ctx, &ins) => Some(len),
+        _ => None, // load faults, let supervisor see the trap
+    }
+}
+
+#[inline]
+pub fn emulate_misaligned_store(ctx: &mut SupervisorContext) -> Option<usize> {
+    match fetch_and_decode(ctx) {
+        Some((ins @ Instruction::Store { .. }, len)) if access(ctx, &ins) => Some(len),
+        _ => None, // store faults, let supervisor see the trap
+    }
+}
+
+// Perform an integer load or store byte by byte with supervisor's address translation, without
+// moving pc; false if it faults or `ins` is neither
+pub(super) fn access(ctx: &mut SupervisorContext, ins: &Instruction) -> bool {
+    match *ins {
+        Instruction::Load {
+            rd,
+            rs1,
+            offset,
+            width,
+            signed,
+        } => {
+            let addr = get_register_xi(ctx, rs1).wrapping_add(offset as usize);
+            let mut bytes = [0u8; 8];
+            for (i, byte) in bytes[..width].iter_mut().enumerate() {
+                match unsafe { get_vaddr_u8(addr.wrapping_add(i)) } {
+                    Some(b) => *byte = b,
+                    None => return false,
+                }
+            }
+            let raw = u64::from_le_bytes(bytes);
+            // move the sign bit of loaded width to bit 63, then shift back
+            let shift = 64 - 8 * width as u32;
+            let value = if signed {
+                ((raw << shift) as i64 >> shift) as u64
+            } else {
+                raw
+            };
+            set_register_xi(ctx, rd, value as usize);
+            true
+        }
+        Instruction::Store {
+            rs1,
+            rs2,
+            offset,
+            width,
+        } => {
+            let addr = get_register_xi(ctx, rs1).wrapping_add(offset as usize);
+            let bytes = (get_register_xi(ctx, rs2) as u64).to_le_bytes();
+            bytes[..width]
+                .iter()
+                .enumerate()
+                .all(|(i, byte)| unsafe { put_vaddr_u8(addr.wrapping_add(i), *byte) })
+        }
+        _ => false,
+    }
+}
+
+// Returns the instruction at supervisor pc and its length
+fn fetch_and_decode(ctx: &SupervisorContext) -> Option<(Instruction, usize)> {
+    let ins = get_vaddr_instruction(ctx.mepc)?;
+    if decode::length(ins as u16) == 2 {
+        decode::decode_compressed(ins as u16).map(|ins| (ins, 2))
+    } else {
+        decode::decode(ins as u32).map(|ins| (ins, 4))
+    }
+}
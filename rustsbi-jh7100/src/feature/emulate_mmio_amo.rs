@@ -0,0 +1,175 @@
+use super::registers::{get_register_xi, set_register_xi};
+use crate::board::{self, PMP_RW};
+use crate::decode::{AmoOp, Instruction};
+use crate::runtime::SupervisorContext;
+use riscv::register::satp::{self, Mode};
+
+// Serializes every emulated atomic, and holds the reservation of each hart's emulated lr
+static MMIO_ATOMIC: spin::Mutex<[Option<usize>; crate::NUM_HARTS]> =
+    spin::Mutex::new([None; crate::NUM_HARTS]);
+
+// Atomics which U74 refuses on device memory, emulated by a plain load and store under a lock.
+//
+// Only naturally aligned accesses whose physical address falls into a board PMP region marked
+// as `mmio` are emulated; any other access fault is supervisor's. Atomicity holds against other
+// emulated atomics only, neither against plain stores of other harts nor against the device.
+// An emulated sc succeeds if the same hart's emulated lr reserved that address and no emulated
+// store to it happened in between.
+pub fn emulate_mmio_amo(
+    ctx: &mut SupervisorContext,
+    ins: &Instruction,
+    len: usize,
+) -> Option<usize> {
+    let (op, rd, rs1, rs2, width) = match *ins {
+        Instruction::Amo {
+            op,
+            rd,
+            rs1,
+            rs2,
+            width,
+        } => (op, rd, rs1, rs2, width),
+        _ => return None, // is not an atomic instruction
+    };
+    let vaddr = get_register_xi(ctx, rs1);
+    if vaddr % width != 0 {
+        return None;
+    }
+    let paddr = match translate(vaddr) {
+        Some(paddr) if is_mmio(paddr, width) => paddr,
+        _ => return None,
+    };
+    let source = get_register_xi(ctx, rs2) as u64;
+    let hart_id = riscv::register::mhartid::read();
+    let mut reservations = MMIO_ATOMIC.lock();
+    let result = match op {
+        AmoOp::LoadReserved => {
+            reservations[hart_id] = Some(paddr);
+            unsafe { read(paddr, width) }
+        }
+        AmoOp::StoreConditional => {
+            let reserved = reservations[hart_id].take() == Some(paddr);
+            if reserved {
+                unsafe { write(paddr, width, source) };
+                invalidate(&mut reservations, paddr);
+            }
+            // sc writes zero on success, non-zero on failure
+            (!reserved) as u64
+        }
+        op => {
+            let old = unsafe { read(paddr, width) };
+            unsafe { write(paddr, width, compute(op, width, old, source)) };
+            invalidate(&mut reservations, paddr);
+            old
+        }
+    };
+    drop(reservations);
+    // 4-byte results are sign extended, as hardware atomics do
+    let result = if width == 4 {
+        result as u32 as i32 as usize
+    } else {
+        result as usize
+    };
+    set_register_xi(ctx, rd, result);
+    Some(len)
+}
+
+fn compute(op: AmoOp, width: usize, old: u64, source: u64) -> u64 {
+    // compare at access width; values are sign extended for the signed ones
+    let signed = |value: u64| {
+        if width == 4 {
+            value as u32 as i32 as i64
+        } else {
+            value as i64
+        }
+    };
+    let unsigned = |value: u64| {
+        if width == 4 {
+            value as u32 as u64
+        } else {
+            value
+        }
+    };
+    match op {
+        AmoOp::Swap => source,
+        AmoOp::Add => old.wrapping_add(source),
+        AmoOp::Xor => old ^ source,
+        AmoOp::And => old & source,
+        AmoOp::Or => old | source,
+        AmoOp::Min => signed(old).min(signed(source)) as u64,
+        AmoOp::Max => signed(old).max(signed(source)) as u64,
+        AmoOp::MinUnsigned => unsigned(old).min(unsigned(source)),
+        AmoOp::MaxUnsigned => unsigned(old).max(unsigned(source)),
+        AmoOp::LoadReserved | AmoOp::StoreConditional => unreachable!(),
+    }
+}
+
+// Any emulated store breaks reservations of every hart on that address
+fn invalidate(reservations: &mut [Option<usize>; crate::NUM_HARTS], paddr: usize) {
+    for reservation in reservations.iter_mut() {
+        if *reservation == Some(paddr) {
+            *reservation = None;
+        }
+    }
+}
+
+// Readable and writable MMIO region of supervisor, holding the whole access
+fn is_mmio(paddr: usize, width: usize) -> bool {
+    board::PMP_REGIONS.iter().any(|region| {
+        region.mmio
+            && region.permission & PMP_RW == PMP_RW
+            && paddr >= region.base
+            && paddr + width <= region.base + region.size
+    })
+}
+
+unsafe fn read(paddr: usize, width: usize) -> u64 {
+    if width == 4 {
+        (paddr as *const u32).read_volatile() as u64
+    } else {
+        (paddr as *const u64).read_volatile()
+    }
+}
+
+unsafe fn write(paddr: usize, width: usize, value: u64) {
+    if width == 4 {
+        (paddr as *mut u32).write_volatile(value as u32)
+    } else {
+        (paddr as *mut u64).write_volatile(value)
+    }
+}
+
+// Physical address of supervisor virtual address `vaddr` under current satp, Bare or Sv39.
+//
+// The access which trapped has passed translation and permission checks of hardware already,
+// only an access fault comes here; the walk just repeats it to find the physical address.
+fn translate(vaddr: usize) -> Option<usize> {
+    const PAGE_SHIFT: usize = 12;
+    const PTE_V: u64 = 1 << 0;
+    const PTE_R: u64 = 1 << 1;
+    const PTE_X: u64 = 1 << 3;
+    const PPN_MASK: u64 = (1 << 44) - 1;
+    let satp = satp::read();
+    match satp.mode() {
+        Mode::Bare => return Some(vaddr),
+        Mode::Sv39 => {}
+        _ => return None,
+    }
+    let mut table = satp.ppn() << PAGE_SHIFT;
+    for level in (0..3).rev() {
+        let shift = PAGE_SHIFT + 9 * level;
+        let index = (vaddr >> shift) & 0x1ff;
+        // page tables are in DRAM, which firmware reads by physical address
+        let pte = unsafe { ((table + index * 8) as *const u64).read_volatile() };
+        if pte & PTE_V == 0 {
+            return None;
+        }
+        let base = (((pte >> 10) & PPN_MASK) << PAGE_SHIFT) as usize;
+        if pte & (PTE_R | PTE_X) != 0 {
+            // leaf, possibly a superpage whose low virtual bits are the page offset
+            let offset_mask = (1 << shift) - 1;
+            return Some((base & !offset_mask) | (vaddr & offset_mask));
+        }
+        table = base;
+    }
+    None
+}
@@ -1,27 +1,36 @@
+use super::registers::set_register_xi;
+use crate::decode::{CsrOp, Instruction};
 use crate::peripheral::Clint;
 use crate::runtime::SupervisorContext;
 
-#[inline]
-pub fn emulate_rdtime(ctx: &mut SupervisorContext, ins: usize) -> bool {
-    if ins & 0xFFFFF07F == 0xC0102073 {
-        let rd = ((ins >> 7) & 0b1_1111) as u8;
-        let clint = Clint::new(0x2000000 as *mut u8);
-        let time_usize = clint.get_mtime() as usize;
-        set_register_xi(ctx, rd, time_usize);
-        ctx.mepc = ctx.mepc.wrapping_add(4); // skip rdtime instruction
-        return true;
-    } else {
-        return false; // is not a rdtime instruction
-    }
-}
+const CSR_TIME: u16 = 0xC01;
+// only valid for RV32 supervisors, but may still be executed by a 32-bit guest under this
+// 64-bit firmware
+const CSR_TIMEH: u16 = 0xC81;
 
+// csrrs rd, time, x0 and csrrs rd, timeh, x0
 #[inline]
-fn set_register_xi(ctx: &mut SupervisorContext, i: u8, data: usize) {
-    let registers = unsafe { &mut *(ctx as *mut _ as *mut [usize; 31]) };
-    assert!(i <= 31, "i should be valid register target");
-    if i == 0 {
-        // x0, don't modify
-        return;
-    }
-    registers[(i - 1) as usize] = data;
+pub fn emulate_rdtime(ctx: &mut SupervisorContext, ins: &Instruction, len: usize) -> Option<usize> {
+    let (rd, csr) = match *ins {
+        Instruction::Csr {
+            op: CsrOp::ReadSet,
+            rd,
+            rs1: 0,
+            csr,
+        } => (rd, csr),
+        _ => return None, // is not a rdtime or rdtimeh instruction
+    };
+    let time_usize = match csr {
+        CSR_TIME => {
+            let clint = Clint::new(0x2000000 as *mut u8);
+            clint.get_mtime() as usize
+        }
+        CSR_TIMEH => {
+            let clint = Clint::new(0x2000000 as *mut u8);
+            (clint.get_mtime() >> 32) as usize
+        }
+        _ => return None,
+    };
+    set_register_xi(ctx, rd, time_usize);
+    Some(len) // skip rdtime instruction
 }
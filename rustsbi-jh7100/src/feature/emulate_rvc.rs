@@ -0,0 +1,161 @@
+use super::emulate_misaligned;
+use super::registers::{get_register_xi, set_register_xi};
+use crate::decode;
+use crate::execute::get_vaddr_instruction;
+use crate::runtime::SupervisorContext;
+
+// Upper bound of instructions emulated in one trap before giving up
+const MAX_STEPS: usize = 4096;
+
+// Compressed instructions on a hart without C extension, expanded and executed one by one.
+//
+// Without C, `mepc` cannot hold a pc which is only 2-byte aligned, thus firmware cannot return
+// to supervisor in the middle of a 4-byte word. After the trapping instruction, firmware keeps
+// emulating (compressed or not) until pc is 4-byte aligned again. Only RV64I integer
+// computation, loads, stores, jumps and branches can be emulated this way; anything else, or a
+// fault on the way, fails and is reported by caller as fatal, as supervisor state cannot be
+// handed back at an unaligned pc.
+//
+// As the sequence may jump or branch, what it returns is not a length: it is the distance from
+// the trapping pc to where supervisor resumes, wrapping, which the caller adds to pc all the same.
+pub fn emulate_rvc(ctx: &mut SupervisorContext, ins: usize) -> Option<usize> {
+    let start = ctx.mepc;
+    let mut pc = start;
+    let mut ins = ins;
+    for _ in 0..MAX_STEPS {
+        let (expanded, len) = if decode::length(ins as u16) == 2 {
+            (decode::expand_compressed(ins as u16)?, 2)
+        } else {
+            (ins as u32, 4)
+        };
+        pc = execute(ctx, pc, expanded, len)?;
+        if pc % 4 == 0 {
+            return Some(pc.wrapping_sub(start));
+        }
+        ins = get_vaddr_instruction(pc)?;
+    }
+    None
+}
+
+// Execute one RV64I instruction of `len` bytes at `pc`, returns pc of the next instruction
+fn execute(ctx: &mut SupervisorContext, pc: usize, ins: u32, len: usize) -> Option<usize> {
+    let rd = decode::rd(ins);
+    let rs1 = get_register_xi(ctx, decode::rs1(ins));
+    let rs2 = get_register_xi(ctx, decode::rs2(ins));
+    let funct3 = decode::funct3(ins);
+    let funct7 = decode::funct7(ins);
+    let imm_i = decode::imm_i(ins) as usize;
+    let mut next_pc = pc.wrapping_add(len);
+    match decode::opcode(ins) {
+        decode::OPCODE_LUI => set_register_xi(ctx, rd, (ins & 0xffff_f000) as i32 as usize),
+        decode::OPCODE_AUIPC => {
+            let offset = (ins & 0xffff_f000) as i32 as usize;
+            set_register_xi(ctx, rd, pc.wrapping_add(offset))
+        }
+        decode::OPCODE_JAL => {
+            set_register_xi(ctx, rd, next_pc);
+            next_pc = pc.wrapping_add(imm_j(ins) as usize);
+        }
+        decode::OPCODE_JALR if funct3 == 0 => {
+            // rs1 is read before rd is written, they may be the same register
+            set_register_xi(ctx, rd, next_pc);
+            next_pc = rs1.wrapping_add(imm_i) & !1;
+        }
+        decode::OPCODE_BRANCH => {
+            let taken = match funct3 {
+                0b000 => rs1 == rs2,
+                0b001 => rs1 != rs2,
+                0b100 => (rs1 as isize) < (rs2 as isize),
+                0b101 => (rs1 as isize) >= (rs2 as isize),
+                0b110 => rs1 < rs2,
+                0b111 => rs1 >= rs2,
+                _ => return None,
+            };
+            if taken {
+                next_pc = pc.wrapping_add(imm_b(ins) as usize);
+            }
+        }
+        decode::OPCODE_LOAD | decode::OPCODE_STORE => match decode::decode(ins) {
+            Some(access) if emulate_misaligned::access(ctx, &access) => {}
+            _ => return None,
+        },
+        decode::OPCODE_OP_IMM => {
+            let shamt = (ins >> 20) & 0b11_1111;
+            let value = match (funct3, ins >> 26) {
+                (0b000, _) => rs1.wrapping_add(imm_i),
+                (0b010, _) => ((rs1 as isize) < (imm_i as isize)) as usize,
+                (0b011, _) => (rs1 < imm_i) as usize,
+                (0b100, _) => rs1 ^ imm_i,
+                (0b110, _) => rs1 | imm_i,
+                (0b111, _) => rs1 & imm_i,
+                (0b001, 0) => rs1 << shamt,
+                (0b101, 0) => rs1 >> shamt,
+                (0b101, 0b01_0000) => ((rs1 as isize) >> shamt) as usize,
+                _ => return None,
+            };
+            set_register_xi(ctx, rd, value);
+        }
+        decode::OPCODE_OP_IMM_32 => {
+            let rs1 = rs1 as u32;
+            let shamt = (ins >> 20) & 0b1_1111;
+            let value = match (funct3, funct7) {
+                (0b000, _) => rs1.wrapping_add(imm_i as u32),
+                (0b001, 0) => rs1 << shamt,
+                (0b101, 0) => rs1 >> shamt,
+                (0b101, 0b010_0000) => ((rs1 as i32) >> shamt) as u32,
+                _ => return None,
+            };
+            set_register_xi(ctx, rd, value as i32 as usize);
+        }
+        decode::OPCODE_OP => {
+            let shamt = rs2 & 0b11_1111;
+            let value = match (funct3, funct7) {
+                (0b000, 0) => rs1.wrapping_add(rs2),
+                (0b000, 0b010_0000) => rs1.wrapping_sub(rs2),
+                (0b001, 0) => rs1 << shamt,
+                (0b010, 0) => ((rs1 as isize) < (rs2 as isize)) as usize,
+                (0b011, 0) => (rs1 < rs2) as usize,
+                (0b100, 0) => rs1 ^ rs2,
+                (0b101, 0) => rs1 >> shamt,
+                (0b101, 0b010_0000) => ((rs1 as isize) >> shamt) as usize,
+                (0b110, 0) => rs1 | rs2,
+                (0b111, 0) => rs1 & rs2,
+                _ => return None,
+            };
+            set_register_xi(ctx, rd, value);
+        }
+        decode::OPCODE_OP_32 => {
+            let (rs1, rs2) = (rs1 as u32, rs2 as u32);
+            let shamt = rs2 & 0b1_1111;
+            let value = match (funct3, funct7) {
+                (0b000, 0) => rs1.wrapping_add(rs2),
+                (0b000, 0b010_0000) => rs1.wrapping_sub(rs2),
+                (0b001, 0) => rs1 << shamt,
+                (0b101, 0) => rs1 >> shamt,
+                (0b101, 0b010_0000) => ((rs1 as i32) >> shamt) as u32,
+                _ => return None,
+            };
+            set_register_xi(ctx, rd, value as i32 as usize);
+        }
+        _ => return None,
+    }
+    Some(next_pc)
+}
+
+// Sign extended immediate of J-type instructions
+fn imm_j(ins: u32) -> i32 {
+    let imm = (ins >> 31) << 20
+        | ((ins >> 12) & 0xff) << 12
+        | ((ins >> 20) & 1) << 11
+        | ((ins >> 21) & 0x3ff) << 1;
+    ((imm << 11) as i32) >> 11
+}
+
+// Sign extended immediate of B-type instructions
+fn imm_b(ins: u32) -> i32 {
+    let imm = (ins >> 31) << 12
+        | ((ins >> 7) & 1) << 11
+        | ((ins >> 25) & 0x3f) << 5
+        | ((ins >> 8) & 0xf) << 1;
+    ((imm << 19) as i32) >> 19
+}
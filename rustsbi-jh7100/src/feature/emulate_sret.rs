@@ -0,0 +1,52 @@
+use crate::decode::Instruction;
+use crate::runtime::SupervisorContext;
+use riscv::register::mstatus::{self, MPP};
+
+// sstatus fields, at the same positions in mstatus
+const SIE: usize = 1 << 1;
+const SPIE: usize = 1 << 5;
+const SPP: usize = 1 << 8;
+const MPRV: usize = 1 << 17;
+
+// sret of supervisor, which traps as an illegal instruction while mstatus.TSR is set; with
+// feature `emulate-sret`, `trap_supervisor_return` sets it on every hart. Does what hardware sret
+// would: return to the privilege level in SPP, restore SIE from SPIE, set SPIE, clear SPP and
+// MPRV, and continue at sepc. sret from user mode is illegal with or without TSR, and is left to
+// be delivered as such.
+#[inline]
+pub fn emulate_sret(ctx: &mut SupervisorContext, ins: &Instruction) -> Option<usize> {
+    if *ins != Instruction::Sret {
+        return None; // is not an sret instruction
+    }
+    if ctx.mstatus.mpp() != MPP::Supervisor {
+        return None;
+    }
+    // live mstatus holds supervisor's value while handling its trap, as in `do_transfer_trap`
+    let old = read_mstatus();
+    let mpp = if old & SPP != 0 {
+        MPP::Supervisor
+    } else {
+        MPP::User
+    };
+    let set = SPIE | if old & SPIE != 0 { SIE } else { 0 };
+    let clear = SPP | MPRV | if old & SPIE != 0 { 0 } else { SIE };
+    unsafe {
+        core::arch::asm!(
+            "csrs mstatus, {set}",
+            "csrc mstatus, {clear}",
+            set = in(reg) set,
+            clear = in(reg) clear,
+        );
+        mstatus::set_mpp(mpp);
+    }
+    ctx.mstatus = mstatus::read();
+    // bit 0 of sepc is always zero on a hart with C, which U74 has
+    let sepc = riscv::register::sepc::read() & !1;
+    Some(sepc.wrapping_sub(ctx.mepc)) // distance to sepc, where sret resumes
+}
+
+fn read_mstatus() -> usize {
+    let bits: usize;
+    unsafe { core::arch::asm!("csrr {}, mstatus", out(reg) bits) };
+    bits
+}
@@ -0,0 +1,83 @@
+use super::registers::{get_register_xi, set_register_xi};
+use crate::decode::{CsrOp, Instruction};
+use crate::runtime::SupervisorContext;
+use riscv::register::mstatus::{self, MPP};
+
+const CSR_SSTATUS: u16 = 0x100;
+
+// sstatus fields, at the same positions in mstatus
+const SIE: usize = 1 << 1;
+const SPIE: usize = 1 << 5;
+const UBE: usize = 1 << 6;
+const SPP: usize = 1 << 8;
+const VS: usize = 0b11 << 9;
+const FS: usize = 0b11 << 13;
+const XS: usize = 0b11 << 15;
+const SUM: usize = 1 << 18;
+const MXR: usize = 1 << 19;
+const UXL: usize = 0b11 << 32;
+const SD: usize = 1 << 63;
+
+// what supervisor reads; WPRI fields read as zero
+const SSTATUS_READ_MASK: usize = SIE | SPIE | UBE | SPP | VS | FS | XS | SUM | MXR | UXL | SD;
+// what supervisor may change; UBE, XS, UXL and SD are read-only, and VS stays zero on U74
+// without V extension
+const SSTATUS_WRITE_MASK: usize = SIE | SPIE | SPP | FS | SUM | MXR;
+
+// csrrw, csrrs and csrrc on sstatus and their immediate forms, should an access from supervisor
+// ever trap; applied to supervisor's mstatus restricted to the sstatus view
+#[inline]
+pub fn emulate_sstatus(
+    ctx: &mut SupervisorContext,
+    ins: &Instruction,
+    len: usize,
+) -> Option<usize> {
+    let (op, rd, rs1) = match *ins {
+        Instruction::Csr {
+            op,
+            rd,
+            rs1,
+            csr: CSR_SSTATUS,
+        } => (op, rd, rs1),
+        _ => return None, // is not an sstatus access
+    };
+    // user mode has no access to sstatus, it stays an illegal instruction
+    if ctx.mstatus.mpp() != MPP::Supervisor {
+        return None;
+    }
+    // live mstatus holds supervisor's value while handling its trap, as in `do_transfer_trap`
+    let old = read_mstatus() & SSTATUS_READ_MASK;
+    let source = match op {
+        CsrOp::ReadWrite | CsrOp::ReadSet | CsrOp::ReadClear => get_register_xi(ctx, rs1),
+        // rs1 field is a zero extended immediate
+        CsrOp::ReadWriteImm | CsrOp::ReadSetImm | CsrOp::ReadClearImm => rs1 as usize,
+    };
+    let new = match op {
+        CsrOp::ReadWrite | CsrOp::ReadWriteImm => source,
+        CsrOp::ReadSet | CsrOp::ReadSetImm => old | source,
+        CsrOp::ReadClear | CsrOp::ReadClearImm => old & !source,
+    };
+    // csrrs and csrrc with x0 or a zero immediate do not write the CSR
+    let writes = matches!(op, CsrOp::ReadWrite | CsrOp::ReadWriteImm) || rs1 != 0;
+    if writes {
+        let set = new & SSTATUS_WRITE_MASK;
+        let clear = !new & SSTATUS_WRITE_MASK;
+        unsafe {
+            core::arch::asm!(
+                "csrs mstatus, {set}",
+                "csrc mstatus, {clear}",
+                set = in(reg) set,
+                clear = in(reg) clear,
+            );
+        }
+        ctx.mstatus = mstatus::read();
+    }
+    set_register_xi(ctx, rd, old);
+    Some(len) // skip sstatus access
+}
+
+fn read_mstatus() -> usize {
+    let bits: usize;
+    unsafe { core::arch::asm!("csrr {}, mstatus", out(reg) bits) };
+    bits
+}
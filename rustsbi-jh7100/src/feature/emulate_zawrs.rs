@@ -0,0 +1,20 @@
+use crate::decode::Instruction;
+use crate::println;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static ZAWRS_NOTED: AtomicBool = AtomicBool::new(false);
+
+// wrs.nto and wrs.sto from Zawrs, which U74 does not implement.
+//
+// Wait-on-reservation-set may terminate at any time for any reason; emulate it
+// as a no-op so the supervisor simply re-checks its reservation
+#[inline]
+pub fn emulate_zawrs(ins: &Instruction, len: usize) -> Option<usize> {
+    if !matches!(ins, Instruction::WrsNto | Instruction::WrsSto) {
+        return None; // is not a wrs instruction
+    }
+    if !ZAWRS_NOTED.swap(true, Ordering::Relaxed) {
+        println!("[rustsbi] note: Zawrs instructions are emulated as no-op");
+    }
+    Some(len) // skip wrs instruction
+}
@@ -0,0 +1,23 @@
+use super::registers::{get_register_xi, set_register_xi};
+use crate::decode::Instruction;
+use crate::runtime::SupervisorContext;
+
+// czero.eqz and czero.nez from Zicond, which U74 does not implement
+#[inline]
+pub fn emulate_zicond(ctx: &mut SupervisorContext, ins: &Instruction, len: usize) -> Option<usize> {
+    let (rd, rs1, rs2, zero_if_rs2_zero) = match *ins {
+        Instruction::CzeroEqz { rd, rs1, rs2 } => (rd, rs1, rs2, true),
+        Instruction::CzeroNez { rd, rs1, rs2 } => (rd, rs1, rs2, false),
+        _ => return None, // is not a czero instruction
+    };
+    // read both sources before writing rd, which may be one of them
+    let condition = get_register_xi(ctx, rs2);
+    let value = get_register_xi(ctx, rs1);
+    let result = if (condition == 0) == zero_if_rs2_zero {
+        0
+    } else {
+        value
+    };
+    set_register_xi(ctx, rd, result);
+    Some(len) // skip czero instruction
+}
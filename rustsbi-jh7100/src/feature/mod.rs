@@ -1,5 +1,44 @@
+#[cfg(feature = "bitmanip-emulation")]
+mod emulate_bitmanip;
+#[cfg(feature = "fixed-counters")]
+mod emulate_counters;
+mod emulate_misaligned;
+#[cfg(feature = "emulate-mmio-amo")]
+mod emulate_mmio_amo;
 mod emulate_rdtime;
+#[cfg(feature = "rvc-emulation")]
+mod emulate_rvc;
+#[cfg(feature = "emulate-sret")]
+mod emulate_sret;
+#[cfg(feature = "emulate-sstatus")]
+mod emulate_sstatus;
+#[cfg(feature = "emulate-zawrs")]
+mod emulate_zawrs;
+#[cfg(feature = "emulate-zicond")]
+mod emulate_zicond;
+mod registers;
 mod transfer_trap;
 
+#[cfg(feature = "bitmanip-emulation")]
+pub use emulate_bitmanip::emulate_bitmanip;
+#[cfg(feature = "fixed-counters")]
+pub use emulate_counters::emulate_counters;
+pub use emulate_misaligned::{emulate_misaligned_load, emulate_misaligned_store};
+#[cfg(feature = "emulate-mmio-amo")]
+pub use emulate_mmio_amo::emulate_mmio_amo;
 pub use emulate_rdtime::emulate_rdtime;
-pub use transfer_trap::{do_transfer_trap, should_transfer_trap};
+#[cfg(feature = "rvc-emulation")]
+pub use emulate_rvc::emulate_rvc;
+#[cfg(feature = "emulate-sret")]
+pub use emulate_sret::emulate_sret;
+#[cfg(feature = "emulate-sstatus")]
+pub use emulate_sstatus::emulate_sstatus;
+#[cfg(feature = "emulate-zawrs")]
+pub use emulate_zawrs::emulate_zawrs;
+#[cfg(feature = "emulate-zicond")]
+pub use emulate_zicond::emulate_zicond;
+pub use transfer_trap::{
+    do_transfer_trap, should_transfer_trap, EXCEPTION_BREAKPOINT, EXCEPTION_ILLEGAL_INSTRUCTION,
+    EXCEPTION_LOAD_FAULT, EXCEPTION_LOAD_MISALIGNED, EXCEPTION_STORE_FAULT,
+    EXCEPTION_STORE_MISALIGNED, INTERRUPT_SUPERVISOR_SOFT,
+};
@@ -0,0 +1,23 @@
+use crate::runtime::SupervisorContext;
+
+#[inline]
+pub fn get_register_xi(ctx: &SupervisorContext, i: u8) -> usize {
+    let registers = unsafe { &*(ctx as *const _ as *const [usize; 31]) };
+    assert!(i <= 31, "i should be valid register target");
+    if i == 0 {
+        // x0, always zero
+        return 0;
+    }
+    registers[(i - 1) as usize]
+}
+
+#[inline]
+pub fn set_register_xi(ctx: &mut SupervisorContext, i: u8, data: usize) {
+    let registers = unsafe { &mut *(ctx as *mut _ as *mut [usize; 31]) };
+    assert!(i <= 31, "i should be valid register target");
+    if i == 0 {
+        // x0, don't modify
+        return;
+    }
+    registers[(i - 1) as usize] = data;
+}
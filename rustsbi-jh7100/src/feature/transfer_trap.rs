@@ -4,22 +4,44 @@ use riscv::register::{
     mtval, scause, sepc, stval, stvec,
 };
 
+// scause codes of what firmware delivers to supervisor; `scause::Exception` of riscv 0.7 has no
+// load address misaligned, thus codes rather than `scause::Trap`
+pub const EXCEPTION_ILLEGAL_INSTRUCTION: usize = 2;
+pub const EXCEPTION_BREAKPOINT: usize = 3;
+pub const EXCEPTION_LOAD_MISALIGNED: usize = 4;
+pub const EXCEPTION_LOAD_FAULT: usize = 5;
+pub const EXCEPTION_STORE_MISALIGNED: usize = 6;
+pub const EXCEPTION_STORE_FAULT: usize = 7;
+pub const INTERRUPT_SUPERVISOR_SOFT: usize = 1 << (usize::BITS - 1) | 1;
+
 #[inline]
 pub unsafe fn should_transfer_trap(ctx: &mut SupervisorContext) -> bool {
     ctx.mstatus.mpp() != MPP::Machine
 }
 
 #[inline]
-pub unsafe fn do_transfer_trap(ctx: &mut SupervisorContext, cause: scause::Trap) {
+pub unsafe fn do_transfer_trap(ctx: &mut SupervisorContext, cause: usize) {
     // 设置S层异常原因
-    scause::set(cause);
+    scause::write(cause);
+    #[cfg(feature = "sbi-trace")]
+    crate::trace::record_delegated_trap(
+        riscv::register::mhartid::read(),
+        cause,
+        ctx.mepc,
+        mtval::read(),
+    );
     // 填写异常指令的指令内容
     stval::write(mtval::read());
     // 填写S层需要返回到的地址，这里的mepc会被随后的代码覆盖掉
     sepc::write(ctx.mepc);
-    // 设置中断位
+    // 设置中断位；SPP keeps the level which trapped, user traps reach here too once access
+    // faults are taken by firmware
+    let spp = match mstatus::read().mpp() {
+        MPP::User => SPP::User,
+        _ => SPP::Supervisor,
+    };
     mstatus::set_mpp(MPP::Supervisor);
-    mstatus::set_spp(SPP::Supervisor);
+    mstatus::set_spp(spp);
     if mstatus::read().sie() {
         mstatus::set_spie()
     }
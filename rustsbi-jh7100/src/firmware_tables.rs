@@ -0,0 +1,124 @@
+//! Supplementary firmware tables
+//!
+//! With feature `firmware-tables`, the SD card image may carry a blob of tables besides the device
+//! tree, e.g. a board information blob or ACPI-style tables for an experiment; firmware passes it
+//! on without looking into it. `cargo xtask image --tables <file>` writes it at `IMAGE_OFFSET` of
+//! the image, behind a 16-byte header: `IMAGE_MAGIC`, then its length as a little endian u64. The
+//! image is loaded to DRAM as a whole, thus firmware finds the blob at `stext + IMAGE_OFFSET`.
+//!
+//! Layout of the SD card image:
+//!
+//! | Offset       | Contents
+//! |:-------------|:---------
+//! | `0x0`        | firmware, then the embedded payload at `0x2_0000` or the next 128KiB boundary
+//! | `0x200_0000` | payload slot B, with feature `payload-slots`, see `payload_slot`
+//! | `0x3F0_0000` | firmware tables, with feature `firmware-tables`, at most `MAX_LEN` bytes
+//! | `0x400_0000` | initramfs, with feature `initramfs`, see `initramfs`
+//!
+//! Boot hart copies the blob into scratch slot `SLOT_FIRMWARE_TABLES` as soon as scratch memory is
+//! set up, before slot B or the initramfs are moved; supervisor may overwrite the loaded image
+//! once it runs. There it stays in firmware reserved memory, never usable memory of supervisor.
+//! Supervisor finds it through vendor extension `EXTENSION_FIRMWARE_TABLES`:
+//!
+//! | Function | Parameters | Returns
+//! |:---------|:-----------|:---------
+//! | 0        |            | physical address of the tables, 0 if there are none
+//! | 1        |            | length of the tables in bytes, 0 if there are none
+//!
+//! or, with feature `handoff-info`, through the same two fields of the boot handoff structure,
+//! see `handoff`. An image without the header boots without tables. A header whose length is 0
+//! or above `MAX_LEN`, which would not fit the slot, is reported and the tables are left out.
+use crate::println;
+use crate::scratch::{self, SLOT_FIRMWARE_TABLES};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rustsbi::SbiRet;
+
+// both move data of the loaded image that this module expects at `stext + IMAGE_OFFSET`
+#[cfg(feature = "dram-test")]
+compile_error!("feature `firmware-tables` cannot be used with `dram-test`, which overwrites them");
+#[cfg(feature = "self-relocate")]
+compile_error!(
+    "feature `firmware-tables` cannot be used with `self-relocate`, which leaves them behind"
+);
+
+// Where the tables header is in the SD card image, and in DRAM from `stext`; slot B must end
+// before it, and the tables before `initramfs::IMAGE_OFFSET`
+pub const IMAGE_OFFSET: usize = 0x3F0_0000;
+pub const IMAGE_MAGIC: &[u8; 8] = b"RSBITABL";
+const HEADER_SIZE: usize = 16;
+
+// Largest blob, all of its scratch slot
+pub const MAX_LEN: usize = SLOT_FIRMWARE_TABLES.size();
+
+const FUNCTION_GET_ADDRESS: usize = 0x0;
+const FUNCTION_GET_LENGTH: usize = 0x1;
+
+// Where the tables were copied to and their length, zero if there are none
+static TABLES_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+static TABLES_LEN: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" {
+    static stext: u8;
+}
+
+// Copy the tables of the image into scratch memory; called once on boot hart after
+// `scratch::init`, `kernel` is where the embedded payload was copied to
+pub fn load(kernel: core::ops::Range<usize>) {
+    let header = unsafe { &stext as *const u8 as usize } + IMAGE_OFFSET;
+    let (source, len) = unsafe {
+        let magic = core::slice::from_raw_parts(header as *const u8, IMAGE_MAGIC.len());
+        if magic != IMAGE_MAGIC {
+            println!(
+                "[rustsbi] no firmware tables at image offset {:#x}",
+                IMAGE_OFFSET
+            );
+            return;
+        }
+        let len = core::ptr::read_unaligned((header + 8) as *const u64) as usize;
+        (header + HEADER_SIZE, len)
+    };
+    if len == 0 || len > MAX_LEN {
+        println!(
+            "[rustsbi] warning: firmware tables length {:#x} in image header is invalid, at most {:#x}; boot without them",
+            len, MAX_LEN
+        );
+        return;
+    }
+    if source < kernel.end && kernel.start < source + len {
+        println!(
+            "[rustsbi] warning: firmware tables at {:#x} were overwritten by payload at {:#x}..{:#x}; boot without them",
+            source, kernel.start, kernel.end
+        );
+        return;
+    }
+    let buf = match unsafe { scratch::slot(SLOT_FIRMWARE_TABLES) } {
+        Some(buf) => buf,
+        None => {
+            println!("[rustsbi] warning: no firmware scratch memory, boot without firmware tables");
+            return;
+        }
+    };
+    unsafe { core::ptr::copy_nonoverlapping(source as *const u8, buf.as_mut_ptr(), len) };
+    TABLES_ADDRESS.store(buf.as_ptr() as usize, Ordering::Relaxed);
+    TABLES_LEN.store(len, Ordering::Release);
+    println!(
+        "[rustsbi] firmware tables: {:#x} bytes at {:#x}",
+        len,
+        buf.as_ptr() as usize
+    );
+}
+
+// Address and length of the tables, or zeros if there are none
+pub fn get() -> (usize, usize) {
+    let len = TABLES_LEN.load(Ordering::Acquire);
+    (TABLES_ADDRESS.load(Ordering::Relaxed), len)
+}
+
+// Handler of vendor extension `EXTENSION_FIRMWARE_TABLES`
+pub fn handle_ecall(function: usize, _param: [usize; 6]) -> SbiRet {
+    match function {
+        FUNCTION_GET_ADDRESS => SbiRet::ok(get().0),
+        FUNCTION_GET_LENGTH => SbiRet::ok(get().1),
+        _ => SbiRet::not_supported(),
+    }
+}
@@ -0,0 +1,82 @@
+//! Forwarding SBI calls to a parent firmware
+//!
+//! For SBI layering experiments, this firmware may be chain-loaded by another machine mode
+//! component, the parent, which keeps serving some SBI extensions itself. Calls to extensions in
+//! `board::FORWARDED_EXTENSIONS`, and base extension probes of them, are re-issued to the parent
+//! instead of being handled here; everything else is handled locally as usual.
+//!
+//! Forwarding ABI:
+//!
+//! - on entry, each hart records the `mtvec` and `mscratch` the parent left, before firmware
+//!   installs its own; a hart entered with `mtvec` zero has no parent, its forwarded calls return
+//!   `SBI_ERR_NOT_SUPPORTED`;
+//! - a forwarded call switches `mtvec` and `mscratch` back to the parent's values and executes
+//!   `ecall` in machine mode with a0 to a7 exactly as supervisor passed them; the parent sees an
+//!   environment call from machine mode, `mcause` 11;
+//! - the parent returns with `mret` to `mepc + 4`, with the SBI error in a0 and the value in a1,
+//!   and must preserve every other register, `mie` and PMP; `mepc`, `mcause`, `mtval` and the
+//!   trap fields of `mstatus` may be clobbered;
+//! - firmware then restores its own `mtvec` and `mscratch` and returns a0 and a1 to supervisor.
+//!   A legacy extension returns a0 only, as if it were handled locally.
+use crate::board;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rustsbi::SbiRet;
+
+const EXTENSION_BASE: usize = 0x10;
+const FUNCTION_BASE_PROBE_EXTENSION: usize = 0x3;
+
+const NO_PARENT: AtomicUsize = AtomicUsize::new(0);
+// Kept in .data, as they are written before boot hart clears .bss
+#[link_section = ".data.parent_mtvec"]
+static PARENT_MTVEC: [AtomicUsize; crate::NUM_HARTS] = [NO_PARENT; crate::NUM_HARTS];
+#[link_section = ".data.parent_mscratch"]
+static PARENT_MSCRATCH: [AtomicUsize; crate::NUM_HARTS] = [NO_PARENT; crate::NUM_HARTS];
+
+// Record trap CSRs of the parent on current hart; called on entry, before firmware sets them
+pub fn save_parent(hart_id: usize) {
+    PARENT_MTVEC[hart_id].store(riscv::register::mtvec::read().bits(), Ordering::Relaxed);
+    PARENT_MSCRATCH[hart_id].store(riscv::register::mscratch::read(), Ordering::Relaxed);
+}
+
+// Forward calls to configured extensions and their probes; None if they are handled locally
+pub fn handle_ecall(extension: usize, function: usize, param: [usize; 6]) -> Option<SbiRet> {
+    let forwarded = |extension| board::FORWARDED_EXTENSIONS.contains(&extension);
+    match (extension, function) {
+        (EXTENSION_BASE, FUNCTION_BASE_PROBE_EXTENSION) if forwarded(param[0]) => {
+            Some(forward_ecall(extension, function, param))
+        }
+        _ if forwarded(extension) => Some(forward_ecall(extension, function, param)),
+        _ => None,
+    }
+}
+
+fn forward_ecall(extension: usize, function: usize, param: [usize; 6]) -> SbiRet {
+    let hart_id = crate::execute::calling_hart();
+    let mtvec = PARENT_MTVEC[hart_id].load(Ordering::Relaxed);
+    if mtvec == 0 {
+        return SbiRet::not_supported();
+    }
+    let mscratch = PARENT_MSCRATCH[hart_id].load(Ordering::Relaxed);
+    let (error, value);
+    // firmware runs with MSTATUS.MIE clear, nothing else traps between the swaps
+    unsafe {
+        core::arch::asm!(
+            "csrrw  {mtvec}, mtvec, {mtvec}",
+            "csrrw  {mscratch}, mscratch, {mscratch}",
+            "ecall",
+            "csrw   mscratch, {mscratch}",
+            "csrw   mtvec, {mtvec}",
+            mtvec = inout(reg) mtvec => _,
+            mscratch = inout(reg) mscratch => _,
+            inlateout("a0") param[0] => error,
+            inlateout("a1") param[1] => value,
+            in("a2") param[2],
+            in("a3") param[3],
+            in("a4") param[4],
+            in("a5") param[5],
+            in("a6") function,
+            in("a7") extension,
+        );
+    }
+    SbiRet { error, value }
+}
@@ -0,0 +1,98 @@
+//! SBI Firmware Features (FWFT) extension
+//!
+//! RustSBI 0.2.2 does not know this extension, thus its calls are handled here before they
+//! reach `rustsbi::ecall`. Only the misaligned exception delegation feature is supported:
+//! value 1 delegates misaligned load and store exceptions to supervisor, value 0 keeps them
+//! in firmware where they are emulated. The setting is per hart and can be locked until next hart reset.
+//!
+//! Other standard features defined by SBI are not supported; reserved and platform specific
+//! feature ids are denied, as the extension requires.
+use core::sync::atomic::{AtomicBool, Ordering};
+use riscv::register::medeleg;
+use rustsbi::SbiRet;
+
+pub const EXTENSION_FWFT: usize = 0x46574654;
+
+const FUNCTION_FWFT_SET: usize = 0x0;
+const FUNCTION_FWFT_GET: usize = 0x1;
+
+const FEATURE_MISALIGNED_EXC_DELEG: usize = 0x0;
+// Standard hart local features defined by SBI, from misaligned exception delegation to pointer
+// masking; the rest of 0x0..=0x3FFF_FFFF and all of 0x8000_0000..=0xBFFF_FFFF are reserved,
+// 0x4000_0000..=0x7FFF_FFFF and 0xC000_0000..=0xFFFF_FFFF are platform specific
+const FEATURES_STANDARD: core::ops::RangeInclusive<usize> = 0x0..=0x5;
+
+const FLAG_LOCK: usize = 1 << 0;
+
+const SBI_ERR_DENIED: usize = -4isize as usize;
+
+const EXTENSION_BASE: usize = 0x10;
+const FUNCTION_BASE_PROBE_EXTENSION: usize = 0x3;
+
+const MISALIGNED_UNLOCKED: AtomicBool = AtomicBool::new(false);
+static MISALIGNED_LOCKED: [AtomicBool; crate::NUM_HARTS] = [MISALIGNED_UNLOCKED; crate::NUM_HARTS];
+
+// Handle FWFT calls, and probe of FWFT extension which RustSBI would report as absent.
+//
+// Returns None if this call should be handled by RustSBI.
+pub fn handle_ecall(extension: usize, function: usize, param: [usize; 6]) -> Option<SbiRet> {
+    match (extension, function) {
+        (EXTENSION_BASE, FUNCTION_BASE_PROBE_EXTENSION) if param[0] == EXTENSION_FWFT => {
+            Some(SbiRet::ok(1))
+        }
+        (EXTENSION_FWFT, FUNCTION_FWFT_SET) => Some(set(param[0], param[1], param[2])),
+        (EXTENSION_FWFT, FUNCTION_FWFT_GET) => Some(get(param[0])),
+        (EXTENSION_FWFT, _) => Some(SbiRet::not_supported()),
+        _ => None,
+    }
+}
+
+fn set(feature: usize, value: usize, flags: usize) -> SbiRet {
+    if feature != FEATURE_MISALIGNED_EXC_DELEG {
+        return unsupported(feature);
+    }
+    if flags & !FLAG_LOCK != 0 || value > 1 {
+        return SbiRet::invalid_param();
+    }
+    let locked = &MISALIGNED_LOCKED[crate::execute::calling_hart()];
+    if locked.load(Ordering::Relaxed) {
+        return denied();
+    }
+    unsafe {
+        if value == 1 {
+            medeleg::set_load_misaligned();
+            medeleg::set_store_misaligned();
+        } else {
+            medeleg::clear_load_misaligned();
+            medeleg::clear_store_misaligned();
+        }
+    }
+    if flags & FLAG_LOCK != 0 {
+        locked.store(true, Ordering::Relaxed);
+    }
+    SbiRet::ok(0)
+}
+
+fn get(feature: usize) -> SbiRet {
+    if feature != FEATURE_MISALIGNED_EXC_DELEG {
+        return unsupported(feature);
+    }
+    let medeleg = medeleg::read();
+    SbiRet::ok((medeleg.load_misaligned() && medeleg.store_misaligned()) as usize)
+}
+
+// Error for a feature other than misaligned exception delegation
+fn unsupported(feature: usize) -> SbiRet {
+    if FEATURES_STANDARD.contains(&feature) {
+        SbiRet::not_supported()
+    } else {
+        denied()
+    }
+}
+
+fn denied() -> SbiRet {
+    SbiRet {
+        error: SBI_ERR_DENIED,
+        value: 0,
+    }
+}
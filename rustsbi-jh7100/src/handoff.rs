@@ -0,0 +1,102 @@
+//! Boot handoff structure
+//!
+//! Before entering the payload, boot hart writes the parameters it hands over into scratch slot
+//! `SLOT_HANDOFF`, for tools which inspect the boot, e.g. over JTAG or from a memory dump, and for
+//! a kernel which wants them without parsing the device tree. The slot lies in the firmware
+//! region listed as `rustsbi` under `/reserved-memory` of the device tree passed in a1, at
+//! offset `0x4_2000` from its start; supervisor never gets it as usable memory.
+//!
+//! Layout, little endian, version 2:
+//!
+//! | Offset | Size | Field
+//! |:-------|:-----|:------
+//! | `0x00` | 8    | magic, `HANDOFF_MAGIC`, "RSBIHOFF" as bytes in memory
+//! | `0x08` | 4    | layout version, `HANDOFF_VERSION`
+//! | `0x0c` | 4    | size of the structure in bytes
+//! | `0x10` | 8    | SBI specification version, as base extension `get_spec_version` returns
+//! | `0x18` | 8    | SBI implementation version, as base extension `get_impl_version` returns
+//! | `0x20` | 8    | address of device tree passed to supervisor in a1
+//! | `0x28` | 8    | supervisor entry address
+//! | `0x30` | 8    | DRAM base reported to supervisor
+//! | `0x38` | 8    | DRAM size reported to supervisor
+//! | `0x40` | 8    | boot hart id, passed to supervisor in a0
+//! | `0x48` | 8    | address of firmware tables, 0 if there are none; since version 2
+//! | `0x50` | 8    | length of firmware tables in bytes, 0 if there are none; since version 2
+//!
+//! Firmware tables are only passed with feature `firmware-tables`, see `firmware_tables`.
+//!
+//! A later version only appends fields and raises the size; readers check the magic, then
+//! read no further than the size. The magic is written last, so a structure of an interrupted
+//! boot is not taken as valid.
+use crate::boot_hart;
+use crate::println;
+use crate::scratch::{self, SLOT_HANDOFF};
+
+// "RSBIHOFF" in memory order
+pub const HANDOFF_MAGIC: u64 = u64::from_le_bytes(*b"RSBIHOFF");
+pub const HANDOFF_VERSION: u32 = 2;
+
+#[repr(C)]
+struct Handoff {
+    magic: u64,
+    version: u32,
+    size: u32,
+    sbi_spec_version: u64,
+    sbi_impl_version: u64,
+    device_tree: u64,
+    entry: u64,
+    dram_base: u64,
+    dram_size: u64,
+    boot_hart_id: u64,
+    firmware_tables: u64,
+    firmware_tables_size: u64,
+}
+
+const _: () = assert!(core::mem::size_of::<Handoff>() == 0x58);
+const _: () = assert!(core::mem::size_of::<Handoff>() <= SLOT_HANDOFF.size());
+
+const EXTENSION_BASE: usize = 0x10;
+const FUNCTION_BASE_GET_SPEC_VERSION: usize = 0x0;
+const FUNCTION_BASE_GET_IMPL_VERSION: usize = 0x2;
+
+// Write the handoff structure for supervisor entered at `entry` with device tree at
+// `device_tree`; called once on boot hart, after scratch region and RustSBI are initialized
+pub fn write(entry: usize, device_tree: usize) {
+    let handoff = match unsafe { scratch::slot(SLOT_HANDOFF) } {
+        // slots are aligned to their offset in 2MiB aligned scratch region
+        Some(buf) => unsafe { &mut *(buf.as_mut_ptr() as *mut Handoff) },
+        None => {
+            println!("[rustsbi] warning: no firmware scratch memory, boot handoff not written");
+            return;
+        }
+    };
+    let base = |function| rustsbi::ecall(EXTENSION_BASE, function, [0; 6]).value as u64;
+    let (dram_base, dram_end) = scratch::supervisor_dram(crate::DEVICE_TREE).unwrap_or((0, 0));
+    #[cfg(feature = "firmware-tables")]
+    let (tables, tables_size) = crate::firmware_tables::get();
+    #[cfg(not(feature = "firmware-tables"))]
+    let (tables, tables_size) = (0, 0);
+    let new = Handoff {
+        magic: 0,
+        version: HANDOFF_VERSION,
+        size: core::mem::size_of::<Handoff>() as u32,
+        sbi_spec_version: base(FUNCTION_BASE_GET_SPEC_VERSION),
+        sbi_impl_version: base(FUNCTION_BASE_GET_IMPL_VERSION),
+        device_tree: device_tree as u64,
+        entry: entry as u64,
+        dram_base: dram_base as u64,
+        dram_size: (dram_end - dram_base) as u64,
+        boot_hart_id: boot_hart::id() as u64,
+        firmware_tables: tables as u64,
+        firmware_tables_size: tables_size as u64,
+    };
+    // invalidate first and publish the magic last; volatile writes keep this order
+    unsafe {
+        core::ptr::write_volatile(handoff, new);
+        core::ptr::write_volatile(&mut handoff.magic, HANDOFF_MAGIC);
+    }
+    println!(
+        "[rustsbi] boot handoff version {} at {:#x}",
+        HANDOFF_VERSION, handoff as *const Handoff as usize
+    );
+}
@@ -1,3 +1,4 @@
+use crate::println;
 use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
@@ -6,7 +7,6 @@ use riscv::register::{
     medeleg, mideleg,
     misa::{self, MXL},
 };
-use rustsbi::println;
 
 pub fn print_hart_csrs() {
     print_misa();
@@ -117,6 +117,82 @@ fn print_medeleg() {
     );
 }
 
+// Print PMP entries as read back from CSRs, with the board region each one was set from; board
+// regions start at entry `first_entry`
+pub fn print_pmp_regions(regions: &[crate::board::PmpRegion], first_entry: usize) {
+    let pmpcfg = [read_csr_dynamic(0x3A0), read_csr_dynamic(0x3A2)];
+    for (index, region) in regions.iter().enumerate() {
+        let i = first_entry + index;
+        let cfg = match pmpcfg[i / 8] {
+            Ok(pmpcfg) => PmpCfg::from((pmpcfg >> (i % 8 * 8)) as u8),
+            Err(_) => break,
+        };
+        let pmpaddr = read_csr_dynamic(0x3B0 + i).unwrap_or(0);
+        println!(
+            "[rustsbi] pmp{}: {:#x} ..= {:#x} ({}{}{}), pmpaddr {:#x}, {}",
+            i,
+            region.base,
+            region.base + region.size - 1,
+            if cfg.r() { "r" } else { "-" },
+            if cfg.w() { "w" } else { "-" },
+            if cfg.x() { "x" } else { "-" },
+            pmpaddr,
+            region.name
+        );
+    }
+}
+
+// Print non-zero trap counters of every hart since boot; does not allocate, so that a panic
+// report can call it
+pub fn print_trap_stats() {
+    use crate::execute::ECALL_HANDLERS;
+    use crate::trap_stats::{self, TRAP_KINDS};
+    for hart_id in 0..crate::NUM_HARTS {
+        println!("[rustsbi] hart {} trap counters:", hart_id);
+        for handler in ECALL_HANDLERS {
+            match trap_stats::ecalls(hart_id, handler) {
+                Some(0) => {}
+                Some(count) => println!("[rustsbi]   SBI calls to {:?}: {}", handler, count),
+                None => {
+                    println!("[rustsbi]   unavailable, no firmware scratch memory");
+                    return;
+                }
+            }
+        }
+        for kind in TRAP_KINDS {
+            match trap_stats::traps(hart_id, kind) {
+                Some(0) | None => {}
+                Some(count) => println!("[rustsbi]   {:?}: {}", kind, count),
+            }
+        }
+        print_hart_emulations(hart_id);
+    }
+}
+
+// Print non-zero emulation counters of every hart since boot, the harts without any left out
+pub fn print_emulation_stats() {
+    use crate::trap_stats::{self, EMULATIONS};
+    for hart_id in 0..crate::NUM_HARTS {
+        let emulated = EMULATIONS
+            .iter()
+            .any(|&emulation| trap_stats::emulations(hart_id, emulation).unwrap_or(0) != 0);
+        if emulated {
+            println!("[rustsbi] hart {} emulation counters:", hart_id);
+            print_hart_emulations(hart_id);
+        }
+    }
+}
+
+fn print_hart_emulations(hart_id: usize) {
+    use crate::trap_stats::{self, EMULATIONS};
+    for emulation in EMULATIONS {
+        match trap_stats::emulations(hart_id, emulation) {
+            Some(0) | None => {}
+            Some(count) => println!("[rustsbi]   emulated {:?}: {}", emulation, count),
+        }
+    }
+}
+
 #[cfg(target_pointer_width = "64")]
 #[inline]
 fn print_pmp() {
@@ -305,3 +381,58 @@ unsafe fn pmpaddr_r(pmpaddr_id: usize) -> usize {
     id = in(reg) pmpaddr_id, tmp = out(reg) _, len = out(reg) _, ans = out(reg) ans);
     ans
 }
+
+// Read or write a CSR whose number is only known at runtime, e.g. typed into a debug shell.
+//
+// CSR instructions encode the CSR number as an immediate, thus each known CSR has its own
+// `csrr` or `csrw` in a match arm. Only machine and supervisor CSRs present on U74 are listed;
+// accessing an absent CSR would raise illegal instruction in firmware itself.
+macro_rules! csr_dispatch {
+    (read_only: [$($ro: literal),* $(,)?], read_write: [$($rw: literal),* $(,)?] $(,)?) => {
+        pub fn read_csr_dynamic(csr_num: usize) -> Result<usize, &'static str> {
+            let value: usize;
+            match csr_num {
+                $($ro => unsafe { core::arch::asm!(concat!("csrr {}, ", stringify!($ro)), out(reg) value) },)*
+                $($rw => unsafe { core::arch::asm!(concat!("csrr {}, ", stringify!($rw)), out(reg) value) },)*
+                _ => return Err("unknown csr"),
+            }
+            Ok(value)
+        }
+
+        pub fn write_csr_dynamic(csr_num: usize, value: usize) -> Result<(), &'static str> {
+            match csr_num {
+                $($ro)|* => Err("read-only csr"),
+                $($rw => {
+                    unsafe { core::arch::asm!(concat!("csrw ", stringify!($rw), ", {}"), in(reg) value) };
+                    Ok(())
+                })*
+                _ => Err("unknown csr"),
+            }
+        }
+    };
+}
+
+csr_dispatch! {
+    read_only: [
+        0xF11, 0xF12, 0xF13, 0xF14, // mvendorid, marchid, mimpid, mhartid
+    ],
+    read_write: [
+        // mstatus, misa, medeleg, mideleg, mie, mtvec, mcounteren
+        0x300, 0x301, 0x302, 0x303, 0x304, 0x305, 0x306,
+        // mhpmevent3, mhpmevent4
+        0x323, 0x324,
+        // mscratch, mepc, mcause, mtval, mip
+        0x340, 0x341, 0x342, 0x343, 0x344,
+        // pmpcfg0, pmpcfg2, pmpaddr0 to pmpaddr15
+        0x3A0, 0x3A2, 0x3B0, 0x3B1, 0x3B2, 0x3B3, 0x3B4, 0x3B5, 0x3B6, 0x3B7,
+        0x3B8, 0x3B9, 0x3BA, 0x3BB, 0x3BC, 0x3BD, 0x3BE, 0x3BF,
+        // mcycle, minstret, mhpmcounter3, mhpmcounter4
+        0xB00, 0xB02, 0xB03, 0xB04,
+        // sstatus, sie, stvec, scounteren
+        0x100, 0x104, 0x105, 0x106,
+        // sscratch, sepc, scause, stval, sip
+        0x140, 0x141, 0x142, 0x143, 0x144,
+        // satp
+        0x180,
+    ],
+}
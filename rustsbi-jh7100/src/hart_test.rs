@@ -0,0 +1,85 @@
+//! Per-hart CLINT self-test at boot
+//!
+//! Each hart checks the parts of CLINT that firmware relies on for it, before it is counted as
+//! usable:
+//!
+//! - mtime advances;
+//! - its `msip` raises and clears `mip.MSIP`, the IPI and HSM wake-up path;
+//! - its `mtimecmp` reads back what was written, and a deadline in the far future leaves
+//!   `mip.MTIP` clear.
+//!
+//! Boot hart runs it once console is up; a failure there is fatal. A secondary runs it when it
+//! checks in at the boot barrier. A failing secondary stops the boot unless feature
+//! `degraded-boot` is enabled, in which case it is reported, left out of HSM and parked for good,
+//! and boot goes on with the healthy harts.
+use crate::peripheral::Clint;
+use riscv::register::mip;
+
+// Polls of mtime, or of mip after a CLINT write, before giving up; far beyond any CLINT latency
+const POLL_LIMIT: usize = 1_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HartTestError {
+    MtimeStopped = 1,
+    MsipNotRaised = 2,
+    MsipNotCleared = 3,
+    MtimecmpReadBack = 4,
+    MtipSpurious = 5,
+}
+
+impl HartTestError {
+    pub fn from_code(code: u8) -> Option<Self> {
+        use HartTestError::*;
+        [
+            MtimeStopped,
+            MsipNotRaised,
+            MsipNotCleared,
+            MtimecmpReadBack,
+            MtipSpurious,
+        ]
+        .into_iter()
+        .find(|error| *error as u8 == code)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HartTestError::MtimeStopped => "mtime does not advance",
+            HartTestError::MsipNotRaised => "msip does not raise mip.MSIP",
+            HartTestError::MsipNotCleared => "mip.MSIP stays set after msip is cleared",
+            HartTestError::MtimecmpReadBack => "mtimecmp does not read back",
+            HartTestError::MtipSpurious => "mip.MTIP set with no deadline",
+        }
+    }
+}
+
+// Test CLINT of current hart; machine interrupts must be disabled. Leaves `msip` clear and
+// `mtimecmp` at u64::MAX, the state of no firmware timer deadline
+pub fn run(hart_id: usize) -> Result<(), HartTestError> {
+    let clint = Clint::new(0x2000000 as *mut u8);
+    let start = clint.get_mtime();
+    if !poll(|| clint.get_mtime() != start) {
+        return Err(HartTestError::MtimeStopped);
+    }
+    clint.send_soft(hart_id);
+    let raised = poll(|| mip::read().msoft());
+    clint.clear_soft(hart_id);
+    if !raised {
+        return Err(HartTestError::MsipNotRaised);
+    }
+    if !poll(|| !mip::read().msoft()) {
+        return Err(HartTestError::MsipNotCleared);
+    }
+    clint.set_timer(hart_id, u64::MAX);
+    if clint.get_timer(hart_id) != u64::MAX {
+        return Err(HartTestError::MtimecmpReadBack);
+    }
+    if !poll(|| !mip::read().mtimer()) {
+        return Err(HartTestError::MtipSpurious);
+    }
+    Ok(())
+}
+
+fn poll(condition: impl Fn() -> bool) -> bool {
+    (0..POLL_LIMIT).any(|_| condition())
+}
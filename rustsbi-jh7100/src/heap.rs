@@ -0,0 +1,78 @@
+//! Firmware heap
+//!
+//! The heap is `[sheap, eheap)` of the linker script, `heap_size` bytes, placed outside the
+//! range boot hart zeroes as .bss; it holds whatever DRAM held at reset or from an earlier boot.
+//! By default allocations are not zeroed: memory from `alloc` has undefined contents, e.g.
+//! free-list links of the buddy allocator or data of an earlier allocation, and code which needs
+//! zeroed memory must ask for it, with `alloc_zeroed` or by initializing it, e.g. `vec![0; n]`.
+//!
+//! With feature `zero-heap`, `ZeroingHeap` zeroes every allocation as it is handed out, only the
+//! bytes allocated; the rest of the heap is left as it is, and nothing is zeroed on free. Boot
+//! hart checks this once console is up, see `check_zeroed`, and panics if an allocation comes
+//! back with data in it.
+#[cfg(feature = "zero-heap")]
+use buddy_system_allocator::LockedHeap;
+#[cfg(feature = "zero-heap")]
+use core::alloc::{GlobalAlloc, Layout};
+
+// Buddy allocator which zeroes each allocation, for feature `zero-heap`
+#[cfg(feature = "zero-heap")]
+pub struct ZeroingHeap(pub LockedHeap<32>);
+
+#[cfg(feature = "zero-heap")]
+unsafe impl GlobalAlloc for ZeroingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc(layout);
+        if !ptr.is_null() {
+            ptr.write_bytes(0, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout)
+    }
+}
+
+// `init_heap` locks the buddy allocator inside
+#[cfg(feature = "zero-heap")]
+impl core::ops::Deref for ZeroingHeap {
+    type Target = LockedHeap<32>;
+
+    fn deref(&self) -> &LockedHeap<32> {
+        &self.0
+    }
+}
+
+// Bytes of the block `check_zeroed` dirties and allocates again
+#[cfg(feature = "zero-heap")]
+const CHECK_LEN: usize = 256;
+
+// Fill a block with a pattern and free it, then allocate the same size, which the buddy
+// allocator hands back from the same free list, and check that it reads as zero
+#[cfg(feature = "zero-heap")]
+pub fn check_zeroed() {
+    let layout = Layout::from_size_align(CHECK_LEN, 8).unwrap();
+    unsafe {
+        let dirty = alloc::alloc::alloc(layout);
+        assert!(!dirty.is_null(), "zero-heap check: heap exhausted");
+        for offset in 0..CHECK_LEN {
+            dirty.add(offset).write_volatile(0xA5);
+        }
+        alloc::alloc::dealloc(dirty, layout);
+        let again = alloc::alloc::alloc(layout);
+        assert!(!again.is_null(), "zero-heap check: heap exhausted");
+        let nonzero = (0..CHECK_LEN).find(|offset| again.add(*offset).read_volatile() != 0);
+        if let Some(offset) = nonzero {
+            panic!(
+                "zero-heap check: allocation at {:#x} not zeroed at offset {}",
+                again as usize, offset
+            )
+        }
+        alloc::alloc::dealloc(again, layout);
+    }
+}
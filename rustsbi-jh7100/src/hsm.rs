@@ -42,14 +42,19 @@ enum HsmState {
 // XxxPending before the actual procedure began. Then, caller should store next command structure
 // to `last_command`, and use IPI to invoke software interrupt on machine level.
 //
-// When target hart received machine software interrupt, it should read and proceed command
+// When target hart received machine software interrupt, it should take and proceed command
 // from `last_command`. Then, after command execution makes progress, it should modify
-// `state` variable to mark that the HSM function has taken effect.
+// `state` variable to mark that the HSM function has taken effect. A command is taken out of
+// `last_command` when handled, so that a later software interrupt with no command is known to
+// be an IPI for supervisor rather than a repeated HSM request.
 //
 // These functions above are defined as asynchronous procedures. That means it returns before
 // actual procedure has finished. There are functions to read its current state when the target hart
-// is still in transition or after the transition is done. These functions may read from `last_command`
-// variable at any time.
+// is still in transition or after the transition is done.
+//
+// Both harts call these functions concurrently. A caller holding both locks takes `state` first,
+// then `last_command`; see the lock order in `main.rs`. Neither lock is held while waiting for
+// another hart, nor while printing.
 #[derive(Clone)]
 pub struct U74Hsm {
     state: Arc<spin::Mutex<HashMap<usize, AtomicU8>>>,
@@ -78,14 +83,11 @@ impl U74Hsm {
             last_command: Arc::new(spin::Mutex::new(HashMap::new())),
         }
     }
-    // Return last command by current hart id.
+    // Take last command by current hart id, leaving none; each command is handled once.
     // This function is used in software interrupt handler to check which HSM function should we execute.
-    pub(crate) fn last_command(&self) -> Option<HsmCommand> {
+    pub(crate) fn take_command(&self) -> Option<HsmCommand> {
         let hart_id = riscv::register::mhartid::read();
-        let last_command_lock = self.last_command.lock();
-        let ans = last_command_lock.get(&hart_id).map(|c| *c);
-        drop(last_command_lock);
-        ans
+        self.last_command.lock().remove(&hart_id)
     }
     // Record that current hart id is marked as `Stopped` state.
     // It is used in interrupt handler, when hart stop command is received. Before this function,
@@ -109,6 +111,33 @@ impl U74Hsm {
             .entry(hart_id)
             .insert(AtomicU8::new(HsmState::Started as u8));
     }
+    // Bitmask of harts in `Started` state, bit i for hart i.
+    // All states are read under one lock, thus the mask is a snapshot that agrees with
+    // `hart_get_status` of every hart at that instant.
+    pub(crate) fn started_hart_mask(&self) -> usize {
+        let state_lock = self.state.lock();
+        (0..crate::NUM_HARTS)
+            .filter(|hart_id| match state_lock.get(hart_id) {
+                Some(state) => state.load(Ordering::Relaxed) == HsmState::Started as u8,
+                // parked since boot, `Stopped`
+                None => false,
+            })
+            .fold(0, |mask, hart_id| mask | (1 << hart_id))
+    }
+}
+
+const FUNCTION_GET_STARTED_HARTS: usize = 0x0;
+
+// Handler of vendor extension `EXTENSION_HART_MASK`.
+//
+// Function `FUNCTION_GET_STARTED_HARTS` returns a bitmask of harts in HSM `Started` state in a1,
+// bit i for hart i, so that supervisor enumerates online harts without one `hart_get_status`
+// per hart; a0 is `SBI_SUCCESS`. Harts in a pending or suspended state are not set.
+pub fn handle_ecall(function: usize, _param: [usize; 6]) -> SbiRet {
+    match function {
+        FUNCTION_GET_STARTED_HARTS => SbiRet::ok(crate::HSM.started_hart_mask()),
+        _ => SbiRet::not_supported(),
+    }
 }
 
 // Adapt RustSBI interface to RustSBI-JH7100's U74Hsm.
@@ -121,6 +150,10 @@ impl rustsbi::Hsm for U74Hsm {
         if mpp != MPP::Supervisor && mpp != MPP::User {
             return SbiRet::invalid_param();
         }
+        // a hart which did not check in at boot is not parked in firmware, it cannot be started
+        if !crate::boot_barrier::is_present(hart_id) {
+            return SbiRet::invalid_param();
+        }
         // try to modify state to start hart
         let mut state_lock = self.state.lock();
         let current_state = state_lock
@@ -187,10 +220,13 @@ impl rustsbi::Hsm for U74Hsm {
         SbiRet::ok(0)
     }
     fn hart_get_status(&self, hart_id: usize) -> SbiRet {
-        self.state.lock().get(&hart_id).map_or(
-            SbiRet::invalid_param(), // not in `state` map structure, the given hart id is invalid
-            |a| SbiRet::ok(a.load(Ordering::Relaxed) as usize),
-        )
+        match self.state.lock().get(&hart_id) {
+            Some(a) => SbiRet::ok(a.load(Ordering::Relaxed) as usize),
+            // not in `state` map structure, the hart is still parked since boot
+            None if hart_id < crate::NUM_HARTS => SbiRet::ok(HsmState::Stopped as usize),
+            // the given hart id is invalid
+            None => SbiRet::invalid_param(),
+        }
     }
     // Supervisor requested current hart to suspend.
     //
@@ -204,7 +240,7 @@ impl rustsbi::Hsm for U74Hsm {
             // will see SBI suspend call return without any failures.
             SUSPEND_RETENTIVE => {
                 // try to set current target hart state to stop pending
-                let hart_id = riscv::register::mhartid::read();
+                let hart_id = crate::execute::calling_hart();
                 let mut state_lock = self.state.lock();
                 let current_state = state_lock
                     .entry(hart_id)
@@ -234,7 +270,7 @@ impl rustsbi::Hsm for U74Hsm {
             // to restore various hart registers and CSRs for all privilege modes.
             SUSPEND_NON_RETENTIVE => {
                 // try to set current target hart state to stop pending
-                let hart_id = riscv::register::mhartid::read();
+                let hart_id = crate::execute::calling_hart();
                 let mut state_lock = self.state.lock();
                 let current_state = state_lock
                     .entry(hart_id)
@@ -311,6 +347,39 @@ pub fn suspend_current_hart(hsm: &U74Hsm) {
     clint.clear_soft(hart_id); // Clear IPI
 }
 
+// Park current hart, recorded as stopped, until `hart_start` leaves a start command for it;
+// returns start address and opaque. The command is checked each time after the IPI is cleared,
+// thus a start requested at any time after the stop was recorded is never missed, and IPIs sent
+// to a stopped hart for anything else keep it parked. A system shutdown halts it from here.
+pub fn wait_for_start(hsm: &U74Hsm, hart_id: usize) -> (usize, usize) {
+    use crate::peripheral::Clint;
+    use riscv::asm::wfi;
+    use riscv::register::{mie, mip};
+    let clint = Clint::new(0x2000000 as *mut u8);
+    let prev_msoft = mie::read().msoft();
+    unsafe { mie::set_msoft() }; // Start listening for software interrupts
+    let start = loop {
+        clint.clear_soft(hart_id); // Clear IPI
+        unsafe { mip::clear_msoft() }; // clear machine software interrupt flag
+        if crate::reset::shutdown_requested() {
+            crate::reset::ack_and_halt(hart_id)
+        }
+        // an IPI for supervisor sent while the hart is stopped is dropped, not delivered on start
+        #[cfg(feature = "ipi-doorbell")]
+        crate::peripheral::take_doorbell(hart_id);
+        if let Some(HsmCommand::Start(start_addr, opaque)) = hsm.take_command() {
+            break (start_addr, opaque);
+        }
+        while !mip::read().msoft() {
+            unsafe { wfi() };
+        }
+    };
+    if !prev_msoft {
+        unsafe { mie::clear_msoft() }; // Stop listening for software interrupts
+    }
+    start
+}
+
 // Pause current hart, wake through inter-processor interrupt
 pub fn pause() {
     use crate::peripheral::Clint;
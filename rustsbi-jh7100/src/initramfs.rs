@@ -0,0 +1,86 @@
+//! Initial ramdisk passed to a Linux payload
+//!
+//! With feature `initramfs`, the SD card image carries an initramfs besides firmware and its
+//! payload. `cargo xtask image --initramfs <file>` writes it at `IMAGE_OFFSET` of the image,
+//! behind a 16-byte header: `IMAGE_MAGIC`, then its length as a little endian u64. The image is
+//! loaded to DRAM as a whole, thus firmware finds the initramfs at `stext + IMAGE_OFFSET`.
+//!
+//! Boot hart moves it to the top of supervisor DRAM, right below firmware scratch memory and
+//! aligned down to a page, and advertises it as `linux,initrd-start` and `linux,initrd-end` of
+//! `/chosen` in the device tree passed to supervisor. The kernel at `payload::LOAD_ADDRESS` grows
+//! upwards and the initramfs sits as high as it can, so both keep apart as long as DRAM holds
+//! them. If it does not, or the initramfs was overwritten where it was loaded, boot stops: a
+//! kernel started without its initramfs fails later, with far less to go on. An image without an
+//! initramfs boots its payload alone.
+use crate::println;
+use crate::scratch;
+
+// both move data of the loaded image that this module expects at `stext + IMAGE_OFFSET`
+#[cfg(feature = "dram-test")]
+compile_error!("feature `initramfs` cannot be used with `dram-test`, which overwrites it");
+#[cfg(feature = "self-relocate")]
+compile_error!("feature `initramfs` cannot be used with `self-relocate`, which leaves it behind");
+
+// Where the initramfs header is in the SD card image, and in DRAM from `stext`; far enough that
+// the payload copied to `payload::LOAD_ADDRESS` does not reach it
+pub const IMAGE_OFFSET: usize = 0x400_0000;
+pub const IMAGE_MAGIC: &[u8; 8] = b"RSBIINRD";
+const HEADER_SIZE: usize = 16;
+
+const PAGE_SIZE: usize = 4096;
+
+extern "C" {
+    static stext: u8;
+}
+
+// Move initramfs of the image below scratch memory and add it to the device tree for supervisor;
+// called once on boot hart after `scratch::init`, `kernel` is where the payload was copied to
+pub fn load(kernel: core::ops::Range<usize>) {
+    let header = unsafe { &stext as *const u8 as usize } + IMAGE_OFFSET;
+    let (source, len) = unsafe {
+        let magic = core::slice::from_raw_parts(header as *const u8, IMAGE_MAGIC.len());
+        if magic != IMAGE_MAGIC {
+            println!(
+                "[rustsbi] warning: no initramfs at image offset {:#x}, boot payload alone",
+                IMAGE_OFFSET
+            );
+            return;
+        }
+        let len = core::ptr::read_unaligned((header + 8) as *const u64) as usize;
+        (header + HEADER_SIZE, len)
+    };
+    let end = match scratch::base() {
+        Some(base) => base,
+        None => panic!("no firmware scratch memory, cannot pass initramfs to supervisor"),
+    };
+    let source_end = match source.checked_add(len) {
+        Some(source_end) if len != 0 && source_end <= end => source_end,
+        _ => panic!("initramfs length {:#x} in image header is invalid", len),
+    };
+    // the payload has been copied and the device tree placed already, over anything here
+    if source < kernel.end && kernel.start < source_end {
+        panic!(
+            "initramfs loaded at {:#x}..{:#x} was overwritten by payload at {:#x}..{:#x}",
+            source, source_end, kernel.start, kernel.end
+        )
+    }
+    let start = (end - len) & !(PAGE_SIZE - 1);
+    if start < kernel.end {
+        panic!(
+            "initramfs of {:#x} bytes does not fit in DRAM between payload end {:#x} and {:#x}",
+            len, kernel.end, end
+        )
+    }
+    let dtb = unsafe { scratch::slot(scratch::SLOT_DEVICE_TREE) }.unwrap();
+    if let Err(e) = crate::device_tree::add_chosen_initrd(dtb, start, start + len) {
+        panic!("cannot add initramfs to device tree, {}", e)
+    }
+    // source and destination may overlap
+    unsafe { core::ptr::copy(source as *const u8, start as *mut u8, len) };
+    println!(
+        "[rustsbi] initramfs: {:#x} bytes at {:#x}..{:#x}",
+        len,
+        start,
+        start + len
+    );
+}
@@ -0,0 +1,207 @@
+//! Boot-time check of supervisor software interrupt delegation
+//!
+//! Once delegation and the supervisor runtime are set up, boot hart runs a small supervisor stub
+//! from firmware text, the way the payload will run, and checks that an IPI it sends itself ends
+//! in its own trap handler:
+//!
+//! 1. the stub points `stvec` at its handler, enables `sie.SSIE` and `sstatus.SIE`, and sends an
+//!    IPI to its own hart with SBI `send_ipi`, served by RustSBI like any supervisor call;
+//! 2. CLINT raises a machine software interrupt, which firmware passes on with
+//!    `execute::relay_soft_interrupt`, as `sip.SSIP` for a hart in `mideleg` with feature
+//!    `ipi-doorbell`, or as a trap transferred to `stvec` without;
+//! 3. the handler reports `scause` to firmware with an ecall of the reserved vendor extension,
+//!    which the check expects to be a supervisor software interrupt.
+//!
+//! If the handler does not run within `SPIN_LOOPS` iterations of the stub, or any other trap
+//! reaches firmware, the check fails. The result is printed; a failure does not stop the boot.
+//! Supervisor CSRs, `mstatus`, the pending bits and the CLINT soft interrupt are restored before
+//! the payload is entered.
+use crate::board;
+use crate::execute::relay_soft_interrupt;
+use crate::println;
+use crate::runtime::{MachineTrap, Runtime};
+use crate::vendor::EXTENSION_VENDOR_START;
+use core::{
+    ops::{Generator, GeneratorState},
+    pin::Pin,
+};
+use riscv::register::mip;
+
+// Ecalls of the stub to firmware, in a6 with a7 `EXTENSION_VENDOR_START`
+const STUB_HANDLED: usize = 0;
+const STUB_TIMEOUT: usize = 1;
+
+const EXTENSION_IPI: usize = 0x73_5049;
+const SPIN_LOOPS: usize = 100_000;
+
+const INTERRUPT_BIT: usize = 1 << (usize::BITS - 1);
+const SUPERVISOR_SOFT: usize = 1;
+
+// Run the check on boot hart, after `runtime::init` and before supervisor has run
+pub fn run(hart_id: usize) {
+    let stub = supervisor_stub as usize;
+    let executable = board::PMP_REGIONS.iter().any(|region| {
+        region.permission & board::PMP_RWX == board::PMP_RWX
+            && (region.base..region.base + region.size).contains(&stub)
+    });
+    if !executable {
+        println!("[rustsbi] interrupt delegation check skipped, firmware text not executable by supervisor");
+        return;
+    }
+    let saved = Saved::save();
+    let result = run_stub(hart_id, stub);
+    saved.restore(hart_id);
+    match result {
+        Ok(()) => println!(
+            "[rustsbi] interrupt delegation check passed, supervisor software interrupt reached stvec"
+        ),
+        Err(e) => println!("[rustsbi] interrupt delegation check FAILED, {}", e),
+    }
+}
+
+fn run_stub(hart_id: usize, stub: usize) -> Result<(), &'static str> {
+    let mut rt = Runtime::new_sbi_supervisor(stub, 1 << hart_id, 0);
+    loop {
+        match Pin::new(&mut rt).resume(()) {
+            GeneratorState::Yielded(MachineTrap::SbiCall()) => {
+                let ctx = rt.context_mut();
+                match (ctx.a7, ctx.a6) {
+                    (EXTENSION_IPI, function) => {
+                        let param = [ctx.a0, ctx.a1, ctx.a2, ctx.a3, ctx.a4, ctx.a5];
+                        let ans = rustsbi::ecall(EXTENSION_IPI, function, param);
+                        if ans.error != 0 {
+                            return Err("send_ipi to itself failed");
+                        }
+                        ctx.mepc = ctx.mepc.wrapping_add(4);
+                    }
+                    (EXTENSION_VENDOR_START, STUB_HANDLED) => {
+                        return if ctx.a0 == INTERRUPT_BIT | SUPERVISOR_SOFT {
+                            Ok(())
+                        } else {
+                            Err("supervisor handler entered with another scause")
+                        };
+                    }
+                    (EXTENSION_VENDOR_START, STUB_TIMEOUT) => {
+                        return Err("supervisor handler did not run");
+                    }
+                    _ => return Err("unexpected SBI call of supervisor stub"),
+                }
+            }
+            GeneratorState::Yielded(MachineTrap::MachineSoft()) => {
+                relay_soft_interrupt(hart_id, rt.context_mut())
+            }
+            GeneratorState::Yielded(_) => return Err("unexpected trap to firmware"),
+            GeneratorState::Complete(()) => return Err("supervisor stub returned"),
+        }
+    }
+}
+
+// State the stub and the relay change, put back before the payload runs
+struct Saved {
+    mstatus: usize,
+    sstatus: usize,
+    sie: usize,
+    stvec: usize,
+    sepc: usize,
+    scause: usize,
+    stval: usize,
+}
+
+impl Saved {
+    fn save() -> Self {
+        let (mstatus, sstatus, sie, stvec, sepc, scause, stval);
+        unsafe {
+            core::arch::asm!(
+                "csrr {}, mstatus",
+                "csrr {}, sstatus",
+                "csrr {}, sie",
+                "csrr {}, stvec",
+                "csrr {}, sepc",
+                "csrr {}, scause",
+                "csrr {}, stval",
+                out(reg) mstatus,
+                out(reg) sstatus,
+                out(reg) sie,
+                out(reg) stvec,
+                out(reg) sepc,
+                out(reg) scause,
+                out(reg) stval,
+            )
+        };
+        Self {
+            mstatus,
+            sstatus,
+            sie,
+            stvec,
+            sepc,
+            scause,
+            stval,
+        }
+    }
+
+    fn restore(&self, hart_id: usize) {
+        let clint = crate::peripheral::Clint::new(0x2000000 as *mut u8);
+        clint.clear_soft(hart_id);
+        #[cfg(feature = "ipi-doorbell")]
+        crate::peripheral::take_doorbell(hart_id);
+        unsafe {
+            mip::clear_ssoft();
+            core::arch::asm!(
+                "csrw mstatus, {}",
+                "csrw sstatus, {}",
+                "csrw sie, {}",
+                "csrw stvec, {}",
+                "csrw sepc, {}",
+                "csrw scause, {}",
+                "csrw stval, {}",
+                in(reg) self.mstatus,
+                in(reg) self.sstatus,
+                in(reg) self.sie,
+                in(reg) self.stvec,
+                in(reg) self.sepc,
+                in(reg) self.scause,
+                in(reg) self.stval,
+            )
+        };
+    }
+}
+
+// Supervisor side of the check, entered with a0 the hart mask of the calling hart. Uses no
+// stack, and ends in an ecall firmware does not return from.
+#[naked]
+#[link_section = ".text"]
+unsafe extern "C" fn supervisor_stub() -> ! {
+    core::arch::asm!(
+        "la     t0, 2f",
+        "csrw   stvec, t0",
+        "li     t0, 2", // sie.SSIE
+        "csrs   sie, t0",
+        "csrsi  sstatus, 2", // sstatus.SIE
+        // send_ipi(hart_mask a0, hart_mask_base 0)
+        "li     a1, 0",
+        "li     a6, 0",
+        "li     a7, {ipi}",
+        "ecall",
+        "li     t1, {spin_loops}",
+        "1: addi t1, t1, -1",
+        "bnez   t1, 1b",
+        "li     a6, {timeout}",
+        "li     a7, {vendor}",
+        "ecall",
+        "3: j   3b",
+        ".p2align 2",
+        "2: csrr a0, scause",
+        "li     t0, 2", // sip.SSIP
+        "csrc   sip, t0",
+        "li     a6, {handled}",
+        "li     a7, {vendor}",
+        "ecall",
+        "j      3b",
+        ipi = const EXTENSION_IPI,
+        spin_loops = const SPIN_LOOPS,
+        timeout = const STUB_TIMEOUT,
+        handled = const STUB_HANDLED,
+        vendor = const EXTENSION_VENDOR_START,
+        options(noreturn)
+    )
+}
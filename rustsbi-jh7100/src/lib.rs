@@ -0,0 +1,30 @@
+//! Host unit tests of firmware modules
+//!
+//! Firmware itself is the binary of `main.rs`. This library only exists for `cargo test`: it
+//! builds the modules which need neither the SoC nor machine mode for the development machine,
+//! where their tests run. As this package builds for the firmware target by default, give the
+//! host target explicitly:
+//!
+//! ```shell
+//! cargo test -p rustsbi-jh7100 --lib --target x86_64-unknown-linux-gnu
+//! ```
+//!
+//! Without `test` it is empty, and the firmware build does not change.
+#![no_std]
+#![allow(dead_code)]
+
+#[cfg(test)]
+extern crate alloc;
+#[cfg(test)]
+extern crate std;
+
+#[cfg(test)]
+mod decode;
+#[cfg(test)]
+mod device_tree;
+#[cfg(test)]
+mod peripheral {
+    mod mmio;
+    mod split;
+    mod uart;
+}
@@ -8,58 +8,235 @@
 
 extern crate alloc;
 
+mod board;
+mod boot_barrier;
+mod boot_hart;
+mod boot_reason;
+mod build_info;
+mod cache;
+#[cfg(feature = "checkpoint")]
+mod checkpoint;
+mod console;
+#[cfg(feature = "diagnostics")]
+mod debug_halt;
+mod decode;
+mod delegation;
 mod device_tree;
+mod downstream;
+#[cfg(feature = "dram-test")]
+mod dram_test;
 mod early_trap;
+mod earlycon;
+#[cfg(feature = "ecall-latency")]
+mod ecall_latency;
 mod execute;
 mod feature;
+#[cfg(feature = "firmware-tables")]
+mod firmware_tables;
+#[cfg(feature = "sbi-forward")]
+mod forward;
+mod fwft;
+#[cfg(feature = "handoff-info")]
+mod handoff;
 mod hart_csr_utils;
+#[cfg(feature = "hart-self-test")]
+mod hart_test;
+mod heap;
 mod hsm;
+#[cfg(feature = "initramfs")]
+mod initramfs;
+#[cfg(feature = "irq-delegation-check")]
+mod irq_check;
+#[cfg(feature = "maintenance-mode")]
+mod maintenance;
+#[cfg(feature = "diagnostics")]
+mod memory_dump;
+#[cfg(feature = "console-fallback")]
+mod memory_log;
+#[cfg(feature = "diagnostics")]
+mod memory_map;
+#[cfg(feature = "diagnostics")]
+mod page_table_dump;
+mod panic_action;
+mod payload;
+#[cfg(feature = "payload-slots")]
+mod payload_slot;
 mod peripheral;
+#[cfg(feature = "pmp-check")]
+mod pmp_check;
+mod reset;
 mod runtime;
+mod scratch;
+mod smp;
+#[cfg(feature = "stack-guard")]
+mod stack_guard;
+mod tick;
+mod timer;
+#[cfg(feature = "sbi-trace")]
+mod trace;
+mod trap_stats;
+mod vendor;
+#[cfg(feature = "hang-watchdog")]
+mod watchdog;
+#[cfg(feature = "diagnostics")]
+mod watchpoint;
 
 use buddy_system_allocator::LockedHeap;
 use core::panic::PanicInfo;
-use rustsbi::println;
 
+// Lock order. Harts handle their traps concurrently, and state shared between them is either
+// atomic or behind a spin lock. A hart holding a lock may only take locks further down this list,
+// thus no two harts ever wait on each other:
+//
+// 1. `vendor::VENDOR_EXTENSIONS` and `tick::TICK_TASKS`, read while a handler or task runs;
+//    written at boot only
+// 2. `HSM` hart states, then `HSM` commands
+// 3. `feature::emulate_mmio_amo::MMIO_ATOMIC`
+// 4. RustSBI legacy stdio, then `console::CONSOLE`; taken by every `println!`
+// 5. `HEAP_ALLOCATOR`
+//
+// RustSBI itself holds no lock while it calls an extension. Trace, trap statistics, timer
+// deadlines, boot barrier, reset and per-hart flags are atomics. No lock is held while waiting
+// for another hart, e.g. in `hsm::wait_for_start` or `smp::broadcast_and_wait`. Machine mode
+// traps do not nest, so a hart never re-enters a lock it holds; a panic report takes no lock
+// above the console, only the panic action `Reboot` reads `HSM` as every system reset does.
+
+// allocations are not zeroed unless built with feature `zero-heap`, see `heap`
+#[cfg(not(feature = "zero-heap"))]
 #[global_allocator]
 static HEAP_ALLOCATOR: LockedHeap<32> = LockedHeap::<32>::empty();
+#[cfg(feature = "zero-heap")]
+#[global_allocator]
+static HEAP_ALLOCATOR: heap::ZeroingHeap = heap::ZeroingHeap(LockedHeap::<32>::empty());
 
 lazy_static::lazy_static! {
     pub static ref HSM: hsm::U74Hsm = hsm::U74Hsm::new();
 }
 
-const SBI_HEAP_SIZE: usize = 64 * 1024; // 64KiB
-#[link_section = ".bss.uninit"]
-static mut HEAP_SPACE: [u8; SBI_HEAP_SIZE] = [0; SBI_HEAP_SIZE];
+const NUM_HARTS: usize = 2; // two U74 cores on JH7100, checked against device tree at boot
+
+// Heap and per-hart stacks are placed by the linker script, which also sets their sizes
+extern "C" {
+    static sstack: u8;
+    static estack: u8;
+    static sheap: u8;
+    static eheap: u8;
+}
 
-const PER_HART_STACK_SIZE: usize = 4 * 4096; // 16KiB
-const SBI_STACK_SIZE: usize = 2 * PER_HART_STACK_SIZE; // 2 harts
-#[link_section = ".bss.uninit"]
-static mut SBI_STACK: [u8; SBI_STACK_SIZE] = [0; SBI_STACK_SIZE];
+// `hart_stack_stride` of the linker script, a hart's stack with its guard region if any; an
+// absolute symbol, not an address firmware may load
+fn hart_stack_stride() -> usize {
+    let stride: usize;
+    unsafe {
+        core::arch::asm!(
+            "lui {stride}, %hi(hart_stack_stride)",
+            "addi {stride}, {stride}, %lo(hart_stack_stride)",
+            stride = out(reg) stride,
+            options(pure, nomem, nostack),
+        )
+    };
+    stride
+}
 
+// Stack top of given hart
+fn hart_stack_top(hart_id: usize) -> usize {
+    unsafe { &sstack as *const u8 as usize + (hart_id + 1) * hart_stack_stride() }
+}
+
+// How long a panicking hart waits for the panic report of another hart
+const PANIC_REPORT_TIMEOUT_US: u64 = 200_000;
+
+// The first panicking hart prints its full report; a hart panicking at the same time prints
+// its report after that one is flushed. If it cannot start within `PANIC_REPORT_TIMEOUT_US`,
+// it only leaves a single `[panic hart N]` line, which is never interleaved with other output.
+// Then the hart takes the panic action of `panic_action`.
 #[panic_handler]
 fn on_panic(info: &PanicInfo) -> ! {
     let hart_id = riscv::register::mhartid::read();
-    println!("[rustsbi-panic] hart {} {}", hart_id, info); // [rustsbi-panic] hart 0 panicked at xxx
-    loop {}
+    let timeout = peripheral::Clint::new(0x2000000 as *mut u8).us_to_ticks(PANIC_REPORT_TIMEOUT_US);
+    if !panic_action::enter(hart_id) {
+        // the first panic may have left the console locked, or its report half printed
+        console::write_line_exclusive(
+            format_args!("[panic hart {}] panicked again in panic handler", hart_id),
+            timeout,
+        );
+        reset::halt()
+    }
+    let reported = console::begin_panic_report(timeout);
+    if reported {
+        // [rustsbi-panic] hart 0 panicked at xxx
+        console::print_line(
+            format_args!("[rustsbi-panic] hart {} {}", hart_id, info),
+            console::MAX_PANIC_LINE_LEN,
+        );
+        #[cfg(feature = "sbi-trace")]
+        trace::dump(trace::PANIC_DUMP_ENTRIES);
+        hart_csr_utils::print_trap_stats();
+        console::flush();
+        console::end_panic_report();
+    } else {
+        console::write_line_exclusive(format_args!("[panic hart {}]", hart_id), timeout);
+    }
+    panic_action::run(hart_id, reported)
 }
 
+// DRAM range opened to supervisor, see DRAM regions in `board::PMP_REGIONS`
+const DRAM_PMP_START: usize = 0x8000_0000;
+const DRAM_PMP_END: usize = 0x2_8000_0000;
+
 static DEVICE_TREE: &'static [u8] = include_bytes!("jh7100-starfive-visionfive-v1.dtb");
 static KERNEL: &'static [u8] = include_bytes!("u-boot.bin");
 
 extern "C" fn rust_main(hart_id: usize) {
+    // a hart without SBI stack never gets here, `entry` parks it
+    // the first hart with supervisor mode to arrive initializes firmware, see `boot_hart`
+    let boot = boot_hart::claim(hart_id);
+    if boot {
+        // delays before device tree is parsed run on a busy loop, measured here
+        peripheral::Clint::new(0x2000000 as *mut u8).calibrate_delay();
+        if board::ENTRY_DELAY_US != 0 {
+            // let slow peripherals settle after reset, before the first UART access
+            peripheral::Clint::new(0x2000000 as *mut u8).delay_us(board::ENTRY_DELAY_US);
+        }
+        // console pins are shared GPIOs, they must be routed to the UART before it is used
+        board::early_uart_pinmux();
+    }
     let opaque = DEVICE_TREE.as_ptr() as usize;
     let uart = unsafe { peripheral::Uart::preloaded_uart0() };
     let clint = peripheral::Clint::new(0x2000000 as *mut u8);
 
+    // parent firmware's trap vector is still in place, record it before installing ours
+    #[cfg(feature = "sbi-forward")]
+    forward::save_parent(hart_id);
     early_trap::init(hart_id);
 
-    if hart_id == 0 {
+    if boot {
         init_bss();
         init_heap(); // 必须先加载堆内存，才能使用rustsbi框架
         init_rustsbi_stdio(uart);
+        #[cfg(feature = "zero-heap")]
+        heap::check_zeroed();
+        // boot hart runs the payload and serves the console, it cannot be left out
+        #[cfg(feature = "hart-self-test")]
+        if let Err(e) = hart_test::run(hart_id) {
+            panic!("boot hart {} failed self-test, {}", hart_id, e.name())
+        }
+        #[cfg(feature = "dram-test")]
+        match device_tree::dram_range(DEVICE_TREE) {
+            Some((start, end)) => dram_test::run(
+                start,
+                end,
+                payload::LOAD_ADDRESS..payload::LOAD_ADDRESS + KERNEL.len(),
+            ),
+            None => println!("[rustsbi] warning: no memory node in device tree, DRAM test skipped"),
+        }
+        let entry = payload::check(KERNEL);
         unsafe {
-            core::ptr::copy(KERNEL.as_ptr(), 0x8020_0000 as *mut u8, KERNEL.len());
+            core::ptr::copy(
+                KERNEL.as_ptr(),
+                payload::LOAD_ADDRESS as *mut u8,
+                KERNEL.len(),
+            );
         }
         println!("[rustsbi] RustSBI version {}", rustsbi::VERSION);
         println!("{}", rustsbi::LOGO);
@@ -68,31 +245,146 @@ extern "C" fn rust_main(hart_id: usize) {
             env!("CARGO_PKG_VERSION")
         );
         init_rustsbi_clint(clint);
-        if let Err(e) = unsafe { device_tree::parse_device_tree(opaque) } {
-            println!("[rustsbi] warning: choose from device tree error, {}", e);
+        match unsafe { device_tree::parse_device_tree_at(opaque) } {
+            Ok(info) => device_tree::apply_board_info(&info),
+            Err(e) => {
+                println!("[rustsbi] warning: choose from device tree error, {}", e);
+                // timebase is not going to be known any better, leave the early delay loop
+                peripheral::set_timebase_frequency(peripheral::DEFAULT_TIMEBASE_FREQUENCY);
+            }
+        }
+        match peripheral::delay_loops_per_us() {
+            Some(loops) => println!("[rustsbi] early delay loop: {} iterations per us", loops),
+            None => println!(
+                "[rustsbi] warning: mtime not running at boot, early delays were uncalibrated"
+            ),
+        }
+        match scratch::init(DEVICE_TREE) {
+            Ok(()) => {
+                println!(
+                    "[rustsbi] device tree for supervisor placed in firmware scratch memory at {:#x}",
+                    scratch::device_tree()
+                );
+                #[cfg(feature = "memory-fixup")]
+                println!(
+                    "[rustsbi] memory node for supervisor: {:#x} bytes at {:#x}",
+                    board::DRAM_SIZE,
+                    board::DRAM_BASE
+                );
+            }
+            Err(e) => println!("[rustsbi] warning: no firmware scratch memory, {}", e),
+        }
+        if let Some(dtb) = unsafe { scratch::slot(scratch::SLOT_DEVICE_TREE) } {
+            earlycon::fixup_device_tree(dtb);
         }
+        #[cfg(feature = "firmware-tables")]
+        firmware_tables::load(payload::LOAD_ADDRESS..payload::LOAD_ADDRESS + KERNEL.len());
+        #[cfg(feature = "payload-slots")]
+        #[cfg_attr(
+            not(any(feature = "initramfs", feature = "diagnostics")),
+            allow(unused_variables)
+        )]
+        let kernel = payload_slot::load(KERNEL.len());
+        #[cfg(all(
+            any(feature = "initramfs", feature = "diagnostics"),
+            not(feature = "payload-slots")
+        ))]
+        let kernel = payload::LOAD_ADDRESS..payload::LOAD_ADDRESS + KERNEL.len();
+        #[cfg(feature = "diagnostics")]
+        memory_map::set_payload(&kernel);
+        #[cfg(feature = "initramfs")]
+        initramfs::load(kernel);
+        trap_stats::init();
+        #[cfg(feature = "ecall-latency")]
+        ecall_latency::init();
+        let (boot_reason, warm_boots) = boot_reason::detect();
+        println!(
+            "[rustsbi] boot reason: {}, {} warm boots in a row",
+            boot_reason.name(),
+            warm_boots
+        );
+        #[cfg(feature = "maintenance-mode")]
+        maintenance::check();
         println!(
-            "[rustsbi] enter supervisor 0x80200000, opaque register {:#x}",
-            opaque
+            "[rustsbi] enter supervisor {:#x}, opaque register {:#x}",
+            entry,
+            scratch::device_tree()
         );
         rustsbi::init_hsm(HSM.clone());
+        rustsbi::init_reset(reset::HaltReset);
+        vendor::register(vendor::EXTENSION_BUILD_INFO, build_info::handle_ecall);
+        vendor::register(vendor::EXTENSION_CONSOLE_CONTROL, console::handle_ecall);
+        vendor::register(vendor::EXTENSION_HART_MASK, hsm::handle_ecall);
+        vendor::register(vendor::EXTENSION_DELEGATION, delegation::handle_ecall);
+        vendor::register(vendor::EXTENSION_CACHE, cache::handle_ecall);
+        #[cfg(feature = "payload-slots")]
+        vendor::register(vendor::EXTENSION_PAYLOAD_SLOT, payload_slot::handle_ecall);
+        #[cfg(feature = "firmware-tables")]
+        vendor::register(
+            vendor::EXTENSION_FIRMWARE_TABLES,
+            firmware_tables::handle_ecall,
+        );
+        #[cfg(feature = "diagnostics")]
+        vendor::register(vendor::EXTENSION_DIAGNOSTICS, debug_halt::handle_ecall);
+        #[cfg(feature = "checkpoint")]
+        vendor::register(vendor::EXTENSION_CHECKPOINT, checkpoint::handle_probe);
+        downstream::register_extensions();
+        #[cfg(feature = "handoff-info")]
+        handoff::write(entry, scratch::device_tree());
+        boot_barrier::wait_for_secondaries(hart_id);
     } else {
+        boot_barrier::check_in(hart_id);
         hsm::pause();
     }
 
-    // TODO: jump to a confuse address and print pmp panic if set pmp
     set_pmp();
+    #[cfg(feature = "pmp-check")]
+    if boot {
+        pmp_check::run();
+    }
     delegate_interrupt_exception();
+    enable_counters();
+    #[cfg(feature = "emulate-sret")]
+    trap_supervisor_return();
     runtime::init();
+    #[cfg(feature = "irq-delegation-check")]
+    if boot {
+        irq_check::run(hart_id);
+    }
 
-    if hart_id == 0 {
+    if boot {
         hart_csr_utils::print_hart_csrs();
+        hart_csr_utils::print_pmp_regions(board::PMP_REGIONS, PMP_FIRST_BOARD_ENTRY);
         // clint.send_soft(1);
+        // runtime is ready, upgrade console from polling to buffered output
+        console::switch_backend(console::Backend::Buffered);
+        tick::register(|_hart_id| console::drain());
+        // supervisor may call from here on, extensions are final
+        vendor::seal();
     }
 
-    execute::execute_supervisor(0x8020_0000, hart_id, opaque, HSM.clone());
+    // secondary harts started by HSM enter supervisor at the requested address with the requested
+    // opaque; if woken by a plain IPI instead, they enter the payload like the boot hart does
+    let (supervisor_mepc, supervisor_opaque) = match HSM.take_command() {
+        Some(hsm::HsmCommand::Start(start_paddr, start_opaque)) if !boot => {
+            (start_paddr, start_opaque)
+        }
+        _ => (payload::LOAD_ADDRESS, scratch::device_tree()),
+    };
+    board::pre_boot_quiesce(hart_id);
+    execute::execute_supervisor(supervisor_mepc, hart_id, supervisor_opaque, HSM.clone());
 }
 
+const PMPCFG0: usize = 0x3A0;
+const PMPCFG2: usize = 0x3A2;
+const PMPADDR0: usize = 0x3B0;
+const PMP_A_NAPOT: u8 = 0b11 << 3;
+// PMP entry of the first board region, after the stack guard if any
+#[cfg(feature = "stack-guard")]
+const PMP_FIRST_BOARD_ENTRY: usize = stack_guard::PMP_ENTRIES;
+#[cfg(not(feature = "stack-guard"))]
+const PMP_FIRST_BOARD_ENTRY: usize = 0;
+
 fn set_pmp() {
     // todo: 根据QEMU的loader device等等，设置这里的权限配置
     // read fdt tree value, parse, and calculate proper pmp configuration for this device tree (issue #7)
@@ -119,63 +411,42 @@ fn set_pmp() {
     // A = NA4(naturally aligned 4-byte region, 2), only support a 4-byte pmp region
     // A = NAPOT(naturally aligned power-of-two region, 3), support a >=8-byte pmp region
     // When using NAPOT to match a address range [S,S+L), then the pmpaddr_i should be set to (S>>2)|((L>>3)-1)
+    //
+    // PMP policy is default deny: once any PMP entry is set, supervisor and user accesses that match
+    // no entry fail with an access fault. Only `board::PMP_REGIONS` are opened to supervisor, in
+    // priority order; feature `pmp-allow-all` appends a lowest-priority catch-all entry which opens
+    // the whole address space.
+    //
+    // With feature `stack-guard`, entry 0 is the locked guard region below this hart's stack, and
+    // board regions start from entry 1, see `stack_guard`.
     let calc_pmpaddr = |start_addr: usize, length: usize| (start_addr >> 2) | ((length >> 3) - 1);
-    let mut pmpcfg0: usize = 0;
-
-    // pmp region 0: RW, A=NAPOT, peripherals CSR
-    pmpcfg0 |= 0b11011;
-    let pmpaddr0 = calc_pmpaddr(0x1000_0000, 0x800_0000);
-
-    // pmp region 1: RW, A=NAPOT, CLINT, Cache Controller, Reverse
-    pmpcfg0 |= 0b11011 << 8;
-    let pmpaddr1 = calc_pmpaddr(0x200_0000, 0x200_0000);
-
-    // pmp region 2: RW, A=NAPOT, QSPI,
-    pmpcfg0 |= 0b11111 << 16;
-    let pmpaddr2 = calc_pmpaddr(0x2000_0000, 0x2000_0000);
-
-    // pmp region 3: RWX, A=NAPOT, DRAM
-    pmpcfg0 |= 0b11111 << 24;
-    let pmpaddr3 = calc_pmpaddr(0x8000_0000, 0x8000_0000);
-
-    // pmp region 4: RWX, A=NAPOT, DRAM
-    pmpcfg0 |= 0b11111 << 32;
-    let pmpaddr4 = calc_pmpaddr(0x1_0000_0000, 0x1_0000_0000);
-
-    // pmp region 5: RWX, A=NAPOT, DRAM
-    pmpcfg0 |= 0b11111 << 40;
-    let pmpaddr5 = calc_pmpaddr(0x2_0000_0000, 0x8000_0000);
-
-    // pmp region 6: RWX, A=NAPOT, PLIC
-    pmpcfg0 |= 0b11111 << 48;
-    let pmpaddr6 = calc_pmpaddr(0xc00_0000, 0x400_0000);
-
-    // pmp region 7: RWX, A=NAPOT, Internal RAM + ROM + slv
-    pmpcfg0 |= 0b11111 << 56;
-    let pmpaddr7 = calc_pmpaddr(0x1800_0000, 0x800_0000);
-
-    unsafe {
-        core::arch::asm!("csrw  pmpcfg0, {}",
-             "csrw  pmpaddr0, {}",
-             "csrw  pmpaddr1, {}",
-             "csrw  pmpaddr2, {}",
-             "csrw  pmpaddr3, {}",
-             "csrw  pmpaddr4, {}",
-             "csrw  pmpaddr5, {}",
-             "csrw  pmpaddr6, {}",
-             "csrw  pmpaddr7, {}",
-             "sfence.vma",
-             in(reg) pmpcfg0,
-             in(reg) pmpaddr0,
-             in(reg) pmpaddr1,
-             in(reg) pmpaddr2,
-             in(reg) pmpaddr3,
-             in(reg) pmpaddr4,
-             in(reg) pmpaddr5,
-             in(reg) pmpaddr6,
-             in(reg) pmpaddr7,
-        );
+    let regions = board::PMP_REGIONS;
+    let mut pmpcfg = [0usize; 2]; // pmpcfg0 and pmpcfg2, 8 entries each on RV64
+    #[cfg(feature = "stack-guard")]
+    {
+        let i = stack_guard::PMP_ENTRY;
+        pmpcfg[i / 8] |= (stack_guard::PMP_CFG as usize) << (i % 8 * 8);
+        let pmpaddr = stack_guard::pmpaddr(riscv::register::mhartid::read());
+        hart_csr_utils::write_csr_dynamic(PMPADDR0 + i, pmpaddr).unwrap();
+    }
+    for (index, region) in regions.iter().enumerate() {
+        let i = PMP_FIRST_BOARD_ENTRY + index;
+        pmpcfg[i / 8] |= ((PMP_A_NAPOT | region.permission) as usize) << (i % 8 * 8);
+        hart_csr_utils::write_csr_dynamic(PMPADDR0 + i, calc_pmpaddr(region.base, region.size))
+            .expect("pmp region out of available pmpaddr");
     }
+    // catch-all for bring-up after board regions; lower numbered regions take priority
+    #[cfg(feature = "pmp-allow-all")]
+    {
+        let i = PMP_FIRST_BOARD_ENTRY + regions.len();
+        pmpcfg[i / 8] |= ((PMP_A_NAPOT | board::PMP_RWX) as usize) << (i % 8 * 8);
+        // all ones in NAPOT mode matches the whole address space
+        hart_csr_utils::write_csr_dynamic(PMPADDR0 + i, usize::MAX)
+            .expect("no pmpaddr left for catch-all region");
+    }
+    hart_csr_utils::write_csr_dynamic(PMPCFG0, pmpcfg[0]).unwrap();
+    hart_csr_utils::write_csr_dynamic(PMPCFG2, pmpcfg[1]).unwrap();
+    unsafe { core::arch::asm!("sfence.vma") };
 }
 
 fn init_bss() {
@@ -193,8 +464,7 @@ fn init_bss() {
 }
 
 fn init_rustsbi_stdio(uart: peripheral::Uart) {
-    use rustsbi::legacy_stdio::init_legacy_stdio_embedded_hal;
-    init_legacy_stdio_embedded_hal(uart);
+    console::init(uart);
 }
 
 fn init_rustsbi_clint(clint: peripheral::Clint) {
@@ -218,14 +488,49 @@ fn delegate_interrupt_exception() {
         medeleg::set_load_page_fault();
         medeleg::set_store_page_fault();
         medeleg::set_instruction_fault();
+        // U74 raises access faults on atomics to device memory, which firmware emulates
+        #[cfg(not(feature = "emulate-mmio-amo"))]
         medeleg::set_load_fault();
+        #[cfg(not(feature = "emulate-mmio-amo"))]
         medeleg::set_store_fault();
         // mie::set_mext();
-        // 不打开mie::set_mtimer
+        // 不打开mie::set_mtimer; `timer` enables it once a deadline is set
         mie::set_msoft();
     }
 }
 
+// Let supervisor read cycle, instret and the hpmcounters of U74 directly instead of trapping.
+//
+// U74 implements mhpmcounter3 and mhpmcounter4 only, counting what their mhpmevent selects; there
+// is no PMU extension to allocate them, so both are always readable. time stays disabled: U74
+// has no time CSR, its reads trap and are emulated from CLINT `mtime`.
+//
+// With feature `fixed-counters`, cycle and instret stay disabled too, so that a test run cannot
+// observe how many cycles or instructions it took: reads of them and of cycleh and instreth trap,
+// and return `FIXED_COUNTER_VALUE` on every hart, every time; time still advances as above. Other
+// accesses to them, which would write a read-only CSR, are illegal instructions for supervisor
+// as before. Only the supervisor view changes, mcycle and minstret keep counting; hpmcounter3
+// and hpmcounter4 stay exposed.
+fn enable_counters() {
+    use riscv::register::mcounteren;
+    unsafe {
+        #[cfg(not(feature = "fixed-counters"))]
+        mcounteren::set_cy();
+        #[cfg(not(feature = "fixed-counters"))]
+        mcounteren::set_ir();
+        mcounteren::set_hpm(3);
+        mcounteren::set_hpm(4);
+    }
+}
+
+// Set mstatus.TSR, so that supervisor sret traps into firmware, which emulates it; a guard path
+// for testing interception of sret as TSR intends, off by default where sret runs in hardware.
+// Set before the runtime takes mstatus for supervisor, every later entry keeps it.
+#[cfg(feature = "emulate-sret")]
+fn trap_supervisor_return() {
+    unsafe { riscv::register::mstatus::set_tsr() };
+}
+
 pub fn pause(clint: peripheral::Clint) {
     use riscv::asm::wfi;
     use riscv::register::{mhartid, mie, mip};
@@ -248,35 +553,142 @@ pub fn pause(clint: peripheral::Clint) {
     }
 }
 
+// Hand `[sheap, eheap)` to the allocator as it is, not zeroed; see `heap`
 #[inline]
 fn init_heap() {
     unsafe {
-        HEAP_ALLOCATOR
-            .lock()
-            .init(HEAP_SPACE.as_ptr() as usize, SBI_HEAP_SIZE);
+        let start = &sheap as *const u8 as usize;
+        let end = &eheap as *const u8 as usize;
+        HEAP_ALLOCATOR.lock().init(start, end - start);
     }
 }
 
+// Progress of the copy to link address, in the image as loaded: `RELOCATE_CLAIMED` by the hart
+// doing the copy, then `RELOCATE_DONE` as well. Kept in .data, as .bss is not part of the loaded
+// image
+#[cfg(feature = "self-relocate")]
+#[link_section = ".data.relocated"]
+static mut RELOCATED: usize = 0;
+#[cfg(feature = "self-relocate")]
+const RELOCATE_CLAIMED: usize = 1;
+#[cfg(feature = "self-relocate")]
+const RELOCATE_DONE: usize = 2;
+
+// With feature `self-relocate`, firmware may be loaded anywhere 8-byte aligned in memory, and
+// moves itself to its link address `stext` before any Rust code runs.
+//
+// Firmware is linked as usual: non-PIE at `stext` of the linker script, with the default
+// `-C relocation-model=static` and the medany code model of the target. It is copied rather than
+// run in place, thus it needs no dynamic relocations, and a PIE build (`-C relocation-model=pie`,
+// `-pie`) is not supported. Only this stub runs at the load address; it uses pc-relative
+// addressing only, and takes the link address from a literal.
+//
+// Every hart enters here at the load address. The first hart to claim `RELOCATED` with an atomic
+// or copies the loaded image [stext, edata) to the link address, then marks it done; any other
+// hart waits for that. The copying hart need not be `board::BOOT_HART_ID`, which may never come
+// out of reset, and boot hart is only elected afterwards, see `boot_hart`. All harts then jump
+// to `entry` at its link address. The load and run ranges must not overlap, otherwise the
+// copying hart parks before the console is up and the others wait forever; the flag relies on
+// the loader writing a fresh image on each boot.
+#[cfg(feature = "self-relocate")]
 #[naked]
 #[link_section = ".text.entry"]
 #[export_name = "_start"]
+unsafe extern "C" fn relocate() -> ! {
+    core::arch::asm!(
+    // t0 = where the image is loaded, t1 = where it is linked
+    "
+    lla     t0, stext
+    ld      t1, 3f
+    beq     t0, t1, 2f
+    lla     t2, {relocated}
+    li      t3, {claimed}
+    amoor.d.aqrl t3, t3, (t2)
+    bnez    t3, 5f
+    ",
+    // claiming hart: copy [stext, edata) of the loaded image word by word, t2 = its end
+    "
+    lla     t2, edata
+    sub     t3, t2, t0
+    add     t4, t1, t3
+    bgeu    t0, t4, 4f
+    bgeu    t1, t2, 4f
+6:  wfi
+    j       6b
+4:  mv      t4, t0
+    mv      t5, t1
+7:  lw      t6, 0(t4)
+    sw      t6, 0(t5)
+    addi    t4, t4, 4
+    addi    t5, t5, 4
+    bltu    t4, t2, 7b
+    lla     t2, {relocated}
+    li      t3, {done}
+    amoor.d.aqrl zero, t3, (t2)
+    j       8f
+    ",
+    // other harts: wait for the copy
+    "
+5:  lla     t2, {relocated}
+9:  ld      t3, 0(t2)
+    andi    t3, t3, {done}
+    beqz    t3, 9b
+    fence   r, rw
+8:  fence.i
+    ",
+    // jump to `entry` at its link address
+    "
+2:  lla     t2, {entry}
+    sub     t2, t2, t0
+    add     t2, t2, t1
+    jr      t2
+    .p2align 3
+3:  .dword  stext
+    ",
+    relocated = sym RELOCATED,
+    claimed = const RELOCATE_CLAIMED,
+    done = const RELOCATE_DONE,
+    entry = sym entry,
+    options(noreturn))
+}
+
+#[naked]
+#[cfg_attr(
+    not(feature = "self-relocate"),
+    link_section = ".text.entry",
+    export_name = "_start"
+)]
 unsafe extern "C" fn entry() -> ! {
     core::arch::asm!(
     // 1. set sp
-    // sp = bootstack + (hart_id + 1) * HART_STACK_SIZE
+    // sp = sstack + (hart_id + 1) * hart_stack_stride, both from linker script
     "
-    la      sp, {stack}
-    li      t0, {per_hart_stack_size}
+    la      sp, sstack
+    lui     t0, %hi(hart_stack_stride)
+    addi    t0, t0, %lo(hart_stack_stride)
     csrr    t1, mhartid
     addi    t2, t1, 1
 1:  add     sp, sp, t0
     addi    t2, t2, -1
     bnez    t2, 1b
     ",
-    // 2. jump to main function (absolute address)
+    // 2. park a hart beyond `NUM_HARTS` or `hart_stack_count`: its sp is past `estack`, on the
+    // heap of other harts. It must not touch memory, thus it neither panics nor prints; it
+    // masks interrupts, which would take it to a trap handler using that sp, and waits forever.
+    "
+    li      t2, {num_harts}
+    bgeu    t1, t2, 2f
+    la      t2, estack
+    bleu    sp, t2, 3f
+2:  csrw    mie, zero
+    csrci   mstatus, 0x8
+4:  wfi
+    j       4b
+3:
+    ",
+    // 3. jump to main function (absolute address)
     "j   {rust_main}",
-    per_hart_stack_size = const PER_HART_STACK_SIZE,
-    stack = sym SBI_STACK,
+    num_harts = const NUM_HARTS,
     rust_main = sym rust_main,
     options(noreturn))
 }
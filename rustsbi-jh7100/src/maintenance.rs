@@ -0,0 +1,188 @@
+//! Maintenance mode
+//!
+//! During `MAINTENANCE_WINDOW_US` of early boot, before the payload is entered, boot hart watches
+//! the console for a trigger:
+//!
+//! - three Ctrl-A (`0x01`) in a row, e.g. typed in a terminal emulator, or
+//! - a break condition on the serial line, e.g. `Ctrl-A F` in picocom or `~#` in cu.
+//!
+//! If seen, firmware does not boot the payload but runs a line-based maintenance shell on the
+//! console; its command `boot` then goes on booting. Otherwise boot goes on once the window has
+//! passed, delayed by no more than that window.
+//!
+//! The same shell is the panic action `Shell`, see `panic_action`; it then runs on the panicked
+//! hart and offers no `boot`.
+//!
+//! Commands, numbers are hexadecimal with or without `0x`:
+//!
+//! | Command               | Action
+//! |:----------------------|:-------
+//! | `help`                | list commands
+//! | `csr <num>`           | read a machine or supervisor CSR of the hart running the shell
+//! | `csr <num> <value>`   | write a machine or supervisor CSR of the hart running the shell
+//! | `md <addr> [<len>]`   | dump `len` bytes of DRAM from `addr`, 8-byte aligned, at most 256
+//! | `stats`               | print trap counters of every hart
+//! | `map`                 | print the memory map, with feature `diagnostics`, see `memory_map`
+//! | `boot`                | leave the shell and boot the payload
+use crate::hart_csr_utils::{read_csr_dynamic, write_csr_dynamic};
+use crate::peripheral::{Clint, Uart};
+use crate::println;
+use embedded_hal::serial::Read;
+
+// How long boot hart waits for a trigger, 200ms
+const MAINTENANCE_WINDOW_US: u64 = 200_000;
+
+const MAGIC_KEY: u8 = 0x01; // Ctrl-A
+const MAGIC_KEY_COUNT: usize = 3;
+
+const MAX_LINE: usize = 64;
+const MAX_DUMP_LENGTH: usize = 256;
+
+// Watch console for the trigger during the maintenance window, run the shell if it is seen;
+// called once on boot hart before the payload is entered
+pub fn check() {
+    let mut uart = unsafe { Uart::preloaded_uart0() };
+    let clint = Clint::new(0x2000000 as *mut u8);
+    let deadline = clint.get_mtime() + clint.us_to_ticks(MAINTENANCE_WINDOW_US);
+    let mut keys = 0;
+    let triggered = loop {
+        if clint.get_mtime() >= deadline {
+            break false;
+        }
+        if uart.take_break() {
+            break true;
+        }
+        match uart.read() {
+            Ok(MAGIC_KEY) => keys += 1,
+            Ok(_) => keys = 0,
+            Err(_) => core::hint::spin_loop(),
+        }
+        if keys == MAGIC_KEY_COUNT {
+            break true;
+        }
+    };
+    if triggered {
+        println!("[rustsbi] maintenance mode, type `help` for commands, `boot` to go on");
+        shell(&mut uart, true);
+    }
+}
+
+// Run the shell on current hart after its panic report; returns never, as there is no `boot`
+pub fn panic_shell() -> ! {
+    let mut uart = unsafe { Uart::preloaded_uart0() };
+    println!("[rustsbi] maintenance shell on panicked hart, type `help` for commands");
+    shell(&mut uart, false);
+    unreachable!()
+}
+
+// `can_boot` offers command `boot`, which leaves the shell
+fn shell(uart: &mut Uart, can_boot: bool) {
+    let mut line = [0u8; MAX_LINE];
+    loop {
+        rustsbi::print!("rustsbi> ");
+        let len = read_line(uart, &mut line);
+        let line = core::str::from_utf8(&line[..len]).unwrap_or("");
+        let mut words = line.split_ascii_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => continue,
+        };
+        let args = [words.next(), words.next(), words.next()];
+        match (command, args) {
+            ("help", [None, ..]) => help(can_boot),
+            ("csr", [Some(csr), value, None]) => csr_command(csr, value),
+            ("md", [Some(addr), len, None]) => dump(addr, len),
+            ("stats", [None, ..]) => crate::hart_csr_utils::print_trap_stats(),
+            #[cfg(feature = "diagnostics")]
+            ("map", [None, ..]) => crate::memory_map::print(),
+            ("boot", [None, ..]) if can_boot => return,
+            _ => println!("unknown command or arguments `{}`, type `help`", line),
+        }
+    }
+}
+
+// Read a line with echo and backspace, returns its length; excess characters are dropped
+fn read_line(uart: &mut Uart, line: &mut [u8; MAX_LINE]) -> usize {
+    let mut len = 0;
+    loop {
+        let byte = match uart.read() {
+            Ok(byte) => byte,
+            Err(_) => {
+                core::hint::spin_loop();
+                continue;
+            }
+        };
+        match byte {
+            b'\r' | b'\n' => {
+                println!("");
+                return len;
+            }
+            // backspace and delete
+            0x08 | 0x7f if len > 0 => {
+                len -= 1;
+                rustsbi::print!("\x08 \x08");
+            }
+            b' '..=b'~' if len < MAX_LINE => {
+                line[len] = byte;
+                len += 1;
+                rustsbi::print!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn help(can_boot: bool) {
+    println!("csr <num> [<value>]  read or write a CSR of this hart");
+    println!(
+        "md <addr> [<len>]    dump DRAM, at most {} bytes",
+        MAX_DUMP_LENGTH
+    );
+    println!("stats                print trap counters");
+    #[cfg(feature = "diagnostics")]
+    println!("map                  print memory map");
+    if can_boot {
+        println!("boot                 boot the payload");
+    }
+}
+
+fn parse_hex(word: &str) -> Option<usize> {
+    let digits = word.strip_prefix("0x").unwrap_or(word);
+    usize::from_str_radix(digits, 16).ok()
+}
+
+fn csr_command(csr: &str, value: Option<&str>) {
+    let csr = match parse_hex(csr) {
+        Some(csr) => csr,
+        None => return println!("invalid csr number `{}`", csr),
+    };
+    match value.map(|value| (value, parse_hex(value))) {
+        None => match read_csr_dynamic(csr) {
+            Ok(value) => println!("csr {:#x} = {:#018x}", csr, value),
+            Err(e) => println!("csr {:#x}: {}", csr, e),
+        },
+        Some((_, Some(value))) => match write_csr_dynamic(csr, value) {
+            Ok(()) => println!("csr {:#x} <- {:#018x}", csr, value),
+            Err(e) => println!("csr {:#x}: {}", csr, e),
+        },
+        Some((word, None)) => println!("invalid value `{}`", word),
+    }
+}
+
+fn dump(addr: &str, len: Option<&str>) {
+    let (addr, len) = match (parse_hex(addr), len.map(parse_hex)) {
+        (Some(addr), None) => (addr, 8),
+        (Some(addr), Some(Some(len))) => (addr, len.min(MAX_DUMP_LENGTH)),
+        _ => return println!("invalid address or length"),
+    };
+    let addr = addr & !0x7;
+    // an address outside DRAM may fault in machine mode, which firmware does not recover from
+    match addr.checked_add(len) {
+        Some(end) if addr >= crate::DRAM_PMP_START && end <= crate::DRAM_PMP_END => {}
+        _ => return println!("range outside DRAM"),
+    }
+    for word in (addr..addr + len).step_by(8) {
+        let value = unsafe { core::ptr::read_volatile(word as *const u64) };
+        println!("{:#x}: {:#018x}", word, value);
+    }
+}
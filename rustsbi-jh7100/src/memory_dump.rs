@@ -0,0 +1,86 @@
+//! Hex dump of supervisor memory over the console
+//!
+//! Function `FUNCTION_DUMP_MEMORY` of vendor extension `EXTENSION_DIAGNOSTICS` takes an address
+//! in a0 and a length in a1, and prints that range in `xxd` format, sixteen bytes a line:
+//!
+//! ```text
+//! [rustsbi] 80200000: 1304 0000 9700 0000 e780 4000 7300 5010  ..........@.s.P.
+//! ```
+//!
+//! Bytes are read one by one under MSTATUS.MPRV, thus with supervisor's translation and PMP
+//! permission; the address is physical while supervisor runs with satp Bare. The range must lie
+//! in DRAM opened to supervisor, and at most `MAX_DUMP_LENGTH` bytes are printed per call so that
+//! a wrong length does not flood the serial line. Returns the count of bytes dumped in a1, which
+//! is short if a read faults in the middle of the range.
+use crate::execute::get_vaddr_u8;
+use crate::println;
+use core::fmt;
+use rustsbi::SbiRet;
+
+// 256 lines of 16 bytes, about 20 KiB of text or two seconds at 115200 baud
+const MAX_DUMP_LENGTH: usize = 4096;
+
+const BYTES_PER_LINE: usize = 16;
+
+pub fn dump(addr: usize, length: usize) -> SbiRet {
+    let length = length.min(MAX_DUMP_LENGTH);
+    match addr.checked_add(length) {
+        Some(end) if addr >= crate::DRAM_PMP_START && end <= crate::DRAM_PMP_END => {}
+        _ => return SbiRet::invalid_address(),
+    }
+    let mut dumped = 0;
+    while dumped < length {
+        let line_addr = addr + dumped;
+        let mut bytes = [0u8; BYTES_PER_LINE];
+        let mut count = 0;
+        let mut faulted = false;
+        for byte in bytes.iter_mut().take((length - dumped).min(BYTES_PER_LINE)) {
+            match unsafe { get_vaddr_u8(line_addr + count) } {
+                Some(value) => *byte = value,
+                None => {
+                    faulted = true;
+                    break;
+                }
+            }
+            count += 1;
+        }
+        if count != 0 {
+            println!("[rustsbi] {}", Line(line_addr, &bytes[..count]));
+        }
+        dumped += count;
+        if faulted {
+            println!("[rustsbi] memory dump: read fault at {:#x}", addr + dumped);
+            break;
+        }
+    }
+    SbiRet::ok(dumped)
+}
+
+// Address, bytes as hex in pairs, then printable ASCII; a short line is padded to align
+struct Line<'a>(usize, &'a [u8]);
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Line(addr, bytes) = *self;
+        write!(f, "{:08x}:", addr)?;
+        for i in 0..BYTES_PER_LINE {
+            if i % 2 == 0 {
+                f.write_str(" ")?;
+            }
+            match bytes.get(i) {
+                Some(byte) => write!(f, "{:02x}", byte)?,
+                None => f.write_str("  ")?,
+            }
+        }
+        f.write_str("  ")?;
+        for byte in bytes {
+            let c = if byte.is_ascii_graphic() || *byte == b' ' {
+                *byte as char
+            } else {
+                '.'
+            };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
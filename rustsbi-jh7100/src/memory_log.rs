@@ -0,0 +1,70 @@
+//! In-memory log, where console output goes once console UART is given up on
+//!
+//! The log lives in scratch slot `SLOT_LOG`, thus supervisor, a debugger or a dump after warm
+//! reset can read it at a fixed place. It starts with a 16-byte header: magic `LOG_MAGIC` as
+//! little-endian u32, 4 reserved bytes, then the count of bytes ever written as little-endian
+//! u64. Byte `i` of the output is at data offset `i % capacity` right after the header; once
+//! full, the oldest output is overwritten.
+//!
+//! Output before the scratch region is set up is kept in an early buffer in firmware memory, of
+//! which the newest `EARLY_LOG_SIZE` bytes are moved to the slot on the first write after. If the
+//! scratch region never comes up, output stays in the early buffer.
+//!
+//! Only written under the console lock, see `console`.
+use crate::scratch::{self, SLOT_LOG};
+
+// "RLOG"
+pub const LOG_MAGIC: u32 = 0x474f_4c52;
+const HEADER_SIZE: usize = 16;
+const EARLY_LOG_SIZE: usize = 1024;
+
+pub struct MemoryLog {
+    early: [u8; EARLY_LOG_SIZE],
+    early_written: usize,
+    // whether `SLOT_LOG` holds the log, with early output moved into it
+    opened: bool,
+}
+
+impl MemoryLog {
+    pub const fn new() -> Self {
+        Self {
+            early: [0; EARLY_LOG_SIZE],
+            early_written: 0,
+            opened: false,
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        // console lock serializes every writer of the log
+        match unsafe { scratch::slot(SLOT_LOG) } {
+            Some(slot) => {
+                if !self.opened {
+                    self.open(slot);
+                }
+                push(slot, byte)
+            }
+            None => {
+                self.early[self.early_written % EARLY_LOG_SIZE] = byte;
+                self.early_written += 1;
+            }
+        }
+    }
+
+    // Start the log in `SLOT_LOG`, and move early output into it
+    fn open(&mut self, slot: &mut [u8]) {
+        slot[..4].copy_from_slice(&LOG_MAGIC.to_le_bytes());
+        slot[4..HEADER_SIZE].fill(0);
+        let start = self.early_written.saturating_sub(EARLY_LOG_SIZE);
+        for i in start..self.early_written {
+            push(slot, self.early[i % EARLY_LOG_SIZE]);
+        }
+        self.opened = true;
+    }
+}
+
+fn push(slot: &mut [u8], byte: u8) {
+    let (header, data) = slot.split_at_mut(HEADER_SIZE);
+    let written = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    data[(written % data.len() as u64) as usize] = byte;
+    header[8..16].copy_from_slice(&(written + 1).to_le_bytes());
+}
@@ -0,0 +1,234 @@
+//! Memory map report
+//!
+//! With feature `diagnostics`, function `FUNCTION_PRINT_MEMORY_MAP` of vendor extension
+//! `EXTENSION_DIAGNOSTICS`, and command `map` of the maintenance shell, print every memory range
+//! firmware knows of to its console:
+//!
+//! | Function | Parameters | Returns
+//! |:---------|:-----------|:---------
+//! | 8        |            | 0; the map is printed on firmware console
+//!
+//! First the DRAM ranges: of the board with feature `memory-fixup`, see `board::DRAM_BASE`, the
+//! memory node of the device tree passed to supervisor, and the range opened to supervisor by PMP. Then a table of regions
+//! in address order, each with its owner:
+//!
+//! - firmware sections, from the linker script: text, read-only data, data and bss, the heap and
+//!   each hart's stack, with its guard region under feature `stack-guard`;
+//! - the payload as loaded at `payload::LOAD_ADDRESS`, and an initramfs as advertised in
+//!   `/chosen` of the supervisor device tree, see `initramfs`;
+//! - firmware scratch memory, which supervisor sees as reserved memory, and each of its slots,
+//!   see `scratch`.
+//!
+//! Ranges are [start, end). A region firmware has not set up, e.g. scratch memory which did not
+//! fit in DRAM, is left out.
+use crate::println;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// at most: 5 firmware sections, 2 stack regions of each hart, payload, initramfs, scratch and
+// its slots
+const MAX_REGIONS: usize = 5 + 2 * crate::NUM_HARTS + 3 + crate::scratch::SLOTS.len();
+
+extern "C" {
+    static stext: u8;
+    static etext: u8;
+    static srodata: u8;
+    static erodata: u8;
+    static sdata: u8;
+    static edata: u8;
+    static sheap: u8;
+    static eheap: u8;
+    static sbss: u8;
+    static ebss: u8;
+}
+
+static PAYLOAD_START: AtomicUsize = AtomicUsize::new(0);
+static PAYLOAD_END: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy)]
+enum Owner {
+    Firmware,
+    Supervisor,
+    // firmware memory supervisor is told about, as `/reserved-memory`
+    Reserved,
+}
+
+impl Owner {
+    fn name(self) -> &'static str {
+        match self {
+            Owner::Firmware => "firmware",
+            Owner::Supervisor => "supervisor",
+            Owner::Reserved => "reserved",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Region {
+    start: usize,
+    end: usize,
+    owner: Owner,
+    name: &'static str,
+    // hart a per-hart region belongs to
+    hart: Option<usize>,
+}
+
+// Record where the payload was copied to; called once on boot hart, before supervisor runs
+pub fn set_payload(payload: &Range<usize>) {
+    PAYLOAD_START.store(payload.start, Ordering::Relaxed);
+    PAYLOAD_END.store(payload.end, Ordering::Release);
+}
+
+pub fn print() {
+    println!("[rustsbi] memory map:");
+    #[cfg(feature = "memory-fixup")]
+    {
+        use crate::board::{DRAM_BASE, DRAM_SIZE};
+        print_dram("DRAM of board", Some((DRAM_BASE, DRAM_BASE + DRAM_SIZE)));
+    }
+    let dtb = crate::scratch::device_tree();
+    let info = unsafe { crate::device_tree::parse_device_tree_at(dtb) }.ok();
+    print_dram("DRAM in device tree", info.and_then(|info| info.memory));
+    print_dram(
+        "DRAM opened by PMP",
+        Some((crate::DRAM_PMP_START, crate::DRAM_PMP_END)),
+    );
+    let mut regions = [None; MAX_REGIONS];
+    let len = collect(&mut regions);
+    let regions = &mut regions[..len];
+    // a region before the regions it contains, which start at the same address
+    regions.sort_unstable_by(|a, b| match (a, b) {
+        (Some(a), Some(b)) => a.start.cmp(&b.start).then(b.end.cmp(&a.end)),
+        _ => core::cmp::Ordering::Equal,
+    });
+    println!(
+        "[rustsbi]   {:<18} {:<18} {:<10} {:<10} region",
+        "start", "end", "size", "owner"
+    );
+    for region in regions.iter().flatten() {
+        match region.hart {
+            Some(hart_id) => println!(
+                "[rustsbi]   {:#018x} {:#018x} {:<#10x} {:<10} {} of hart {}",
+                region.start,
+                region.end,
+                region.end - region.start,
+                region.owner.name(),
+                region.name,
+                hart_id
+            ),
+            None => println!(
+                "[rustsbi]   {:#018x} {:#018x} {:<#10x} {:<10} {}",
+                region.start,
+                region.end,
+                region.end - region.start,
+                region.owner.name(),
+                region.name
+            ),
+        }
+    }
+}
+
+fn print_dram(name: &str, range: Option<(usize, usize)>) {
+    match range {
+        Some((start, end)) => println!(
+            "[rustsbi]   {:<20} {:#018x}..{:#018x}, {} MiB",
+            name,
+            start,
+            end,
+            (end - start) >> 20
+        ),
+        None => println!("[rustsbi]   {:<20} unknown", name),
+    }
+}
+
+// Fill `regions` from the front, returns how many are set
+fn collect(regions: &mut [Option<Region>; MAX_REGIONS]) -> usize {
+    let mut len = 0;
+    let mut push = |start: usize, end: usize, owner, name, hart| {
+        regions[len] = Some(Region {
+            start,
+            end,
+            owner,
+            name,
+            hart,
+        });
+        len += 1;
+    };
+    let symbol = |symbol: &u8| symbol as *const u8 as usize;
+    unsafe {
+        push(
+            symbol(&stext),
+            symbol(&etext),
+            Owner::Firmware,
+            "text",
+            None,
+        );
+        push(
+            symbol(&srodata),
+            symbol(&erodata),
+            Owner::Firmware,
+            "read-only data",
+            None,
+        );
+        push(
+            symbol(&sdata),
+            symbol(&edata),
+            Owner::Firmware,
+            "data",
+            None,
+        );
+        push(
+            symbol(&sheap),
+            symbol(&eheap),
+            Owner::Firmware,
+            "heap",
+            None,
+        );
+        push(symbol(&sbss), symbol(&ebss), Owner::Firmware, "bss", None);
+    }
+    for hart_id in 0..crate::NUM_HARTS {
+        let top = crate::hart_stack_top(hart_id);
+        #[cfg(feature = "stack-guard")]
+        let bottom = {
+            let guard = crate::stack_guard::guard(hart_id);
+            push(
+                guard.start,
+                guard.end,
+                Owner::Firmware,
+                "stack guard",
+                Some(hart_id),
+            );
+            guard.end
+        };
+        #[cfg(not(feature = "stack-guard"))]
+        let bottom = top - crate::hart_stack_stride();
+        push(bottom, top, Owner::Firmware, "stack", Some(hart_id));
+    }
+    let payload_end = PAYLOAD_END.load(Ordering::Acquire);
+    if payload_end != 0 {
+        let payload_start = PAYLOAD_START.load(Ordering::Relaxed);
+        push(
+            payload_start,
+            payload_end,
+            Owner::Supervisor,
+            "payload",
+            None,
+        );
+    }
+    if let Some(base) = crate::scratch::base() {
+        // only read; it is the supervisor device tree unless placing it there failed
+        let dtb = unsafe { crate::scratch::slot(crate::scratch::SLOT_DEVICE_TREE) }
+            .map(|buf| &*buf)
+            .filter(|buf| buf.as_ptr() as usize == crate::scratch::device_tree());
+        if let Some((start, end)) = dtb.and_then(|dtb| crate::device_tree::chosen_initrd(dtb)) {
+            push(start, end, Owner::Supervisor, "initramfs", None);
+        }
+        let size = crate::scratch::SCRATCH_SIZE;
+        push(base, base + size, Owner::Reserved, "scratch memory", None);
+        for (name, slot) in crate::scratch::SLOTS {
+            let start = base + slot.offset();
+            push(start, start + slot.size(), Owner::Reserved, name, None);
+        }
+    }
+    len
+}
@@ -0,0 +1,268 @@
+//! Dump of supervisor Sv39 page tables over the console
+//!
+//! Function `FUNCTION_DUMP_PAGE_TABLES` of vendor extension `EXTENSION_DIAGNOSTICS` takes a satp
+//! value in a0, or 0 for the calling hart's current satp, walks its Sv39 page tables and prints
+//! the valid leaf mappings. Adjacent leaves whose virtual and physical ranges both continue each
+//! other with the same permissions are printed as one range:
+//!
+//! ```text
+//! [rustsbi] ffffffc000000000..ffffffc040000000 -> 80200000 rwx-gad
+//! ```
+//!
+//! Permissions are `r`, `w`, `x`, `u`, `g`, `a` and `d`, `-` for a clear bit. Page table entries
+//! are read at physical addresses, under MSTATUS.MPRV with MPP Machine, so that a read fault is
+//! reported instead of taken. A table pointer outside DRAM opened to supervisor, an entry with
+//! reserved bits set, a misaligned superpage or a non-leaf entry at the last level is printed as
+//! an invalid entry and not followed. At most `MAX_LINES` lines are printed per call, the rest is
+//! only counted, so that a sprawling address space does not flood the serial line.
+//!
+//! Returns the count of valid leaf entries in a1; `SBI_ERR_INVALID_PARAM` if satp is not Bare or
+//! Sv39, `SBI_ERR_INVALID_ADDRESS` if its root table is outside DRAM. With satp Bare there is
+//! nothing to walk, and 0 is returned.
+//!
+//! `translate` looks up a single address on the same tables, for `watchpoint`.
+use crate::execute::get_vaddr_u64;
+use crate::println;
+use riscv::register::mstatus::{self, MPP};
+use rustsbi::SbiRet;
+
+// 256 lines, about 16 KiB of text or under two seconds at 115200 baud
+const MAX_LINES: usize = 256;
+
+const SATP_MODE_BARE: usize = 0;
+const SATP_MODE_SV39: usize = 8;
+const SATP_PPN_MASK: usize = (1 << 44) - 1;
+
+const PAGE_SIZE: usize = 4096;
+const PTES_PER_TABLE: usize = 512;
+const LEVELS: usize = 3;
+const VA_BITS: u32 = 39;
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+pub const PTE_X: u64 = 1 << 3;
+// permission and status bits printed for a leaf, from R to D
+const PTE_FLAGS: u64 = 0xfe;
+const PTE_PPN_SHIFT: u32 = 10;
+const PTE_PPN_MASK: u64 = (1 << 44) - 1;
+// bits 63..54, reserved for Svnapot, Svpbmt and future use; U74 implements none of them
+const PTE_RESERVED: u64 = 0x3ff << 54;
+
+pub fn dump(satp: usize) -> SbiRet {
+    let satp = match satp {
+        0 => riscv::register::satp::read().bits(),
+        satp => satp,
+    };
+    match satp >> 60 {
+        SATP_MODE_BARE => {
+            println!(
+                "[rustsbi] page tables: satp {:#x} is Bare, no translation",
+                satp
+            );
+            return SbiRet::ok(0);
+        }
+        SATP_MODE_SV39 => {}
+        _ => return SbiRet::invalid_param(),
+    }
+    let root = (satp & SATP_PPN_MASK) * PAGE_SIZE;
+    if !in_dram(root) {
+        return SbiRet::invalid_address();
+    }
+    println!(
+        "[rustsbi] page tables: satp {:#x}, Sv39 root {:#x}",
+        satp, root
+    );
+    let mut walk = Walk::default();
+    walk.table(root, LEVELS - 1, 0);
+    walk.flush();
+    if walk.suppressed != 0 {
+        println!(
+            "[rustsbi] page tables: {} more lines not printed",
+            walk.suppressed
+        );
+    }
+    println!(
+        "[rustsbi] page tables: {} valid leaf entries, {} invalid entries",
+        walk.leaves, walk.invalid
+    );
+    SbiRet::ok(walk.leaves)
+}
+
+// Physical address and leaf permission bits `va` maps to under `satp`, as the hart would
+// translate it for supervisor; None if it is not mapped, or through an invalid entry. With satp
+// Bare the address maps to itself with every permission.
+pub fn translate(satp: usize, va: usize) -> Option<(usize, u64)> {
+    match satp >> 60 {
+        SATP_MODE_BARE => return Some((va, PTE_FLAGS)),
+        SATP_MODE_SV39 => {}
+        _ => return None,
+    }
+    if sign_extend(va) != va {
+        return None;
+    }
+    let mut table = (satp & SATP_PPN_MASK) * PAGE_SIZE;
+    for level in (0..LEVELS).rev() {
+        if !in_dram(table) {
+            return None;
+        }
+        let page_size = PAGE_SIZE << (9 * level);
+        let index = (va / page_size) % PTES_PER_TABLE;
+        let pte = read_physical_u64(table + index * 8)?;
+        if pte & PTE_V == 0 || pte & PTE_RESERVED != 0 || pte & (PTE_R | PTE_W | PTE_X) == PTE_W {
+            return None;
+        }
+        let pa = ((pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK) as usize * PAGE_SIZE;
+        if pte & (PTE_R | PTE_W | PTE_X) != 0 {
+            if pa % page_size != 0 {
+                return None;
+            }
+            return Some((pa + va % page_size, pte & PTE_FLAGS));
+        }
+        table = pa;
+    }
+    // non-leaf entry at the last level
+    None
+}
+
+// Leaf mappings [va, va + len) -> pa with the same flags, not printed yet
+struct Range {
+    va: usize,
+    pa: usize,
+    len: usize,
+    flags: u64,
+}
+
+#[derive(Default)]
+struct Walk {
+    pending: Option<Range>,
+    lines: usize,
+    suppressed: usize,
+    leaves: usize,
+    invalid: usize,
+}
+
+impl Walk {
+    // Walk table at physical `table` of given level, which maps virtual addresses from `va_base`
+    fn table(&mut self, table: usize, level: usize, va_base: usize) {
+        let page_size = PAGE_SIZE << (9 * level);
+        for index in 0..PTES_PER_TABLE {
+            let va = sign_extend(va_base + index * page_size);
+            let pte_addr = table + index * 8;
+            let pte = match read_physical_u64(pte_addr) {
+                Some(pte) => pte,
+                None => {
+                    self.report(format_args!(
+                        "read fault at page table entry {:#x}",
+                        pte_addr
+                    ));
+                    return;
+                }
+            };
+            if pte & PTE_V == 0 {
+                continue;
+            }
+            let pa = ((pte >> PTE_PPN_SHIFT) & PTE_PPN_MASK) as usize * PAGE_SIZE;
+            let leaf = pte & (PTE_R | PTE_W | PTE_X) != 0;
+            let problem = if pte & PTE_RESERVED != 0 {
+                Some("reserved bits set")
+            } else if pte & (PTE_R | PTE_W | PTE_X) == PTE_W {
+                Some("write without read")
+            } else if leaf && pa % page_size != 0 {
+                Some("misaligned superpage")
+            } else if !leaf && level == 0 {
+                Some("non-leaf entry at last level")
+            } else if !leaf && !in_dram(pa) {
+                Some("table pointer outside DRAM")
+            } else {
+                None
+            };
+            match problem {
+                Some(problem) => {
+                    self.invalid += 1;
+                    self.report(format_args!(
+                        "invalid entry {:#x} at {:#x} for va {:x}: {}",
+                        pte, pte_addr, va, problem
+                    ));
+                }
+                None if leaf => self.leaf(va, pa, page_size, pte & PTE_FLAGS),
+                None => self.table(pa, level - 1, va_base + index * page_size),
+            }
+        }
+    }
+
+    fn leaf(&mut self, va: usize, pa: usize, len: usize, flags: u64) {
+        self.leaves += 1;
+        if let Some(range) = &mut self.pending {
+            if range.va.wrapping_add(range.len) == va
+                && range.pa + range.len == pa
+                && range.flags == flags
+            {
+                range.len += len;
+                return;
+            }
+        }
+        self.flush();
+        self.pending = Some(Range { va, pa, len, flags });
+    }
+
+    fn flush(&mut self) {
+        if let Some(range) = self.pending.take() {
+            let end = range.va.wrapping_add(range.len);
+            let flags = Flags(range.flags);
+            self.print(format_args!(
+                "{:x}..{:x} -> {:x} {}",
+                range.va, end, range.pa, flags
+            ));
+        }
+    }
+
+    // An invalid entry or read fault, printed in order with the ranges before it
+    fn report(&mut self, args: core::fmt::Arguments) {
+        self.flush();
+        self.print(args);
+    }
+
+    fn print(&mut self, args: core::fmt::Arguments) {
+        if self.lines < MAX_LINES {
+            println!("[rustsbi] {}", args);
+            self.lines += 1;
+        } else {
+            self.suppressed += 1;
+        }
+    }
+}
+
+struct Flags(u64);
+
+impl core::fmt::Display for Flags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (bit, c) in "rwxugad".chars().enumerate() {
+            let c = if self.0 & 1 << (bit + 1) != 0 { c } else { '-' };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+// Sv39 virtual addresses are bits 63..39 copies of bit 38
+fn sign_extend(va: usize) -> usize {
+    let shift = usize::BITS - VA_BITS;
+    (((va << shift) as isize) >> shift) as usize
+}
+
+// Whether a page table at `pa` lies in DRAM opened to supervisor
+fn in_dram(pa: usize) -> bool {
+    pa >= crate::DRAM_PMP_START && pa + PAGE_SIZE <= crate::DRAM_PMP_END
+}
+
+// Load from physical address with machine privilege for MSTATUS.MPRV, None if it faults
+fn read_physical_u64(addr: usize) -> Option<u64> {
+    let saved = mstatus::read().mpp();
+    unsafe {
+        mstatus::set_mpp(MPP::Machine);
+        let value = get_vaddr_u64(addr);
+        mstatus::set_mpp(saved);
+        value
+    }
+}
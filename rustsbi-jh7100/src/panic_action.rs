@@ -0,0 +1,89 @@
+//! What a hart does after its panic report
+//!
+//! `board::PANIC_ACTION` chooses, unless a feature overrides it:
+//!
+//! | Action   | Feature        | After the report
+//! |:---------|:---------------|:-----------------
+//! | `Halt`   |                | halt the hart, waiting for interrupts with all of them disabled
+//! | `Reboot` | `panic-reboot` | wait `REBOOT_DELAY_US`, then warm reboot with reason system failure
+//! | `Shell`  | `panic-shell`  | run the maintenance shell on the console, to inspect the hart
+//!
+//! The report is flushed to UART before a reboot. JH7100 firmware cannot reset the system, see
+//! `reset`, so a reboot ends in the `system halted: code=1, type=warm reboot` sentinel line with
+//! every hart halted; a test runner power cycles the board on it. Only the first hart whose report
+//! was printed gets the shell, any other panicking hart halts.
+//!
+//! A hart panicking again inside the panic handler, e.g. while printing its report, takes no
+//! action: it leaves a single line, which does not wait for a console that stays locked, and halts.
+use crate::board::{PanicAction, PANIC_ACTION};
+use crate::peripheral::Clint;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[cfg(all(feature = "panic-reboot", feature = "panic-shell"))]
+compile_error!("features `panic-reboot` and `panic-shell` choose different panic actions");
+
+const ACTION: PanicAction = if cfg!(feature = "panic-reboot") {
+    PanicAction::Reboot
+} else if cfg!(feature = "panic-shell") {
+    PanicAction::Shell
+} else {
+    PANIC_ACTION
+};
+
+// Time to read the report on the console before reboot, 3s
+const REBOOT_DELAY_US: u64 = 3_000_000;
+
+// Bit `i` set once hart `i` has entered the panic handler
+static PANICKING_HARTS: AtomicUsize = AtomicUsize::new(0);
+// Set by the hart which runs the shell
+static SHELL_TAKEN: AtomicBool = AtomicBool::new(false);
+
+// Record that current hart entered the panic handler; returns false if it already had, that is
+// it panicked again while handling a panic
+pub fn enter(hart_id: usize) -> bool {
+    let bit = 1usize.checked_shl(hart_id as u32).unwrap_or(0);
+    PANICKING_HARTS.fetch_or(bit, Ordering::AcqRel) & bit == 0
+}
+
+// Take the panic action on current hart; `reported` is false if its report could not be started
+// and only a single line was printed
+pub fn run(hart_id: usize, reported: bool) -> ! {
+    match ACTION {
+        PanicAction::Halt => {}
+        PanicAction::Reboot => reboot(hart_id, reported),
+        PanicAction::Shell if reported && !SHELL_TAKEN.swap(true, Ordering::AcqRel) => shell(),
+        PanicAction::Shell => {}
+    }
+    crate::reset::halt()
+}
+
+fn reboot(hart_id: usize, reported: bool) {
+    use crate::reset::{HaltReset, RESET_REASON_SYSTEM_FAILURE, RESET_TYPE_WARM_REBOOT};
+    // without a report of its own, another hart may be printing one; do not wait on the console
+    if reported {
+        crate::println!(
+            "[rustsbi] hart {} reboots in {} ms",
+            hart_id,
+            REBOOT_DELAY_US / 1000
+        );
+        crate::console::flush();
+    }
+    Clint::new(0x2000000 as *mut u8).delay_us(REBOOT_DELAY_US);
+    // returns only for invalid parameters, which these are not
+    rustsbi::Reset::system_reset(
+        &HaltReset,
+        RESET_TYPE_WARM_REBOOT,
+        RESET_REASON_SYSTEM_FAILURE,
+    );
+}
+
+#[cfg(feature = "maintenance-mode")]
+fn shell() {
+    crate::maintenance::panic_shell()
+}
+
+#[cfg(not(feature = "maintenance-mode"))]
+fn shell() {
+    crate::println!("[rustsbi] panic action shell needs feature `maintenance-mode`, halting");
+    crate::console::flush();
+}
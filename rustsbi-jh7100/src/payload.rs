@@ -0,0 +1,96 @@
+//! Sanity check of the embedded payload
+//!
+//! The payload is copied as a raw binary to `LOAD_ADDRESS`, and boot hart enters supervisor at
+//! its first byte. A payload built for another entry, or an ELF file embedded without being
+//! converted to a raw binary, would fault on the first instruction with nothing on the console
+//! but a trap cause. Before the jump, the image is checked:
+//!
+//! - an ELF file is rejected, reporting its `e_entry`; `objcopy -O binary` turns it into a raw
+//!   binary whose entry, for a kernel linked at `LOAD_ADDRESS`, is its first byte;
+//! - for a raw binary, a warning is printed if the first instruction is not a plausible one,
+//!   i.e. an all-zero or all-ones word, a reserved compressed encoding, or an unknown major
+//!   opcode. Boot goes on, as a valid but unusual first instruction is not an error.
+use crate::decode;
+use crate::println;
+
+// Where the payload is copied to and entered, `payload_start` of the linker script
+pub const LOAD_ADDRESS: usize = 0x8020_0000;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_MACHINE_RISCV: u16 = 243;
+// offsets into the ELF header of a 64-bit file
+const ELF_OFFSET_CLASS: usize = 4;
+const ELF_OFFSET_DATA: usize = 5;
+const ELF_OFFSET_MACHINE: usize = 18;
+const ELF_OFFSET_ENTRY: usize = 24;
+
+// Major opcodes which a kernel's first instruction may have: RV64I, Zifencei and A
+const PLAUSIBLE_OPCODES: [u32; 14] = [
+    decode::OPCODE_LOAD,
+    0b000_1111, // MISC-MEM, fence and fence.i
+    decode::OPCODE_OP_IMM,
+    decode::OPCODE_AUIPC,
+    decode::OPCODE_OP_IMM_32,
+    decode::OPCODE_STORE,
+    decode::OPCODE_AMO,
+    decode::OPCODE_OP,
+    decode::OPCODE_LUI,
+    decode::OPCODE_OP_32,
+    decode::OPCODE_BRANCH,
+    decode::OPCODE_JALR,
+    decode::OPCODE_JAL,
+    decode::OPCODE_SYSTEM,
+];
+
+// Check payload `image` copied to `LOAD_ADDRESS`, returns the address supervisor is entered at
+pub fn check(image: &[u8]) -> usize {
+    if image.starts_with(ELF_MAGIC) {
+        match elf_entry(image) {
+            Some(entry) => panic!(
+                "payload is an ELF file with entry {:#x}, convert it to a raw binary linked at {:#x}",
+                entry, LOAD_ADDRESS
+            ),
+            None => panic!("payload is an ELF file, but not a 64-bit little endian RISC-V one"),
+        }
+    }
+    let first = match image {
+        [b0, b1, b2, b3, ..] => u32::from_le_bytes([*b0, *b1, *b2, *b3]),
+        [b0, b1] | [b0, b1, _] => u16::from_le_bytes([*b0, *b1]) as u32,
+        _ => panic!("payload is empty"),
+    };
+    if !is_plausible_instruction(first) {
+        println!(
+            "[rustsbi] warning: payload at {:#x} does not start with a valid instruction, first word {:#010x}",
+            LOAD_ADDRESS, first
+        );
+    }
+    LOAD_ADDRESS
+}
+
+// `e_entry` of a 64-bit little endian RISC-V ELF file
+fn elf_entry(image: &[u8]) -> Option<usize> {
+    let header = image.get(..ELF_OFFSET_ENTRY + 8)?;
+    let machine = u16::from_le_bytes([header[ELF_OFFSET_MACHINE], header[ELF_OFFSET_MACHINE + 1]]);
+    if header[ELF_OFFSET_CLASS] != ELF_CLASS_64
+        || header[ELF_OFFSET_DATA] != ELF_DATA_LITTLE_ENDIAN
+        || machine != ELF_MACHINE_RISCV
+    {
+        return None;
+    }
+    let mut entry = [0u8; 8];
+    entry.copy_from_slice(&header[ELF_OFFSET_ENTRY..ELF_OFFSET_ENTRY + 8]);
+    Some(u64::from_le_bytes(entry) as usize)
+}
+
+fn is_plausible_instruction(ins: u32) -> bool {
+    // both are defined illegal, and are what erased flash or zeroed memory reads as
+    if ins & 0xffff == 0 || ins == u32::MAX {
+        return false;
+    }
+    if decode::length(ins as u16) == 2 {
+        return decode::expand_compressed(ins as u16).is_some();
+    }
+    PLAUSIBLE_OPCODES.contains(&decode::opcode(ins))
+}
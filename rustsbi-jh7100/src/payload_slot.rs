@@ -0,0 +1,235 @@
+//! A/B payload slots
+//!
+//! With feature `payload-slots`, the SD card image may carry a second payload, and a flag in
+//! firmware scratch memory chooses which one boot hart copies to `payload::LOAD_ADDRESS`:
+//!
+//! - slot A is the payload embedded in firmware, booted by default;
+//! - slot B is written by `cargo xtask image --slot-b <file>` at `SLOT_B_IMAGE_OFFSET` of the
+//!   image, behind a 16-byte header: `SLOT_B_MAGIC`, then its length as a little endian u64. The
+//!   image is loaded to DRAM as a whole, thus firmware finds it at `stext + SLOT_B_IMAGE_OFFSET`.
+//!
+//! The flag lives in scratch slot `SLOT_PAYLOAD_SLOT`, which keeps it across a warm reset only.
+//! After power-on, when it reads as garbage, slot A is booted. Supervisor sets and clears it
+//! through vendor extension `EXTENSION_PAYLOAD_SLOT`:
+//!
+//! | Function | Parameters   | Returns
+//! |:---------|:-------------|:---------
+//! | 0        |              | slot booted this time, 0 for A and 1 for B
+//! | 1        |              | bitmask of slots present in the image, bit 0 for A and 1 for B
+//! | 2        | a0: slot     | 0; boot `slot` from the next warm reset on
+//! | 3        |              | 0; clear the count of unconfirmed boots, see below
+//! | 4        | a0: slot     | does not return; function 2, then warm reboot
+//!
+//! Functions 2 and 4 return `SBI_ERR_INVALID_PARAM` for a slot which is not in the image, and
+//! `SBI_ERR_FAILED` without scratch memory to keep the flag in.
+//!
+//! Slot B is booted on trial. Each boot of it counts one unconfirmed boot, which supervisor clears
+//! with function 3 once it is up. After `MAX_UNCONFIRMED_BOOTS` boots without that, the flag
+//! falls back to slot A, which is taken as the known good one and never counts. Selecting a slot
+//! by function 2 or 4 also clears the count. JH7100 firmware cannot reset the system, see
+//! `reset`: a reboot halts, and the flag takes effect on the following warm reset, e.g. by the
+//! reset button.
+use crate::payload;
+use crate::println;
+use crate::scratch::{self, SLOT_PAYLOAD_SLOT};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rustsbi::SbiRet;
+
+// both move data of the loaded image that this module expects at `stext + SLOT_B_IMAGE_OFFSET`
+#[cfg(feature = "dram-test")]
+compile_error!("feature `payload-slots` cannot be used with `dram-test`, which overwrites slot B");
+#[cfg(feature = "self-relocate")]
+compile_error!(
+    "feature `payload-slots` cannot be used with `self-relocate`, which leaves slot B behind"
+);
+
+// Where the slot B header is in the SD card image, and in DRAM from `stext`; firmware with the
+// embedded payload must end before it, and slot B before `SLOT_B_IMAGE_END`, which is
+// `firmware_tables::IMAGE_OFFSET`, followed by `initramfs::IMAGE_OFFSET`
+pub const SLOT_B_IMAGE_OFFSET: usize = 0x200_0000;
+const SLOT_B_IMAGE_END: usize = 0x3F0_0000;
+pub const SLOT_B_MAGIC: &[u8; 8] = b"RSBISLTB";
+const HEADER_SIZE: usize = 16;
+
+pub const SLOT_A: usize = 0;
+pub const SLOT_B: usize = 1;
+
+// Boots of slot B supervisor may leave unconfirmed before the flag falls back to slot A
+pub const MAX_UNCONFIRMED_BOOTS: u64 = 3;
+
+const FUNCTION_GET_BOOTED_SLOT: usize = 0x0;
+const FUNCTION_GET_PRESENT_SLOTS: usize = 0x1;
+const FUNCTION_SET_NEXT_SLOT: usize = 0x2;
+const FUNCTION_CONFIRM_BOOT: usize = 0x3;
+const FUNCTION_REBOOT_INTO_SLOT: usize = 0x4;
+
+// "RSBISLOT", the chance that DRAM after power-on holds it by accident is negligible
+const MAGIC: u64 = 0x5253_4249_534c_4f54;
+
+#[repr(C)]
+struct SlotState {
+    magic: u64,
+    // slot to boot on the next boot
+    next: u64,
+    // boots of `next` which supervisor did not confirm
+    unconfirmed: u64,
+}
+
+static BOOTED_SLOT: AtomicUsize = AtomicUsize::new(SLOT_A);
+// bitmask of slots present in the image, as function 1 returns
+static PRESENT_SLOTS: AtomicUsize = AtomicUsize::new(1 << SLOT_A);
+
+extern "C" {
+    static stext: u8;
+}
+
+// Choose the payload slot to boot, copy slot B over slot A at `payload::LOAD_ADDRESS` if it is
+// chosen, and update the flag for the next boot. Called once on boot hart after `scratch::init`,
+// with the length of slot A already copied; returns where the chosen payload is.
+pub fn load(slot_a_len: usize) -> core::ops::Range<usize> {
+    let slot_a = payload::LOAD_ADDRESS..payload::LOAD_ADDRESS + slot_a_len;
+    let slot_b = find_slot_b(slot_a.end);
+    if slot_b.is_some() {
+        PRESENT_SLOTS.fetch_or(1 << SLOT_B, Ordering::Relaxed);
+    }
+    let state = match unsafe { state() } {
+        Some(state) => state,
+        None => {
+            println!(
+                "[rustsbi] warning: no firmware scratch memory for payload slot flag, boot slot A"
+            );
+            return slot_a;
+        }
+    };
+    let (mut next, mut unconfirmed) = unsafe {
+        if core::ptr::read_volatile(&state.magic) == MAGIC {
+            (
+                core::ptr::read_volatile(&state.next) as usize,
+                core::ptr::read_volatile(&state.unconfirmed),
+            )
+        } else {
+            (SLOT_A, 0)
+        }
+    };
+    if next != SLOT_A && (next != SLOT_B || slot_b.is_none()) {
+        println!(
+            "[rustsbi] warning: payload slot {} is not in the image, boot slot A",
+            next
+        );
+        next = SLOT_A;
+        unconfirmed = 0;
+    }
+    if next == SLOT_B {
+        if unconfirmed >= MAX_UNCONFIRMED_BOOTS {
+            println!(
+                "[rustsbi] warning: payload slot B not confirmed after {} boots, fall back to slot A",
+                unconfirmed
+            );
+            next = SLOT_A;
+            unconfirmed = 0;
+        } else {
+            unconfirmed += 1;
+        }
+    }
+    unsafe { write_state(state, next, unconfirmed) };
+    BOOTED_SLOT.store(next, Ordering::Relaxed);
+    let (source, len) = match slot_b {
+        Some(slot_b) if next == SLOT_B => slot_b,
+        _ => {
+            println!("[rustsbi] payload slot: A");
+            return slot_a;
+        }
+    };
+    // source and destination may overlap
+    unsafe { core::ptr::copy(source as *const u8, payload::LOAD_ADDRESS as *mut u8, len) };
+    payload::check(unsafe { core::slice::from_raw_parts(payload::LOAD_ADDRESS as *const u8, len) });
+    println!(
+        "[rustsbi] payload slot: B, {:#x} bytes, boot {} of {} before fall back",
+        len, unconfirmed, MAX_UNCONFIRMED_BOOTS
+    );
+    payload::LOAD_ADDRESS..payload::LOAD_ADDRESS + len
+}
+
+// Start and length of slot B in the loaded image, None if the image has none
+fn find_slot_b(slot_a_end: usize) -> Option<(usize, usize)> {
+    let header = unsafe { &stext as *const u8 as usize } + SLOT_B_IMAGE_OFFSET;
+    // slot A has been copied already, over anything here; xtask refuses to add slot B to a
+    // firmware image this large
+    if slot_a_end > header {
+        return None;
+    }
+    let len = unsafe {
+        let magic = core::slice::from_raw_parts(header as *const u8, SLOT_B_MAGIC.len());
+        if magic != SLOT_B_MAGIC {
+            return None;
+        }
+        core::ptr::read_unaligned((header + 8) as *const u64) as usize
+    };
+    if len == 0 || len > SLOT_B_IMAGE_END - SLOT_B_IMAGE_OFFSET - HEADER_SIZE {
+        println!(
+            "[rustsbi] warning: payload slot B length {:#x} in image header is invalid, ignored",
+            len
+        );
+        return None;
+    }
+    Some((header + HEADER_SIZE, len))
+}
+
+// Safety: the slot is only written by boot hart during boot, then under a single ecall at a time
+// from supervisor, which owns the flag
+unsafe fn state() -> Option<&'static mut SlotState> {
+    // slots are aligned to their offset in 2MiB aligned scratch region
+    scratch::slot(SLOT_PAYLOAD_SLOT).map(|buf| &mut *(buf.as_mut_ptr() as *mut SlotState))
+}
+
+unsafe fn write_state(state: &mut SlotState, next: usize, unconfirmed: u64) {
+    core::ptr::write_volatile(&mut state.magic, MAGIC);
+    core::ptr::write_volatile(&mut state.next, next as u64);
+    core::ptr::write_volatile(&mut state.unconfirmed, unconfirmed);
+}
+
+// Handler of vendor extension `EXTENSION_PAYLOAD_SLOT`
+pub fn handle_ecall(function: usize, param: [usize; 6]) -> SbiRet {
+    match function {
+        FUNCTION_GET_BOOTED_SLOT => SbiRet::ok(BOOTED_SLOT.load(Ordering::Relaxed)),
+        FUNCTION_GET_PRESENT_SLOTS => SbiRet::ok(PRESENT_SLOTS.load(Ordering::Relaxed)),
+        FUNCTION_SET_NEXT_SLOT => set_next_slot(param[0]),
+        FUNCTION_CONFIRM_BOOT => confirm_boot(),
+        FUNCTION_REBOOT_INTO_SLOT => {
+            let ret = set_next_slot(param[0]);
+            if ret.error != 0 {
+                return ret;
+            }
+            println!("[rustsbi] reboot into payload slot {}", param[0]);
+            rustsbi::Reset::system_reset(
+                &crate::reset::HaltReset,
+                crate::reset::RESET_TYPE_WARM_REBOOT,
+                0,
+            )
+        }
+        _ => SbiRet::not_supported(),
+    }
+}
+
+fn set_next_slot(slot: usize) -> SbiRet {
+    if slot >= usize::BITS as usize || PRESENT_SLOTS.load(Ordering::Relaxed) & 1 << slot == 0 {
+        return SbiRet::invalid_param();
+    }
+    match unsafe { state() } {
+        Some(state) => {
+            unsafe { write_state(state, slot, 0) };
+            SbiRet::ok(0)
+        }
+        None => SbiRet::failed(),
+    }
+}
+
+fn confirm_boot() -> SbiRet {
+    match unsafe { state() } {
+        Some(state) => {
+            unsafe { core::ptr::write_volatile(&mut state.unconfirmed, 0) };
+            SbiRet::ok(0)
+        }
+        None => SbiRet::failed(),
+    }
+}
@@ -1,37 +1,165 @@
+use super::mmio::Mmio;
+use super::split::read_split_u64;
+use crate::board::MTIMECMP_WRITE;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// Per-hart doorbell flags, rung by `send_ipi_many` before the software interrupt is raised
+#[cfg(feature = "ipi-doorbell")]
+const DOORBELL_IDLE: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "ipi-doorbell")]
+static DOORBELL: [AtomicBool; crate::NUM_HARTS] = [DOORBELL_IDLE; crate::NUM_HARTS];
+
+// Take the doorbell of given hart; returns true if it was rung since last taken
+#[cfg(feature = "ipi-doorbell")]
+pub fn take_doorbell(hart_id: usize) -> bool {
+    DOORBELL[hart_id].swap(false, Ordering::AcqRel)
+}
+
+// `timebase-frequency` of JH7100 in device tree, used until the device tree is parsed
+pub const DEFAULT_TIMEBASE_FREQUENCY: u64 = 6_250_000;
+
+// mtime frequency in Hz, set once on boot hart from a validated device tree value
+static TIMEBASE_FREQUENCY: AtomicU64 = AtomicU64::new(DEFAULT_TIMEBASE_FREQUENCY);
+// Whether `TIMEBASE_FREQUENCY` is settled; until then `delay_us` uses the calibrated loop.
+// Kept in .data, as delays run before boot hart clears .bss
+#[link_section = ".data.timebase_known"]
+static TIMEBASE_KNOWN: AtomicBool = AtomicBool::new(false);
+
+// mtime frequency in Hz; every tick and time conversion goes through it
+pub fn timebase_frequency() -> u64 {
+    TIMEBASE_FREQUENCY.load(Ordering::Relaxed)
+}
+
+// Set mtime frequency; caller has checked it against `device_tree::TIMEBASE_FREQUENCY_RANGE`.
+// From now on, delays are measured on mtime.
+pub fn set_timebase_frequency(frequency: u64) {
+    TIMEBASE_FREQUENCY.store(frequency, Ordering::Relaxed);
+    TIMEBASE_KNOWN.store(true, Ordering::Release);
+}
+
+// Delay loop iterations per microsecond, 0 until calibrated or if calibration failed
+#[link_section = ".data.delay_loops_per_us"]
+static DELAY_LOOPS_PER_US: AtomicU64 = AtomicU64::new(0);
+
+// Used if mtime does not advance during calibration: one iteration per cycle at 1.5GHz, faster
+// than U74 cores of JH7100 run the loop, so that a delay is never shorter than asked for
+const FALLBACK_DELAY_LOOPS_PER_US: u64 = 1_500;
+// Iterations of the calibration window, about 100us on a 1GHz core
+const CALIBRATION_LOOPS: u64 = 100_000;
+// Fewer ticks measured over the window are too coarse to derive a rate from
+const MIN_CALIBRATION_TICKS: u64 = 64;
+
+// Busy loop of given iterations; never inlined, so calibration measures the same code delays run
+#[inline(never)]
+fn delay_loop(loops: u64) {
+    for _ in 0..loops {
+        core::hint::spin_loop();
+    }
+}
+
+// Calibrated delay loop iterations per microsecond, or None if `FALLBACK_DELAY_LOOPS_PER_US`
+// is used
+pub fn delay_loops_per_us() -> Option<u64> {
+    match DELAY_LOOPS_PER_US.load(Ordering::Relaxed) {
+        0 => None,
+        loops => Some(loops),
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct Clint {
-    base: *mut u8,
+    msip: Mmio<u32>,
+    mtimecmp: Mmio<u64>,
+    // low and high halves of each hart's mtimecmp, for `MtimecmpWrite::Split`
+    mtimecmp_lo: Mmio<u32>,
+    mtimecmp_hi: Mmio<u32>,
+    // mtime as its two 32-bit halves, see `get_mtime`
+    mtime: Mmio<u32>,
 }
 
-unsafe impl Send for Clint {}
-unsafe impl Sync for Clint {}
-
 #[allow(unused)]
 impl Clint {
     pub fn new(base: *mut u8) -> Clint {
-        Clint { base }
+        let base = base as usize;
+        unsafe {
+            Clint {
+                msip: Mmio::new(base),
+                mtimecmp: Mmio::new(base + 0x4000),
+                mtimecmp_lo: Mmio::with_stride(base + 0x4000, 8),
+                mtimecmp_hi: Mmio::with_stride(base + 0x4004, 8),
+                mtime: Mmio::new(base + 0xbff8),
+            }
+        }
     }
 
+    // Read mtime as two 32-bit halves; correct on split-register CLINTs as well, where a single
+    // 64-bit load may tear when the low half rolls over
     pub fn get_mtime(&self) -> u64 {
-        unsafe { core::ptr::read_volatile(self.base.offset(0xbff8) as *mut u64) }
+        read_split_u64(|| self.mtime.read(1), || self.mtime.read(0))
     }
 
-    pub fn set_timer(&self, hart_id: usize, instant: u64) {
-        unsafe {
-            core::ptr::write_volatile((self.base.offset(0x4000) as *mut u64).add(hart_id), instant);
+    // mtime ticks in given microseconds
+    pub fn us_to_ticks(&self, us: u64) -> u64 {
+        us * timebase_frequency() / 1_000_000
+    }
+
+    // Measure delay loop iterations per microsecond against mtime; called once on boot hart
+    // before its first delay. The window is converted with the default timebase, the only one
+    // known this early; if mtime does not advance, `FALLBACK_DELAY_LOOPS_PER_US` is used instead.
+    pub fn calibrate_delay(&self) {
+        let start = self.get_mtime();
+        delay_loop(CALIBRATION_LOOPS);
+        let ticks = self.get_mtime().wrapping_sub(start);
+        // mtime stopped, not yet clocked, or wrapped: leave it uncalibrated
+        let loops_per_us = if (MIN_CALIBRATION_TICKS..u64::MAX / 1_000_000).contains(&ticks) {
+            (CALIBRATION_LOOPS * DEFAULT_TIMEBASE_FREQUENCY / (ticks * 1_000_000)).max(1)
+        } else {
+            0
+        };
+        DELAY_LOOPS_PER_US.store(loops_per_us, Ordering::Relaxed);
+    }
+
+    // Busy wait; on mtime once timebase frequency is settled, otherwise on the calibrated delay
+    // loop. Usable from reset on, as CLINT needs no initialization
+    pub fn delay_us(&self, us: u64) {
+        if !TIMEBASE_KNOWN.load(Ordering::Acquire) {
+            let loops_per_us = match DELAY_LOOPS_PER_US.load(Ordering::Relaxed) {
+                0 => FALLBACK_DELAY_LOOPS_PER_US,
+                loops => loops,
+            };
+            delay_loop(us.saturating_mul(loops_per_us));
+            return;
+        }
+        let deadline = self.get_mtime() + self.us_to_ticks(us);
+        while self.get_mtime() < deadline {
+            core::hint::spin_loop();
         }
     }
 
+    // Program mtimecmp of given hart as `board::MTIMECMP_WRITE` says the CLINT needs
+    pub fn set_timer(&self, hart_id: usize, instant: u64) {
+        MTIMECMP_WRITE.write(
+            instant,
+            |value| self.mtimecmp.write(hart_id, value),
+            |hi| self.mtimecmp_hi.write(hart_id, hi),
+            |lo| self.mtimecmp_lo.write(hart_id, lo),
+        )
+    }
+
+    pub fn get_timer(&self, hart_id: usize) -> u64 {
+        MTIMECMP_WRITE.read(
+            || self.mtimecmp.read(hart_id),
+            || self.mtimecmp_hi.read(hart_id),
+            || self.mtimecmp_lo.read(hart_id),
+        )
+    }
+
     pub fn send_soft(&self, hart_id: usize) {
-        unsafe {
-            core::ptr::write_volatile((self.base as *mut u32).add(hart_id), 1);
-        }
+        self.msip.write(hart_id, 1);
     }
 
     pub fn clear_soft(&self, hart_id: usize) {
-        unsafe {
-            core::ptr::write_volatile((self.base as *mut u32).add(hart_id), 0);
-        }
+        self.msip.write(hart_id, 0);
     }
 }
 
@@ -43,6 +171,9 @@ impl rustsbi::Ipi for Clint {
     fn send_ipi_many(&self, hart_mask: rustsbi::HartMask) -> rustsbi::SbiRet {
         for i in 0..=self.max_hart_id() {
             if hart_mask.has_bit(i) {
+                // ring the doorbell before the interrupt, so the target always sees it
+                #[cfg(feature = "ipi-doorbell")]
+                DOORBELL[i].store(true, Ordering::Release);
                 self.send_soft(i);
             }
         }
@@ -51,8 +182,12 @@ impl rustsbi::Ipi for Clint {
 }
 
 impl rustsbi::Timer for Clint {
+    // Order matters: drop the relayed supervisor timer, program `mtimecmp`, then enable machine
+    // timer, all done by `timer`. A pending supervisor timer of the previous deadline is never
+    // delivered late; a deadline already passed is pending when the ecall returns.
     fn set_timer(&self, time_value: u64) {
-        let this_mhartid = riscv::register::mhartid::read();
-        self.set_timer(this_mhartid, time_value);
+        let this_mhartid = crate::execute::calling_hart();
+        // `mtimecmp` may be shared with firmware deadlines, see `timer`
+        crate::timer::set_supervisor_deadline(this_mhartid, time_value);
     }
 }
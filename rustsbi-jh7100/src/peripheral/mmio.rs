@@ -0,0 +1,132 @@
+//! Memory mapped register blocks
+//!
+//! `Mmio<T>` is an array of device registers of width `T` starting at a base address, register
+//! `index` at `base + index * stride`. Every access is volatile and of exactly `T`'s width; a
+//! stride larger than the width covers blocks like the 8250 UART, whose byte registers are 4
+//! bytes apart. Drivers keep register numbers as in their datasheets and leave the address
+//! arithmetic here. Its tests run on the host over plain arrays, see `lib.rs`.
+use core::marker::PhantomData;
+use core::ptr::{read_volatile, write_volatile};
+
+// Register widths the harts can access in one load or store
+pub trait Register: Copy + private::Sealed {}
+impl Register for u8 {}
+impl Register for u32 {}
+impl Register for u64 {}
+
+mod private {
+    pub trait Sealed {}
+    impl Sealed for u8 {}
+    impl Sealed for u32 {}
+    impl Sealed for u64 {}
+}
+
+#[derive(Clone, Copy)]
+pub struct Mmio<T> {
+    base: usize,
+    stride: usize,
+    _register: PhantomData<T>,
+}
+
+// Device registers are reachable from every hart
+unsafe impl<T> Send for Mmio<T> {}
+unsafe impl<T> Sync for Mmio<T> {}
+
+impl<T> Mmio<T> {
+    // Registers packed back to back, stride of their own width.
+    // Safety: `base` must be a register block of `T` that every index used stays within
+    pub const unsafe fn new(base: usize) -> Self {
+        Self::with_stride(base, core::mem::size_of::<T>())
+    }
+
+    // Registers `stride` bytes apart; `stride` must be a multiple of `T`'s width.
+    // Safety: as for `new`
+    pub const unsafe fn with_stride(base: usize, stride: usize) -> Self {
+        Mmio {
+            base,
+            stride,
+            _register: PhantomData,
+        }
+    }
+
+    // Register block starting `offset` bytes further, with the same stride
+    pub const fn offset(&self, offset: usize) -> Self {
+        Mmio {
+            base: self.base + offset,
+            stride: self.stride,
+            _register: PhantomData,
+        }
+    }
+}
+
+impl<T: Register> Mmio<T> {
+    #[inline]
+    fn address(&self, index: usize) -> usize {
+        self.base + index * self.stride
+    }
+
+    #[inline]
+    pub fn read(&self, index: usize) -> T {
+        unsafe { read_volatile(self.address(index) as *const T) }
+    }
+
+    #[inline]
+    pub fn write(&self, index: usize, value: T) {
+        unsafe { write_volatile(self.address(index) as *mut T, value) }
+    }
+
+    // Read, change and write back; not atomic against other harts or the device itself
+    #[inline]
+    pub fn modify(&self, index: usize, f: impl FnOnce(T) -> T) {
+        self.write(index, f(self.read(index)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_registers_with_stride() {
+        // 8250 style: byte registers 4 bytes apart
+        let mut block = [0u8; 16];
+        let regs = unsafe { Mmio::<u8>::with_stride(block.as_mut_ptr() as usize, 4) };
+        regs.write(1, 0xA5);
+        regs.write(3, 0x5A);
+        regs.modify(1, |value| value | 0x0F);
+        assert_eq!(regs.read(1), 0xAF);
+        assert_eq!(regs.read(3), 0x5A);
+        let mut expected = [0u8; 16];
+        expected[4] = 0xAF;
+        expected[12] = 0x5A;
+        assert_eq!(block, expected);
+    }
+
+    #[test]
+    fn word_registers_packed() {
+        let mut block = [0u32; 4];
+        let regs = unsafe { Mmio::<u32>::new(block.as_mut_ptr() as usize) };
+        regs.write(0, 0x1234_5678);
+        regs.write(2, 0xDEAD_BEEF);
+        regs.modify(2, |value| value & 0xFFFF);
+        assert_eq!(regs.read(0), 0x1234_5678);
+        assert_eq!(regs.read(2), 0xBEEF);
+        assert_eq!(block, [0x1234_5678, 0, 0xBEEF, 0]);
+    }
+
+    #[test]
+    fn double_word_registers_with_offset() {
+        // two 64-bit registers 16 bytes apart, starting 8 bytes into the block
+        let mut block = [0u64; 6];
+        let regs = unsafe { Mmio::<u64>::with_stride(block.as_mut_ptr() as usize, 16) }.offset(8);
+        regs.write(0, u64::MAX);
+        regs.write(2, 0x1_0000_0001);
+        regs.modify(0, |value| value - 1);
+        assert_eq!(regs.read(0), u64::MAX - 1);
+        assert_eq!(block, [0, u64::MAX - 1, 0, 0, 0, 0x1_0000_0001]);
+        // an offset keeps the stride
+        let next = regs.offset(16);
+        assert_eq!(next.read(0), 0);
+        assert_eq!(next.read(1), 0x1_0000_0001);
+    }
+}
@@ -1,5 +1,16 @@
+mod mmio;
+pub use mmio::Mmio;
 #[doc(hidden)]
 pub(crate) mod uart;
-pub use uart::Uart;
+pub use uart::{LineSettings, Uart, UartConfig};
 mod clint;
+#[cfg(feature = "ipi-doorbell")]
+pub use clint::take_doorbell;
 pub use clint::Clint;
+pub use clint::{
+    delay_loops_per_us, set_timebase_frequency, timebase_frequency, DEFAULT_TIMEBASE_FREQUENCY,
+};
+mod plic;
+pub use plic::Plic;
+mod split;
+pub use split::MtimecmpWrite;
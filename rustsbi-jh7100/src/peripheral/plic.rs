@@ -0,0 +1,23 @@
+use super::mmio::Mmio;
+
+#[derive(Clone, Copy)]
+pub struct Plic {
+    base: Mmio<u32>,
+}
+
+#[allow(unused)]
+impl Plic {
+    pub fn new(base: *mut u8) -> Plic {
+        Plic {
+            base: unsafe { Mmio::new(base as usize) },
+        }
+    }
+
+    // Disable interrupt sources 0..num_sources for given context
+    pub fn disable_all(&self, context: usize, num_sources: usize) {
+        let enable = self.base.offset(0x2000 + context * 0x80);
+        for word in 0..(num_sources + 31) / 32 {
+            enable.write(word, 0);
+        }
+    }
+}
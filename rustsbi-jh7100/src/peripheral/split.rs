@@ -0,0 +1,170 @@
+//! 64-bit registers accessed as two 32-bit halves
+//!
+//! Some CLINTs split mtime and mtimecmp into 32-bit registers, others tear a 64-bit access when
+//! the low half rolls over. These helpers order the half accesses so that neither tears; the
+//! registers are reached through closures, thus the order is tested on the host, see `lib.rs`.
+
+// How mtimecmp is written, chosen by `board::MTIMECMP_WRITE`
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MtimecmpWrite {
+    // one 64-bit store, for CLINTs which update the whole register at once
+    Single,
+    // two 32-bit stores, for CLINTs whose registers are split in halves: high half to all ones,
+    // then low half, then high half
+    Split,
+}
+
+impl MtimecmpWrite {
+    // Write `value` with `write` as a whole, or with `write_hi` and `write_lo` as halves
+    pub fn write(
+        self,
+        value: u64,
+        write: impl Fn(u64),
+        write_hi: impl Fn(u32),
+        write_lo: impl Fn(u32),
+    ) {
+        match self {
+            MtimecmpWrite::Single => write(value),
+            MtimecmpWrite::Split => write_split_u64(value, write_hi, write_lo),
+        }
+    }
+
+    // Read a value written by `write`, as a whole or as halves
+    pub fn read(
+        self,
+        read: impl Fn() -> u64,
+        read_hi: impl Fn() -> u32,
+        read_lo: impl Fn() -> u32,
+    ) -> u64 {
+        match self {
+            MtimecmpWrite::Single => read(),
+            MtimecmpWrite::Split => read_split_u64(read_hi, read_lo),
+        }
+    }
+}
+
+// Read high half, low half, then high half again; retry if high half changed in between
+pub fn read_split_u64(read_hi: impl Fn() -> u32, read_lo: impl Fn() -> u32) -> u64 {
+    loop {
+        let hi = read_hi();
+        let lo = read_lo();
+        if read_hi() == hi {
+            return ((hi as u64) << 32) | lo as u64;
+        }
+    }
+}
+
+// Write high half to its maximum, low half, then high half; the compare value never drops below
+// both the old and the new one on the way, thus no timer interrupt is raised in between
+pub fn write_split_u64(value: u64, write_hi: impl Fn(u32), write_lo: impl Fn(u32)) {
+    write_hi(u32::MAX);
+    write_lo(value as u32);
+    write_hi((value >> 32) as u32);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    // High half reads of a counter rolling over from 0x1_FFFF_FFFF to 0x2_0000_0000 between
+    // the first high half read and the low half read
+    #[test]
+    fn read_retries_across_rollover() {
+        let hi_reads = [0x1, 0x2, 0x2, 0x2];
+        let lo_reads = [0x0000_0000, 0x0000_0001];
+        let (hi, lo) = (Cell::new(0), Cell::new(0));
+        let value = read_split_u64(
+            || {
+                hi.set(hi.get() + 1);
+                hi_reads[hi.get() - 1]
+            },
+            || {
+                lo.set(lo.get() + 1);
+                lo_reads[lo.get() - 1]
+            },
+        );
+        // 0x1_0000_0000 would be the torn value of the first attempt
+        assert_eq!(value, 0x2_0000_0001);
+        assert_eq!((hi.get(), lo.get()), (4, 2));
+    }
+
+    #[test]
+    fn read_without_rollover() {
+        let hi = Cell::new(0);
+        let value = read_split_u64(
+            || {
+                hi.set(hi.get() + 1);
+                0x1
+            },
+            || 0xFFFF_FFFF,
+        );
+        assert_eq!(value, 0x1_FFFF_FFFF);
+        assert_eq!(hi.get(), 2);
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Access {
+        Whole(u64),
+        Hi(u32),
+        Lo(u32),
+    }
+
+    // Register accesses in the order they were made, at most 4
+    struct Log {
+        accesses: Cell<[Option<Access>; 4]>,
+        len: Cell<usize>,
+    }
+
+    impl Log {
+        fn new() -> Self {
+            Log {
+                accesses: Cell::new([None; 4]),
+                len: Cell::new(0),
+            }
+        }
+
+        fn push(&self, access: Access) {
+            let mut accesses = self.accesses.get();
+            accesses[self.len.get()] = Some(access);
+            self.accesses.set(accesses);
+            self.len.set(self.len.get() + 1);
+        }
+    }
+
+    #[test]
+    fn split_write_order() {
+        let log = Log::new();
+        MtimecmpWrite::Split.write(
+            0x1234_5678_9ABC_DEF0,
+            |value| log.push(Access::Whole(value)),
+            |hi| log.push(Access::Hi(hi)),
+            |lo| log.push(Access::Lo(lo)),
+        );
+        assert_eq!(
+            log.accesses.get(),
+            [
+                Some(Access::Hi(u32::MAX)),
+                Some(Access::Lo(0x9ABC_DEF0)),
+                Some(Access::Hi(0x1234_5678)),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn single_write() {
+        let log = Log::new();
+        MtimecmpWrite::Single.write(
+            0x1234_5678_9ABC_DEF0,
+            |value| log.push(Access::Whole(value)),
+            |hi| log.push(Access::Hi(hi)),
+            |lo| log.push(Access::Lo(lo)),
+        );
+        assert_eq!(
+            log.accesses.get(),
+            [Some(Access::Whole(0x1234_5678_9ABC_DEF0)), None, None, None]
+        );
+    }
+}
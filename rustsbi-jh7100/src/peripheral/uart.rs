@@ -1,7 +1,112 @@
+use super::mmio::Mmio;
 use core::convert::Infallible;
-use core::ptr::{read_volatile, write_volatile};
 use embedded_hal::serial::{Read, Write};
 
+// How firmware drives the console UART, as device tree describes an 8250 compatible one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UartConfig {
+    pub base: usize,
+    // register `i` is at `base + (i << reg_shift)`, accessed `reg_io_width` bytes wide
+    pub reg_shift: u32,
+    pub reg_io_width: u32,
+    // input clock in Hz
+    pub clock: u32,
+    // baud rate programmed by the previous boot stage, None if the divisor is zero
+    pub baud: Option<u32>,
+}
+
+// Parity of a UART line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+// Line settings of the console UART, as the options of `stdout-path` give them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineSettings {
+    pub baud: u32,
+    pub parity: Parity,
+    pub data_bits: u32,
+}
+
+impl LineSettings {
+    // What console UART is assumed to run at when `stdout-path` has no valid options
+    pub const DEFAULT: LineSettings = LineSettings {
+        baud: UART_BUADRATE_32MCLK_115200 as u32,
+        parity: Parity::None,
+        data_bits: 8,
+    };
+
+    // Parse options after the colon of `stdout-path`, `<baud>[<parity>[<bits>[<flow>]]]` as Linux
+    // `uart_parse_options` reads them, e.g. `115200n8`. Parity is `n`, `o` or `e`, and bits are
+    // `5` to `8`; omitted ones are no parity and 8 bits. Flow control `r` is accepted and ignored,
+    // firmware does not wire RTS and CTS. There is no stop bit option, one stop bit is used.
+    //
+    // For example `115200` and `115200n8` give the default, `9600e7` 9600 baud with even parity
+    // and 7 bits; `n8`, `115200x8`, `115200n9`, `115200n8rx` and a rate the UART clock cannot
+    // divide down to are errors.
+    pub fn parse(options: &str) -> Result<LineSettings, &'static str> {
+        let digits = options
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(options.len());
+        let (baud, rest) = options.split_at(digits);
+        let baud = match baud.parse::<u32>() {
+            Ok(baud) if baud != 0 && divisor(baud).is_some() => baud,
+            Ok(_) => return Err("baud rate out of range"),
+            Err(_) => return Err("no baud rate"),
+        };
+        let mut rest = rest.bytes();
+        let parity = match rest.next() {
+            None | Some(b'n') => Parity::None,
+            Some(b'o') => Parity::Odd,
+            Some(b'e') => Parity::Even,
+            Some(_) => return Err("invalid parity"),
+        };
+        let data_bits = match rest.next() {
+            None => 8,
+            Some(bits @ b'5'..=b'8') => (bits - b'0') as u32,
+            Some(_) => return Err("invalid data bits"),
+        };
+        match (rest.next(), rest.next()) {
+            (None, _) | (Some(b'r'), None) => Ok(LineSettings {
+                baud,
+                parity,
+                data_bits,
+            }),
+            _ => Err("invalid flow control"),
+        }
+    }
+}
+
+impl core::fmt::Display for LineSettings {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let parity = match self.parity {
+            Parity::None => 'n',
+            Parity::Odd => 'o',
+            Parity::Even => 'e',
+        };
+        write!(f, "{}{}{}", self.baud, parity, self.data_bits)
+    }
+}
+
+// Divisor latch value for a baud rate, rounded to nearest; None if it does not fit the latch.
+// Computed in u64, as `16 * baud` overflows u32 for rates above 268 Mbaud
+fn divisor(baud: u32) -> Option<u32> {
+    let (clock, baud) = (UART_CLK as u64, baud as u64);
+    match (clock + 8 * baud).checked_div(16 * baud)? {
+        0 => None,
+        divisor if divisor > 0xffff => None,
+        divisor => Some(divisor as u32),
+    }
+}
+
+// Rates a divisor is matched against, so that a rounded divisor reports the rate it was set for
+const STANDARD_BAUD_RATES: [u32; 9] = [
+    9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600, 1500000,
+];
+
 // UART that is initialized by prior steps of bootloading
 #[derive(Clone, Copy)]
 pub struct Uart {
@@ -18,6 +123,66 @@ impl Uart {
         // Uart is inited at DDRinit
         Self { pre_byte: 0 }
     }
+
+    // Whether a break condition was received since last checked; reading line status clears it
+    #[inline]
+    pub fn take_break(&self) -> bool {
+        UART0.read(REG_LSR) & LSR_BI != 0
+    }
+
+    // Parameters of console UART; the baud rate is read back from its divisor latch.
+    // Waits for the transmitter to drain, as the divisor is not accessible while it is busy
+    pub fn config(&self) -> UartConfig {
+        while UART0.read(REG_LSR) & LSR_TEMT == 0 {
+            core::hint::spin_loop();
+        }
+        let lcr = UART0.read(REG_LCR);
+        UART0.write(REG_LCR, lcr | LCR_DLAB);
+        let divisor = UART0.read(REG_BRDL) & 0xff | (UART0.read(REG_BRDH) & 0xff) << 8;
+        UART0.write(REG_LCR, lcr);
+        let clock = UART_CLK as u32;
+        let baud = match divisor {
+            0 => None,
+            divisor => Some(
+                STANDARD_BAUD_RATES
+                    .into_iter()
+                    .find(|rate| (clock + 8 * rate) / (16 * rate) == divisor)
+                    .unwrap_or(clock / (16 * divisor)),
+            ),
+        };
+        UartConfig {
+            base: UART_BASE,
+            reg_shift: 2,
+            reg_io_width: 4,
+            clock,
+            baud,
+        }
+    }
+
+    // Program baud rate, parity and data bits, with one stop bit. Waits for the transmitter to
+    // drain, so that no byte on its way is sent with the new settings
+    pub fn set_line(&self, settings: &LineSettings) {
+        let divisor = divisor(settings.baud).expect("baud rate checked when parsed");
+        while UART0.read(REG_LSR) & LSR_TEMT == 0 {
+            core::hint::spin_loop();
+        }
+        let parity = match settings.parity {
+            Parity::None => LCR_PDIS,
+            Parity::Odd => LCR_PEN,
+            Parity::Even => LCR_PEN | LCR_EPS,
+        };
+        let lcr = (settings.data_bits - 5) | parity;
+        UART0.write(REG_LCR, lcr | LCR_DLAB);
+        UART0.write(REG_BRDL, divisor & 0xff);
+        UART0.write(REG_BRDH, divisor >> 8 & 0xff);
+        UART0.write(REG_LCR, lcr);
+    }
+
+    // Firmware polls UART, its interrupts are never used
+    #[inline]
+    pub fn disable_interrupts(&self) {
+        UART0.write(REG_IER, 0);
+    }
 }
 
 // Ref: JH7100-secondBoot
@@ -27,30 +192,43 @@ impl Read<u8> for Uart {
 
     #[inline]
     fn read(&mut self) -> nb::Result<u8, Self::Error> {
-        if serial_in(REG_LSR) & (1 << 0) != 0 {
-            Ok(serial_in(REG_RDR) as u8)
+        if UART0.read(REG_LSR) & (1 << 0) != 0 {
+            Ok(UART0.read(REG_RDR) as u8)
         } else {
             Err(nb::Error::WouldBlock)
         }
     }
 }
 
+// THR takes a byte only while LSR.THRE is set: with FIFO disabled, as a bootrom may leave it, a
+// byte written to a full holding register is lost. With FIFO enabled, THRE means the FIFO is
+// empty; waiting for it sends a byte at a time but is correct either way, without reading back
+// the FIFO state, which FCR does not allow. `write` returns `WouldBlock` until THRE is set; on
+// the fast path, THRE already set, it costs one LSR read per byte.
 impl Write<u8> for Uart {
     type Error = Infallible;
 
     #[inline]
     fn write(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+        if UART0.read(REG_LSR) & LSR_THRE == 0 {
+            return Err(nb::Error::WouldBlock);
+        }
         if byte == '\n' as u8 && self.pre_byte != '\r' as u8 {
-            serial_out(REG_THR, '\r' as u32);
+            UART0.write(REG_THR, '\r' as u32);
+            // the newline goes once THRE is set again, pre_byte keeps the CR from being repeated
+            self.pre_byte = '\r' as u8;
+            if UART0.read(REG_LSR) & LSR_THRE == 0 {
+                return Err(nb::Error::WouldBlock);
+            }
         }
-        serial_out(REG_THR, byte as u32);
+        UART0.write(REG_THR, byte as u32);
         self.pre_byte = byte;
         Ok(())
     }
 
     #[inline]
     fn flush(&mut self) -> nb::Result<(), Infallible> {
-        if (serial_in(REG_LSR) & LSR_THRE) != 0 {
+        if (UART0.read(REG_LSR) & LSR_THRE) != 0 {
             Ok(())
         } else {
             Err(nb::Error::WouldBlock)
@@ -58,33 +236,28 @@ impl Write<u8> for Uart {
     }
 }
 
-fn serial_in(offset: u32) -> u32 {
-    let offset = offset << 2 as u32;
-    unsafe { read_volatile((UART_BASE + offset as usize) as *const u32) }
-}
-
-fn serial_out(offset: u32, val: u32) {
-    let offset = offset << 2 as u32;
-    unsafe {
-        write_volatile((UART_BASE + offset as usize) as *mut u32, val);
-    }
-}
+// 8250 registers, each 32 bits wide and 4 bytes apart; see `Uart::config`
+const UART0: Mmio<u32> = unsafe { Mmio::with_stride(UART_BASE, 4) };
 
 const UART_BASE: usize = 0x1244_0000;
-const REG_THR: u32 = 0x00; /* Transmitter holding reg. */
-const REG_RDR: u32 = 0x00; /* Receiver data reg.       */
-const REG_LSR: u32 = 0x05; /* Line status reg.         */
-const REG_LCR: u32 = 0x03; /* Line control reg.        */
+const REG_THR: usize = 0x00; /* Transmitter holding reg. */
+const REG_RDR: usize = 0x00; /* Receiver data reg.       */
+const REG_LSR: usize = 0x05; /* Line status reg.         */
+const REG_LCR: usize = 0x03; /* Line control reg.        */
 const LCR_DLAB: u32 = 0x80; /* divisor latch access enable */
-const REG_BRDL: u32 = 0x00; /* Baud rate divisor (LSB)  */
-const REG_BRDH: u32 = 0x01; /* Baud rate divisor (MSB)  */
+const REG_BRDL: usize = 0x00; /* Baud rate divisor (LSB)  */
+const REG_BRDH: usize = 0x01; /* Baud rate divisor (MSB)  */
 const LCR_CS8: u32 = 0x03; /* 8 bits data size */
 const LCR_1_STB: u32 = 0x01; /* 1 stop bit */
 const LCR_PDIS: u32 = 0x00; /* parity disable */
-const REG_MDC: u32 = 0x04; /* Modem control reg.       */
-const REG_FCR: u32 = 0x02; /* FIFO control reg.        */
-const REG_IER: u32 = 0x01; /* Interrupt enable reg.    */
+const LCR_PEN: u32 = 0x08; /* parity enable */
+const LCR_EPS: u32 = 0x10; /* even parity select */
+const REG_MDC: usize = 0x04; /* Modem control reg.       */
+const REG_FCR: usize = 0x02; /* FIFO control reg.        */
+const REG_IER: usize = 0x01; /* Interrupt enable reg.    */
+const LSR_BI: u32 = 0x10; /* break interrupt */
 const LSR_THRE: u32 = 0x20; /* transmit holding register empty */
+const LSR_TEMT: u32 = 0x40; /* transmitter empty */
 const FCR_FIFO: u32 = 0x01; /* enable XMIT and RCVR FIFO */
 const FCR_RCVRCLR: u32 = 0x02; /* clear RCVR FIFO */
 const FCR_XMITCLR: u32 = 0x03; /* clear XMIT FIFO */
@@ -92,3 +265,86 @@ const FCR_MODE1: u32 = 0x08; /* set receiver in mode 1 */
 const FCR_FIFO_8: u32 = 0x80; /* 8 bytes in RCVR FIFO */
 const UART_CLK: usize = 100000000;
 const UART_BUADRATE_32MCLK_115200: usize = 115200;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(baud: u32, parity: Parity, data_bits: u32) -> Result<LineSettings, &'static str> {
+        Ok(LineSettings {
+            baud,
+            parity,
+            data_bits,
+        })
+    }
+
+    #[test]
+    fn parse_valid_options() {
+        assert_eq!(LineSettings::parse("115200"), Ok(LineSettings::DEFAULT));
+        assert_eq!(LineSettings::parse("115200n8"), Ok(LineSettings::DEFAULT));
+        assert_eq!(LineSettings::parse("115200n8r"), Ok(LineSettings::DEFAULT));
+        assert_eq!(
+            LineSettings::parse("9600e7"),
+            settings(9600, Parity::Even, 7)
+        );
+        assert_eq!(
+            LineSettings::parse("57600o5"),
+            settings(57600, Parity::Odd, 5)
+        );
+        assert_eq!(
+            LineSettings::parse("1500000n"),
+            settings(1500000, Parity::None, 8)
+        );
+    }
+
+    #[test]
+    fn parse_invalid_options() {
+        assert_eq!(LineSettings::parse(""), Err("no baud rate"));
+        assert_eq!(LineSettings::parse("n8"), Err("no baud rate"));
+        assert_eq!(LineSettings::parse("115200x8"), Err("invalid parity"));
+        assert_eq!(LineSettings::parse("115200n9"), Err("invalid data bits"));
+        assert_eq!(LineSettings::parse("115200n4"), Err("invalid data bits"));
+        assert_eq!(
+            LineSettings::parse("115200n8rx"),
+            Err("invalid flow control")
+        );
+        assert_eq!(
+            LineSettings::parse("115200n8x"),
+            Err("invalid flow control")
+        );
+    }
+
+    #[test]
+    fn parse_rates_out_of_range() {
+        assert_eq!(LineSettings::parse("0"), Err("baud rate out of range"));
+        // divisor above 0xffff
+        assert_eq!(LineSettings::parse("50"), Err("baud rate out of range"));
+        // `16 * baud` beyond u32, divisor rounds to zero
+        assert_eq!(
+            LineSettings::parse("300000000"),
+            Err("baud rate out of range")
+        );
+        assert_eq!(
+            LineSettings::parse("4294967295n8"),
+            Err("baud rate out of range")
+        );
+        // does not fit u32 at all
+        assert_eq!(LineSettings::parse("4294967296"), Err("no baud rate"));
+    }
+
+    #[test]
+    fn divisors() {
+        assert_eq!(divisor(115200), Some(54));
+        assert_eq!(divisor(9600), Some(651));
+        assert_eq!(divisor(0), None);
+        assert_eq!(divisor(u32::MAX), None);
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for options in ["115200n8", "9600e7", "57600o5"] {
+            let settings = LineSettings::parse(options).unwrap();
+            assert_eq!(std::format!("{}", settings), options);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! Boot-time check of PMP enforcement
+//!
+//! Once PMP is configured, boot hart reads one byte at an address outside every region of
+//! `board::PMP_REGIONS` twice, with the recoverable loads of `execute::get_vaddr_u8`:
+//!
+//! - as machine mode, MSTATUS.MPP Machine, which PMP does not restrict; if this faults, nothing
+//!   is backed at that address and the next candidate is tried, as a bus error would look like a
+//!   PMP denial;
+//! - as supervisor, MSTATUS.MPRV with MPP Supervisor and satp Bare, which must fault.
+//!
+//! Candidates are address zero and the end of each region. The result is printed; a failure does
+//! not stop the boot. With feature `pmp-allow-all` every address is open, the check is skipped.
+use crate::board::{self, PmpRegion};
+use crate::execute::get_vaddr_u8;
+use crate::println;
+use riscv::register::mstatus::{self, MPP};
+
+// Run the check on boot hart, after PMP is set and before supervisor has run
+pub fn run() {
+    if cfg!(feature = "pmp-allow-all") {
+        println!("[rustsbi] PMP check skipped, catch-all region opens every address");
+        return;
+    }
+    if riscv::register::satp::read().bits() != 0 {
+        println!("[rustsbi] PMP check skipped, satp is not Bare");
+        return;
+    }
+    let candidates = core::iter::once(0)
+        .chain(
+            board::PMP_REGIONS
+                .iter()
+                .map(|region| region.base + region.size),
+        )
+        .filter(|addr| !covered(board::PMP_REGIONS, *addr));
+    for addr in candidates {
+        if read_as(MPP::Machine, addr).is_none() {
+            continue; // nothing readable there, not a useful probe
+        }
+        match read_as(MPP::Supervisor, addr) {
+            None => println!(
+                "[rustsbi] PMP check passed, supervisor read of {:#x} outside PMP regions denied",
+                addr
+            ),
+            Some(_) => println!(
+                "[rustsbi] PMP check FAILED, supervisor read of {:#x} outside PMP regions allowed",
+                addr
+            ),
+        }
+        return;
+    }
+    println!("[rustsbi] PMP check skipped, no readable address outside PMP regions");
+}
+
+fn covered(regions: &[PmpRegion], addr: usize) -> bool {
+    regions
+        .iter()
+        .any(|region| (region.base..region.base + region.size).contains(&addr))
+}
+
+// Load a byte of physical address `addr` with privilege `mpp` for MSTATUS.MPRV, None if it faults
+fn read_as(mpp: MPP, addr: usize) -> Option<u8> {
+    let saved = mstatus::read().mpp();
+    unsafe {
+        mstatus::set_mpp(mpp);
+        let value = get_vaddr_u8(addr);
+        mstatus::set_mpp(saved);
+        value
+    }
+}
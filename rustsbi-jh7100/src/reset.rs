@@ -0,0 +1,112 @@
+//! System reset
+//!
+//! JH7100 firmware has no way to power off or reset the whole system, thus every reset request
+//! halts the calling hart. Before halting, a sentinel line is printed and flushed to UART:
+//!
+//! ```text
+//! [rustsbi] system halted: code=<reset reason>, type=<reset type>
+//! ```
+//!
+//! Host side test runners grep for this line; `code=0` means no reason (a successful run) and
+//! `code=1` means system failure.
+//!
+//! Other started harts may still have output on its way. The resetting hart sends them a halt
+//! IPI; each of them flushes the console, acknowledges and halts. The sentinel is printed once
+//! all of them acknowledged, or `SHUTDOWN_ACK_TIMEOUT_US` passed with the missing harts reported.
+use crate::println;
+use crate::smp;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use rustsbi::SbiRet;
+
+pub const RESET_TYPE_SHUTDOWN: usize = 0x0000_0000;
+pub const RESET_TYPE_COLD_REBOOT: usize = 0x0000_0001;
+pub const RESET_TYPE_WARM_REBOOT: usize = 0x0000_0002;
+
+pub const RESET_REASON_SYSTEM_FAILURE: usize = 0x0000_0001;
+const RESET_REASON_SBI_SPECIFIC_START: usize = 0xE000_0000;
+
+const HSM_STATE_STARTED: usize = 0;
+
+// How long the resetting hart waits for other harts to flush and halt, 100ms
+const SHUTDOWN_ACK_TIMEOUT_US: u64 = 100_000;
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+// Bit `i` set once hart `i` has flushed the console and is about to halt
+static HALTED_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+pub struct HaltReset;
+
+impl rustsbi::Reset for HaltReset {
+    fn system_reset(&self, reset_type: usize, reset_reason: usize) -> SbiRet {
+        let type_name = match reset_type {
+            RESET_TYPE_SHUTDOWN => "shutdown",
+            RESET_TYPE_COLD_REBOOT => "cold reboot",
+            RESET_TYPE_WARM_REBOOT => "warm reboot",
+            _ => return SbiRet::invalid_param(),
+        };
+        // reasons between system failure and SBI specific reasons are reserved
+        if reset_reason > RESET_REASON_SYSTEM_FAILURE
+            && reset_reason < RESET_REASON_SBI_SPECIFIC_START
+        {
+            return SbiRet::invalid_param();
+        }
+        let hart_id = crate::execute::calling_hart();
+        if SHUTDOWN_REQUESTED.swap(true, Ordering::AcqRel) {
+            // another hart is resetting the system, and our halt IPI is on its way
+            ack_and_halt(hart_id)
+        }
+        halt_other_harts(hart_id);
+        // which compatibility shims this run needed, while every hart is halted
+        crate::hart_csr_utils::print_emulation_stats();
+        // test runners wait for this line even if supervisor has silenced firmware output
+        crate::console::force_output();
+        println!(
+            "[rustsbi] system halted: code={}, type={}",
+            reset_reason, type_name
+        );
+        crate::console::flush();
+        halt()
+    }
+}
+
+fn halt_other_harts(hart_id: usize) {
+    // stopped and suspended harts wait in firmware for an HSM command and print nothing
+    let targets = (0..crate::NUM_HARTS)
+        .filter(|&id| id != hart_id)
+        .filter(|&id| rustsbi::Hsm::hart_get_status(&*crate::HSM, id).value == HSM_STATE_STARTED)
+        .fold(0, |mask, id| mask | 1 << id);
+    let acked = smp::broadcast_and_wait(targets, &HALTED_HARTS, SHUTDOWN_ACK_TIMEOUT_US);
+    for missing in smp::missing(targets, acked) {
+        println!(
+            "[rustsbi] warning: hart {} did not halt within {} us",
+            missing, SHUTDOWN_ACK_TIMEOUT_US
+        );
+    }
+}
+
+// Whether a system reset is in progress; checked on every machine software interrupt
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::Acquire)
+}
+
+// Flush console, tell the resetting hart, then halt current hart
+pub fn ack_and_halt(hart_id: usize) -> ! {
+    crate::peripheral::Clint::new(0x2000000 as *mut u8).clear_soft(hart_id);
+    crate::console::flush();
+    HALTED_HARTS.fetch_or(1 << hart_id, Ordering::Release);
+    halt()
+}
+
+// Halt current hart for good, waiting for interrupts with all of them disabled
+pub fn halt() -> ! {
+    use riscv::register::{mie, mstatus};
+    unsafe {
+        mstatus::clear_mie();
+        mie::clear_msoft();
+        mie::clear_mtimer();
+        mie::clear_mext();
+        loop {
+            riscv::asm::wfi();
+        }
+    }
+}
@@ -19,6 +19,32 @@ pub fn init() {
     unsafe { mtvec::write(addr, TrapMode::Direct) };
 }
 
+// Privilege level the payload runs at, entered through mret
+const SUPERVISOR_MPP: MPP = MPP::Supervisor;
+
+// Panic if `ctx` would enter the payload at another privilege level than `SUPERVISOR_MPP`;
+// with MPP Machine, mret would run supervisor code in machine mode without any protection
+pub fn check_supervisor_entry(ctx: &SupervisorContext) {
+    let mpp = ctx.mstatus.mpp();
+    if mpp != SUPERVISOR_MPP {
+        panic!(
+            "supervisor entry at {:#x} with mstatus.MPP {:?}, intended {:?}",
+            ctx.mepc, mpp, SUPERVISOR_MPP
+        )
+    }
+}
+
+// Scratch registers across privilege transitions:
+//
+// - `mscratch` is firmware's. While supervisor runs it holds the address of the hart's
+//   `SupervisorContext`, which `from_supervisor_save` swaps with sp on a trap and
+//   `to_supervisor_restore` puts back before mret; it is never readable from supervisor, a csr
+//   access to it traps as an illegal instruction and is delegated as such.
+// - `sscratch` is supervisor's. Firmware clears it on every supervisor entry, at boot, on HSM
+//   start and on resume from non-retentive suspend, so that no value of an earlier boot stage or
+//   firmware pointer is left for supervisor to find. Afterwards firmware never writes it, traps
+//   and SBI calls keep what supervisor put there; the only exception is a restore from
+//   `checkpoint`, which writes back what supervisor itself saved.
 pub struct Runtime {
     context: SupervisorContext,
 }
@@ -30,11 +56,13 @@ impl Runtime {
         ans.prepare_supervisor(supervisor_mepc);
         ans.context.a0 = a0;
         ans.context.a1 = a1;
+        riscv::register::sscratch::write(0);
+        check_supervisor_entry(&ans.context);
         ans
     }
 
     fn reset(&mut self) {
-        unsafe { mstatus::set_mpp(MPP::Supervisor) };
+        unsafe { mstatus::set_mpp(SUPERVISOR_MPP) };
         self.context.mstatus = mstatus::read();
         self.context.machine_stack = 0x2333333366666666; // 将会被resume函数覆盖
     }
@@ -56,11 +84,26 @@ impl Generator for Runtime {
     fn resume(mut self: Pin<&mut Self>, _arg: ()) -> GeneratorState<Self::Yield, Self::Return> {
         unsafe { do_resume(&mut self.context as *mut _) };
         let mtval = mtval::read();
-        let trap = match mcause::read().cause() {
+        let mcause = mcause::read();
+        #[cfg(feature = "stack-guard")]
+        crate::stack_guard::check_machine_trap(&self.context, mcause, mtval);
+        let trap = match mcause.cause() {
             Trap::Exception(Exception::SupervisorEnvCall) => MachineTrap::SbiCall(),
             Trap::Exception(Exception::IllegalInstruction) => MachineTrap::IllegalInstruction(),
+            Trap::Exception(Exception::LoadMisaligned) => MachineTrap::LoadMisaligned(mtval),
+            Trap::Exception(Exception::StoreMisaligned) => MachineTrap::StoreMisaligned(mtval),
+            #[cfg(feature = "emulate-mmio-amo")]
+            Trap::Exception(Exception::LoadFault) => MachineTrap::LoadFault(mtval),
+            #[cfg(feature = "emulate-mmio-amo")]
+            Trap::Exception(Exception::StoreFault) => MachineTrap::StoreFault(mtval),
             Trap::Interrupt(Interrupt::MachineTimer) => MachineTrap::MachineTimer(),
             Trap::Interrupt(Interrupt::MachineSoft) => MachineTrap::MachineSoft(),
+            Trap::Interrupt(_) => MachineTrap::UnhandledInterrupt(mcause.code()),
+            #[cfg(feature = "diagnostics")]
+            Trap::Exception(Exception::Breakpoint) => MachineTrap::Breakpoint(),
+            Trap::Exception(_) if crate::delegation::is_undelegated(mcause.code()) => {
+                MachineTrap::Undelegated(mcause.code(), mtval)
+            }
             e => panic!(
                 "unhandled exception: {:?}! mtval: {:x?}, ctx: {:x?}",
                 e, mtval, self.context
@@ -74,8 +117,23 @@ impl Generator for Runtime {
 pub enum MachineTrap {
     SbiCall(),
     IllegalInstruction(),
+    LoadMisaligned(usize),
+    StoreMisaligned(usize),
+    // access faults are delegated to supervisor, unless atomics on MMIO are emulated
+    #[cfg(feature = "emulate-mmio-amo")]
+    LoadFault(usize),
+    #[cfg(feature = "emulate-mmio-amo")]
+    StoreFault(usize),
     MachineTimer(),
     MachineSoft(),
+    // any other interrupt reaching machine level, with its code from mcause
+    UnhandledInterrupt(usize),
+    // breakpoints are delegated to supervisor, unless a `watchpoint` is armed or supervisor
+    // un-delegated them
+    #[cfg(feature = "diagnostics")]
+    Breakpoint(),
+    // an exception supervisor un-delegated through `delegation`, with its code and mtval
+    Undelegated(usize, usize),
 }
 
 #[derive(Debug)]
@@ -0,0 +1,163 @@
+//! Firmware owned scratch DRAM
+//!
+//! Firmware buffers that must not be clobbered by supervisor live in one region at the top of
+//! DRAM. The region is added as a `no-map` child of `/reserved-memory` in the device tree passed
+//! to supervisor, thus the kernel keeps it out of its allocator. DRAM size is taken from the
+//! `memory` node of the device tree; with feature `memory-fixup`, it is taken from the board
+//! instead, and the `memory` node passed to supervisor is rewritten to match.
+//!
+//! Layout, as offset from region start:
+//!
+//! | Offset     | Size    | Usage
+//! |:-----------|:--------|:------
+//! | `0x0`      | 64KiB   | device tree passed to supervisor
+//! | `0x1_0000` | 64KiB   | in-memory log, see `memory_log`
+//! | `0x2_0000` | 128KiB  | SBI trace
+//! | `0x4_0000` | 4KiB    | boot reason flag, kept across warm resets
+//! | `0x4_1000` | 4KiB    | trap counters of each hart
+//! | `0x4_2000` | 4KiB    | boot handoff structure, see `handoff`
+//! | `0x4_3000` | 4KiB    | SBI call latency histograms of each hart, see `ecall_latency`
+//! | `0x4_4000` | 4KiB    | payload slot flag, kept across warm resets, see `payload_slot`
+//! | `0x4_5000` | 64KiB   | firmware tables of the image, see `firmware_tables`
+//! | `0x5_5000` | 1708KiB | unused
+//!
+//! The region is 2MiB and aligned to its size, so it never splits a huge page of supervisor.
+use crate::device_tree;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub const SCRATCH_SIZE: usize = 2 * 1024 * 1024;
+
+// A fixed part of scratch region
+#[derive(Clone, Copy, Debug)]
+pub struct Slot {
+    offset: usize,
+    size: usize,
+}
+
+impl Slot {
+    pub const fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+}
+
+pub const SLOT_DEVICE_TREE: Slot = Slot {
+    offset: 0x0,
+    size: 0x1_0000,
+};
+pub const SLOT_LOG: Slot = Slot {
+    offset: 0x1_0000,
+    size: 0x1_0000,
+};
+pub const SLOT_TRACE: Slot = Slot {
+    offset: 0x2_0000,
+    size: 0x2_0000,
+};
+pub const SLOT_BOOT_STATE: Slot = Slot {
+    offset: 0x4_0000,
+    size: 0x1000,
+};
+pub const SLOT_TRAP_STATS: Slot = Slot {
+    offset: 0x4_1000,
+    size: 0x1000,
+};
+pub const SLOT_HANDOFF: Slot = Slot {
+    offset: 0x4_2000,
+    size: 0x1000,
+};
+pub const SLOT_ECALL_LATENCY: Slot = Slot {
+    offset: 0x4_3000,
+    size: 0x1000,
+};
+pub const SLOT_PAYLOAD_SLOT: Slot = Slot {
+    offset: 0x4_4000,
+    size: 0x1000,
+};
+pub const SLOT_FIRMWARE_TABLES: Slot = Slot {
+    offset: 0x4_5000,
+    size: 0x1_0000,
+};
+
+// Every slot with what it holds, in offset order, as `memory_map` reports them
+pub const SLOTS: [(&str, Slot); 9] = [
+    ("device tree for supervisor", SLOT_DEVICE_TREE),
+    ("in-memory log", SLOT_LOG),
+    ("SBI trace", SLOT_TRACE),
+    ("boot reason flag", SLOT_BOOT_STATE),
+    ("trap counters", SLOT_TRAP_STATS),
+    ("boot handoff structure", SLOT_HANDOFF),
+    ("SBI call latency histograms", SLOT_ECALL_LATENCY),
+    ("payload slot flag", SLOT_PAYLOAD_SLOT),
+    ("firmware tables", SLOT_FIRMWARE_TABLES),
+];
+
+static SCRATCH_BASE: AtomicUsize = AtomicUsize::new(0);
+static SUPERVISOR_DEVICE_TREE: AtomicUsize = AtomicUsize::new(0);
+
+// Carve scratch region out of DRAM, and place device tree for supervisor into it.
+//
+// Called once on boot hart before any slot is used.
+pub fn init(dtb: &[u8]) -> Result<(), &'static str> {
+    let (dram_start, dram_end) = supervisor_dram(dtb).ok_or("no memory node")?;
+    // firmware never places supervisor data outside the DRAM range opened by PMP
+    let dram_end = dram_end.min(crate::DRAM_PMP_END);
+    let base = dram_end.checked_sub(SCRATCH_SIZE).ok_or("DRAM too small")? & !(SCRATCH_SIZE - 1);
+    if base < dram_start.max(crate::DRAM_PMP_START) {
+        return Err("DRAM too small");
+    }
+    SCRATCH_BASE.store(base, Ordering::Release);
+    let buf = unsafe { slot(SLOT_DEVICE_TREE) }.unwrap();
+    device_tree::copy_with_reserved_memory(dtb, buf, "rustsbi", base, SCRATCH_SIZE)?;
+    #[cfg(feature = "memory-fixup")]
+    device_tree::fixup_memory_node(buf, dram_start, dram_end - dram_start)?;
+    SUPERVISOR_DEVICE_TREE.store(buf.as_ptr() as usize, Ordering::Release);
+    Ok(())
+}
+
+// DRAM range [start, end) reported to supervisor: the memory node of device tree, or the board's
+// DRAM with feature `memory-fixup`
+pub fn supervisor_dram(dtb: &[u8]) -> Option<(usize, usize)> {
+    #[cfg(not(feature = "memory-fixup"))]
+    return device_tree::dram_range(dtb);
+    #[cfg(feature = "memory-fixup")]
+    {
+        let _ = dtb;
+        Some((
+            crate::board::DRAM_BASE,
+            crate::board::DRAM_BASE + crate::board::DRAM_SIZE,
+        ))
+    }
+}
+
+// Start of scratch region, which is also the end of DRAM left to supervisor; None if it is
+// not initialized
+pub fn base() -> Option<usize> {
+    match SCRATCH_BASE.load(Ordering::Acquire) {
+        0 => None,
+        base => Some(base),
+    }
+}
+
+// Memory of given slot, or None if scratch region is not initialized.
+//
+// Safety: caller must make sure a slot is only accessed by one owner at a time.
+pub unsafe fn slot(slot: Slot) -> Option<&'static mut [u8]> {
+    match SCRATCH_BASE.load(Ordering::Acquire) {
+        0 => None,
+        base => Some(core::slice::from_raw_parts_mut(
+            (base + slot.offset) as *mut u8,
+            slot.size,
+        )),
+    }
+}
+
+// Address of device tree passed to supervisor; the embedded one if relocation failed
+pub fn device_tree() -> usize {
+    match SUPERVISOR_DEVICE_TREE.load(Ordering::Acquire) {
+        0 => crate::DEVICE_TREE.as_ptr() as usize,
+        addr => addr,
+    }
+}
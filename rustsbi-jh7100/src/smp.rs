@@ -0,0 +1,29 @@
+//! Coordination of harts over machine software interrupts
+use crate::peripheral::Clint;
+use crate::NUM_HARTS;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// Raise machine software interrupt on every hart whose bit is set in `targets`, then wait until
+// each of them has set its bit in `acked`, or `timeout_us` has passed.
+//
+// Returns `acked` at the time waiting ended; bits of harts outside `targets` are kept as they are.
+pub fn broadcast_and_wait(targets: usize, acked: &AtomicUsize, timeout_us: u64) -> usize {
+    let clint = Clint::new(0x2000000 as *mut u8);
+    for hart_id in (0..NUM_HARTS).filter(|id| targets & (1 << id) != 0) {
+        clint.send_soft(hart_id);
+    }
+    let timeout = clint.us_to_ticks(timeout_us);
+    let start = clint.get_mtime();
+    loop {
+        let done = acked.load(Ordering::Acquire);
+        if done & targets == targets || clint.get_mtime() - start >= timeout {
+            return done;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// Harts whose bit is set in `targets` but not in `acked`
+pub fn missing(targets: usize, acked: usize) -> impl Iterator<Item = usize> {
+    (0..NUM_HARTS).filter(move |id| targets & !acked & (1 << id) != 0)
+}
@@ -0,0 +1,100 @@
+//! Stack overflow guard regions
+//!
+//! With feature `stack-guard`, build.rs sets `stack_guard_size` of the linker script to 4KiB, and
+//! each hart's stack is laid out above a guard region of that size, `hart_stack_stride` apart:
+//!
+//! | Address                                      | Contents
+//! |:---------------------------------------------|:-----------------------------------
+//! | `sstack + hart_id * hart_stack_stride`       | guard region of hart `hart_id`
+//! | `... + stack_guard_size`                     | lowest address of its stack
+//! | `sstack + (hart_id + 1) * hart_stack_stride` | its stack top, where sp starts
+//!
+//! Without the feature, `stack_guard_size` is 0 and the stacks follow each other directly.
+//!
+//! `set_pmp` sets PMP entry `PMP_ENTRY` of each hart to its own guard region as a locked NAPOT
+//! entry with no permission; board regions follow it. A locked entry applies to machine mode as
+//! well, and takes priority over the DRAM regions, being numbered lower. A hart which runs off
+//! the bottom of its stack thus takes an access fault on its first load or store to the guard,
+//! before it corrupts the heap or the stack of another hart. The entry stays locked until the
+//! next reset. A hart's PMP only guards its own stack, which is all it can overflow.
+//!
+//! The fault is reported as a panic "stack overflow on hart N":
+//!
+//! - before `runtime::init`, by `early_trap`, which prints it;
+//! - after, the runtime trap entry saves the fault as if supervisor trapped, and switches back to
+//!   the machine stack pointer saved when supervisor was last resumed, near the stack top;
+//!   `check_machine_trap` then panics on that stack, with room for the report.
+//!
+//! An overflow is fatal either way, the frames below are lost. With feature `diagnostics`, function
+//! `FUNCTION_OVERFLOW_STACK` of `EXTENSION_DIAGNOSTICS` recurses until it overflows on purpose.
+use crate::runtime::SupervisorContext;
+use riscv::register::{mcause::Mcause, mhartid, mstatus::MPP};
+
+// Highest priority entry, before every board region
+pub const PMP_ENTRY: usize = 0;
+// PMP entries taken before board regions
+pub const PMP_ENTRIES: usize = 1;
+
+// L, NAPOT, no R, W or X
+pub const PMP_CFG: u8 = 1 << 7 | 0b11 << 3;
+
+// `stack_guard_size` of the linker script; an absolute symbol, not an address firmware may load
+fn stack_guard_size() -> usize {
+    let size: usize;
+    unsafe {
+        core::arch::asm!(
+            "lui {size}, %hi(stack_guard_size)",
+            "addi {size}, {size}, %lo(stack_guard_size)",
+            size = out(reg) size,
+            options(pure, nomem, nostack),
+        )
+    };
+    size
+}
+
+// Guard region of given hart, right below its stack
+pub fn guard(hart_id: usize) -> core::ops::Range<usize> {
+    let bottom = crate::hart_stack_top(hart_id) - crate::hart_stack_stride();
+    bottom..bottom + stack_guard_size()
+}
+
+// NAPOT pmpaddr of the guard region of given hart
+pub fn pmpaddr(hart_id: usize) -> usize {
+    let guard = guard(hart_id);
+    (guard.start >> 2) | ((guard.len() >> 3) - 1)
+}
+
+// Panic on a trap firmware took from machine mode, which the runtime trap entry saved as if
+// supervisor trapped; called on every trap before it is dispatched
+pub fn check_machine_trap(ctx: &SupervisorContext, mcause: Mcause, mtval: usize) {
+    if ctx.mstatus.mpp() != MPP::Machine {
+        return;
+    }
+    let hart_id = mhartid::read();
+    if guard(hart_id).contains(&mtval) {
+        panic!(
+            "stack overflow on hart {}, access to {:#x} at pc {:#x}",
+            hart_id, mtval, ctx.mepc
+        )
+    }
+    panic!(
+        "exception {:?} from machine mode on hart {}, mtval {:#x}, pc {:#x}",
+        mcause.cause(),
+        hart_id,
+        mtval,
+        ctx.mepc
+    )
+}
+
+// Recurse until the stack overflows into the guard region, for `FUNCTION_OVERFLOW_STACK`
+#[cfg(feature = "diagnostics")]
+#[inline(never)]
+pub fn overflow(depth: usize) -> usize {
+    // a frame the compiler cannot fold away, nor turn the recursion into a loop
+    let frame = [depth; 32];
+    let frame = unsafe { core::ptr::read_volatile(&frame) };
+    if depth == usize::MAX {
+        return 0;
+    }
+    overflow(depth + 1).wrapping_add(frame[depth % 32])
+}
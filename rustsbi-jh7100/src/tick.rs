@@ -0,0 +1,59 @@
+//! Cooperative background tasks run on machine timer ticks
+//!
+//! Firmware periodic work (watchdog petting, statistics snapshots and so on) registers a task
+//! at boot. Every `MachineTimer` trap runs all registered tasks once on the current hart, then
+//! the timer is relayed to supervisor as usual. Tasks must be short and must never block,
+//! otherwise supervisor timer latency suffers.
+//!
+//! By default tasks run only when supervisor timer fires. With feature `firmware-timer`, a
+//! firmware owned tick deadline also runs them at a fixed interval, see `timer`.
+//!
+//! Tasks run on every hart by default. With feature `service-hart`, they only run on
+//! `board::SERVICE_HART_ID`, and the firmware tick is only armed there, so that other harts take
+//! no machine timer trap but their supervisor's own. This lowers total overhead at the cost of the
+//! service hart, whose supervisor timer waits for every task on each trap, and of task latency:
+//! tasks run only as often as that one hart traps, and not at all while it is stopped by HSM.
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+// Upper bound of registered tasks, this keeps per-tick overhead bounded
+const MAX_TICK_TASKS: usize = 8;
+
+type TickTask = Box<dyn Fn(usize) + Send + Sync>;
+
+static TICK_TASKS: spin::RwLock<Vec<TickTask>> = spin::RwLock::new(Vec::new());
+
+// Register a task to run on every machine timer tick; the task receives current hart id.
+//
+// Should be called during boot, before supervisor is entered.
+pub fn register<F>(task: F)
+where
+    F: Fn(usize) + Send + Sync + 'static,
+{
+    let mut tasks = TICK_TASKS.write();
+    assert!(
+        tasks.len() < MAX_TICK_TASKS,
+        "too many tick tasks, at most {} allowed",
+        MAX_TICK_TASKS
+    );
+    tasks.push(Box::new(task));
+}
+
+// Whether background tasks run on given hart
+#[inline]
+pub fn on_service_hart(hart_id: usize) -> bool {
+    !cfg!(feature = "service-hart") || hart_id == crate::board::SERVICE_HART_ID
+}
+
+// Run all registered tasks once; called from the machine timer trap handler.
+#[inline]
+pub fn run(hart_id: usize) {
+    if !on_service_hart(hart_id) {
+        return;
+    }
+    // tasks are registered at boot only, a reader never waits on a writer at runtime
+    let tasks = TICK_TASKS.read();
+    for task in tasks.iter() {
+        task(hart_id);
+    }
+}
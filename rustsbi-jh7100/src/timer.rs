@@ -0,0 +1,134 @@
+//! Machine timer multiplexing
+//!
+//! Each hart has a single `mtimecmp`, shared by the supervisor timer and firmware deadlines:
+//! periodic tick of background tasks with feature `firmware-timer`, and the hang watchdog with
+//! feature `hang-watchdog`. Every owner records its deadline here instead of writing `mtimecmp`,
+//! which is always programmed to the nearest recorded deadline; machine timer is enabled as long
+//! as any deadline is recorded.
+//!
+//! On a machine timer interrupt, a due supervisor deadline is dropped and relayed as sip.stimer,
+//! a due tick deadline is moved one interval forward, and the watchdog re-arms its own. Firmware
+//! deadlines are never visible to supervisor: supervisor timer only fires at the instant it asked
+//! for, however often firmware deadlines make the machine timer trap in between.
+//!
+//! A supervisor deadline which has already passed when it is set, the usual way to ask for an
+//! interrupt right now, is relayed as sip.stimer at once, before returning from the ecall.
+//!
+//! Machine timer enable and sip.stimer are only written by this module while supervisor runs;
+//! every way to set the supervisor timer, TIME extension and legacy set timer, ends up in
+//! `set_supervisor_deadline`.
+//!
+//! Without firmware deadlines this reduces to the plain relay: machine timer is masked once the
+//! supervisor timer is relayed, until supervisor sets a new one.
+use crate::peripheral::Clint;
+use core::sync::atomic::{AtomicU64, Ordering};
+use riscv::register::{mie, mip};
+
+// Interval of firmware tick, 10 milliseconds
+#[cfg(feature = "firmware-timer")]
+const TICK_INTERVAL_US: u64 = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Owner {
+    Supervisor = 0,
+    #[cfg(feature = "firmware-timer")]
+    Tick = 1,
+    #[cfg(feature = "hang-watchdog")]
+    Watchdog = 2,
+}
+
+const NUM_OWNERS: usize = 3;
+
+const NO_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+const NO_DEADLINES: [AtomicU64; NUM_OWNERS] = [NO_DEADLINE; NUM_OWNERS];
+// only accessed by its own hart, from ecalls and machine timer traps of that hart
+static DEADLINES: [[AtomicU64; NUM_OWNERS]; crate::NUM_HARTS] = [NO_DEADLINES; crate::NUM_HARTS];
+
+fn clint() -> Clint {
+    Clint::new(0x2000000 as *mut u8)
+}
+
+// Record deadline of given owner on current hart, u64::MAX removes it
+pub fn set_deadline(hart_id: usize, owner: Owner, instant: u64) {
+    DEADLINES[hart_id][owner as usize].store(instant, Ordering::Relaxed);
+    reprogram(hart_id);
+}
+
+// Record supervisor deadline on current hart, dropping a relayed supervisor timer of the previous
+// one. One at or before current mtime is relayed right away; one passing on the way back to
+// supervisor makes the machine timer trap as usual.
+pub fn set_supervisor_deadline(hart_id: usize, instant: u64) {
+    unsafe { mip::clear_stimer() };
+    if instant <= clint().get_mtime() {
+        set_deadline(hart_id, Owner::Supervisor, u64::MAX);
+        unsafe { mip::set_stimer() };
+    } else {
+        set_deadline(hart_id, Owner::Supervisor, instant);
+    }
+}
+
+pub fn deadline(hart_id: usize, owner: Owner) -> u64 {
+    DEADLINES[hart_id][owner as usize].load(Ordering::Relaxed)
+}
+
+// Arm periodic firmware tick on current hart; called every time the hart (re)enters supervisor.
+// Harts other than the service hart have no tick, see `tick`
+#[cfg(feature = "firmware-timer")]
+pub fn start_tick(hart_id: usize) {
+    if !crate::tick::on_service_hart(hart_id) {
+        return;
+    }
+    let clint = clint();
+    let deadline = clint.get_mtime() + clint.us_to_ticks(TICK_INTERVAL_US);
+    set_deadline(hart_id, Owner::Tick, deadline);
+}
+
+// Drop every deadline of current hart and mask machine timer, before the hart stops
+pub fn stop(hart_id: usize) {
+    for deadline in &DEADLINES[hart_id] {
+        deadline.store(u64::MAX, Ordering::Relaxed);
+    }
+    reprogram(hart_id);
+}
+
+// Handle machine timer interrupt after firmware tasks have run, relaying supervisor timer if due
+pub fn on_machine_timer(hart_id: usize) {
+    let now = clint().get_mtime();
+    let supervisor = &DEADLINES[hart_id][Owner::Supervisor as usize];
+    let supervisor_due = now >= supervisor.load(Ordering::Relaxed);
+    if supervisor_due {
+        supervisor.store(u64::MAX, Ordering::Relaxed);
+    }
+    #[cfg(feature = "firmware-timer")]
+    {
+        let tick = &DEADLINES[hart_id][Owner::Tick as usize];
+        if now >= tick.load(Ordering::Relaxed) {
+            // from now rather than the missed deadline, a long stall does not cause a burst
+            tick.store(
+                now + clint().us_to_ticks(TICK_INTERVAL_US),
+                Ordering::Relaxed,
+            );
+        }
+    }
+    reprogram(hart_id);
+    if supervisor_due {
+        unsafe { mip::set_stimer() };
+    }
+}
+
+// Program `mtimecmp` to the nearest deadline; machine timer is masked when there is none
+fn reprogram(hart_id: usize) {
+    let nearest = DEADLINES[hart_id]
+        .iter()
+        .map(|deadline| deadline.load(Ordering::Relaxed))
+        .min()
+        .unwrap_or(u64::MAX);
+    clint().set_timer(hart_id, nearest);
+    unsafe {
+        if nearest == u64::MAX {
+            mie::clear_mtimer();
+        } else {
+            mie::set_mtimer();
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! SBI call trace
+//!
+//! Every ecall from supervisor is recorded into a ring buffer in scratch slot `SLOT_TRACE`, with
+//! its result and the firmware handler which serviced it, so the dispatch path of a call that
+//! returned an unexpected error can be followed. Every trap firmware hands to supervisor through
+//! `feature::do_transfer_trap` goes into the same buffer as an entry of its own kind, with its
+//! cause, `mepc` and `mtval`, so the interrupts and exceptions supervisor was given show up in
+//! order with the calls. The most recent entries are printed in the panic report. Nothing is
+//! recorded before scratch region is initialized.
+//!
+//! Harts record concurrently into distinct entries; an entry overwritten or still being written
+//! when dumped does not match its sequence number and is skipped.
+use crate::execute::{EcallHandler, ECALL_HANDLERS};
+use crate::println;
+use crate::scratch::{self, SLOT_TRACE};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rustsbi::SbiRet;
+
+// Entries printed in the panic report
+pub const PANIC_DUMP_ENTRIES: usize = 16;
+
+// Kinds of entries; a delegated trap keeps its cause in `extension`, `mepc` in `function` and
+// `mtval` in `value`, and leaves `error` and `handler` zero
+const KIND_ECALL: u8 = 0;
+const KIND_DELEGATED_TRAP: u8 = 1;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Entry {
+    // position of the entry in the whole trace plus one, zero if never written
+    sequence: usize,
+    time: u64,
+    extension: usize,
+    function: usize,
+    error: usize,
+    value: usize,
+    hart_id: u16,
+    // `EcallHandler` as u8; scratch memory may hold anything, which must not be read as an enum
+    handler: u8,
+    kind: u8,
+}
+
+const ENTRIES: usize = SLOT_TRACE.size() / core::mem::size_of::<Entry>();
+
+// Number of entries ever recorded
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+fn entries() -> Option<&'static mut [Entry]> {
+    // entries are aligned, as scratch region is aligned to 2MiB and so is every slot offset
+    unsafe { scratch::slot(SLOT_TRACE) }
+        .map(|buf| unsafe { core::slice::from_raw_parts_mut(buf.as_mut_ptr().cast(), ENTRIES) })
+}
+
+// Record a serviced ecall on current hart
+pub fn record(
+    hart_id: usize,
+    extension: usize,
+    function: usize,
+    ans: &SbiRet,
+    handler: EcallHandler,
+) {
+    push(Entry {
+        sequence: 0,
+        time: crate::peripheral::Clint::new(0x2000000 as *mut u8).get_mtime(),
+        extension,
+        function,
+        error: ans.error,
+        value: ans.value,
+        hart_id: hart_id as u16,
+        handler: handler as u8,
+        kind: KIND_ECALL,
+    })
+}
+
+// Record a trap delegated to supervisor on current hart; `cause` as written to scause
+pub fn record_delegated_trap(hart_id: usize, cause: usize, mepc: usize, mtval: usize) {
+    push(Entry {
+        sequence: 0,
+        time: crate::peripheral::Clint::new(0x2000000 as *mut u8).get_mtime(),
+        extension: cause,
+        function: mepc,
+        error: 0,
+        value: mtval,
+        hart_id: hart_id as u16,
+        handler: 0,
+        kind: KIND_DELEGATED_TRAP,
+    })
+}
+
+fn push(new: Entry) {
+    let entries = match entries() {
+        Some(entries) => entries,
+        None => return,
+    };
+    let index = NEXT.fetch_add(1, Ordering::Relaxed);
+    let entry = &mut entries[index % ENTRIES];
+    // invalidate first and publish the sequence last; volatile writes keep this order
+    unsafe {
+        core::ptr::write_volatile(entry, new);
+        core::ptr::write_volatile(&mut entry.sequence, index + 1);
+    }
+}
+
+// Print up to `count` most recent entries, oldest first
+pub fn dump(count: usize) {
+    let entries = match entries() {
+        Some(entries) => entries,
+        None => return,
+    };
+    let next = NEXT.load(Ordering::Relaxed);
+    let first = next.saturating_sub(count.min(ENTRIES));
+    println!(
+        "[rustsbi] last {} of {} SBI calls and delegated traps:",
+        next - first,
+        next
+    );
+    for index in first..next {
+        let entry = unsafe { core::ptr::read_volatile(&entries[index % ENTRIES]) };
+        if entry.sequence != index + 1 {
+            continue;
+        }
+        if entry.kind == KIND_DELEGATED_TRAP {
+            let (kind, code) = match entry.extension {
+                cause if cause >> (usize::BITS - 1) != 0 => ("interrupt", cause << 1 >> 1),
+                cause => ("exception", cause),
+            };
+            println!(
+                "[rustsbi]   #{} t={} hart {} delegated {} {} at mepc {:#x}, mtval {:#x}",
+                index, entry.time, entry.hart_id, kind, code, entry.function, entry.value
+            );
+            continue;
+        }
+        let handler = match ECALL_HANDLERS.get(entry.handler as usize) {
+            Some(handler) if entry.kind == KIND_ECALL => handler,
+            _ => continue,
+        };
+        println!(
+            "[rustsbi]   #{} t={} hart {} eid {:#x} fid {:#x} -> error {}, value {:#x} by {:?}",
+            index,
+            entry.time,
+            entry.hart_id,
+            entry.extension,
+            entry.function,
+            entry.error as isize,
+            entry.value,
+            handler
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Per-hart trap counters
+//!
+//! Every trap firmware handles for supervisor is counted by cause in scratch slot
+//! `SLOT_TRAP_STATS`: SBI calls by the handler which serviced them, illegal instructions by
+//! emulation outcome, and the other exceptions and machine interrupts by kind. Each emulated
+//! instruction or access is also counted by the emulation which serviced it, to show which
+//! compatibility shims a supervisor actually needs. Counters start from zero at each boot and are
+//! printed by `hart_csr_utils::print_trap_stats`, in the panic report and with feature
+//! `diagnostics` on request of supervisor; emulation counters are also printed on system reset.
+//! Nothing is counted before scratch region is initialized.
+//!
+//! Each hart only increments its own counters; another hart may read them at any time, a counter
+//! is one aligned word and never reads torn.
+use crate::execute::{EcallHandler, ECALL_HANDLERS};
+use crate::scratch::{self, SLOT_TRAP_STATS};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// Counted traps other than SBI calls
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrapKind {
+    // illegal instruction emulated by firmware
+    IllegalEmulated,
+    // illegal instruction handed to supervisor's trap handler
+    IllegalDelegated,
+    // illegal instruction from supervisor with no trap handler to take it, fatal
+    IllegalFatal,
+    // misaligned load or store, emulated or handed to supervisor
+    Misaligned,
+    // load or store access fault, with feature `emulate-mmio-amo`
+    AccessFault,
+    MachineTimer,
+    // software interrupt for an HSM command or an IPI to supervisor
+    MachineSoft,
+    UnhandledInterrupt,
+    // exception supervisor un-delegated, reported and handed to supervisor; or a breakpoint
+    // taken back for a diagnostics watchpoint
+    Undelegated,
+}
+
+pub const TRAP_KINDS: [TrapKind; 9] = [
+    TrapKind::IllegalEmulated,
+    TrapKind::IllegalDelegated,
+    TrapKind::IllegalFatal,
+    TrapKind::Misaligned,
+    TrapKind::AccessFault,
+    TrapKind::MachineTimer,
+    TrapKind::MachineSoft,
+    TrapKind::UnhandledInterrupt,
+    TrapKind::Undelegated,
+];
+
+// Emulations of an instruction or access, counted whenever one completes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Emulation {
+    // rdtime, read from CLINT mtime
+    Rdtime,
+    // sstatus access, with feature `emulate-sstatus`
+    Sstatus,
+    // wrs.nto and wrs.sto, with feature `emulate-zawrs`
+    Zawrs,
+    // czero.eqz and czero.nez, with feature `emulate-zicond`
+    Zicond,
+    // Zbb instructions, with feature `bitmanip-emulation`
+    Bitmanip,
+    // lr, sc and amo on MMIO, with feature `emulate-mmio-amo`
+    MmioAmo,
+    // compressed instructions on harts without C, with feature `rvc-emulation`
+    Rvc,
+    // cycle and instret reads, with feature `fixed-counters`
+    Counters,
+    // sret trapped by mstatus.TSR, with feature `emulate-sret`
+    Sret,
+    MisalignedLoad,
+    MisalignedStore,
+}
+
+pub const EMULATIONS: [Emulation; 11] = [
+    Emulation::Rdtime,
+    Emulation::Sstatus,
+    Emulation::Zawrs,
+    Emulation::Zicond,
+    Emulation::Bitmanip,
+    Emulation::MmioAmo,
+    Emulation::Rvc,
+    Emulation::Counters,
+    Emulation::Sret,
+    Emulation::MisalignedLoad,
+    Emulation::MisalignedStore,
+];
+
+// Counters of one hart: SBI calls in `ECALL_HANDLERS` order, other traps in `TRAP_KINDS` order,
+// then emulations in `EMULATIONS` order
+const COUNTERS: usize = ECALL_HANDLERS.len() + TRAP_KINDS.len() + EMULATIONS.len();
+
+type HartCounters = [AtomicUsize; COUNTERS];
+
+const _: () = assert!(
+    core::mem::size_of::<HartCounters>() * crate::NUM_HARTS <= SLOT_TRAP_STATS.size(),
+    "trap counters do not fit in their scratch slot"
+);
+
+fn counters(hart_id: usize) -> Option<&'static HartCounters> {
+    if hart_id >= crate::NUM_HARTS {
+        return None;
+    }
+    // slots are aligned to their offset in 2MiB aligned scratch region
+    unsafe { scratch::slot(SLOT_TRAP_STATS) }
+        .map(|buf| unsafe { &*(buf.as_ptr() as *const HartCounters).add(hart_id) })
+}
+
+// Clear counters of every hart; called once on boot hart after scratch region is initialized,
+// as scratch memory holds whatever the last boot or power-on left there
+pub fn init() {
+    for hart_id in 0..crate::NUM_HARTS {
+        if let Some(counters) = counters(hart_id) {
+            for counter in counters {
+                counter.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// Count an SBI call serviced by `handler` on current hart
+pub fn count_ecall(hart_id: usize, handler: EcallHandler) {
+    increment(hart_id, handler as usize);
+}
+
+// Count a trap other than SBI call on current hart
+pub fn count(hart_id: usize, trap: TrapKind) {
+    increment(hart_id, ECALL_HANDLERS.len() + trap as usize);
+}
+
+// Count an instruction or access emulated on current hart
+pub fn count_emulation(hart_id: usize, emulation: Emulation) {
+    increment(
+        hart_id,
+        ECALL_HANDLERS.len() + TRAP_KINDS.len() + emulation as usize,
+    );
+}
+
+fn increment(hart_id: usize, index: usize) {
+    if let Some(counters) = counters(hart_id) {
+        // only this hart writes, a plain load and store is enough
+        let counter = &counters[index];
+        counter.store(counter.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+    }
+}
+
+// SBI calls of given hart serviced by `handler`, or None if scratch region is not initialized
+pub fn ecalls(hart_id: usize, handler: EcallHandler) -> Option<usize> {
+    counters(hart_id).map(|counters| counters[handler as usize].load(Ordering::Relaxed))
+}
+
+// Traps of given kind taken by given hart, or None if scratch region is not initialized
+pub fn traps(hart_id: usize, trap: TrapKind) -> Option<usize> {
+    counters(hart_id)
+        .map(|counters| counters[ECALL_HANDLERS.len() + trap as usize].load(Ordering::Relaxed))
+}
+
+// Emulations of given kind done on given hart, or None if scratch region is not initialized
+pub fn emulations(hart_id: usize, emulation: Emulation) -> Option<usize> {
+    counters(hart_id).map(|counters| {
+        counters[ECALL_HANDLERS.len() + TRAP_KINDS.len() + emulation as usize]
+            .load(Ordering::Relaxed)
+    })
+}
@@ -0,0 +1,137 @@
+//! Vendor specific SBI extensions
+//!
+//! Extensions in the vendor range `0x09000000..=0x09FFFFFF` are registered at boot into a
+//! dispatch table, which is consulted before RustSBI. A call to an unregistered vendor extension
+//! returns `SBI_ERR_NOT_SUPPORTED`, and so should a handler for function ids it does not know.
+//! Registered extensions are reported as available by base extension probe.
+//!
+//! Registration is closed by `seal` once boot hart is about to enter supervisor; registering
+//! later panics, so that no handler appears while supervisor may already be probing or calling.
+//! Secondary harts only reach supervisor through HSM start, an SBI call made after that.
+//!
+//! Extension ids allocated by RustSBI-JH7100:
+//!
+//! | Extension id  | Usage                      | Cargo feature
+//! |:--------------|:---------------------------|:--------------
+//! | `0x0900_0000` | reserved                   |
+//! | `0x0900_0001` | halt hart, dump memory     | `diagnostics`
+//! | `0x0900_0002` | trace dump, reserved       |
+//! | `0x0900_0003` | query build configuration  |
+//! | `0x0900_0004` | firmware output, fallback  |
+//! | `0x0900_0005` | mask of started harts      |
+//! | `0x0900_0006` | exception delegation       |
+//! | `0x0900_0007` | A/B payload slots          | `payload-slots`
+//! | `0x0900_0008` | cache maintenance          |
+//! | `0x0900_0009` | firmware tables            | `firmware-tables`
+//! | `0x0900_000A` | supervisor checkpoint      | `checkpoint`
+//! | `0x0900_1000` | first downstream extension | `example-extension` for the example
+//!
+//! Ids from `EXTENSION_DOWNSTREAM_START` to `EXTENSION_VENDOR_END` are left to downstream forks,
+//! which add their board specific extensions with `register_vendor_extension` from
+//! `downstream::register_extensions`, without touching this table or the dispatch in `execute`.
+//! Their handlers also get the calling hart, see `ExtensionHandler`.
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use rustsbi::SbiRet;
+
+pub const EXTENSION_VENDOR_START: usize = 0x0900_0000;
+pub const EXTENSION_VENDOR_END: usize = 0x09FF_FFFF;
+
+pub const EXTENSION_DIAGNOSTICS: usize = 0x0900_0001;
+pub const EXTENSION_TRACE: usize = 0x0900_0002;
+pub const EXTENSION_BUILD_INFO: usize = 0x0900_0003;
+pub const EXTENSION_CONSOLE_CONTROL: usize = 0x0900_0004;
+pub const EXTENSION_HART_MASK: usize = 0x0900_0005;
+pub const EXTENSION_DELEGATION: usize = 0x0900_0006;
+pub const EXTENSION_PAYLOAD_SLOT: usize = 0x0900_0007;
+pub const EXTENSION_CACHE: usize = 0x0900_0008;
+pub const EXTENSION_FIRMWARE_TABLES: usize = 0x0900_0009;
+pub const EXTENSION_CHECKPOINT: usize = 0x0900_000A;
+
+// First extension id left to downstream forks
+pub const EXTENSION_DOWNSTREAM_START: usize = 0x0900_1000;
+
+const EXTENSION_BASE: usize = 0x10;
+const FUNCTION_BASE_PROBE_EXTENSION: usize = 0x3;
+
+// Handles a vendor extension call with function id and parameters a0 to a5
+pub type VendorHandler = fn(function: usize, param: [usize; 6]) -> SbiRet;
+
+// Handles a downstream extension call with function id, parameters a0 to a5 and the calling hart
+pub type ExtensionHandler = fn(function: usize, param: [usize; 6], hart_id: usize) -> SbiRet;
+
+#[derive(Clone, Copy)]
+enum Handler {
+    Firmware(VendorHandler),
+    Downstream(ExtensionHandler),
+}
+
+static VENDOR_EXTENSIONS: spin::RwLock<Vec<(usize, Handler)>> = spin::RwLock::new(Vec::new());
+
+// Set once supervisor may run, see `seal`
+static SEALED: AtomicBool = AtomicBool::new(false);
+
+// Register handler of a vendor extension of RustSBI-JH7100; should be called during boot.
+pub fn register(extension: usize, handler: VendorHandler) {
+    assert!(
+        (EXTENSION_VENDOR_START..EXTENSION_DOWNSTREAM_START).contains(&extension),
+        "extension {:#x} is not in vendor range of RustSBI-JH7100",
+        extension
+    );
+    insert(extension, Handler::Firmware(handler));
+}
+
+// Register handler of a downstream vendor extension, from `EXTENSION_DOWNSTREAM_START` to
+// `EXTENSION_VENDOR_END`; must be called during boot, before `seal`.
+pub fn register_vendor_extension(extension: usize, handler: ExtensionHandler) {
+    assert!(
+        (EXTENSION_DOWNSTREAM_START..=EXTENSION_VENDOR_END).contains(&extension),
+        "extension {:#x} is not in downstream vendor range",
+        extension
+    );
+    insert(extension, Handler::Downstream(handler));
+}
+
+// Close registration; called on boot hart right before it enters supervisor
+pub fn seal() {
+    SEALED.store(true, Ordering::Release);
+}
+
+fn insert(extension: usize, handler: Handler) {
+    assert!(
+        !SEALED.load(Ordering::Acquire),
+        "vendor extension {:#x} registered after supervisor entry",
+        extension
+    );
+    let mut extensions = VENDOR_EXTENSIONS.write();
+    assert!(
+        extensions
+            .iter()
+            .all(|(registered, _)| *registered != extension),
+        "vendor extension {:#x} registered twice",
+        extension
+    );
+    extensions.push((extension, handler));
+}
+
+// Dispatch vendor extension calls and their probes; returns None if RustSBI should handle this call
+pub fn handle_ecall(extension: usize, function: usize, param: [usize; 6]) -> Option<SbiRet> {
+    let extensions = VENDOR_EXTENSIONS.read();
+    let find = |extension| {
+        extensions
+            .iter()
+            .find(|(registered, _)| *registered == extension)
+            .map(|(_, handler)| *handler)
+    };
+    match (extension, function) {
+        (EXTENSION_BASE, FUNCTION_BASE_PROBE_EXTENSION) => find(param[0]).map(|_| SbiRet::ok(1)),
+        (EXTENSION_VENDOR_START..=EXTENSION_VENDOR_END, _) => Some(match find(extension) {
+            Some(Handler::Firmware(handler)) => handler(function, param),
+            Some(Handler::Downstream(handler)) => {
+                handler(function, param, crate::execute::calling_hart())
+            }
+            None => SbiRet::not_supported(),
+        }),
+        _ => None,
+    }
+}
@@ -0,0 +1,88 @@
+//! Supervisor hang watchdog
+//!
+//! A diagnostic for supervisors that stopped making progress, unrelated to the hardware watchdog.
+//! The watchdog owns a machine timer deadline of each hart, multiplexed with the supervisor timer
+//! by `timer`. If the watchdog deadline is reached while supervisor made no ecall in the last
+//! interval, supervisor pc and context are printed.
+//!
+//! Delegated interrupts never trap into firmware, thus only ecalls count as supervisor activity.
+//!
+//! A common cause is a supervisor spinning with its interrupts masked while one is pending, e.g.
+//! a kernel waiting for a timer or IPI with `sstatus.SIE` clear. When a hang is reported with a
+//! supervisor interrupt pending in `sip` that `sstatus.SIE` or `sie` masks, a specific hint is
+//! printed as well. The watchdog only reports, it never forces an interrupt or changes state.
+use crate::peripheral::Clint;
+use crate::println;
+use crate::runtime::SupervisorContext;
+use crate::timer::{self, Owner};
+use core::sync::atomic::{AtomicBool, Ordering};
+use riscv::register::{mstatus::MPP, scause, sepc, stval};
+
+// Supervisor interrupts in sip and sie: software, timer, external
+const SUPERVISOR_INTERRUPTS: usize = (1 << 1) | (1 << 5) | (1 << 9);
+
+// 10 seconds
+const WATCHDOG_INTERVAL_US: u64 = 10_000_000;
+
+const NOT_FED: AtomicBool = AtomicBool::new(false);
+static FED: [AtomicBool; crate::NUM_HARTS] = [NOT_FED; crate::NUM_HARTS];
+
+fn clint() -> Clint {
+    Clint::new(0x2000000 as *mut u8)
+}
+
+// Arm watchdog on current hart; called every time the hart (re)enters supervisor
+pub fn start(hart_id: usize) {
+    feed(hart_id);
+    let clint = clint();
+    let deadline = clint.get_mtime() + clint.us_to_ticks(WATCHDOG_INTERVAL_US);
+    timer::set_deadline(hart_id, Owner::Watchdog, deadline);
+}
+
+// Record supervisor activity on current hart
+#[inline]
+pub fn feed(hart_id: usize) {
+    FED[hart_id].store(true, Ordering::Relaxed);
+}
+
+// Handle machine timer interrupt, re-arms watchdog deadline if it is due
+pub fn on_machine_timer(hart_id: usize, ctx: &SupervisorContext) {
+    let clint = clint();
+    let now = clint.get_mtime();
+    let interval = clint.us_to_ticks(WATCHDOG_INTERVAL_US);
+    if now >= timer::deadline(hart_id, Owner::Watchdog) {
+        if !FED[hart_id].swap(false, Ordering::Relaxed) {
+            println!(
+                "[rustsbi] hart {} supervisor appears hung, no ecall in {} ticks",
+                hart_id, interval
+            );
+            println!(
+                "[rustsbi] supervisor pc: {:#x}, sepc: {:#x}, scause: {:#x}, stval: {:#x}",
+                ctx.mepc,
+                sepc::read(),
+                scause::read().bits(),
+                stval::read()
+            );
+            println!("[rustsbi] supervisor context: {:x?}", ctx);
+            report_masked_interrupts(ctx);
+        }
+        timer::set_deadline(hart_id, Owner::Watchdog, now + interval);
+    }
+}
+
+// Print a hint if a supervisor interrupt is pending but masked from the hung supervisor
+fn report_masked_interrupts(ctx: &SupervisorContext) {
+    let (sip, sie): (usize, usize);
+    unsafe { core::arch::asm!("csrr {}, sip", "csrr {}, sie", out(reg) sip, out(reg) sie) };
+    let pending = sip & SUPERVISOR_INTERRUPTS;
+    // in user mode, supervisor interrupts are enabled regardless of sstatus.SIE
+    let globally_masked = ctx.mstatus.mpp() == MPP::Supervisor && !ctx.mstatus.sie();
+    if pending != 0 && (globally_masked || pending & sie == 0) {
+        println!(
+            "[rustsbi] hint: supervisor has interrupts masked with work pending, sip: {:#x}, sie: {:#x}, sstatus.SIE: {}",
+            sip,
+            sie,
+            ctx.mstatus.sie()
+        );
+    }
+}
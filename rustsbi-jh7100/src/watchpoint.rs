@@ -0,0 +1,221 @@
+//! PC watchpoints on supervisor code
+//!
+//! Functions `FUNCTION_SET_WATCHPOINT` and `FUNCTION_CLEAR_WATCHPOINT` of vendor extension
+//! `EXTENSION_DIAGNOSTICS` arm and disarm a watchpoint at the supervisor virtual address in a0,
+//! translated by the calling hart's current satp, see `page_table_dump::translate`:
+//!
+//! | Function | Parameters  | Returns
+//! |:---------|:------------|:---------
+//! | 5        | a0: address | index of the armed watchpoint
+//! | 6        | a0: address | 0; the original instruction is put back
+//!
+//! Arming saves the halfword at the address and writes `c.ebreak` over it. A two byte breakpoint
+//! fits at the start of any instruction, and patches only the first page of a four byte one
+//! which crosses a page boundary. When a hart reaches it, firmware prints its context as a debug
+//! halt does, puts the original halfword back and resumes the hart at the same pc, where it runs
+//! the original instruction. A watchpoint fires once, its hit disarms it.
+//!
+//! Function 5 returns `SBI_ERR_INVALID_PARAM` for an odd address, `SBI_ERR_INVALID_ADDRESS` if the
+//! address is not mapped executable, or maps outside DRAM above the firmware, and
+//! `SBI_ERR_FAILED` if all `MAX_WATCHPOINTS` are armed; `SBI_ERR_ALREADY_AVAILABLE` if one is
+//! armed at the same physical address. An address which is not mapped yet is rejected, not
+//! deferred: firmware does not see supervisor map pages, and would never know when to arm it.
+//! Function 6 returns `SBI_ERR_INVALID_PARAM` if no watchpoint is armed there, including one
+//! which already fired, and `SBI_ERR_INVALID_ADDRESS` if the address is no longer mapped.
+//!
+//! Breakpoints are delegated to supervisor. While any watchpoint is armed, firmware takes them
+//! back on every hart: the calling hart at once, other started harts on an IPI from it, stopped
+//! harts as they start. The same IPI runs `fence.i` on them, so that none runs the code from a
+//! stale instruction cache; it also reaches supervisor as a software interrupt with nothing to
+//! do. Breakpoints which are not watchpoints are handed to supervisor's trap handler as they
+//! were. Once the last watchpoint is gone, breakpoints are delegated again, unless supervisor
+//! had un-delegated them itself through `delegation`.
+use crate::page_table_dump::{self, PTE_X};
+use crate::println;
+use crate::runtime::SupervisorContext;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::{medeleg, satp};
+use rustsbi::SbiRet;
+
+pub const MAX_WATCHPOINTS: usize = 8;
+
+const C_EBREAK: u16 = 0x9002;
+// `ebreak` as two halfwords, lower first
+const EBREAK: [u16; 2] = [0x0073, 0x0010];
+
+const HSM_STATE_STARTED: usize = 0;
+
+#[derive(Clone, Copy)]
+struct Watchpoint {
+    // address as supervisor armed it, and where it maps to
+    va: usize,
+    pa: usize,
+    // halfword `c.ebreak` replaced
+    original: u16,
+}
+
+static WATCHPOINTS: spin::Mutex<[Option<Watchpoint>; MAX_WATCHPOINTS]> =
+    spin::Mutex::new([None; MAX_WATCHPOINTS]);
+static ARMED: AtomicUsize = AtomicUsize::new(0);
+
+const CLEAR: AtomicBool = AtomicBool::new(false);
+// harts on which firmware un-delegated breakpoints for watchpoints
+static TAKEN: [AtomicBool; crate::NUM_HARTS] = [CLEAR; crate::NUM_HARTS];
+
+pub fn set(va: usize) -> SbiRet {
+    if va % 2 != 0 {
+        return SbiRet::invalid_param();
+    }
+    let pa = match translate_executable(va) {
+        Some(pa) => pa,
+        None => return SbiRet::invalid_address(),
+    };
+    let mut watchpoints = WATCHPOINTS.lock();
+    if watchpoints.iter().flatten().any(|w| w.pa == pa) {
+        return SbiRet::already_available();
+    }
+    let index = match watchpoints.iter().position(Option::is_none) {
+        Some(index) => index,
+        None => return SbiRet::failed(),
+    };
+    let original = unsafe { core::ptr::read_volatile(pa as *const u16) };
+    watchpoints[index] = Some(Watchpoint { va, pa, original });
+    unsafe { patch(pa, C_EBREAK) };
+    let first = ARMED.fetch_add(1, Ordering::AcqRel) == 0;
+    drop(watchpoints);
+    println!(
+        "[rustsbi] watchpoint {} armed at {:#x}, physical {:#x}",
+        index, va, pa
+    );
+    if first {
+        sync_harts();
+    }
+    SbiRet::ok(index)
+}
+
+pub fn clear(va: usize) -> SbiRet {
+    let pa = match translate_executable(va) {
+        Some(pa) => pa,
+        None => return SbiRet::invalid_address(),
+    };
+    let mut watchpoints = WATCHPOINTS.lock();
+    let watchpoint = match take(&mut *watchpoints, pa) {
+        Some(watchpoint) => watchpoint,
+        None => return SbiRet::invalid_param(),
+    };
+    unsafe { patch(pa, watchpoint.original) };
+    let last = ARMED.fetch_sub(1, Ordering::AcqRel) == 1;
+    drop(watchpoints);
+    if last {
+        sync_harts();
+    }
+    SbiRet::ok(0)
+}
+
+// Handle a breakpoint which reached firmware: fire the watchpoint at pc, or hand it on
+pub fn on_breakpoint(hart_id: usize, ctx: &mut SupervisorContext) {
+    let pa = page_table_dump::translate(satp::read().bits(), ctx.mepc)
+        .map(|(pa, _)| pa)
+        .filter(|&pa| in_payload_dram(pa));
+    if let Some(pa) = pa {
+        let mut watchpoints = WATCHPOINTS.lock();
+        if let Some(watchpoint) = take(&mut *watchpoints, pa) {
+            unsafe { patch(pa, watchpoint.original) };
+            let last = ARMED.fetch_sub(1, Ordering::AcqRel) == 1;
+            drop(watchpoints);
+            println!(
+                "[rustsbi] hart {} reached watchpoint at {:#x}, physical {:#x}",
+                hart_id, watchpoint.va, pa
+            );
+            crate::debug_halt::print_context(ctx);
+            if last {
+                sync_harts();
+            }
+            // resume at the same pc, on the original instruction
+            return;
+        }
+        drop(watchpoints);
+        // a watchpoint another hart fired or cleared, fetched from a stale instruction cache
+        if !is_ebreak(pa) {
+            unsafe { fence_i() };
+            return;
+        }
+    }
+    unsafe {
+        if crate::feature::should_transfer_trap(ctx) {
+            crate::feature::do_transfer_trap(ctx, crate::feature::EXCEPTION_BREAKPOINT)
+        } else {
+            panic!(
+                "breakpoint from machine level, mepc: {:016x?}, context: {:016x?}",
+                ctx.mepc, ctx
+            )
+        }
+    }
+}
+
+// Bring breakpoint delegation of current hart in line with the armed watchpoints, and drop its
+// stale instructions; called on every machine software interrupt and supervisor entry
+pub fn sync_delegation(hart_id: usize) {
+    let armed = ARMED.load(Ordering::Acquire) != 0;
+    let taken = TAKEN[hart_id].load(Ordering::Relaxed);
+    unsafe {
+        if armed && !taken && medeleg::read().breakpoint() {
+            medeleg::clear_breakpoint();
+            TAKEN[hart_id].store(true, Ordering::Relaxed);
+        } else if !armed && taken {
+            medeleg::set_breakpoint();
+            TAKEN[hart_id].store(false, Ordering::Relaxed);
+        }
+        fence_i();
+    }
+}
+
+// Sync calling hart, and send other started harts an IPI to sync themselves
+fn sync_harts() {
+    let hart_id = crate::execute::calling_hart();
+    sync_delegation(hart_id);
+    let clint = crate::peripheral::Clint::new(0x2000000 as *mut u8);
+    for target in (0..crate::NUM_HARTS).filter(|&target| target != hart_id) {
+        // stopped and suspended harts wait for an HSM command, they sync as they start
+        if rustsbi::Hsm::hart_get_status(&*crate::HSM, target).value == HSM_STATE_STARTED {
+            clint.send_soft(target);
+        }
+    }
+}
+
+// Disarm the watchpoint at physical `pa`, if any
+fn take(watchpoints: &mut [Option<Watchpoint>], pa: usize) -> Option<Watchpoint> {
+    let slot = watchpoints
+        .iter_mut()
+        .find(|w| w.map_or(false, |w| w.pa == pa))?;
+    slot.take()
+}
+
+fn translate_executable(va: usize) -> Option<usize> {
+    match page_table_dump::translate(satp::read().bits(), va) {
+        Some((pa, flags)) if flags & PTE_X != 0 && in_payload_dram(pa) => Some(pa),
+        _ => None,
+    }
+}
+
+// Whether the halfword at `pa` is supervisor memory in DRAM, above firmware
+fn in_payload_dram(pa: usize) -> bool {
+    pa >= crate::payload::LOAD_ADDRESS && pa + 2 <= crate::DRAM_PMP_END
+}
+
+fn is_ebreak(pa: usize) -> bool {
+    let low = unsafe { core::ptr::read_volatile(pa as *const u16) };
+    low == C_EBREAK
+        || low == EBREAK[0]
+            && in_payload_dram(pa + 2)
+            && unsafe { core::ptr::read_volatile((pa + 2) as *const u16) } == EBREAK[1]
+}
+
+unsafe fn patch(pa: usize, halfword: u16) {
+    core::ptr::write_volatile(pa as *mut u16, halfword);
+    fence_i();
+}
+
+unsafe fn fence_i() {
+    core::arch::asm!("fence.i");
+}
@@ -0,0 +1,94 @@
+//! Minimal reader of the device tree passed by SBI, enough to check nodes firmware writes
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+fn be32(addr: usize) -> u32 {
+    u32::from_be(unsafe { core::ptr::read_volatile(addr as *const u32) })
+}
+
+fn be64(addr: usize) -> u64 {
+    ((be32(addr) as u64) << 32) | be32(addr + 4) as u64
+}
+
+fn c_str(addr: usize) -> &'static [u8] {
+    let mut len = 0;
+    while unsafe { *((addr + len) as *const u8) } != 0 {
+        len += 1;
+    }
+    unsafe { core::slice::from_raw_parts(addr as *const u8, len) }
+}
+
+// First `reg` entry (address, size) of the first node whose parent name and own name match,
+// assuming two address cells and two size cells. Parent of top level nodes is root, named "".
+#[cfg(any(
+    feature = "memory-fixup",
+    feature = "handoff-info",
+    feature = "firmware-tables"
+))]
+pub fn find_reg(dtb_pa: usize, parent: &str, node: &str) -> Option<(u64, u64)> {
+    match find_prop(dtb_pa, parent, node, "reg") {
+        Some((value, len)) if len >= 16 => Some((be64(value), be64(value + 8))),
+        _ => None,
+    }
+}
+
+// `timebase-frequency` of `/cpus`, one or two cells
+pub fn timebase_frequency(dtb_pa: usize) -> Option<u64> {
+    match find_prop(dtb_pa, "", "cpus", "timebase-frequency") {
+        Some((value, 4)) => Some(be32(value) as u64),
+        Some((value, 8)) => Some(be64(value)),
+        _ => None,
+    }
+}
+
+// Address and length of the value of property `prop`, in the first node whose parent name and
+// own name match
+fn find_prop(dtb_pa: usize, parent: &str, node: &str, prop: &str) -> Option<(usize, usize)> {
+    if be32(dtb_pa) != FDT_MAGIC {
+        return None;
+    }
+    let strings = dtb_pa + be32(dtb_pa + 12) as usize;
+    let mut pos = dtb_pa + be32(dtb_pa + 8) as usize;
+    let matches = |name: &[u8], expected: &str| {
+        name == expected.as_bytes()
+            || (name.starts_with(expected.as_bytes()) && name.get(expected.len()) == Some(&b'@'))
+    };
+    // names of nodes from root to current one
+    let mut path: [&[u8]; 8] = [&[]; 8];
+    let mut depth = 0;
+    loop {
+        let token = be32(pos);
+        pos += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = c_str(pos);
+                pos = (pos + name.len() + 1 + 3) & !3;
+                if depth >= path.len() {
+                    return None;
+                }
+                path[depth] = name;
+                depth += 1;
+            }
+            FDT_END_NODE => depth -= 1,
+            FDT_PROP => {
+                let len = be32(pos) as usize;
+                let name = c_str(strings + be32(pos + 4) as usize);
+                let value = pos + 8;
+                pos = (value + len + 3) & !3;
+                if name == prop.as_bytes()
+                    && depth >= 2
+                    && matches(path[depth - 2], parent)
+                    && matches(path[depth - 1], node)
+                {
+                    return Some((value, len));
+                }
+            }
+            FDT_NOP => {}
+            _ => return None, // FDT_END
+        }
+    }
+}
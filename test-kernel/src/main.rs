@@ -4,57 +4,152 @@
 #![no_main]
 
 mod console;
+mod dtb;
 mod mm;
 mod sbi;
 mod util;
 
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use riscv::register::{
-    scause::{self, Exception, Trap},
-    sepc,
+    scause::{self, Exception, Interrupt, Trap},
+    sepc, stval,
     stvec::{self, TrapMode},
 };
+use util::AmoMutex;
 
 pub extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
-    if hartid == 0 {
-        // initialization
-        mm::init_heap();
-    }
-    if hartid == 0 {
-        println!(
-            "<< Test-kernel: Hart id = {}, DTB physical address = {:#x}",
-            hartid, dtb_pa
-        );
-        test_base_extension();
-        test_sbi_ins_emulation();
-        unsafe { stvec::write(start_trap as usize, TrapMode::Direct) };
-        println!(">> Test-kernel: Trigger illegal exception");
-        unsafe { core::arch::asm!("csrw mcycle, x0") }; // mcycle cannot be written, this is always a 4-byte illegal instruction
+    // before anything else could write it
+    let entry_sscratch = riscv::register::sscratch::read();
+    #[cfg(feature = "reverse-boot-order")]
+    test_reverse_boot_order(hartid);
+    if hartid != 0 {
+        // secondary harts are started by the HSM test on hart 0
+        secondary_main(hartid)
     }
+    // initialization
+    mm::init_heap();
+    println!(
+        "<< Test-kernel: Hart id = {}, DTB physical address = {:#x}",
+        hartid, dtb_pa
+    );
+    test_base_extension();
+    test_build_config();
+    test_timebase_frequency(dtb_pa);
+    test_firmware_output_control();
+    #[cfg(feature = "diagnostics")]
+    test_memory_dump();
+    #[cfg(feature = "diagnostics")]
+    test_trap_stats();
+    #[cfg(feature = "diagnostics")]
+    test_memory_map();
+    #[cfg(feature = "memory-fixup")]
+    test_memory_node(dtb_pa);
+    #[cfg(feature = "handoff-info")]
+    test_boot_handoff(hartid, dtb_pa);
+    #[cfg(feature = "firmware-tables")]
+    test_firmware_tables(dtb_pa);
+    #[cfg(feature = "example-extension")]
+    test_example_extension(hartid);
+    test_legacy_return();
+    test_sbi_ins_emulation();
+    unsafe { stvec::write(start_trap as usize, TrapMode::Direct) };
+    test_counters();
+    test_scratch_ownership(entry_sscratch);
+    test_illegal_instruction_delegate();
+    test_illegal_instruction_execute_only();
+    #[cfg(feature = "diagnostics")]
+    test_page_table_dump();
+    #[cfg(feature = "diagnostics")]
+    test_watchpoint();
+    test_machine_return_from_supervisor();
+    test_hypervisor_instructions();
+    #[cfg(feature = "emulate-zicond")]
+    test_zicond_emulation();
+    #[cfg(feature = "bitmanip-emulation")]
+    test_bitmanip_emulation();
+    test_sstatus_sum();
+    #[cfg(feature = "emulate-sret")]
+    test_sret_emulation();
+    test_pmp();
+    test_fwft();
+    test_delegation();
+    test_cache_flush();
+    test_misaligned_emulation();
+    #[cfg(feature = "emulate-mmio-amo")]
+    test_mmio_amo_emulation();
+    test_timer_reprogram();
+    test_timer_in_past();
+    #[cfg(feature = "ipi-doorbell")]
+    test_ipi_doorbell();
+    test_hsm();
+    test_concurrent_calls();
+    test_rfence();
+    // after the concurrent calls, so that both harts have some
+    #[cfg(feature = "ecall-latency")]
+    test_ecall_latency();
+    #[cfg(feature = "payload-slots")]
+    test_payload_slots();
+    #[cfg(feature = "checkpoint")]
+    test_checkpoint();
+    // last, it ends in a firmware panic
+    #[cfg(feature = "stack-guard")]
+    test_stack_overflow();
+    println!("<< Test-kernel: SBI test SUCCESS, shutdown");
+    sbi::shutdown()
+}
+
+// Requires SBI built with features `stack-guard` and `diagnostics`. Firmware must panic with
+// "stack overflow on hart 0" and halt, thus this test never reports success; check the firmware
+// console, the run ends in a timeout.
+#[cfg(feature = "stack-guard")]
+fn test_stack_overflow() {
+    println!(">> Test-kernel: Testing firmware stack guard, expect a stack overflow panic");
+    let ret = sbi::overflow_firmware_stack();
+    println!(
+        "!! Test-kernel: SBI test FAILED due to firmware stack overflow returned {:?}",
+        ret
+    );
+    sbi::shutdown_failure()
+}
+
+// Set by hart 0 once HSM has started it, in the reverse boot order test
+#[cfg(feature = "reverse-boot-order")]
+static LATE_HART_STARTED: AtomicBool = AtomicBool::new(false);
+
+// Requires SBI built with feature `reverse-boot-order`: hart 1 claims boot and enters the
+// payload, hart 0 is parked as a secondary until HSM starts it. Runs alone, as every other test
+// takes hart 0 for boot hart
+#[cfg(feature = "reverse-boot-order")]
+fn test_reverse_boot_order(hartid: usize) {
     if hartid == 0 {
-        for i in 0..2 {
-            let sbi_ret = sbi::hart_get_status(i);
-            println!(">> Hart {} state return value: {:?}", i, sbi_ret);
+        // started below, after hart 1 has booted
+        LATE_HART_STARTED.store(true, Ordering::Release);
+        loop {
+            core::hint::spin_loop();
         }
-    } else {
-        let sbi_ret = sbi::hart_suspend(0x00000000, 0, 0);
+    }
+    println!(">> Test-kernel: Testing boot hart claim in reverse arrival order");
+    if hartid != 1 {
         println!(
-            ">> Start test for hart {}, retentive suspend return value {:?}",
-            hartid, sbi_ret
+            "!! Test-kernel: SBI test FAILED due to payload entered on hart {}, expected hart 1",
+            hartid
         );
+        sbi::shutdown_failure()
     }
-    if hartid == 0 {
+    assert_hart_status(0, sbi::HSM_STATE_STOPPED);
+    let sbi_ret = sbi::hart_start(0, entry as usize, 0);
+    if sbi_ret.error != 0 {
         println!(
-            "<< Test-kernel: test for hart {} success, wake another hart",
-            hartid
+            "!! Test-kernel: SBI test FAILED due to starting hart 0 returned {:?}",
+            sbi_ret
         );
-        let bv: usize = 0b10;
-        let sbi_ret = sbi::send_ipi(&bv as *const _ as usize, hartid); // wake hartid + 1
-        println!(">> Wake hart 1, sbi return value {:?}", sbi_ret);
-        loop {} // wait for machine shutdown
-    } else {
-        // hartid == 2 || hartid == 3
-        unreachable!()
+        sbi::shutdown_failure()
     }
+    wait_for("hart 0 start", || LATE_HART_STARTED.load(Ordering::Acquire));
+    assert_hart_status(0, sbi::HSM_STATE_STARTED);
+    println!("<< Test-kernel: Boot hart claim success");
+    println!("<< Test-kernel: SBI test SUCCESS, shutdown");
+    sbi::shutdown()
 }
 
 fn test_base_extension() {
@@ -66,7 +161,7 @@ fn test_base_extension() {
             "!! Test-kernel: This SBI implementation may only have legacy extension implemented"
         );
         println!("!! Test-kernel: SBI test FAILED due to no base extension found");
-        sbi::shutdown()
+        sbi::shutdown_failure()
     }
     println!("<< Test-kernel: Base extension version: {:x}", base_version);
     println!(
@@ -89,6 +184,424 @@ fn test_base_extension() {
     println!("<< Test-kernel: Device mimpid: {:x}", sbi::get_mimpid());
 }
 
+fn test_build_config() {
+    println!(">> Test-kernel: Testing build configuration query");
+    let config = sbi::get_build_config();
+    if config.error != 0 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to build configuration query returned {:?}",
+            config
+        );
+        sbi::shutdown_failure()
+    }
+    let bitmap = config.value;
+    println!("<< Test-kernel: Build configuration: {:#x}", bitmap);
+    for (bit, extension) in sbi::BUILD_CONFIG_EXTENSIONS.iter().enumerate() {
+        let reported = bitmap & (1 << bit) != 0;
+        if reported != (sbi::probe_extension(*extension) != 0) {
+            println!(
+                "!! Test-kernel: SBI test FAILED due to build configuration bit {} disagreeing with probe of extension {:#x}",
+                bit, extension
+            );
+            sbi::shutdown_failure()
+        }
+    }
+    let expected = [
+        (sbi::BUILD_CONFIG_EMULATE_RDTIME, true),
+        (sbi::BUILD_CONFIG_EMULATE_MISALIGNED, true),
+        (
+            sbi::BUILD_CONFIG_EMULATE_ZICOND,
+            cfg!(feature = "emulate-zicond"),
+        ),
+        (
+            sbi::BUILD_CONFIG_EMULATE_BITMANIP,
+            cfg!(feature = "bitmanip-emulation"),
+        ),
+        (
+            sbi::BUILD_CONFIG_FIXED_COUNTERS,
+            cfg!(feature = "fixed-counters"),
+        ),
+        (
+            sbi::BUILD_CONFIG_EMULATE_SRET,
+            cfg!(feature = "emulate-sret"),
+        ),
+        (
+            sbi::BUILD_CONFIG_IPI_DOORBELL,
+            cfg!(feature = "ipi-doorbell"),
+        ),
+        (sbi::BUILD_CONFIG_CHECKPOINT, cfg!(feature = "checkpoint")),
+        (
+            sbi::BUILD_CONFIG_MEMORY_FIXUP,
+            cfg!(feature = "memory-fixup"),
+        ),
+        (
+            sbi::BUILD_CONFIG_ECALL_LATENCY,
+            cfg!(feature = "ecall-latency"),
+        ),
+        (sbi::BUILD_CONFIG_STACK_GUARD, cfg!(feature = "stack-guard")),
+        (
+            sbi::BUILD_CONFIG_FIRMWARE_TABLES,
+            cfg!(feature = "firmware-tables"),
+        ),
+    ];
+    for (bit, built) in expected {
+        // a test feature requires the SBI feature; SBI built with more than tested is fine
+        if built && bitmap & bit == 0 {
+            println!(
+                "!! Test-kernel: SBI test FAILED due to build configuration missing bit {:#x}",
+                bit
+            );
+            sbi::shutdown_failure()
+        }
+    }
+    println!("<< Test-kernel: Build configuration query success");
+}
+
+// Firmware keeps a `timebase-frequency` of its device tree within range and falls back to the
+// JH7100 default otherwise. The tree is the one built into firmware, whose value is in range, so
+// this only sees it passed through; the fallback is tested with crafted trees in `device_tree`
+fn test_timebase_frequency(dtb_pa: usize) {
+    println!(">> Test-kernel: Testing timebase frequency");
+    let frequency = sbi::get_timebase_frequency();
+    if frequency.error != 0 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to timebase frequency query returned {:?}",
+            frequency
+        );
+        sbi::shutdown_failure()
+    }
+    let expected = match dtb::timebase_frequency(dtb_pa) {
+        Some(tree) if sbi::TIMEBASE_FREQUENCY_RANGE.contains(&tree) => tree,
+        _ => sbi::DEFAULT_TIMEBASE_FREQUENCY,
+    };
+    if frequency.value as u64 != expected {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to timebase frequency {}, expected {}",
+            frequency.value, expected
+        );
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Timebase frequency {} Hz", frequency.value);
+}
+
+fn test_firmware_output_control() {
+    println!(">> Test-kernel: Testing firmware output control");
+    let silence = sbi::set_firmware_output(0);
+    // supervisor output must still get through while firmware is silenced
+    println!("<< Test-kernel: Firmware output silenced");
+    let restore = sbi::set_firmware_output(1);
+    let invalid = sbi::set_firmware_output(2);
+    if silence.error != 0 || silence.value != 1 || restore.error != 0 || restore.value != 0 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to output control returned {:?}, then {:?}",
+            silence, restore
+        );
+        sbi::shutdown_failure()
+    }
+    if invalid.error == 0 {
+        println!("!! Test-kernel: SBI test FAILED due to output control accepted value 2");
+        sbi::shutdown_failure()
+    }
+    // this very output reaches the console, so firmware must not have given up on it
+    let fallback = sbi::get_console_fallback();
+    if fallback.error != 0 || fallback.value != 0 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to console fallback query returned {:?}",
+            fallback
+        );
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Firmware output control success");
+}
+
+// Requires SBI built with feature `diagnostics`
+#[cfg(feature = "diagnostics")]
+fn test_memory_dump() {
+    println!(">> Test-kernel: Testing memory dump");
+    static PATTERN: [u8; 20] = *b"RustSBI memory dump\n";
+    let dump = sbi::dump_memory(PATTERN.as_ptr() as usize, PATTERN.len());
+    if dump.error != 0 || dump.value != PATTERN.len() {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to memory dump returned {:?}",
+            dump
+        );
+        sbi::shutdown_failure()
+    }
+    // MMIO is outside supervisor DRAM and is not dumped
+    let outside = sbi::dump_memory(0x1000_0000, 16);
+    if outside.error == 0 {
+        println!("!! Test-kernel: SBI test FAILED due to memory dump outside DRAM succeeded");
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Memory dump success");
+}
+
+// Requires SBI built with feature `diagnostics`
+#[cfg(feature = "diagnostics")]
+fn test_trap_stats() {
+    println!(">> Test-kernel: Testing trap counters");
+    // counters are only printed on firmware console, this checks the call itself
+    let stats = sbi::print_trap_stats();
+    if stats.error != 0 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to printing trap counters returned {:?}",
+            stats
+        );
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Trap counters success");
+}
+
+// Requires SBI built with feature `diagnostics`
+#[cfg(feature = "diagnostics")]
+fn test_memory_map() {
+    println!(">> Test-kernel: Testing memory map");
+    // the map is only printed on firmware console, this checks the call itself
+    let map = sbi::print_memory_map();
+    if map.error != 0 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to printing memory map returned {:?}",
+            map
+        );
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Memory map success");
+}
+
+// Requires SBI built with feature `ecall-latency`
+#[cfg(feature = "ecall-latency")]
+fn test_ecall_latency() {
+    println!(">> Test-kernel: Testing SBI call latency histograms");
+    // histograms are only printed on firmware console, this checks the call itself
+    let latency = sbi::print_ecall_latency();
+    if latency.error != 0 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to printing SBI call latency returned {:?}",
+            latency
+        );
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: SBI call latency histograms success");
+}
+
+// Requires SBI built with feature `payload-slots`, and an image without payload slot B
+#[cfg(feature = "payload-slots")]
+fn test_payload_slots() {
+    println!(">> Test-kernel: Testing payload slots");
+    let booted = sbi::get_booted_slot();
+    let present = sbi::get_present_slots();
+    println!(
+        "<< Test-kernel: booted slot {:?}, present slots {:?}",
+        booted, present
+    );
+    if booted.error != 0 || booted.value != 0 || present.error != 0 || present.value != 0b1 {
+        println!("!! Test-kernel: SBI test FAILED due to unexpected payload slots");
+        sbi::shutdown_failure()
+    }
+    let missing = sbi::set_next_slot(1);
+    let unknown = sbi::set_next_slot(2);
+    let slot_a = sbi::set_next_slot(0);
+    let confirm = sbi::confirm_boot();
+    if missing.error != sbi::SBI_ERR_INVALID_PARAM
+        || unknown.error != sbi::SBI_ERR_INVALID_PARAM
+        || slot_a.error != 0
+        || confirm.error != 0
+    {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to selecting slots B, 2, A returned {:?}, {:?}, {:?}, confirm {:?}",
+            missing, unknown, slot_a, confirm
+        );
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Payload slots success");
+}
+
+// Requires SBI built with feature `checkpoint`
+#[cfg(feature = "checkpoint")]
+fn test_checkpoint() {
+    const MARKER: usize = 0x5253_4249_4350_7465;
+    println!(">> Test-kernel: Testing supervisor checkpoint");
+    if sbi::probe_extension(sbi::EXTENSION_CHECKPOINT) == 0 {
+        println!("!! Test-kernel: SBI test FAILED due to no checkpoint extension found");
+        sbi::shutdown_failure()
+    }
+    let blank = sbi::checkpoint_restore(&[0; sbi::CHECKPOINT_SIZE]);
+    if blank.error != sbi::SBI_ERR_INVALID_PARAM {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to restoring an empty buffer returned {:?}",
+            blank
+        );
+        sbi::shutdown_failure()
+    }
+    let mut buf = [0; sbi::CHECKPOINT_SIZE];
+    let (ret, t3, sscratch) = sbi::checkpoint_round_trip(&mut buf, MARKER);
+    riscv::register::sscratch::write(0);
+    if ret.error != 0 || ret.value != 1 || t3 != MARKER || sscratch != MARKER {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to checkpoint round trip returned {:?}, t3 {:#x}, sscratch {:#x}",
+            ret, t3, sscratch
+        );
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Supervisor checkpoint success");
+}
+
+// Requires SBI built with feature `handoff-info`
+#[cfg(feature = "handoff-info")]
+fn test_boot_handoff(hartid: usize, dtb_pa: usize) {
+    // boot handoff structure of RustSBI-JH7100, in its reserved region of the device tree
+    const HANDOFF_OFFSET: u64 = 0x4_2000;
+    const HANDOFF_MAGIC: u64 = u64::from_le_bytes(*b"RSBIHOFF");
+    println!(">> Test-kernel: Testing boot handoff structure");
+    let (reserved_base, _) = match dtb::find_reg(dtb_pa, "reserved-memory", "rustsbi") {
+        Some(reserved) => reserved,
+        None => {
+            println!("!! Test-kernel: SBI test FAILED due to no firmware reserved region");
+            sbi::shutdown_failure()
+        }
+    };
+    let handoff = (reserved_base + HANDOFF_OFFSET) as *const u64;
+    let field = |offset: usize| unsafe { handoff.add(offset / 8).read_volatile() };
+    let (magic, version_size) = (field(0x00), field(0x08));
+    let (device_tree, entry, boot_hart) = (field(0x20), field(0x28), field(0x40));
+    println!(
+        "<< Test-kernel: Boot handoff version {}, device tree {:#x}, entry {:#x}, DRAM {:#x} bytes at {:#x}",
+        version_size as u32,
+        device_tree,
+        entry,
+        field(0x38),
+        field(0x30)
+    );
+    if magic != HANDOFF_MAGIC
+        || version_size as u32 == 0
+        || device_tree != dtb_pa as u64
+        || entry != 0x8020_0000
+        || boot_hart != hartid as u64
+    {
+        println!("!! Test-kernel: SBI test FAILED due to boot handoff does not match the boot");
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Boot handoff structure success");
+}
+
+// Requires SBI built with feature `example-extension`, the example of a downstream extension
+#[cfg(feature = "example-extension")]
+fn test_example_extension(hartid: usize) {
+    println!(">> Test-kernel: Testing example downstream extension");
+    if sbi::probe_extension(sbi::EXTENSION_EXAMPLE) == 0 {
+        println!("!! Test-kernel: SBI test FAILED due to example extension not available");
+        sbi::shutdown_failure()
+    }
+    let hart = sbi::example_hart_id();
+    let sum = sbi::example_add(usize::MAX, 2);
+    let unknown = sbi::example_unknown();
+    println!(
+        "<< Test-kernel: Example extension hart {:?}, sum {:?}, unknown function {:?}",
+        hart, sum, unknown
+    );
+    if hart.error != 0
+        || hart.value != hartid
+        || sum.error != 0
+        || sum.value != 1
+        || unknown.error != sbi::SBI_ERR_NOT_SUPPORTED
+    {
+        println!("!! Test-kernel: SBI test FAILED due to unexpected example extension results");
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Example downstream extension success");
+}
+
+// Requires SBI built with feature `firmware-tables`; passes on an image with or without tables
+#[cfg(feature = "firmware-tables")]
+fn test_firmware_tables(dtb_pa: usize) {
+    // firmware tables slot of RustSBI-JH7100, in its reserved region of the device tree
+    const TABLES_OFFSET: u64 = 0x4_5000;
+    const TABLES_MAX_LEN: usize = 0x1_0000;
+    println!(">> Test-kernel: Testing firmware tables");
+    let address = sbi::get_firmware_tables_address();
+    let length = sbi::get_firmware_tables_length();
+    println!(
+        "<< Test-kernel: Firmware tables address {:?}, length {:?}",
+        address, length
+    );
+    if address.error != 0 || length.error != 0 {
+        println!("!! Test-kernel: SBI test FAILED due to firmware tables query failed");
+        sbi::shutdown_failure()
+    }
+    let valid = match (address.value, length.value) {
+        (0, 0) => true,
+        (0, _) | (_, 0) => false,
+        (address, length) => {
+            let reserved = dtb::find_reg(dtb_pa, "reserved-memory", "rustsbi");
+            length <= TABLES_MAX_LEN
+                && reserved.map(|(base, _)| base + TABLES_OFFSET) == Some(address as u64)
+        }
+    };
+    if !valid {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to firmware tables outside their reserved slot"
+        );
+        sbi::shutdown_failure()
+    }
+    #[cfg(feature = "handoff-info")]
+    {
+        const HANDOFF_OFFSET: u64 = 0x4_2000;
+        let reserved = dtb::find_reg(dtb_pa, "reserved-memory", "rustsbi");
+        let handoff = reserved.map(|(base, _)| (base + HANDOFF_OFFSET) as *const u64);
+        let field = |handoff: *const u64, offset: usize| unsafe {
+            handoff.add(offset / 8).read_volatile() as usize
+        };
+        if handoff.map(|handoff| (field(handoff, 0x48), field(handoff, 0x50)))
+            != Some((address.value, length.value))
+        {
+            println!("!! Test-kernel: SBI test FAILED due to boot handoff disagreeing on firmware tables");
+            sbi::shutdown_failure()
+        }
+    }
+    println!("<< Test-kernel: Firmware tables success");
+}
+
+#[cfg(feature = "memory-fixup")]
+fn test_memory_node(dtb_pa: usize) {
+    println!(">> Test-kernel: Testing memory node of device tree");
+    let memory = dtb::find_reg(dtb_pa, "", "memory");
+    let firmware = dtb::find_reg(dtb_pa, "reserved-memory", "rustsbi");
+    println!(
+        "<< Test-kernel: Memory node: {:x?}, firmware reserved: {:x?}",
+        memory, firmware
+    );
+    // firmware places its reserved region at the top of DRAM size it reported in memory node
+    match (memory, firmware) {
+        (Some((base, size)), Some((reserved_base, reserved_size)))
+            if reserved_base >= base && reserved_base + reserved_size == base + size => {}
+        _ => {
+            println!("!! Test-kernel: SBI test FAILED due to memory node does not match firmware reserved region");
+            sbi::shutdown_failure()
+        }
+    }
+    println!("<< Test-kernel: Memory node success");
+}
+
+fn test_legacy_return() {
+    println!(">> Test-kernel: Testing legacy extension return convention");
+    const A1: usize = 0x5a5a_5a5a;
+    // getchar returns a character or -1 in a0
+    let (ch, a1) = sbi::sbi_call_legacy_a1(sbi::SBI_CONSOLE_GETCHAR, 0, A1);
+    if (ch > 0xff && ch != usize::MAX) || a1 != A1 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to getchar returned a0 {:#x}, a1 {:#x}",
+            ch, a1
+        );
+        sbi::shutdown_failure()
+    }
+    let (_, a1) = sbi::sbi_call_legacy_a1(sbi::SBI_CONSOLE_PUTCHAR, b'\n' as usize, A1);
+    if a1 != A1 {
+        println!("!! Test-kernel: SBI test FAILED due to putchar changed a1");
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Legacy getchar returned {:#x}", ch);
+}
+
 fn test_sbi_ins_emulation() {
     println!(">> Test-kernel: Testing SBI instruction emulation");
     let time_start = riscv::register::time::read64();
@@ -98,19 +611,1262 @@ fn test_sbi_ins_emulation() {
         println!("<< Test-kernel: Time after operation: {:x}", time_end);
     } else {
         println!("!! Test-kernel: SBI test FAILED due to incorrect time counter");
-        sbi::shutdown()
+        sbi::shutdown_failure()
+    }
+    // timeh does not exist on RV64, the SBI implementation should emulate it
+    // as the upper 32 bits of time
+    let time_high: usize;
+    unsafe { core::arch::asm!("csrr {}, 0xC81", out(reg) time_high) };
+    let time_now = riscv::register::time::read64();
+    if time_high as u64 == time_now >> 32 {
+        println!("<< Test-kernel: Current timeh: {:x}", time_high);
+    } else {
+        println!("!! Test-kernel: SBI test FAILED due to incorrect timeh counter");
+        sbi::shutdown_failure()
+    }
+}
+
+fn test_counters() {
+    println!(">> Test-kernel: Testing performance counters");
+    #[cfg(feature = "fixed-counters")]
+    test_fixed_counters();
+    #[cfg(not(feature = "fixed-counters"))]
+    test_exposed_counters();
+    // hpmcounter3 and hpmcounter4 are read directly, whatever event they count
+    let trapped = expect_trap(Trap::Exception(Exception::IllegalInstruction), || {
+        let _ = riscv::register::hpmcounter3::read();
+        let _ = riscv::register::hpmcounter4::read();
+    });
+    if trapped {
+        println!("!! Test-kernel: SBI test FAILED due to hpmcounter read trapped");
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Performance counters success");
+}
+
+#[cfg(not(feature = "fixed-counters"))]
+fn test_exposed_counters() {
+    let cycle_start = riscv::register::cycle::read64();
+    let instret_start = riscv::register::instret::read64();
+    for _ in 0..1000 {
+        unsafe { core::arch::asm!("nop") };
+    }
+    let cycle_end = riscv::register::cycle::read64();
+    let instret_end = riscv::register::instret::read64();
+    // the loop retires at least one instruction per iteration, taking at least a cycle each
+    if cycle_end < cycle_start + 1000 || instret_end < instret_start + 1000 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to cycle {} -> {}, instret {} -> {} over 1000 iterations",
+            cycle_start, cycle_end, instret_start, instret_end
+        );
+        sbi::shutdown_failure()
+    }
+    println!(
+        "<< Test-kernel: {} cycles and {} instructions over 1000 iterations",
+        cycle_end - cycle_start,
+        instret_end - instret_start
+    );
+}
+
+// Requires SBI built with feature `fixed-counters`: cycle and instret read as 0 however long the
+// supervisor runs, while time advances
+#[cfg(feature = "fixed-counters")]
+fn test_fixed_counters() {
+    let time_start = riscv::register::time::read64();
+    let cycle_start = riscv::register::cycle::read();
+    let instret_start = riscv::register::instret::read();
+    for _ in 0..100_000 {
+        unsafe { core::arch::asm!("nop") };
+    }
+    let cycle_end = riscv::register::cycle::read();
+    let instret_end = riscv::register::instret::read();
+    let time_end = riscv::register::time::read64();
+    if [cycle_start, cycle_end, instret_start, instret_end] != [0; 4] {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to cycle {} -> {}, instret {} -> {}, expected fixed 0",
+            cycle_start, cycle_end, instret_start, instret_end
+        );
+        sbi::shutdown_failure()
+    }
+    if time_end <= time_start {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to time {} -> {} not advancing with fixed counters",
+            time_start, time_end
+        );
+        sbi::shutdown_failure()
+    }
+}
+
+// Firmware clears sscratch on supervisor entry and never writes it afterwards; its own
+// mscratch, which points into firmware memory while supervisor runs, is not readable
+fn test_scratch_ownership(entry_sscratch: usize) {
+    println!(">> Test-kernel: Testing scratch register ownership");
+    if entry_sscratch != 0 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to sscratch {:#x} on entry, expected 0",
+            entry_sscratch
+        );
+        sbi::shutdown_failure()
+    }
+    const MARKER: usize = 0x5343_5241_5443_4821;
+    riscv::register::sscratch::write(MARKER);
+    // an SBI call, an emulated instruction and an emulated misaligned load all trap to firmware
+    let _ = sbi::get_spec_version();
+    let _ = riscv::register::time::read64();
+    let buffer = [0u8; 16];
+    unsafe { core::arch::asm!("lw {}, 1({})", out(reg) _, in(reg) buffer.as_ptr()) };
+    let after = riscv::register::sscratch::read();
+    riscv::register::sscratch::write(0);
+    if after != MARKER {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to sscratch {:#x} after traps to firmware, expected {:#x}",
+            after, MARKER
+        );
+        sbi::shutdown_failure()
+    }
+    let caught = expect_trap(Trap::Exception(Exception::IllegalInstruction), || unsafe {
+        core::arch::asm!("csrr {}, mscratch", out(reg) _)
+    });
+    if !caught {
+        println!("!! Test-kernel: SBI test FAILED due to mscratch readable from supervisor");
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Scratch register ownership success");
+}
+
+fn test_illegal_instruction_delegate() {
+    println!(">> Test-kernel: Trigger illegal exception");
+    let caught = expect_trap(Trap::Exception(Exception::IllegalInstruction), || unsafe {
+        core::arch::asm!("csrw mcycle, x0") // mcycle cannot be written, this is always a 4-byte illegal instruction
+    });
+    if !caught {
+        println!("!! Test-kernel: SBI test FAILED due to illegal instruction not delegated");
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Illegal exception delegate success");
+}
+
+// Sv39 root page table: DRAM identity mapped, and an execute-only alias of it
+#[repr(align(4096))]
+struct PageTable([usize; 512]);
+static mut EXECUTE_ONLY_PAGE_TABLE: PageTable = PageTable([0; 512]);
+const EXECUTE_ONLY_ALIAS: usize = 0x1_8000_0000;
+
+#[naked]
+unsafe extern "C" fn illegal_instruction_then_return() {
+    core::arch::asm!("csrw mcycle, x0", "ret", options(noreturn))
+}
+
+// Firmware reads the illegal instruction with supervisor's translation to emulate it; on an
+// execute-only page this read faults. Firmware must recover, leave MPRV clear and delegate the
+// illegal instruction. A firmware stuck with MPRV set would fault on its own console output,
+// which is mapped in no supervisor page table, on the next line printed.
+fn test_illegal_instruction_execute_only() {
+    println!(">> Test-kernel: Trigger illegal exception on execute-only page");
+    const PTE_V: usize = 1 << 0;
+    const PTE_R: usize = 1 << 1;
+    const PTE_W: usize = 1 << 2;
+    const PTE_X: usize = 1 << 3;
+    const PTE_A: usize = 1 << 6;
+    const PTE_D: usize = 1 << 7;
+    const DRAM: usize = 0x8000_0000;
+    let table = unsafe { &mut EXECUTE_ONLY_PAGE_TABLE.0 };
+    // 1GiB pages
+    table[DRAM >> 30] = (DRAM >> 12) << 10 | PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+    table[EXECUTE_ONLY_ALIAS >> 30] = (DRAM >> 12) << 10 | PTE_V | PTE_X | PTE_A;
+    let satp = 8 << 60 | table.as_ptr() as usize >> 12; // Sv39
+    let function: extern "C" fn() = unsafe {
+        core::mem::transmute(illegal_instruction_then_return as usize - DRAM + EXECUTE_ONLY_ALIAS)
+    };
+    unsafe { core::arch::asm!("csrw satp, {}", "sfence.vma", in(reg) satp) };
+    let caught = expect_trap(Trap::Exception(Exception::IllegalInstruction), || {
+        function()
+    });
+    println!("<< Test-kernel: Firmware output still works with paging enabled");
+    unsafe { core::arch::asm!("csrw satp, zero", "sfence.vma") };
+    if !caught {
+        println!("!! Test-kernel: SBI test FAILED due to illegal instruction on execute-only page not delegated");
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Illegal exception on execute-only page success");
+}
+
+// `mret` is illegal below machine level; firmware must hand it back to supervisor with the
+// instruction in stval instead of panicking
+// Walks the execute-only test's page table, which must be set up already; it is not enabled
+#[cfg(feature = "diagnostics")]
+fn test_page_table_dump() {
+    println!(">> Test-kernel: Testing page table dump");
+    let satp = 8 << 60 | unsafe { EXECUTE_ONLY_PAGE_TABLE.0.as_ptr() } as usize >> 12;
+    let bare = sbi::dump_page_tables(0);
+    let sv39 = sbi::dump_page_tables(satp);
+    let sv48 = sbi::dump_page_tables(9 << 60 | satp & (1 << 44) - 1);
+    let outside = sbi::dump_page_tables(8 << 60);
+    println!(
+        "<< Test-kernel: page table dump returned {:?}, {:?}, {:?}, {:?}",
+        bare, sv39, sv48, outside
+    );
+    // satp is Bare here; the table maps DRAM and its execute-only alias with one 1GiB page each
+    if bare.error != 0
+        || bare.value != 0
+        || sv39.error != 0
+        || sv39.value != 2
+        || sv48.error != sbi::SBI_ERR_INVALID_PARAM
+        || outside.error != sbi::SBI_ERR_INVALID_ADDRESS
+    {
+        println!("!! Test-kernel: SBI test FAILED due to unexpected page table dump results");
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Page table dump success");
+}
+
+// Requires SBI built with feature `diagnostics`
+#[cfg(feature = "diagnostics")]
+fn test_watchpoint() {
+    println!(">> Test-kernel: Testing PC watchpoint");
+    let address = watched as usize;
+    let odd = sbi::set_watchpoint(address + 1);
+    let unmapped = sbi::set_watchpoint(0x1000);
+    let armed = sbi::set_watchpoint(address);
+    let again = sbi::set_watchpoint(address);
+    // other breakpoints still reach supervisor while a watchpoint is armed
+    let caught = expect_trap(Trap::Exception(Exception::Breakpoint), || unsafe {
+        core::arch::asm!("ebreak")
+    });
+    // fires, firmware puts the original instruction back and resumes here
+    let value = watched(41);
+    let fired = sbi::clear_watchpoint(address);
+    println!(
+        "<< Test-kernel: watchpoint returned {:?}, {:?}, {:?}, {:?}, cleared after hit {:?}",
+        odd, unmapped, armed, again, fired
+    );
+    if odd.error != sbi::SBI_ERR_INVALID_PARAM
+        || unmapped.error != sbi::SBI_ERR_INVALID_ADDRESS
+        || armed.error != 0
+        || again.error != sbi::SBI_ERR_ALREADY_AVAILABLE
+        || fired.error != sbi::SBI_ERR_INVALID_PARAM
+    {
+        println!("!! Test-kernel: SBI test FAILED due to unexpected watchpoint results");
+        sbi::shutdown_failure()
+    }
+    if !caught || value != 42 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to breakpoint caught {}, watched function returned {}",
+            caught, value
+        );
+        sbi::shutdown_failure()
+    }
+    // disarmed without a hit, the function runs as before
+    let armed = sbi::set_watchpoint(address);
+    let cleared = sbi::clear_watchpoint(address);
+    if armed.error != 0 || cleared.error != 0 || watched(1) != 2 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to watchpoint set {:?}, then clear {:?}",
+            armed, cleared
+        );
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: PC watchpoint success");
+}
+
+#[cfg(feature = "diagnostics")]
+#[inline(never)]
+fn watched(value: usize) -> usize {
+    // a volatile read keeps the call from being folded into a constant
+    unsafe { core::ptr::read_volatile(&value) + 1 }
+}
+
+fn test_machine_return_from_supervisor() {
+    println!(">> Test-kernel: Trigger illegal exception with mret");
+    const INS_MRET: usize = 0x3020_0073;
+    let caught = expect_trap(Trap::Exception(Exception::IllegalInstruction), || unsafe {
+        core::arch::asm!("mret")
+    });
+    if !caught {
+        println!("!! Test-kernel: SBI test FAILED due to mret not delegated");
+        sbi::shutdown_failure()
+    }
+    let stval = TRAP_VALUE.load(Ordering::Relaxed);
+    if stval != INS_MRET {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to stval {:#x} of mret, expected {:#x}",
+            stval, INS_MRET
+        );
+        sbi::shutdown_failure()
     }
+    println!("<< Test-kernel: Illegal exception with mret success");
+}
+
+// U74 has no H extension; hypervisor loads, stores and fences of a supervisor probing for it
+// must come back as illegal instructions with the instruction in stval
+fn test_hypervisor_instructions() {
+    println!(">> Test-kernel: Trigger illegal exception with hypervisor instructions");
+    const INS_HLV_W: usize = 0x6805_c573; // hlv.w a0, (a1)
+    const INS_HSV_W: usize = 0x6ab5_4073; // hsv.w a1, (a0)
+    const INS_HFENCE_GVMA: usize = 0x6200_0073; // hfence.gvma zero, zero
+    let mut word = 0u32;
+    let addr = &mut word as *mut u32 as usize;
+    // encoded by hand, the assembler may not know the H extension
+    let cases: [(usize, &dyn Fn()); 3] = [
+        (INS_HLV_W, &|| unsafe {
+            core::arch::asm!(".word 0x6805c573", in("a1") addr, out("a0") _)
+        }),
+        (INS_HSV_W, &|| unsafe {
+            core::arch::asm!(".word 0x6ab54073", in("a0") addr, in("a1") 0)
+        }),
+        (INS_HFENCE_GVMA, &|| unsafe {
+            core::arch::asm!(".word 0x62000073")
+        }),
+    ];
+    for (ins, f) in cases {
+        let caught = expect_trap(Trap::Exception(Exception::IllegalInstruction), f);
+        let stval = TRAP_VALUE.load(Ordering::Relaxed);
+        if !caught || stval != ins {
+            println!(
+                "!! Test-kernel: SBI test FAILED due to hypervisor instruction {:#010x} not delegated, caught {}, stval {:#x}",
+                ins, caught, stval
+            );
+            sbi::shutdown_failure()
+        }
+    }
+    println!("<< Test-kernel: Illegal exception with hypervisor instructions success");
+}
+
+// Toggle sstatus.SUM; U74 never traps this, but SBI built with feature `emulate-sstatus` must
+// give the same result if it does
+fn test_sstatus_sum() {
+    println!(">> Test-kernel: Testing sstatus SUM toggle");
+    const SUM: usize = 1 << 18;
+    let read = || -> usize {
+        let value: usize;
+        unsafe { core::arch::asm!("csrr {}, sstatus", out(reg) value) };
+        value
+    };
+    let before = read();
+    let old: usize;
+    unsafe { core::arch::asm!("csrrs {}, sstatus, {}", out(reg) old, in(reg) SUM) };
+    let set = read();
+    unsafe { core::arch::asm!("csrc sstatus, {}", in(reg) SUM) };
+    let cleared = read();
+    if before & SUM != 0 {
+        unsafe { core::arch::asm!("csrs sstatus, {}", in(reg) SUM) };
+    }
+    // only SUM changes, other fields keep their value
+    if old != before || set != before | SUM || cleared != before & !SUM {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to sstatus {:#x}, old {:#x}, SUM set {:#x}, cleared {:#x}",
+            before, old, set, cleared
+        );
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: sstatus SUM toggle success");
+}
+
+// Requires SBI built with feature `emulate-sret`: with mstatus.TSR set, sret traps into firmware,
+// which must return to sepc in supervisor with the sstatus fields hardware sret would leave
+#[cfg(feature = "emulate-sret")]
+fn test_sret_emulation() {
+    println!(">> Test-kernel: Testing sret emulation");
+    const SIE: usize = 1 << 1;
+    const SPIE: usize = 1 << 5;
+    const SPP: usize = 1 << 8;
+    let read = || -> usize {
+        let value: usize;
+        unsafe { core::arch::asm!("csrr {}, sstatus", out(reg) value) };
+        value
+    };
+    let before = read();
+    // no interrupt is taken once sret sets SIE, sie masks them all
+    let sie: usize;
+    unsafe { core::arch::asm!("csrrw {}, sie, zero", out(reg) sie) };
+    for spie in [0, SPIE] {
+        unsafe {
+            core::arch::asm!(
+                "la      {tmp}, 1f",
+                "csrw    sepc, {tmp}",
+                "csrs    sstatus, {spp}",
+                "csrc    sstatus, {sie}",
+                "csrc    sstatus, {spie_mask}",
+                "csrs    sstatus, {spie}",
+                "sret",
+                "1:",
+                tmp = out(reg) _,
+                spp = in(reg) SPP,
+                sie = in(reg) SIE,
+                spie_mask = in(reg) SPIE,
+                spie = in(reg) spie,
+            )
+        };
+        // back in supervisor, or reading sstatus would have trapped
+        let after = read();
+        unsafe { core::arch::asm!("csrc sstatus, {}", in(reg) SIE) };
+        let expected = (before & !(SIE | SPP)) | SPIE | if spie != 0 { SIE } else { 0 };
+        if after != expected {
+            println!(
+                "!! Test-kernel: SBI test FAILED due to sstatus {:#x} after sret with SPIE {}, expected {:#x}",
+                after,
+                spie != 0,
+                expected
+            );
+            sbi::shutdown_failure()
+        }
+    }
+    unsafe {
+        core::arch::asm!("csrc sstatus, {}", in(reg) SIE | SPIE | SPP);
+        core::arch::asm!("csrs sstatus, {}", in(reg) before & (SIE | SPIE | SPP));
+        core::arch::asm!("csrw sie, {}", in(reg) sie);
+    }
+    println!("<< Test-kernel: sret emulation success");
+}
+
+// Requires SBI built with feature `emulate-zicond`
+#[cfg(feature = "emulate-zicond")]
+fn test_zicond_emulation() {
+    println!(">> Test-kernel: Testing Zicond instruction emulation");
+    // czero.eqz a0, a1, a2 and czero.nez a0, a1, a2
+    let czero = |nez: bool, value: usize, condition: usize| -> usize {
+        let ans: usize;
+        unsafe {
+            if nez {
+                core::arch::asm!(".word 0x0ec5f533", lateout("a0") ans, in("a1") value, in("a2") condition)
+            } else {
+                core::arch::asm!(".word 0x0ec5d533", lateout("a0") ans, in("a1") value, in("a2") condition)
+            }
+        }
+        ans
+    };
+    for (value, condition) in [(0x1234, 0), (0x1234, 1), (usize::MAX, usize::MAX), (0, 5)] {
+        let eqz = czero(false, value, condition);
+        let nez = czero(true, value, condition);
+        let expected_eqz = if condition == 0 { 0 } else { value };
+        let expected_nez = if condition != 0 { 0 } else { value };
+        if eqz != expected_eqz || nez != expected_nez {
+            println!(
+                "!! Test-kernel: SBI test FAILED due to czero of {:#x}, {:#x} gives eqz {:#x}, nez {:#x}",
+                value, condition, eqz, nez
+            );
+            sbi::shutdown_failure()
+        }
+    }
+    // rd is also rs2: czero.eqz a0, a1, a0
+    let ans: usize;
+    unsafe {
+        core::arch::asm!(".word 0x0ea5d533", inlateout("a0") 7usize => ans, in("a1") 0x55usize)
+    };
+    if ans != 0x55 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to czero.eqz with rd = rs2 gives {:#x}",
+            ans
+        );
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Zicond instruction emulation success");
+}
+
+// Requires SBI built with feature `bitmanip-emulation`
+#[cfg(feature = "bitmanip-emulation")]
+fn test_bitmanip_emulation() {
+    println!(">> Test-kernel: Testing Zbb instruction emulation");
+    // instruction word with rd a0, rs1 a1 and, for binary ones, rs2 a2
+    macro_rules! zbb {
+        ($word:literal, $a:expr, $b:expr) => {{
+            let ans: usize;
+            unsafe {
+                core::arch::asm!(concat!(".word ", $word), lateout("a0") ans, in("a1") $a, in("a2") $b)
+            };
+            ans
+        }};
+    }
+    let a: usize = 0x0000_0f00_8000_0100;
+    let b: usize = usize::MAX - 1; // -2
+    let cases = [
+        ("clz", zbb!("0x60059513", a, 0), 20),
+        ("clz of 0", zbb!("0x60059513", 0, 0), 64),
+        ("ctz", zbb!("0x60159513", a, 0), 8),
+        ("cpop", zbb!("0x60259513", a, 0), 6),
+        ("clzw", zbb!("0x6005951b", a, 0), 0),
+        ("ctzw", zbb!("0x6015951b", a, 0), 8),
+        ("cpopw", zbb!("0x6025951b", a, 0), 2),
+        ("orc.b", zbb!("0x2875d513", a, 0), 0x0000_ff00_ff00_ff00),
+        ("rev8", zbb!("0x6b85d513", a, 0), 0x0001_0080_000f_0000),
+        ("min", zbb!("0x0ac5c533", a, b), b),
+        ("minu", zbb!("0x0ac5d533", a, b), a),
+        ("max", zbb!("0x0ac5e533", a, b), a),
+        ("maxu", zbb!("0x0ac5f533", a, b), b),
+    ];
+    for (name, ans, expected) in cases {
+        if ans != expected {
+            println!(
+                "!! Test-kernel: SBI test FAILED due to {} gives {:#x}, expected {:#x}",
+                name, ans, expected
+            );
+            sbi::shutdown_failure()
+        }
+    }
+    // rd is also rs2: max a0, a1, a0
+    let ans: usize;
+    unsafe { core::arch::asm!(".word 0x0aa5e533", inlateout("a0") 7usize => ans, in("a1") 3usize) };
+    if ans != 7 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to max with rd = rs2 gives {:#x}",
+            ans
+        );
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Zbb instruction emulation success");
+}
+
+fn test_pmp() {
+    println!(">> Test-kernel: Testing PMP regions");
+    // CLINT mtime and UART line status register are inside opened MMIO regions
+    for addr in [0x0200_bff8, 0x1244_0014] {
+        let caught = expect_trap(Trap::Exception(Exception::LoadFault), || unsafe {
+            core::ptr::read_volatile(addr as *const u32);
+        });
+        if caught {
+            println!(
+                "!! Test-kernel: SBI test FAILED due to PMP denied access to {:#x}",
+                addr
+            );
+            sbi::shutdown_failure()
+        }
+    }
+    // no PMP region covers this address; PMP is default deny, the access must fault.
+    // This test fails if SBI is built with feature `pmp-allow-all`
+    let caught = expect_trap(Trap::Exception(Exception::LoadFault), || unsafe {
+        core::ptr::read_volatile(0x4000_0000 as *const u32);
+    });
+    if !caught {
+        println!("!! Test-kernel: SBI test FAILED due to PMP allowed access outside all regions");
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: PMP default deny success");
+}
+
+fn test_fwft() {
+    println!(">> Test-kernel: Testing firmware features extension");
+    if sbi::probe_extension(sbi::EXTENSION_FWFT) == 0 {
+        println!("!! Test-kernel: SBI test FAILED due to no firmware features extension found");
+        sbi::shutdown_failure()
+    }
+    let initial = sbi::fwft_get(sbi::FWFT_MISALIGNED_EXC_DELEG);
+    println!(
+        "<< Test-kernel: Misaligned exception delegation: {:?}",
+        initial
+    );
+    for value in [1, 0, initial.value] {
+        let set = sbi::fwft_set(sbi::FWFT_MISALIGNED_EXC_DELEG, value, 0);
+        let get = sbi::fwft_get(sbi::FWFT_MISALIGNED_EXC_DELEG);
+        if set.error != 0 || get.error != 0 || get.value != value {
+            println!(
+                "!! Test-kernel: SBI test FAILED due to misaligned delegation set {} returned {:?}, read back {:?}",
+                value, set, get
+            );
+            sbi::shutdown_failure()
+        }
+    }
+    // landing pad, a standard feature; a reserved one; a platform specific one
+    let standard = sbi::fwft_get(0x1);
+    let reserved = sbi::fwft_get(0x6);
+    let platform = sbi::fwft_get(0x4000_0000);
+    if standard.error != sbi::SBI_ERR_NOT_SUPPORTED
+        || reserved.error != sbi::SBI_ERR_DENIED
+        || platform.error != sbi::SBI_ERR_DENIED
+    {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to unknown firmware features returned {:?}, {:?}, {:?}",
+            standard, reserved, platform
+        );
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Firmware features extension success");
+}
+
+fn test_delegation() {
+    println!(">> Test-kernel: Testing delegation query");
+    let medeleg = sbi::get_medeleg();
+    let mideleg = sbi::get_mideleg();
+    let mutable = sbi::get_medeleg_mutable();
+    println!(
+        "<< Test-kernel: medeleg {:?}, mideleg {:?}, mutable {:?}",
+        medeleg, mideleg, mutable
+    );
+    if medeleg.error != 0 || mideleg.error != 0 || mutable.error != 0 {
+        println!("!! Test-kernel: SBI test FAILED due to delegation query failed");
+        sbi::shutdown_failure()
+    }
+    // breakpoints and page faults go to supervisor, supervisor ecalls never do; supervisor
+    // timer, software and external interrupts are delegated
+    let delegated = 1 << 3 | 1 << 12 | 1 << 13 | 1 << 15;
+    if medeleg.value & delegated != delegated
+        || medeleg.value & 1 << 9 != 0
+        || mideleg.value & 0x222 != 0x222
+    {
+        println!("!! Test-kernel: SBI test FAILED due to unexpected delegation");
+        sbi::shutdown_failure()
+    }
+    let same = sbi::set_medeleg(medeleg.value);
+    // illegal instructions are firmware's to emulate
+    let illegal = sbi::set_medeleg(medeleg.value | 1 << 2);
+    if same.error != 0 || same.value != medeleg.value || illegal.error != sbi::SBI_ERR_DENIED {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to delegation set returned {:?}, then {:?}",
+            same, illegal
+        );
+        sbi::shutdown_failure()
+    }
+    if mutable.value != 0 {
+        let bit = mutable.value & mutable.value.wrapping_neg();
+        let toggled = sbi::set_medeleg(medeleg.value ^ bit);
+        let read_back = sbi::get_medeleg();
+        let restored = sbi::set_medeleg(medeleg.value);
+        if toggled.error != 0
+            || read_back.value != medeleg.value ^ bit
+            || restored.value != medeleg.value ^ bit
+        {
+            println!(
+                "!! Test-kernel: SBI test FAILED due to toggling delegation bit {:#x} returned {:?}, read back {:?}",
+                bit, toggled, read_back
+            );
+            sbi::shutdown_failure()
+        }
+    }
+    println!("<< Test-kernel: Delegation query success");
+}
+
+// Only the checks, which touch no cache controller; QEMU virt has none where JH7100 has its own
+fn test_cache_flush() {
+    println!(">> Test-kernel: Testing cache flush range checks");
+    let text = test_cache_flush as usize;
+    let empty = sbi::flush_dcache_range(text, 0);
+    let outside = sbi::flush_dcache_range(0x1000, 64);
+    let wraps = sbi::flush_dcache_range(text, usize::MAX);
+    println!(
+        "<< Test-kernel: cache flush returned {:?}, {:?}, {:?}",
+        empty, outside, wraps
+    );
+    if sbi::probe_extension(sbi::EXTENSION_CACHE) == 0
+        || empty.error != 0
+        || outside.error != sbi::SBI_ERR_INVALID_PARAM
+        || wraps.error != sbi::SBI_ERR_INVALID_PARAM
+    {
+        println!("!! Test-kernel: SBI test FAILED due to unexpected cache flush results");
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Cache flush range checks success");
+}
+
+fn test_misaligned_emulation() {
+    println!(">> Test-kernel: Testing misaligned load and store emulation");
+    // keep misaligned exceptions in firmware so that it emulates them
+    let delegation = sbi::fwft_get(sbi::FWFT_MISALIGNED_EXC_DELEG).value;
+    sbi::fwft_set(sbi::FWFT_MISALIGNED_EXC_DELEG, 0, 0);
+    #[repr(align(8))]
+    struct Buffer([u8; 16]);
+    let mut buf = Buffer([
+        0x11, 0x80, 0xff, 0x22, 0xf0, 0xde, 0xbc, 0x9a, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99,
+        0xaa,
+    ]);
+    let base = buf.0.as_mut_ptr() as usize;
+    let (lh, lhu, lw, lwu, ld): (usize, usize, usize, usize, usize);
+    unsafe {
+        core::arch::asm!(
+            "lh {lh}, 1({base})",
+            "lhu {lhu}, 1({base})",
+            "lw {lw}, 3({base})",
+            "lwu {lwu}, 3({base})",
+            "ld {ld}, 3({base})",
+            base = in(reg) base,
+            lh = out(reg) lh,
+            lhu = out(reg) lhu,
+            lw = out(reg) lw,
+            lwu = out(reg) lwu,
+            ld = out(reg) ld,
+        )
+    };
+    for (name, value, expected) in [
+        ("lh", lh, 0xffff_ffff_ffff_ff80),
+        ("lhu", lhu, 0xff80),
+        ("lw", lw, 0xffff_ffff_bcde_f022),
+        ("lwu", lwu, 0xbcde_f022),
+        ("ld", ld, 0x5544_339a_bcde_f022),
+    ] {
+        if value != expected {
+            println!(
+                "!! Test-kernel: SBI test FAILED due to misaligned {} gives {:#x}, expected {:#x}",
+                name, value, expected
+            );
+            sbi::shutdown_failure()
+        }
+    }
+    // stores must not touch bytes around the stored width
+    unsafe {
+        core::arch::asm!(
+            "sh {value}, 1({base})",
+            "sw {value}, 11({base})",
+            base = in(reg) base,
+            value = in(reg) 0x0102_0304_0506_0708usize,
+        )
+    };
+    let expected = [
+        0x11, 0x08, 0x07, 0x22, 0xf0, 0xde, 0xbc, 0x9a, 0x33, 0x44, 0x55, 0x08, 0x07, 0x06, 0x05,
+        0xaa,
+    ];
+    if buf.0 != expected {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to misaligned stores give {:x?}, expected {:x?}",
+            buf.0, expected
+        );
+        sbi::shutdown_failure()
+    }
+    test_misaligned_instruction_length(base);
+    sbi::fwft_set(sbi::FWFT_MISALIGNED_EXC_DELEG, delegation, 0);
+    println!("<< Test-kernel: Misaligned load and store emulation success");
+}
+
+// Supervisor resumes right after an emulated 2-byte and 4-byte instruction; a wrong pc advance
+// skips the marker after a compressed access, or resumes in the middle of a 4-byte one
+fn test_misaligned_instruction_length(base: usize) {
+    let (compressed, full): (usize, usize);
+    unsafe {
+        core::arch::asm!(
+            "c.lw a1, 0(a0)",
+            "c.li a2, 1",
+            "c.sw a1, 0(a0)",
+            "c.addi a2, 1",
+            "mv {compressed}, a2",
+            ".option push",
+            ".option norvc",
+            "li a2, 0",
+            "lw a1, 0(a0)",
+            "addi a2, a2, 1",
+            "sw a1, 0(a0)",
+            "addi a2, a2, 1",
+            ".option pop",
+            "mv {full}, a2",
+            compressed = out(reg) compressed,
+            full = out(reg) full,
+            in("a0") base + 1,
+            out("a1") _,
+            out("a2") _,
+        )
+    };
+    if compressed != 2 || full != 2 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to pc after emulated 2-byte and 4-byte accesses, markers {} and {}, expected 2",
+            compressed, full
+        );
+        sbi::shutdown_failure()
+    }
+}
+
+// Requires SBI built with feature `emulate-mmio-amo`
+#[cfg(feature = "emulate-mmio-amo")]
+fn test_mmio_amo_emulation() {
+    println!(">> Test-kernel: Testing atomics on MMIO");
+    // priority of PLIC source 1, 3 bits wide; no interrupt is enabled, changing it is harmless
+    const PLIC_PRIORITY_1: usize = 0x0c00_0004;
+    let (saved, after_swap, after_add, after_and, sc, after_sc): (u32, u32, u32, u32, usize, u32);
+    unsafe {
+        core::arch::asm!(
+            "amoswap.w {saved}, {three}, ({addr})",
+            "amoadd.w  {after_swap}, {two}, ({addr})",
+            "amoand.w  {after_add}, {four}, ({addr})",
+            "lr.w      {after_and}, ({addr})",
+            "sc.w      {sc}, {one}, ({addr})",
+            "lw        {after_sc}, 0({addr})",
+            addr = in(reg) PLIC_PRIORITY_1,
+            one = in(reg) 1,
+            two = in(reg) 2,
+            three = in(reg) 3,
+            four = in(reg) 4,
+            saved = out(reg) saved,
+            after_swap = out(reg) after_swap,
+            after_add = out(reg) after_add,
+            after_and = out(reg) after_and,
+            sc = out(reg) sc,
+            after_sc = out(reg) after_sc,
+        );
+        (PLIC_PRIORITY_1 as *mut u32).write_volatile(saved);
+    }
+    // swap 3, add 2 to 5, and with 4, then lr reads 4 and sc stores 1
+    let results = [after_swap, after_add, after_and, sc as u32, after_sc];
+    if results != [3, 5, 4, 0, 1] {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to atomics on MMIO gave {:?}",
+            results
+        );
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: Atomics on MMIO success");
+}
+
+fn test_timer_reprogram() {
+    use riscv::register::{sie, sip, sstatus, time};
+    println!(">> Test-kernel: Stress testing timer reprogramming");
+    unsafe { sie::set_stimer() };
+    for i in 0..256 {
+        // a due timer replaced by a far one must not fire; an interrupt taken
+        // while interrupts are briefly enabled is an unexpected trap
+        sbi::timer_set_timer(0);
+        sbi::timer_set_timer(u64::MAX);
+        unsafe {
+            sstatus::set_sie();
+            sstatus::clear_sie();
+        }
+        // a far timer replaced by a close one must not be lost
+        let caught = expect_trap(Trap::Interrupt(Interrupt::SupervisorTimer), || {
+            sbi::timer_set_timer(time::read64() + i % 16);
+            wait_for("supervisor timer pending", || sip::read().stimer());
+            unsafe {
+                sstatus::set_sie();
+                sstatus::clear_sie();
+            }
+        });
+        if !caught {
+            println!(
+                "!! Test-kernel: SBI test FAILED due to timer lost on round {}",
+                i
+            );
+            sbi::shutdown_failure()
+        }
+    }
+    unsafe { sie::clear_stimer() };
+    println!("<< Test-kernel: Timer reprogramming success");
+}
+
+fn test_timer_in_past() {
+    use riscv::register::{sie, sstatus, time};
+    println!(">> Test-kernel: Testing timer set in the past");
+    unsafe { sie::set_stimer() };
+    // legacy set timer goes through a different path in SBI than TIME extension
+    for legacy in [false, true] {
+        for past in [0, time::read64().saturating_sub(1000), time::read64()] {
+            let mut arrived = false;
+            let caught = expect_trap(Trap::Interrupt(Interrupt::SupervisorTimer), || unsafe {
+                sstatus::set_sie();
+                if legacy {
+                    sbi::set_timer(past as usize);
+                } else {
+                    sbi::timer_set_timer(past);
+                }
+                // the interrupt is taken as soon as the ecall returns, before this load
+                arrived = TRAP_CAUGHT.load(Ordering::Acquire);
+                sstatus::clear_sie();
+            });
+            if !caught || !arrived {
+                println!(
+                    "!! Test-kernel: SBI test FAILED due to timer at {:#x} (legacy {}) caught {}, right after ecall {}",
+                    past, legacy, caught, arrived
+                );
+                sbi::shutdown_failure()
+            }
+        }
+    }
+    unsafe { sie::clear_stimer() };
+    println!("<< Test-kernel: Timer set in the past success");
+}
+
+// Requires SBI built with feature `ipi-doorbell`
+#[cfg(feature = "ipi-doorbell")]
+fn test_ipi_doorbell() {
+    use riscv::register::{sie, sstatus};
+    println!(">> Test-kernel: Testing IPI doorbell");
+    let send_ipi_to_self = || {
+        sbi::send_ipi(1, 0);
+        for _ in 0..1_000_000 {
+            if TRAP_CAUGHT.load(Ordering::Acquire) {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    };
+    let cause = Trap::Interrupt(Interrupt::SupervisorSoft);
+    unsafe {
+        sie::clear_ssoft();
+        sstatus::set_sie();
+    }
+    // supervisor soft interrupt is masked, the handler must not run
+    if expect_trap(cause, send_ipi_to_self) {
+        println!("!! Test-kernel: SBI test FAILED due to soft interrupt handled while masked");
+        sbi::shutdown_failure()
+    }
+    // the masked one stays pending, and is taken as soon as it is enabled
+    if !expect_trap(cause, || unsafe { sie::set_ssoft() }) {
+        println!("!! Test-kernel: SBI test FAILED due to masked soft interrupt lost");
+        sbi::shutdown_failure()
+    }
+    let caught = expect_trap(cause, send_ipi_to_self);
+    unsafe {
+        sstatus::clear_sie();
+        sie::clear_ssoft();
+    }
+    if !caught {
+        println!("!! Test-kernel: SBI test FAILED due to doorbell not delivered");
+        sbi::shutdown_failure()
+    }
+    println!("<< Test-kernel: IPI doorbell success");
+}
+
+// Progress of hart 1 during HSM test, written by hart 1 and checked by hart 0
+static SECONDARY_PHASE: AtomicUsize = AtomicUsize::new(0);
+const PHASE_STARTED: usize = 1;
+const PHASE_RESTARTED: usize = 2;
+const PHASE_RESUMED: usize = 3;
+const PHASE_STRESSED: usize = 4;
+// Set by hart 0 to ask hart 1 to stop itself
+static SECONDARY_STOP: AtomicBool = AtomicBool::new(false);
+
+fn test_hsm() {
+    println!(">> Test-kernel: Testing HSM state machine");
+    // hart 1 is parked by SBI since boot
+    assert_hart_status(1, sbi::HSM_STATE_STOPPED);
+    // start hart 1 at kernel entry, it runs `secondary_main`
+    let sbi_ret = sbi::hart_start(1, entry as usize, 0);
+    println!(">> Start hart 1, sbi return value {:?}", sbi_ret);
+    wait_for("hart 1 start", || {
+        SECONDARY_PHASE.load(Ordering::Acquire) == PHASE_STARTED
+    });
+    assert_hart_status(1, sbi::HSM_STATE_STARTED);
+    // let hart 1 stop itself
+    SECONDARY_STOP.store(true, Ordering::Release);
+    wait_for("hart 1 stop", || {
+        sbi::hart_get_status(1).value == sbi::HSM_STATE_STOPPED
+    });
+    assert_hart_status(1, sbi::HSM_STATE_STOPPED);
+    // an IPI is not a start request, hart 1 stays stopped
+    let bv: usize = 0b10;
+    sbi::send_ipi(&bv as *const _ as usize, 0);
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+    assert_hart_status(1, sbi::HSM_STATE_STOPPED);
+    // start hart 1 again at another address, it runs `secondary_restart_main`
+    let sbi_ret = sbi::hart_start(1, restart_entry as usize, 0);
+    println!(">> Restart hart 1, sbi return value {:?}", sbi_ret);
+    wait_for("hart 1 restart", || {
+        SECONDARY_PHASE.load(Ordering::Acquire) == PHASE_RESTARTED
+    });
+    // hart 1 then suspends itself, wake it through IPI
+    wait_for("hart 1 suspend", || {
+        sbi::hart_get_status(1).value == sbi::HSM_STATE_SUSPENDED
+    });
+    assert_hart_status(1, sbi::HSM_STATE_SUSPENDED);
+    // suspend acts on its caller, hart 1; hart 0 making this call is unaffected
+    assert_hart_status(0, sbi::HSM_STATE_STARTED);
+    let bv: usize = 0b10;
+    let sbi_ret = sbi::send_ipi(&bv as *const _ as usize, 0);
+    println!(">> Wake hart 1, sbi return value {:?}", sbi_ret);
+    wait_for("hart 1 resume", || {
+        SECONDARY_PHASE.load(Ordering::Acquire) == PHASE_RESUMED
+    });
+    assert_hart_status(1, sbi::HSM_STATE_STARTED);
+    println!("<< Test-kernel: HSM state machine test success");
+}
+
+// Rounds of HSM and RFENCE calls each hart makes in the concurrent call test
+const STRESS_ROUNDS: usize = 10_000;
+// Set by hart 0 to let hart 1 begin its calls
+static STRESS_START: AtomicBool = AtomicBool::new(false);
+
+// Both harts, started, call HSM and RFENCE at the same time; every answer must be the one a
+// single hart would get, and neither hart may hang
+fn test_concurrent_calls() {
+    println!(">> Test-kernel: Testing concurrent HSM and RFENCE calls");
+    STRESS_START.store(true, Ordering::Release);
+    stress_calls(0);
+    wait_for("hart 1 concurrent calls", || {
+        SECONDARY_PHASE.load(Ordering::Acquire) == PHASE_STRESSED
+    });
+    assert_hart_status(0, sbi::HSM_STATE_STARTED);
+    assert_hart_status(1, sbi::HSM_STATE_STARTED);
+    println!("<< Test-kernel: Concurrent HSM and RFENCE calls success");
+}
+
+fn stress_calls(hartid: usize) {
+    let other = hartid ^ 1;
+    let rfence_expected = match sbi::probe_extension(sbi::EXTENSION_RFENCE) {
+        0 => sbi::SBI_ERR_NOT_SUPPORTED,
+        _ => 0,
+    };
+    for round in 0..STRESS_ROUNDS {
+        let status = sbi::hart_get_status(other);
+        let started = sbi::get_started_harts();
+        let start = sbi::hart_start(other, entry as usize, 0);
+        let fence = sbi::remote_fence_i(0b11, 0);
+        if status.error != 0
+            || status.value != sbi::HSM_STATE_STARTED
+            || started.error != 0
+            || started.value != 0b11
+            || start.error != sbi::SBI_ERR_ALREADY_AVAILABLE
+            || fence.error != rfence_expected
+        {
+            println!(
+                "!! Test-kernel: SBI test FAILED due to hart {} round {}: status {:?}, started harts {:?}, start {:?}, fence {:?}",
+                hartid, round, status, started, start, fence
+            );
+            sbi::shutdown_failure()
+        }
+    }
+}
+
+fn assert_hart_status(hartid: usize, expected: usize) {
+    let sbi_ret = sbi::hart_get_status(hartid);
+    println!(">> Hart {} state return value: {:?}", hartid, sbi_ret);
+    if sbi_ret.error != 0 || sbi_ret.value != expected {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to hart {} state not {}",
+            hartid, expected
+        );
+        sbi::shutdown_failure()
+    }
+    // started hart mask must agree with the state; no other hart changes it meanwhile
+    let started = sbi::get_started_harts();
+    let expected_bit = (expected == sbi::HSM_STATE_STARTED) as usize;
+    if started.error != 0 || (started.value >> hartid) & 1 != expected_bit {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to started hart mask {:?} disagreeing with hart {} state",
+            started, hartid
+        );
+        sbi::shutdown_failure()
+    }
+}
+
+// Spin until `cond` holds, fail the test if it takes too long
+fn wait_for(what: &str, cond: impl Fn() -> bool) {
+    for _ in 0..100_000_000 {
+        if cond() {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    println!(
+        "!! Test-kernel: SBI test FAILED due to timeout waiting for {}",
+        what
+    );
+    sbi::shutdown_failure()
+}
+
+fn secondary_main(hartid: usize) -> ! {
+    println!("<< Test-kernel: Hart {} started", hartid);
+    SECONDARY_PHASE.store(PHASE_STARTED, Ordering::Release);
+    while !SECONDARY_STOP.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    let sbi_ret = sbi::hart_stop(hartid);
+    println!(
+        "!! Test-kernel: SBI test FAILED due to hart stop returned {:?}",
+        sbi_ret
+    );
+    sbi::shutdown_failure()
+}
+
+extern "C" fn secondary_restart_main(hartid: usize) -> ! {
+    println!("<< Test-kernel: Hart {} restarted", hartid);
+    SECONDARY_PHASE.store(PHASE_RESTARTED, Ordering::Release);
+    // SBI sees this call come from hart 1, not from boot hart
+    let status = sbi::hart_get_status(hartid);
+    if status.error != 0 || status.value != sbi::HSM_STATE_STARTED {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to hart {} seeing itself in state {:?}",
+            hartid, status
+        );
+        sbi::shutdown_failure()
+    }
+    let sbi_ret = sbi::hart_suspend(0x00000000, 0, 0);
+    println!(
+        ">> Start test for hart {}, retentive suspend return value {:?}",
+        hartid, sbi_ret
+    );
+    SECONDARY_PHASE.store(PHASE_RESUMED, Ordering::Release);
+    while !STRESS_START.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    stress_calls(hartid);
+    SECONDARY_PHASE.store(PHASE_STRESSED, Ordering::Release);
+    rfence_observer();
+    loop {}
+}
+
+// Steps of the RFENCE test; hart 0 moves to odd steps after changing memory, hart 1 to even
+// steps after observing it. Shared memory coordinates them, not IPIs: hart 1 has no trap handler
+// for a supervisor soft interrupt, and its IPIs are what RFENCE itself is built on
+static RFENCE_STEP: AtomicUsize = AtomicUsize::new(0);
+const RFENCE_CODE_READY: usize = 1;
+const RFENCE_CODE_RUN: usize = 2;
+const RFENCE_CODE_CHANGED: usize = 3;
+const RFENCE_CODE_RERUN: usize = 4;
+const RFENCE_MAPPING_READY: usize = 5;
+const RFENCE_MAPPING_READ: usize = 6;
+const RFENCE_MAPPING_CHANGED: usize = 7;
+const RFENCE_MAPPING_REREAD: usize = 8;
+// What hart 1 saw at its last even step
+static RFENCE_OBSERVED: AtomicUsize = AtomicUsize::new(0);
+
+// Function hart 1 runs, `li a0, <value>; ret`, rewritten by hart 0
+static mut RFENCE_CODE: PageTable = PageTable([0; 512]);
+const INS_RET: usize = 0x0000_8067;
+const fn li_a0_ret(value: usize) -> usize {
+    INS_RET << 32 | value << 20 | 10 << 7 | 0x13
+}
+
+// Sv39 tables of hart 1: DRAM identity mapped, and `RFENCE_VA` mapped to one of two pages
+static mut RFENCE_ROOT: PageTable = PageTable([0; 512]);
+static mut RFENCE_L1: PageTable = PageTable([0; 512]);
+static mut RFENCE_L0: PageTable = PageTable([0; 512]);
+static mut RFENCE_PAGE_A: PageTable = PageTable([0; 512]);
+static mut RFENCE_PAGE_B: PageTable = PageTable([0; 512]);
+const RFENCE_VA: usize = 0x4000_0000;
+const RFENCE_MARK_A: usize = 0xaaaa_0001;
+const RFENCE_MARK_B: usize = 0xbbbb_0002;
+
+const PTE_V: usize = 1 << 0;
+const PTE_RW_AD: usize = 1 << 1 | 1 << 2 | 1 << 6 | 1 << 7;
+const PTE_X: usize = 1 << 3;
+
+fn pte(pa: usize, flags: usize) -> usize {
+    (pa >> 12) << 10 | flags
+}
+
+// Hart 0 changes code and a page mapping that hart 1 has used, and fences them on hart 1 through
+// RFENCE alone; hart 1 must see the change. Runs once SBI provides RFENCE, skipped before.
+fn test_rfence() {
+    println!(">> Test-kernel: Testing RFENCE across harts");
+    if sbi::probe_extension(sbi::EXTENSION_RFENCE) == 0 {
+        println!("<< Test-kernel: RFENCE not provided by SBI, test skipped");
+        return;
+    }
+    let hart_1: usize = 0b10;
+    // instruction cache: hart 1 runs the function once, then hart 0 rewrites it
+    unsafe { core::ptr::write_volatile(&mut RFENCE_CODE.0[0], li_a0_ret(1)) };
+    rfence_step(RFENCE_CODE_READY, RFENCE_CODE_RUN, 1);
+    unsafe { core::ptr::write_volatile(&mut RFENCE_CODE.0[0], li_a0_ret(2)) };
+    rfence_check_ret("remote_fence_i", sbi::remote_fence_i(hart_1, 0));
+    rfence_step(RFENCE_CODE_CHANGED, RFENCE_CODE_RERUN, 2);
+    // address translation: hart 1 reads through the mapping, then hart 0 moves it
+    unsafe {
+        const DRAM: usize = 0x8000_0000;
+        RFENCE_PAGE_A.0[0] = RFENCE_MARK_A;
+        RFENCE_PAGE_B.0[0] = RFENCE_MARK_B;
+        RFENCE_ROOT.0[DRAM >> 30] = pte(DRAM, PTE_V | PTE_RW_AD | PTE_X);
+        RFENCE_ROOT.0[RFENCE_VA >> 30] = pte(RFENCE_L1.0.as_ptr() as usize, PTE_V);
+        RFENCE_L1.0[0] = pte(RFENCE_L0.0.as_ptr() as usize, PTE_V);
+        RFENCE_L0.0[0] = pte(RFENCE_PAGE_A.0.as_ptr() as usize, PTE_V | PTE_RW_AD);
+    }
+    rfence_step(RFENCE_MAPPING_READY, RFENCE_MAPPING_READ, RFENCE_MARK_A);
+    unsafe {
+        let leaf = pte(RFENCE_PAGE_B.0.as_ptr() as usize, PTE_V | PTE_RW_AD);
+        core::ptr::write_volatile(&mut RFENCE_L0.0[0], leaf);
+    }
+    let sbi_ret = sbi::remote_sfence_vma(hart_1, 0, RFENCE_VA, 4096);
+    rfence_check_ret("remote_sfence_vma", sbi_ret);
+    rfence_step(RFENCE_MAPPING_CHANGED, RFENCE_MAPPING_REREAD, RFENCE_MARK_B);
+    println!("<< Test-kernel: RFENCE across harts success");
+}
+
+fn rfence_check_ret(call: &str, sbi_ret: sbi::SbiRet) {
+    if sbi_ret.error != 0 {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to {} returned {:?}",
+            call, sbi_ret
+        );
+        sbi::shutdown_failure()
+    }
+}
+
+// Publish `step` to hart 1, wait for it to reach `observed_step` and check what it saw
+fn rfence_step(step: usize, observed_step: usize, expected: usize) {
+    RFENCE_STEP.store(step, Ordering::Release);
+    wait_for("hart 1 RFENCE step", || {
+        RFENCE_STEP.load(Ordering::Acquire) == observed_step
+    });
+    let observed = RFENCE_OBSERVED.load(Ordering::Relaxed);
+    if observed != expected {
+        println!(
+            "!! Test-kernel: SBI test FAILED due to hart 1 seeing stale {:#x} at step {}, expected {:#x}",
+            observed, observed_step, expected
+        );
+        sbi::shutdown_failure()
+    }
+}
+
+// Hart 1 side of the RFENCE test; it fences locally only before first use, never after a change
+fn rfence_observer() {
+    let function: extern "C" fn() -> usize =
+        unsafe { core::mem::transmute(RFENCE_CODE.0.as_ptr()) };
+    let read_va = || unsafe { core::ptr::read_volatile(RFENCE_VA as *const usize) };
+    let observe = |step: usize, observe: &dyn Fn() -> usize| {
+        while RFENCE_STEP.load(Ordering::Acquire) != step {
+            core::hint::spin_loop();
+        }
+        RFENCE_OBSERVED.store(observe(), Ordering::Relaxed);
+        RFENCE_STEP.store(step + 1, Ordering::Release);
+    };
+    observe(RFENCE_CODE_READY, &|| {
+        unsafe { core::arch::asm!("fence.i") };
+        function()
+    });
+    observe(RFENCE_CODE_CHANGED, &|| function());
+    observe(RFENCE_MAPPING_READY, &|| {
+        let satp = 8 << 60 | unsafe { RFENCE_ROOT.0.as_ptr() } as usize >> 12; // Sv39
+        unsafe { core::arch::asm!("csrw satp, {}", "sfence.vma", in(reg) satp) };
+        read_va()
+    });
+    observe(RFENCE_MAPPING_CHANGED, &|| {
+        let value = read_va();
+        unsafe { core::arch::asm!("csrw satp, zero", "sfence.vma") };
+        value
+    });
+}
+
+// Trap cause the running test expects, the trap handler fails the test on any other trap
+static EXPECTED_TRAP: AmoMutex<Option<Trap>> = AmoMutex::new(None);
+static TRAP_CAUGHT: AtomicBool = AtomicBool::new(false);
+// stval of the last caught trap
+static TRAP_VALUE: AtomicUsize = AtomicUsize::new(0);
+
+// Run `f` which may trap with given cause, returns whether such trap was caught
+fn expect_trap(cause: Trap, f: impl FnOnce()) -> bool {
+    TRAP_CAUGHT.store(false, Ordering::Release);
+    *EXPECTED_TRAP.lock() = Some(cause);
+    f();
+    *EXPECTED_TRAP.lock() = None;
+    TRAP_CAUGHT.load(Ordering::Acquire)
 }
 
 pub extern "C" fn rust_trap_exception() {
     let cause = scause::read().cause();
     println!("<< Test-kernel: Value of scause: {:?}", cause);
-    if cause != Trap::Exception(Exception::IllegalInstruction) {
-        println!("!! Test-kernel: Wrong cause associated to illegal instruction");
-        sbi::shutdown()
+    let expected = *EXPECTED_TRAP.lock();
+    if expected != Some(cause) {
+        println!("!! Test-kernel: Unexpected trap, expected {:?}", expected);
+        sbi::shutdown_failure()
     }
-    println!("<< Test-kernel: Illegal exception delegate success");
-    sepc::write(sepc::read().wrapping_add(4));
+    TRAP_VALUE.store(stval::read(), Ordering::Relaxed);
+    TRAP_CAUGHT.store(true, Ordering::Release);
+    if let Trap::Interrupt(interrupt) = cause {
+        match interrupt {
+            Interrupt::SupervisorSoft => unsafe {
+                core::arch::asm!("csrc sip, {}", in(reg) 1 << 1) // clear sip.ssoft
+            },
+            Interrupt::SupervisorTimer => {
+                sbi::timer_set_timer(u64::MAX); // clears sip.stimer
+            }
+            _ => {}
+        }
+        return; // interrupted instruction did not execute, do not skip it
+    }
+    // skip the trapping instruction, which may be compressed; it may be on an execute-only page,
+    // make it readable with sstatus.MXR
+    let sepc = sepc::read();
+    let ins = unsafe {
+        core::arch::asm!("csrs sstatus, {}", in(reg) 1 << 19);
+        let ins = core::ptr::read_volatile(sepc as *const u16);
+        core::arch::asm!("csrc sstatus, {}", in(reg) 1 << 19);
+        ins
+    };
+    let ins_len = if ins & 0b11 == 0b11 { 4 } else { 2 };
+    sepc::write(sepc.wrapping_add(ins_len));
 }
 
 use core::panic::PanicInfo;
@@ -152,6 +1908,25 @@ unsafe extern "C" fn entry() -> ! {
     options(noreturn))
 }
 
+// Entry for hart 1 restarted by HSM test, a0 = hartid
+#[naked]
+unsafe extern "C" fn restart_entry() -> ! {
+    core::arch::asm!(
+    "
+    la      sp, {boot_stack}
+    li      t0, {per_hart_stack_size}
+    addi    t1, a0, 1
+1:  add     sp, sp, t0
+    addi    t1, t1, -1
+    bnez    t1, 1b
+    ",
+    "j      {secondary_restart_main}",
+    boot_stack = sym BOOT_STACK,
+    per_hart_stack_size = const PER_HART_STACK_SIZE,
+    secondary_restart_main = sym secondary_restart_main,
+    options(noreturn))
+}
+
 #[cfg(target_pointer_width = "128")]
 macro_rules! define_store_load {
     () => {
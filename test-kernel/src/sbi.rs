@@ -8,6 +8,20 @@ pub const EXTENSION_IPI: usize = 0x735049;
 pub const EXTENSION_RFENCE: usize = 0x52464E43;
 pub const EXTENSION_HSM: usize = 0x48534D;
 pub const EXTENSION_SRST: usize = 0x53525354;
+pub const EXTENSION_FWFT: usize = 0x46574654;
+pub const EXTENSION_PMU: usize = 0x504D55;
+pub const EXTENSION_DBCN: usize = 0x4442434E;
+pub const EXTENSION_DIAGNOSTICS: usize = 0x09000001;
+pub const EXTENSION_BUILD_INFO: usize = 0x09000003;
+pub const EXTENSION_CONSOLE_CONTROL: usize = 0x09000004;
+pub const EXTENSION_HART_MASK: usize = 0x09000005;
+pub const EXTENSION_DELEGATION: usize = 0x09000006;
+pub const EXTENSION_PAYLOAD_SLOT: usize = 0x09000007;
+pub const EXTENSION_CACHE: usize = 0x09000008;
+pub const EXTENSION_FIRMWARE_TABLES: usize = 0x09000009;
+pub const EXTENSION_CHECKPOINT: usize = 0x0900000A;
+// example downstream extension of RustSBI-JH7100, with its feature `example-extension`
+pub const EXTENSION_EXAMPLE: usize = 0x09001000;
 
 const FUNCTION_BASE_GET_SPEC_VERSION: usize = 0x0;
 const FUNCTION_BASE_GET_SBI_IMPL_ID: usize = 0x1;
@@ -27,11 +41,11 @@ pub struct SbiRet {
 
 const SBI_SUCCESS: usize = 0;
 const SBI_ERR_FAILED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-1));
-const SBI_ERR_NOT_SUPPORTED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-2));
-const SBI_ERR_INVALID_PARAM: usize = usize::from_ne_bytes(isize::to_ne_bytes(-3));
-const SBI_ERR_DENIED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-4));
-const SBI_ERR_INVALID_ADDRESS: usize = usize::from_ne_bytes(isize::to_ne_bytes(-5));
-const SBI_ERR_ALREADY_AVAILABLE: usize = usize::from_ne_bytes(isize::to_ne_bytes(-6));
+pub const SBI_ERR_NOT_SUPPORTED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-2));
+pub const SBI_ERR_INVALID_PARAM: usize = usize::from_ne_bytes(isize::to_ne_bytes(-3));
+pub const SBI_ERR_DENIED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-4));
+pub const SBI_ERR_INVALID_ADDRESS: usize = usize::from_ne_bytes(isize::to_ne_bytes(-5));
+pub const SBI_ERR_ALREADY_AVAILABLE: usize = usize::from_ne_bytes(isize::to_ne_bytes(-6));
 const SBI_ERR_ALREADY_STARTED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-7));
 const SBI_ERR_ALREADY_STOPPED: usize = usize::from_ne_bytes(isize::to_ne_bytes(-8));
 
@@ -115,6 +129,17 @@ pub fn shutdown() -> ! {
     unreachable!()
 }
 
+// Shutdown with system failure reason, so that test runner sees the test failed
+pub fn shutdown_failure() -> ! {
+    sbi_call_2(
+        EXTENSION_SRST,
+        FUNCTION_SYSTEM_RESET,
+        RESET_TYPE_SHUTDOWN,
+        RESET_REASON_SYSTEM_FAILURE,
+    );
+    unreachable!()
+}
+
 #[inline(always)]
 fn sbi_call_legacy(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     let ret;
@@ -137,9 +162,22 @@ fn sbi_call_legacy(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize
     ret
 }
 
-const SBI_SET_TIMER: usize = 0;
-const SBI_CONSOLE_PUTCHAR: usize = 1;
-const SBI_CONSOLE_GETCHAR: usize = 2;
+// Legacy call returning both a0 and a1, to check a1 is preserved
+pub fn sbi_call_legacy_a1(which: usize, arg0: usize, arg1: usize) -> (usize, usize) {
+    let (a0, a1);
+    unsafe {
+        asm!(
+            "ecall",
+            in("a7") which,
+            inlateout("a0") arg0 => a0, inlateout("a1") arg1 => a1,
+        )
+    };
+    (a0, a1)
+}
+
+pub const SBI_SET_TIMER: usize = 0;
+pub const SBI_CONSOLE_PUTCHAR: usize = 1;
+pub const SBI_CONSOLE_GETCHAR: usize = 2;
 const SBI_CLEAR_IPI: usize = 3;
 const SBI_SEND_IPI: usize = 4;
 const SBI_REMOTE_FENCE_I: usize = 5;
@@ -159,6 +197,16 @@ pub fn set_timer(time: usize) {
     sbi_call_legacy(SBI_SET_TIMER, time, 0, 0);
 }
 
+const FUNCTION_TIMER_SET_TIMER: usize = 0x0;
+
+pub fn timer_set_timer(stime_value: u64) -> SbiRet {
+    sbi_call_1(
+        EXTENSION_TIMER,
+        FUNCTION_TIMER_SET_TIMER,
+        stime_value as usize,
+    )
+}
+
 const FUNCTION_IPI_SEND_IPI: usize = 0x0;
 
 pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiRet {
@@ -170,6 +218,38 @@ pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiRet {
     )
 }
 
+const FUNCTION_RFENCE_REMOTE_FENCE_I: usize = 0x0;
+const FUNCTION_RFENCE_REMOTE_SFENCE_VMA: usize = 0x1;
+
+pub fn remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> SbiRet {
+    sbi_call_2(
+        EXTENSION_RFENCE,
+        FUNCTION_RFENCE_REMOTE_FENCE_I,
+        hart_mask,
+        hart_mask_base,
+    )
+}
+
+pub fn remote_sfence_vma(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start_addr: usize,
+    size: usize,
+) -> SbiRet {
+    sbi_call_4(
+        EXTENSION_RFENCE,
+        FUNCTION_RFENCE_REMOTE_SFENCE_VMA,
+        hart_mask,
+        hart_mask_base,
+        start_addr,
+        size,
+    )
+}
+
+pub const HSM_STATE_STARTED: usize = 0;
+pub const HSM_STATE_STOPPED: usize = 1;
+pub const HSM_STATE_SUSPENDED: usize = 4;
+
 const FUNCTION_HSM_HART_START: usize = 0x0;
 const FUNCTION_HSM_HART_STOP: usize = 0x1;
 const FUNCTION_HSM_HART_GET_STATUS: usize = 0x2;
@@ -203,6 +283,288 @@ pub fn hart_suspend(suspend_type: u32, resume_addr: usize, opaque: usize) -> Sbi
     )
 }
 
+pub const FWFT_MISALIGNED_EXC_DELEG: usize = 0x0;
+
+const FUNCTION_FWFT_SET: usize = 0x0;
+const FUNCTION_FWFT_GET: usize = 0x1;
+
+pub fn fwft_set(feature: usize, value: usize, flags: usize) -> SbiRet {
+    sbi_call_3(EXTENSION_FWFT, FUNCTION_FWFT_SET, feature, value, flags)
+}
+
+pub fn fwft_get(feature: usize) -> SbiRet {
+    sbi_call_1(EXTENSION_FWFT, FUNCTION_FWFT_GET, feature)
+}
+
+// Bits of build configuration bitmap, see `build_info` module of RustSBI-JH7100
+pub const BUILD_CONFIG_EXTENSIONS: [usize; 8] = [
+    EXTENSION_TIMER,
+    EXTENSION_IPI,
+    EXTENSION_RFENCE,
+    EXTENSION_HSM,
+    EXTENSION_SRST,
+    EXTENSION_PMU,
+    EXTENSION_DBCN,
+    EXTENSION_FWFT,
+];
+pub const BUILD_CONFIG_EMULATE_RDTIME: usize = 1 << 16;
+pub const BUILD_CONFIG_EMULATE_MISALIGNED: usize = 1 << 17;
+pub const BUILD_CONFIG_EMULATE_ZICOND: usize = 1 << 19;
+pub const BUILD_CONFIG_EMULATE_BITMANIP: usize = 1 << 23;
+pub const BUILD_CONFIG_FIXED_COUNTERS: usize = 1 << 24;
+pub const BUILD_CONFIG_EMULATE_SRET: usize = 1 << 25;
+pub const BUILD_CONFIG_IPI_DOORBELL: usize = 1 << 32;
+pub const BUILD_CONFIG_CHECKPOINT: usize = 1 << 37;
+pub const BUILD_CONFIG_MEMORY_FIXUP: usize = 1 << 38;
+pub const BUILD_CONFIG_ECALL_LATENCY: usize = 1 << 43;
+pub const BUILD_CONFIG_STACK_GUARD: usize = 1 << 47;
+pub const BUILD_CONFIG_FIRMWARE_TABLES: usize = 1 << 48;
+
+const FUNCTION_DUMP_MEMORY: usize = 0x1;
+
+// Hex dump memory range to firmware console, returns count of bytes dumped
+pub fn dump_memory(addr: usize, length: usize) -> SbiRet {
+    sbi_call_2(EXTENSION_DIAGNOSTICS, FUNCTION_DUMP_MEMORY, addr, length)
+}
+
+const FUNCTION_PRINT_TRAP_STATS: usize = 0x2;
+
+// Print trap counters of every hart to firmware console
+pub fn print_trap_stats() -> SbiRet {
+    sbi_call_0(EXTENSION_DIAGNOSTICS, FUNCTION_PRINT_TRAP_STATS)
+}
+
+const FUNCTION_PRINT_ECALL_LATENCY: usize = 0x3;
+
+// Print SBI call latency histograms of every hart to firmware console
+pub fn print_ecall_latency() -> SbiRet {
+    sbi_call_0(EXTENSION_DIAGNOSTICS, FUNCTION_PRINT_ECALL_LATENCY)
+}
+
+const FUNCTION_DUMP_PAGE_TABLES: usize = 0x4;
+const FUNCTION_SET_WATCHPOINT: usize = 0x5;
+const FUNCTION_CLEAR_WATCHPOINT: usize = 0x6;
+const FUNCTION_OVERFLOW_STACK: usize = 0x7;
+
+// Print the Sv39 page tables of `satp` to firmware console, 0 for the current satp; returns the
+// count of valid leaf entries
+pub fn dump_page_tables(satp: usize) -> SbiRet {
+    sbi_call_1(EXTENSION_DIAGNOSTICS, FUNCTION_DUMP_PAGE_TABLES, satp)
+}
+
+// Have firmware print the context of the first hart to reach `address`, once; returns the index
+// of the watchpoint
+pub fn set_watchpoint(address: usize) -> SbiRet {
+    sbi_call_1(EXTENSION_DIAGNOSTICS, FUNCTION_SET_WATCHPOINT, address)
+}
+
+pub fn clear_watchpoint(address: usize) -> SbiRet {
+    sbi_call_1(EXTENSION_DIAGNOSTICS, FUNCTION_CLEAR_WATCHPOINT, address)
+}
+
+// Have firmware recurse until its stack guard of the calling hart faults; returns only if the
+// guard does not catch it
+pub fn overflow_firmware_stack() -> SbiRet {
+    sbi_call_0(EXTENSION_DIAGNOSTICS, FUNCTION_OVERFLOW_STACK)
+}
+
+const FUNCTION_PRINT_MEMORY_MAP: usize = 0x8;
+
+// Print the memory map firmware knows of to firmware console
+pub fn print_memory_map() -> SbiRet {
+    sbi_call_0(EXTENSION_DIAGNOSTICS, FUNCTION_PRINT_MEMORY_MAP)
+}
+
+const FUNCTION_GET_BUILD_CONFIG: usize = 0x0;
+const FUNCTION_GET_TIMEBASE_FREQUENCY: usize = 0x1;
+
+pub fn get_build_config() -> SbiRet {
+    sbi_call_0(EXTENSION_BUILD_INFO, FUNCTION_GET_BUILD_CONFIG)
+}
+
+// Firmware's range of accepted `timebase-frequency`, and its value outside that range
+pub const TIMEBASE_FREQUENCY_RANGE: core::ops::RangeInclusive<u64> = 10_000..=1_000_000_000;
+pub const DEFAULT_TIMEBASE_FREQUENCY: u64 = 6_250_000;
+
+// mtime frequency firmware uses, in Hz
+pub fn get_timebase_frequency() -> SbiRet {
+    sbi_call_0(EXTENSION_BUILD_INFO, FUNCTION_GET_TIMEBASE_FREQUENCY)
+}
+
+const FUNCTION_SET_FIRMWARE_OUTPUT: usize = 0x0;
+
+// Enable or silence firmware's own console output, returns whether it was enabled
+pub fn set_firmware_output(enable: usize) -> SbiRet {
+    sbi_call_1(
+        EXTENSION_CONSOLE_CONTROL,
+        FUNCTION_SET_FIRMWARE_OUTPUT,
+        enable,
+    )
+}
+
+const FUNCTION_GET_CONSOLE_FALLBACK: usize = 0x1;
+
+// Whether firmware gave up on console UART and writes its output to the in-memory log
+pub fn get_console_fallback() -> SbiRet {
+    sbi_call_0(EXTENSION_CONSOLE_CONTROL, FUNCTION_GET_CONSOLE_FALLBACK)
+}
+
+const FUNCTION_GET_STARTED_HARTS: usize = 0x0;
+
+// Bitmask of harts in HSM started state, bit i for hart i
+pub fn get_started_harts() -> SbiRet {
+    sbi_call_0(EXTENSION_HART_MASK, FUNCTION_GET_STARTED_HARTS)
+}
+
+const FUNCTION_GET_MEDELEG: usize = 0x0;
+const FUNCTION_GET_MIDELEG: usize = 0x1;
+const FUNCTION_SET_MEDELEG: usize = 0x2;
+const FUNCTION_GET_MEDELEG_MUTABLE: usize = 0x3;
+
+// Exceptions firmware delegates to supervisor on the calling hart
+pub fn get_medeleg() -> SbiRet {
+    sbi_call_0(EXTENSION_DELEGATION, FUNCTION_GET_MEDELEG)
+}
+
+// Interrupts firmware delegates to supervisor on the calling hart
+pub fn get_mideleg() -> SbiRet {
+    sbi_call_0(EXTENSION_DELEGATION, FUNCTION_GET_MIDELEG)
+}
+
+// Change exception delegation of the calling hart, returns the previous one
+pub fn set_medeleg(value: usize) -> SbiRet {
+    sbi_call_1(EXTENSION_DELEGATION, FUNCTION_SET_MEDELEG, value)
+}
+
+// Exception delegation bits `set_medeleg` may change
+pub fn get_medeleg_mutable() -> SbiRet {
+    sbi_call_0(EXTENSION_DELEGATION, FUNCTION_GET_MEDELEG_MUTABLE)
+}
+
+const FUNCTION_GET_BOOTED_SLOT: usize = 0x0;
+const FUNCTION_GET_PRESENT_SLOTS: usize = 0x1;
+const FUNCTION_SET_NEXT_SLOT: usize = 0x2;
+const FUNCTION_CONFIRM_BOOT: usize = 0x3;
+
+// Payload slot booted this time, 0 for A and 1 for B
+pub fn get_booted_slot() -> SbiRet {
+    sbi_call_0(EXTENSION_PAYLOAD_SLOT, FUNCTION_GET_BOOTED_SLOT)
+}
+
+// Bitmask of payload slots in the image, bit 0 for A and 1 for B
+pub fn get_present_slots() -> SbiRet {
+    sbi_call_0(EXTENSION_PAYLOAD_SLOT, FUNCTION_GET_PRESENT_SLOTS)
+}
+
+// Payload slot to boot from the next warm reset on
+pub fn set_next_slot(slot: usize) -> SbiRet {
+    sbi_call_1(EXTENSION_PAYLOAD_SLOT, FUNCTION_SET_NEXT_SLOT, slot)
+}
+
+// Confirm this boot, so that firmware does not fall back to slot A
+pub fn confirm_boot() -> SbiRet {
+    sbi_call_0(EXTENSION_PAYLOAD_SLOT, FUNCTION_CONFIRM_BOOT)
+}
+
+const FUNCTION_FLUSH_DCACHE_RANGE: usize = 0x0;
+
+// Write back and invalidate cache lines over a physical range of DRAM
+pub fn flush_dcache_range(paddr: usize, len: usize) -> SbiRet {
+    sbi_call_2(EXTENSION_CACHE, FUNCTION_FLUSH_DCACHE_RANGE, paddr, len)
+}
+
+const FUNCTION_GET_TABLES_ADDRESS: usize = 0x0;
+const FUNCTION_GET_TABLES_LENGTH: usize = 0x1;
+
+// Physical address of firmware tables, 0 if the image has none
+pub fn get_firmware_tables_address() -> SbiRet {
+    sbi_call_0(EXTENSION_FIRMWARE_TABLES, FUNCTION_GET_TABLES_ADDRESS)
+}
+
+// Length of firmware tables in bytes, 0 if the image has none
+pub fn get_firmware_tables_length() -> SbiRet {
+    sbi_call_0(EXTENSION_FIRMWARE_TABLES, FUNCTION_GET_TABLES_LENGTH)
+}
+
+// Size of a supervisor checkpoint of RustSBI-JH7100 in bytes
+pub const CHECKPOINT_SIZE: usize = 352;
+
+const FUNCTION_CHECKPOINT_SAVE: usize = 0x0;
+const FUNCTION_CHECKPOINT_RESTORE: usize = 0x1;
+
+// Restore a checkpoint from `buf`; only returns on error
+pub fn checkpoint_restore(buf: &[u8; CHECKPOINT_SIZE]) -> SbiRet {
+    sbi_call_2(
+        EXTENSION_CHECKPOINT,
+        FUNCTION_CHECKPOINT_RESTORE,
+        buf.as_ptr() as usize,
+        CHECKPOINT_SIZE,
+    )
+}
+
+// Save a checkpoint into `buf` with `marker` in t3 and sscratch, clear both, then restore it.
+//
+// Returns what the save call returns the second time, after the restore, or the error of either
+// call; and t3 and sscratch then. All in one asm block, since a restore brings back registers
+// but not memory, e.g. the stack.
+pub fn checkpoint_round_trip(
+    buf: &mut [u8; CHECKPOINT_SIZE],
+    marker: usize,
+) -> (SbiRet, usize, usize) {
+    let (error, value, t3, sscratch);
+    unsafe {
+        asm!(
+            "csrw   sscratch, {marker}",
+            "mv     t3, {marker}",
+            "mv     a0, {buf}",
+            "li     a1, {size}",
+            "li     a6, {save}",
+            "li     a7, {extension}",
+            "ecall",
+            // error, or returning again after the restore
+            "bnez   a0, 1f",
+            "bnez   a1, 1f",
+            "li     t3, 0",
+            "csrw   sscratch, zero",
+            "mv     a0, {buf}",
+            "li     a1, {size}",
+            "li     a6, {restore}",
+            "li     a7, {extension}",
+            "ecall",
+            "1:",
+            "csrr   {sscratch}, sscratch",
+            marker = in(reg) marker,
+            buf = in(reg) buf.as_mut_ptr(),
+            size = const CHECKPOINT_SIZE,
+            save = const FUNCTION_CHECKPOINT_SAVE,
+            restore = const FUNCTION_CHECKPOINT_RESTORE,
+            extension = const EXTENSION_CHECKPOINT,
+            sscratch = lateout(reg) sscratch,
+            out("a0") error, out("a1") value, out("a6") _, out("a7") _, out("t3") t3,
+        )
+    };
+    (SbiRet { error, value }, t3, sscratch)
+}
+
+const FUNCTION_EXAMPLE_HART_ID: usize = 0x0;
+const FUNCTION_EXAMPLE_ADD: usize = 0x1;
+const FUNCTION_EXAMPLE_UNKNOWN: usize = 0x2;
+
+// Hart id of the caller, as the example extension sees it
+pub fn example_hart_id() -> SbiRet {
+    sbi_call_0(EXTENSION_EXAMPLE, FUNCTION_EXAMPLE_HART_ID)
+}
+
+// Wrapping sum of `a` and `b`, computed by the example extension
+pub fn example_add(a: usize, b: usize) -> SbiRet {
+    sbi_call_2(EXTENSION_EXAMPLE, FUNCTION_EXAMPLE_ADD, a, b)
+}
+
+// A function id the example extension does not know
+pub fn example_unknown() -> SbiRet {
+    sbi_call_0(EXTENSION_EXAMPLE, FUNCTION_EXAMPLE_UNKNOWN)
+}
+
 #[inline(always)]
 fn sbi_call_0(extension: usize, function: usize) -> SbiRet {
     let (error, value);
@@ -257,3 +619,24 @@ fn sbi_call_3(extension: usize, function: usize, arg0: usize, arg1: usize, arg2:
     };
     SbiRet { error, value }
 }
+
+#[inline(always)]
+fn sbi_call_4(
+    extension: usize,
+    function: usize,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+) -> SbiRet {
+    let (error, value);
+    unsafe {
+        asm!(
+            "ecall",
+            in("a0") arg0, in("a1") arg1, in("a2") arg2, in("a3") arg3,
+            in("a6") function, in("a7") extension,
+            lateout("a0") error, lateout("a1") value,
+        )
+    };
+    SbiRet { error, value }
+}
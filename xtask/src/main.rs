@@ -1,8 +1,12 @@
 use std::fmt;
 use std::{
-    env,
+    env, fs,
+    io::{BufRead, BufReader, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
-    process::{self, Command},
+    process::{self, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
 };
 
 use clap::{clap_app, crate_authors, crate_description, crate_version};
@@ -10,6 +14,8 @@ use clap::{clap_app, crate_authors, crate_description, crate_version};
 #[derive(Debug)]
 struct XtaskEnv {
     compile_mode: CompileMode,
+    // Cargo features SBI is built with besides its defaults
+    sbi_features: Vec<&'static str>,
 }
 
 #[derive(Debug)]
@@ -29,6 +35,27 @@ impl fmt::Display for CompileMode {
 
 const DEFAULT_TARGET: &'static str = "riscv64imac-unknown-none-elf";
 
+// Where the payload is written into the SD card image behind SBI binary, and the alignment of
+// that offset if SBI binary has grown past it
+const PAYLOAD_IMAGE_OFFSET: u64 = 0x2_0000;
+const PAYLOAD_IMAGE_ALIGN: u64 = 0x2_0000;
+
+// Where an initramfs is written into the SD card image, and its header magic; must match
+// `IMAGE_OFFSET` and `IMAGE_MAGIC` of module `initramfs` in SBI
+const INITRAMFS_IMAGE_OFFSET: u64 = 0x400_0000;
+const INITRAMFS_IMAGE_MAGIC: &[u8; 8] = b"RSBIINRD";
+
+// Where payload slot B is written into the SD card image, and its header magic; must match
+// `SLOT_B_IMAGE_OFFSET` and `SLOT_B_MAGIC` of module `payload_slot` in SBI
+const SLOT_B_IMAGE_OFFSET: u64 = 0x200_0000;
+const SLOT_B_IMAGE_MAGIC: &[u8; 8] = b"RSBISLTB";
+
+// Where firmware tables are written into the SD card image, their header magic and largest
+// length; must match `IMAGE_OFFSET`, `IMAGE_MAGIC` and `MAX_LEN` of module `firmware_tables` in SBI
+const TABLES_IMAGE_OFFSET: u64 = 0x3F0_0000;
+const TABLES_IMAGE_MAGIC: &[u8; 8] = b"RSBITABL";
+const TABLES_MAX_LEN: u64 = 0x1_0000;
+
 fn main() {
     let matches = clap_app!(xtask =>
         (version: crate_version!())
@@ -45,15 +72,25 @@ fn main() {
         (@subcommand image =>
             (about: "Build SD card partition image")
             (@arg PAYLOAD: "Set the build payload, may be 'test-kernel'")
+            (@arg initramfs: --initramfs +takes_value "Initramfs file to pass to the payload, builds SBI with feature 'initramfs'")
+            (@arg slot_b: --("slot-b") +takes_value "Raw binary payload for slot B, builds SBI with feature 'payload-slots'")
+            (@arg tables: --tables +takes_value "Firmware tables file to pass to the payload, builds SBI with feature 'firmware-tables'")
             (@arg release: --release "Build artifacts in release mode, with optimizations")
         )
         (@subcommand gdb =>
             (about: "Run GDB debugger")
         )
+        (@subcommand test =>
+            (about: "Run test kernel image in QEMU, fails unless test kernel reports success")
+            (@arg machine: --machine +takes_value "QEMU machine to run on, required; it must model JH7100's UART and CLINT")
+            (@arg timeout: --timeout +takes_value "Seconds to wait for test result, 60 by default")
+            (@arg release: --release "Build artifacts in release mode, with optimizations")
+        )
     )
     .get_matches();
     let mut xtask_env = XtaskEnv {
         compile_mode: CompileMode::Debug,
+        sbi_features: Vec::new(),
     };
     if let Some(matches) = matches.subcommand_matches("make") {
         if matches.is_present("release") {
@@ -73,14 +110,100 @@ fn main() {
         if matches.is_present("release") {
             xtask_env.compile_mode = CompileMode::Release;
         }
+        let initramfs = matches.value_of("initramfs");
+        if initramfs.is_some() {
+            xtask_env.sbi_features.push("initramfs");
+        }
+        let slot_b = matches.value_of("slot_b");
+        if slot_b.is_some() {
+            xtask_env.sbi_features.push("payload-slots");
+        }
+        let tables = matches.value_of("tables");
+        if let Some(tables) = tables {
+            match fs::metadata(tables) {
+                Ok(metadata) if metadata.len() > TABLES_MAX_LEN => {
+                    eprintln!(
+                        "firmware tables {} of {} bytes exceed {} bytes",
+                        tables,
+                        metadata.len(),
+                        TABLES_MAX_LEN
+                    );
+                    process::exit(1);
+                }
+                _ => {} // other errors are reported when it is written
+            }
+            xtask_env.sbi_features.push("firmware-tables");
+        }
         eprintln!("xtask image: mode: {:?}", xtask_env.compile_mode);
         xtask_build_sbi(&xtask_env);
         xtask_binary_sbi(&xtask_env);
-        if matches.value_of("PAYLOAD") == Some("test-kernel") {
+        let image = if matches.value_of("PAYLOAD") == Some("test-kernel") {
             xtask_build_test_kernel(&xtask_env);
             xtask_binary_test_kernel(&xtask_env);
             xtask_image(&xtask_env);
+            "test-kernel.image"
+        } else {
+            "rustsbi-jh7100.bin"
+        };
+        // in offset order: slot B must end before the tables, the tables before the initramfs
+        if let Some(slot_b) = slot_b {
+            xtask_image_blob(
+                &xtask_env,
+                image,
+                "payload slot B",
+                Path::new(slot_b),
+                SLOT_B_IMAGE_OFFSET,
+                SLOT_B_IMAGE_MAGIC,
+            );
         }
+        if let Some(tables) = tables {
+            xtask_image_blob(
+                &xtask_env,
+                image,
+                "firmware tables",
+                Path::new(tables),
+                TABLES_IMAGE_OFFSET,
+                TABLES_IMAGE_MAGIC,
+            );
+        }
+        if let Some(initramfs) = initramfs {
+            xtask_image_blob(
+                &xtask_env,
+                image,
+                "initramfs",
+                Path::new(initramfs),
+                INITRAMFS_IMAGE_OFFSET,
+                INITRAMFS_IMAGE_MAGIC,
+            );
+        }
+    } else if let Some(matches) = matches.subcommand_matches("test") {
+        if matches.is_present("release") {
+            xtask_env.compile_mode = CompileMode::Release;
+        }
+        // upstream QEMU has no JH7100 machine, and on any of its machines firmware finds no
+        // console UART; there is no default which could pass
+        let machine = match matches.value_of("machine") {
+            Some(machine) => machine,
+            None => {
+                eprintln!("xtask test: --machine is required, a QEMU machine modelling JH7100");
+                process::exit(1);
+            }
+        };
+        let timeout = match matches.value_of("timeout").map(str::parse) {
+            None => 60,
+            Some(Ok(seconds)) => seconds,
+            Some(Err(_)) => {
+                eprintln!("xtask test: timeout should be a number of seconds");
+                process::exit(1);
+            }
+        };
+        eprintln!("xtask test: mode: {:?}", xtask_env.compile_mode);
+        xtask_build_sbi(&xtask_env);
+        xtask_binary_sbi(&xtask_env);
+        xtask_build_test_kernel(&xtask_env);
+        xtask_binary_test_kernel(&xtask_env);
+        xtask_image(&xtask_env);
+        xtask_qemu_test(&xtask_env, machine, Duration::from_secs(timeout));
     } else if let Some(_matches) = matches.subcommand_matches("gdb") {
         eprintln!("xtask gdb: mode: {:?}", xtask_env.compile_mode);
         xtask_build_sbi(&xtask_env);
@@ -104,6 +227,9 @@ fn xtask_build_sbi(xtask_env: &XtaskEnv) {
     }
     command.args(&["--package", "rustsbi-jh7100"]);
     command.args(&["--target", DEFAULT_TARGET]);
+    if !xtask_env.sbi_features.is_empty() {
+        command.args(&["--features", &xtask_env.sbi_features.join(",")]);
+    }
     let status = command.status().unwrap();
     if !status.success() {
         eprintln!("cargo build failed");
@@ -159,6 +285,8 @@ fn xtask_unmatched_gdb(xtask_env: &XtaskEnv) {
     }
 }
 
+// Place test kernel behind SBI in one image, at `PAYLOAD_IMAGE_OFFSET` unless SBI binary reaches
+// past it, then at the next aligned offset after it; the offset chosen is printed
 fn xtask_image(xtask_env: &XtaskEnv) {
     let path_buf = project_root().join("target/riscv64imac-unknown-none-elf/");
     let path_buf = match xtask_env.compile_mode {
@@ -166,6 +294,21 @@ fn xtask_image(xtask_env: &XtaskEnv) {
         CompileMode::Release => path_buf.join("release"),
     };
 
+    let sbi_size = match fs::metadata(path_buf.join("rustsbi-jh7100.bin")) {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            eprintln!("mkimage: read size of rustsbi-jh7100.bin: {}", e);
+            process::exit(1);
+        }
+    };
+    let offset = payload_image_offset(sbi_size);
+    if offset != PAYLOAD_IMAGE_OFFSET {
+        eprintln!(
+            "xtask image: warning: SBI binary of {:#x} bytes overlaps payload at fixed offset {:#x}, payload moved to {:#x}",
+            sbi_size, PAYLOAD_IMAGE_OFFSET, offset
+        );
+    }
+
     let mut command = Command::new("cp");
     command.current_dir(&path_buf);
     command.arg("rustsbi-jh7100.bin");
@@ -180,14 +323,154 @@ fn xtask_image(xtask_env: &XtaskEnv) {
     command.current_dir(&path_buf);
     command.arg("if=test-kernel.bin");
     command.arg("of=test-kernel.image");
-    command.arg("bs=128k");
-    command.arg("seek=1");
-    
+    command.arg(format!("bs={}", PAYLOAD_IMAGE_ALIGN));
+    command.arg(format!("seek={}", offset / PAYLOAD_IMAGE_ALIGN));
+
     let status = command.status().expect("xtask_image");
     if !status.success() {
         eprintln!("mkimage failed with status {}", status);
         process::exit(status.code().unwrap_or(1));
     }
+    eprintln!(
+        "xtask image: SBI {:#x} bytes, payload at offset {:#x} of test-kernel.image",
+        sbi_size, offset
+    );
+}
+
+// First offset at or after `PAYLOAD_IMAGE_OFFSET` that is aligned and past `sbi_size` bytes
+fn payload_image_offset(sbi_size: u64) -> u64 {
+    let past_sbi = sbi_size.div_ceil(PAYLOAD_IMAGE_ALIGN) * PAYLOAD_IMAGE_ALIGN;
+    past_sbi.max(PAYLOAD_IMAGE_OFFSET)
+}
+
+// Write file behind its 16-byte header, `magic` then its length as a little endian u64, at
+// `offset` of the image, which is extended as needed; the payload keeps its own place before it
+fn xtask_image_blob(
+    xtask_env: &XtaskEnv,
+    image: &str,
+    name: &str,
+    file: &Path,
+    offset: u64,
+    magic: &[u8; 8],
+) {
+    let data = match fs::read(file) {
+        Ok(data) if !data.is_empty() => data,
+        Ok(_) => {
+            eprintln!("{} {} is empty", name, file.display());
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("read {} {}: {}", name, file.display(), e);
+            process::exit(1);
+        }
+    };
+    let path = dist_dir(xtask_env).join(image);
+    let result = fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .and_then(|mut file| {
+            if file.metadata()?.len() > offset {
+                return Err(std::io::Error::other(format!(
+                    "image already reaches {} offset",
+                    name
+                )));
+            }
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(magic)?;
+            file.write_all(&(data.len() as u64).to_le_bytes())?;
+            file.write_all(&data)
+        });
+    if let Err(e) = result {
+        eprintln!("write {} into {}: {}", name, path.display(), e);
+        process::exit(1);
+    }
+    eprintln!(
+        "xtask image: {} of {} bytes at offset {:#x} of {}",
+        name,
+        data.len(),
+        offset,
+        image
+    );
+}
+
+// Printed by test kernel when all tests passed, or when any of them failed
+const TEST_SUCCESS: &str = "SBI test SUCCESS";
+const TEST_FAILED: &str = "SBI test FAILED";
+// Printed by SBI when the system is reset, followed by the reset reason, see `reset` of SBI
+const SYSTEM_HALTED: &str = "[rustsbi] system halted: code=";
+
+// Boot test kernel image in QEMU and watch its serial output. Exits with zero if the success
+// sentinel is printed, then SBI halts with reason code 0; otherwise, on a failure marker, on a
+// halt with any other code or before the success sentinel, on timeout, or if QEMU exits early,
+// prints the captured output and exits with non-zero.
+fn xtask_qemu_test(xtask_env: &XtaskEnv, machine: &str, timeout: Duration) {
+    let mut command = Command::new("qemu-system-riscv64");
+    command.current_dir(dist_dir(xtask_env));
+    command.args(["-machine", machine]);
+    command.args(["-bios", "test-kernel.image"]);
+    command.args(["-smp", "2"]);
+    command.arg("-nographic");
+    command.stdin(Stdio::null());
+    command.stdout(Stdio::piped());
+    let mut child = command.spawn().expect("run qemu");
+
+    let stdout = child.stdout.take().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let sent = line.map(|line| sender.send(line).is_ok());
+            if sent.ok() != Some(true) {
+                break;
+            }
+        }
+    });
+
+    let deadline = Instant::now() + timeout;
+    let mut log = Vec::new();
+    let mut passed = false;
+    let result = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(remaining) {
+            Ok(line) => {
+                passed |= line.contains(TEST_SUCCESS);
+                let failed = line.contains(TEST_FAILED);
+                let halted_ok = halt_code(&line).map(|code| code == "0");
+                log.push(line);
+                if failed {
+                    break Err("test kernel reported failure");
+                }
+                match halted_ok {
+                    Some(true) if passed => break Ok(()),
+                    Some(true) => break Err("system halted before test kernel reported success"),
+                    Some(false) => break Err("system halted with failure code"),
+                    None => {}
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => break Err("timed out"),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break Err("qemu exited without result"),
+        }
+    };
+    child.kill().ok();
+    child.wait().ok();
+
+    match result {
+        Ok(()) => eprintln!("xtask test: passed"),
+        Err(reason) => {
+            for line in &log {
+                println!("{}", line);
+            }
+            eprintln!("xtask test: {}", reason);
+            process::exit(1);
+        }
+    }
+}
+
+// Reset reason code of the system halted sentinel in `line`, if it is one
+fn halt_code(line: &str) -> Option<&str> {
+    let start = line.find(SYSTEM_HALTED)? + SYSTEM_HALTED.len();
+    let rest = &line[start..];
+    let end = rest.find(',').unwrap_or(rest.len());
+    Some(&rest[..end])
 }
 
 fn xtask_build_test_kernel(xtask_env: &XtaskEnv) {